@@ -0,0 +1,640 @@
+use std::collections::HashMap;
+use std::io::Read;
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::sync::Arc;
+
+use dbus::arg::{PropMap, RefArg, Variant};
+use dbus::message::MatchRule;
+use dbus::nonblock::SyncConnection;
+use dbus_crossroads::Crossroads;
+use futures::StreamExt;
+
+use crate::bluez::{self, DBUS_TIMEOUT, RETRY_DELAY};
+use crate::sbc;
+use crate::MessageFromAsync;
+
+pub const A2DP_SINK_UUID: &str = "0000110b-0000-1000-8000-00805f9b34fb";
+
+/// Where the sbc media endpoint lives on the bus
+const ENDPOINT_PATH: &str = "/radio/a2dp/sbc";
+
+/// The A2DP codec id for sbc
+const SBC_CODEC: u8 = 0;
+
+/// Every sbc setting, with bitpools from 2 to 53
+const SBC_CAPABILITIES: [u8; 4] = [0xff, 0xff, 2, 53];
+
+/// A phone currently playing audio through the radio
+#[derive(Clone, Debug)]
+pub struct AudioStream {
+    pub device: bluer::Address,
+    pub rate: u32,
+    pub channels: u16,
+}
+
+#[derive(Debug)]
+pub enum A2dpError {
+    Dbus(String),
+    Bluetooth(String),
+}
+
+impl std::fmt::Display for A2dpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            A2dpError::Dbus(e) => write!(f, "Dbus error: {}", e),
+            A2dpError::Bluetooth(e) => write!(f, "Bluetooth error: {}", e),
+        }
+    }
+}
+
+impl From<dbus::Error> for A2dpError {
+    fn from(e: dbus::Error) -> Self {
+        A2dpError::Dbus(e.to_string())
+    }
+}
+
+impl From<bluer::Error> for A2dpError {
+    fn from(e: bluer::Error) -> Self {
+        A2dpError::Bluetooth(e.to_string())
+    }
+}
+
+/// Calls from bluez to the media endpoint
+enum EndpointEvent {
+    /// A transport was configured, with the device, codec configuration and transport state
+    SetConfiguration(dbus::Path<'static>, bluer::Address, Vec<u8>, Option<String>),
+    ClearConfiguration(dbus::Path<'static>),
+}
+
+/// A configured transport from a phone
+struct Transport {
+    device: bluer::Address,
+    rate: u32,
+    channels: u16,
+    playing: bool,
+}
+
+/// Pick the sbc settings to use from the capabilities of the remote device
+fn select_configuration(caps: &[u8]) -> Option<Vec<u8>> {
+    if caps.len() < 4 {
+        return None;
+    }
+    let pick = |bits: u8, prefs: &[u8]| prefs.iter().copied().find(|p| bits & p != 0);
+    let rate = pick(caps[0] & 0xf0, &[0x20, 0x10, 0x40, 0x80])?;
+    let mode = pick(caps[0] & 0x0f, &[0x01, 0x02, 0x04, 0x08])?;
+    let blocks = pick(caps[1] & 0xf0, &[0x10, 0x20, 0x40, 0x80])?;
+    let subbands = pick(caps[1] & 0x0c, &[0x04, 0x08])?;
+    let alloc = pick(caps[1] & 0x03, &[0x01, 0x02])?;
+    let min = caps[2].max(SBC_CAPABILITIES[2]);
+    let max = caps[3].min(SBC_CAPABILITIES[3]);
+    if min > max {
+        return None;
+    }
+    Some(vec![rate | mode, blocks | subbands | alloc, min, max])
+}
+
+/// The sample rate and channel count of an sbc configuration
+fn configuration_format(config: &[u8]) -> (u32, u16) {
+    let rate = match config.first().map(|c| c & 0xf0) {
+        Some(0x80) => 16000,
+        Some(0x40) => 32000,
+        Some(0x10) => 48000,
+        _ => 44100,
+    };
+    let channels = match config.first().map(|c| c & 0x0f) {
+        Some(0x08) => 1,
+        _ => 2,
+    };
+    (rate, channels)
+}
+
+/// Where decoded audio goes
+trait Output {
+    fn write(&mut self, rate: u32, channels: usize, samples: &[i16]);
+}
+
+/// The default sound card
+struct Alsa {
+    pcm: Option<(alsa::PCM, u32, usize)>,
+}
+
+impl Alsa {
+    fn new() -> Self {
+        Self { pcm: None }
+    }
+
+    fn open(rate: u32, channels: usize) -> Result<alsa::PCM, alsa::Error> {
+        let pcm = alsa::PCM::new("default", alsa::Direction::Playback, false)?;
+        {
+            let hwp = alsa::pcm::HwParams::any(&pcm)?;
+            hwp.set_channels(channels as u32)?;
+            hwp.set_rate(rate, alsa::ValueOr::Nearest)?;
+            hwp.set_format(alsa::pcm::Format::s16())?;
+            hwp.set_access(alsa::pcm::Access::RWInterleaved)?;
+            pcm.hw_params(&hwp)?;
+        }
+        Ok(pcm)
+    }
+
+    fn play(&mut self, rate: u32, channels: usize, samples: &[i16]) -> Result<(), alsa::Error> {
+        if !matches!(&self.pcm, Some((_, r, c)) if *r == rate && *c == channels) {
+            self.pcm = Some((Self::open(rate, channels)?, rate, channels));
+        }
+        let Some((pcm, _, _)) = &self.pcm else {
+            return Ok(());
+        };
+        let io = pcm.io_i16()?;
+        if let Err(e) = io.writei(samples) {
+            pcm.try_recover(e, true)?;
+            io.writei(samples)?;
+        }
+        Ok(())
+    }
+}
+
+impl Output for Alsa {
+    fn write(&mut self, rate: u32, channels: usize, samples: &[i16]) {
+        if let Err(e) = self.play(rate, channels, samples) {
+            println!("Failed to play audio {}", e);
+        }
+    }
+}
+
+/// Decodes the sbc frames of a transport and plays them
+struct Player<O: Output> {
+    decoder: sbc::Decoder,
+    output: O,
+    /// The sequence number of the last packet played
+    seq: Option<u16>,
+    /// The start of a frame too big for one packet
+    fragment: Option<Vec<u8>>,
+}
+
+impl<O: Output> Player<O> {
+    fn new(output: O) -> Self {
+        Self {
+            decoder: sbc::Decoder::new(),
+            output,
+            seq: None,
+            fragment: None,
+        }
+    }
+
+    /// Play every complete sbc frame in data
+    fn play_frames(&mut self, data: &[u8]) {
+        let mut used = 0;
+        while used < data.len() {
+            match self.decoder.decode(&data[used..]) {
+                Ok((h, samples)) => {
+                    used += h.frame_length();
+                    self.output.write(h.rate, h.channels(), &samples);
+                }
+                Err(sbc::SbcError::Short) => break,
+                Err(e) => {
+                    println!("Dropping bad sbc data {}", e);
+                    used += 1;
+                }
+            }
+        }
+    }
+
+    /// Play the media payload of an rtp packet from a transport
+    fn play_packet(&mut self, packet: &[u8]) {
+        if packet.len() < 13 {
+            return;
+        }
+        let seq = u16::from_be_bytes([packet[2], packet[3]]);
+        if let Some(last) = self.seq {
+            match seq.wrapping_sub(last) {
+                // Repeated, or too late to be played
+                0 | 0x8000..=0xffff => return,
+                1 => {}
+                // Packets were lost, so the rest of a split frame will not come
+                _ => self.fragment = None,
+            }
+        }
+        self.seq = Some(seq);
+        let csrc = (packet[0] & 0x0f) as usize;
+        let start = 12 + 4 * csrc + 1;
+        if packet.len() <= start {
+            return;
+        }
+        // The sbc payload header, saying whether the packet holds part of a frame
+        let header = packet[start - 1];
+        let payload = &packet[start..];
+        if header & 0x80 == 0 {
+            self.fragment = None;
+            self.play_frames(payload);
+            return;
+        }
+        if header & 0x40 != 0 {
+            self.fragment = Some(payload.to_vec());
+        } else if let Some(f) = &mut self.fragment {
+            f.extend_from_slice(payload);
+        }
+        if header & 0x20 != 0 {
+            if let Some(f) = self.fragment.take() {
+                self.play_frames(&f);
+            }
+        }
+    }
+}
+
+/// Read packets from an acquired transport until the phone stops streaming
+async fn play_transport<O: Output>(sock: std::fs::File, mtu: u16, player: &mut Player<O>) {
+    unsafe {
+        let flags = libc::fcntl(sock.as_raw_fd(), libc::F_GETFL);
+        libc::fcntl(sock.as_raw_fd(), libc::F_SETFL, flags | libc::O_NONBLOCK);
+    }
+    let sock = match tokio::io::unix::AsyncFd::new(sock) {
+        Ok(s) => s,
+        Err(e) => {
+            println!("Unable to wait for audio {}", e);
+            return;
+        }
+    };
+    let mut buf = vec![0u8; mtu.max(1024) as usize];
+    loop {
+        let mut ready = match sock.readable().await {
+            Ok(r) => r,
+            Err(e) => {
+                println!("Audio transport closed {}", e);
+                break;
+            }
+        };
+        match ready.try_io(|s| s.get_ref().read(&mut buf)) {
+            Ok(Ok(0)) => break,
+            Ok(Ok(n)) => player.play_packet(&buf[..n]),
+            Ok(Err(e)) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Ok(Err(e)) => {
+                println!("Audio transport closed {}", e);
+                break;
+            }
+            // Woken without a packet waiting after all
+            Err(_) => {}
+        }
+    }
+}
+
+/// Serve the media endpoint interface, passing every call on to the sink task
+fn endpoint(conn: &Arc<SyncConnection>, events: tokio::sync::mpsc::UnboundedSender<EndpointEvent>) {
+    let mut cr = Crossroads::new();
+    let iface = cr.register(
+        "org.bluez.MediaEndpoint1",
+        |b: &mut dbus_crossroads::IfaceBuilder<
+            tokio::sync::mpsc::UnboundedSender<EndpointEvent>,
+        >| {
+            b.method(
+                "SetConfiguration",
+                ("transport", "properties"),
+                (),
+                |_, events, (transport, props): (dbus::Path<'static>, PropMap)| {
+                    let device = dbus::arg::prop_cast::<dbus::Path<'static>>(&props, "Device")
+                        .and_then(bluez::path_address)
+                        .ok_or_else(|| dbus::MethodErr::invalid_arg("properties"))?;
+                    let config = dbus::arg::prop_cast::<Vec<u8>>(&props, "Configuration")
+                        .cloned()
+                        .unwrap_or_default();
+                    let state = dbus::arg::prop_cast::<String>(&props, "State").cloned();
+                    let _ = events.send(EndpointEvent::SetConfiguration(
+                        transport, device, config, state,
+                    ));
+                    Ok(())
+                },
+            );
+            b.method(
+                "SelectConfiguration",
+                ("capabilities",),
+                ("configuration",),
+                |_, _, (caps,): (Vec<u8>,)| match select_configuration(&caps) {
+                    Some(c) => Ok((c,)),
+                    None => Err(dbus::MethodErr::invalid_arg("capabilities")),
+                },
+            );
+            b.method(
+                "ClearConfiguration",
+                ("transport",),
+                (),
+                |_, events, (transport,): (dbus::Path<'static>,)| {
+                    let _ = events.send(EndpointEvent::ClearConfiguration(transport));
+                    Ok(())
+                },
+            );
+            b.method("Release", (), (), |_, _, _: ()| Ok(()));
+        },
+    );
+    cr.insert(ENDPOINT_PATH, &[iface], events);
+    conn.start_receive(
+        MatchRule::new_method_call(),
+        Box::new(move |msg, conn| {
+            let _ = cr.handle_message(msg, conn);
+            true
+        }),
+    );
+}
+
+fn endpoint_properties() -> PropMap {
+    let mut props: PropMap = HashMap::new();
+    props.insert(
+        "UUID".to_string(),
+        Variant(Box::new(A2DP_SINK_UUID.to_string()) as Box<dyn RefArg>),
+    );
+    props.insert(
+        "Codec".to_string(),
+        Variant(Box::new(SBC_CODEC) as Box<dyn RefArg>),
+    );
+    props.insert(
+        "Capabilities".to_string(),
+        Variant(Box::new(SBC_CAPABILITIES.to_vec()) as Box<dyn RefArg>),
+    );
+    props
+}
+
+/// Register the sbc endpoint with the media interface of an adapter
+async fn register_endpoint(conn: &Arc<SyncConnection>, adapter: &str) -> Result<(), A2dpError> {
+    let proxy = dbus::nonblock::Proxy::new(
+        "org.bluez",
+        format!("/org/bluez/{}", adapter),
+        DBUS_TIMEOUT,
+        conn.clone(),
+    );
+    proxy
+        .method_call::<(), _, _, _>(
+            "org.bluez.Media1",
+            "RegisterEndpoint",
+            (dbus::Path::from(ENDPOINT_PATH), endpoint_properties()),
+        )
+        .await?;
+    println!("Registered audio sink on {}", adapter);
+    Ok(())
+}
+
+/// Take the audio socket for a transport that has audio waiting and start playing it
+async fn acquire(
+    conn: &Arc<SyncConnection>,
+    path: &dbus::Path<'static>,
+    transport: &Transport,
+    tx: &tokio::sync::mpsc::Sender<MessageFromAsync>,
+) -> Result<(), A2dpError> {
+    let proxy = dbus::nonblock::Proxy::new("org.bluez", path.clone(), DBUS_TIMEOUT, conn.clone());
+    let (fd, read_mtu, _write_mtu): (dbus::arg::OwnedFd, u16, u16) = proxy
+        .method_call("org.bluez.MediaTransport1", "TryAcquire", ())
+        .await?;
+    let sock = unsafe { std::fs::File::from_raw_fd(fd.into_fd()) };
+    println!("Streaming audio from {}", transport.device);
+    let _ = tx
+        .send(MessageFromAsync::AudioStream(Some(AudioStream {
+            device: transport.device,
+            rate: transport.rate,
+            channels: transport.channels,
+        })))
+        .await;
+    let tx2 = tx.clone();
+    let runtime = tokio::runtime::Handle::current();
+    // Writing to the sound card blocks, so the transport gets a thread of its own
+    tokio::task::spawn_blocking(move || {
+        let mut player = Player::new(Alsa::new());
+        runtime.block_on(play_transport(sock, read_mtu, &mut player));
+        let _ = tx2.blocking_send(MessageFromAsync::AudioStream(None));
+    });
+    Ok(())
+}
+
+/// Read the new state of a transport from a PropertiesChanged signal
+fn transport_changed(msg: &dbus::Message) -> Option<(dbus::Path<'static>, String)> {
+    let (iface, props, _): (String, PropMap, Vec<String>) = msg.read3().ok()?;
+    if iface != "org.bluez.MediaTransport1" {
+        return None;
+    }
+    let state = dbus::arg::prop_cast::<String>(&props, "State")?.clone();
+    Some((msg.path()?.into_static(), state))
+}
+
+/// Track the state of a transport, playing it when the phone starts sending audio
+async fn transport_state(
+    conn: &Arc<SyncConnection>,
+    path: &dbus::Path<'static>,
+    transport: &mut Transport,
+    state: &str,
+    tx: &tokio::sync::mpsc::Sender<MessageFromAsync>,
+) {
+    match state {
+        "pending" if !transport.playing => match acquire(conn, path, transport, tx).await {
+            Ok(()) => transport.playing = true,
+            Err(e) => println!("Failed to acquire audio from {} {}", transport.device, e),
+        },
+        "idle" => transport.playing = false,
+        _ => {}
+    }
+}
+
+/// Run the audio sink until the connection to bluez is lost
+async fn sink_session(tx: &tokio::sync::mpsc::Sender<MessageFromAsync>) -> Result<(), A2dpError> {
+    let (resource, conn) = dbus_tokio::connection::new_system_sync()?;
+    let mut lost = tokio::spawn(resource);
+
+    let (events_tx, mut events) = tokio::sync::mpsc::unbounded_channel();
+    endpoint(&conn, events_tx);
+
+    let rule = MatchRule::new_signal("org.freedesktop.DBus.Properties", "PropertiesChanged");
+    let (_changes_match, mut changes) = conn.add_match(rule).await?.msg_stream();
+
+    let session = bluer::Session::new().await?;
+    let mut session_events = Box::pin(session.events().await?);
+    for name in session.adapter_names().await? {
+        if let Err(e) = register_endpoint(&conn, &name).await {
+            println!("Failed to register audio sink on {} {}", name, e);
+        }
+    }
+
+    let mut transports: HashMap<dbus::Path<'static>, Transport> = HashMap::new();
+    let r = loop {
+        tokio::select! {
+            e = events.recv() => {
+                match e {
+                    Some(EndpointEvent::SetConfiguration(path, device, config, state)) => {
+                        let (rate, channels) = configuration_format(&config);
+                        println!("Audio configured for {} {} Hz", device, rate);
+                        let mut t = Transport {
+                            device,
+                            rate,
+                            channels,
+                            playing: false,
+                        };
+                        if let Some(state) = state {
+                            transport_state(&conn, &path, &mut t, &state, tx).await;
+                        }
+                        transports.insert(path, t);
+                    }
+                    Some(EndpointEvent::ClearConfiguration(path)) => {
+                        transports.remove(&path);
+                    }
+                    None => break Ok(()),
+                }
+            }
+            Some(msg) = changes.next() => {
+                let Some((path, state)) = transport_changed(&msg) else {
+                    continue;
+                };
+                if let Some(t) = transports.get_mut(&path) {
+                    transport_state(&conn, &path, t, &state, tx).await;
+                }
+            }
+            e = session_events.next() => {
+                match e {
+                    Some(bluer::SessionEvent::AdapterAdded(name)) => {
+                        if let Err(e) = register_endpoint(&conn, &name).await {
+                            println!("Failed to register audio sink on {} {}", name, e);
+                        }
+                    }
+                    Some(bluer::SessionEvent::AdapterRemoved(_)) => {}
+                    None => break Err(A2dpError::Bluetooth("Session closed".to_string())),
+                }
+            }
+            _ = &mut lost => {
+                break Err(A2dpError::Dbus("Connection lost".to_string()));
+            }
+        }
+    };
+    lost.abort();
+    r
+}
+
+/// Receive music from phones and play it on the local sound card
+pub async fn a2dp_sink(tx: tokio::sync::mpsc::Sender<MessageFromAsync>) {
+    loop {
+        if let Err(e) = sink_session(&tx).await {
+            println!("Audio sink stopped {}", e);
+        }
+        tokio::time::sleep(RETRY_DELAY).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::fd::OwnedFd;
+
+    /// 64 frames of 44.1 kHz joint stereo sbc, 119 bytes each
+    const TONE: &[u8] = include_bytes!("../testdata/sbc_tone.sbc");
+    const FRAME: usize = 119;
+
+    impl Output for Vec<i16> {
+        fn write(&mut self, _rate: u32, _channels: usize, samples: &[i16]) {
+            self.extend_from_slice(samples);
+        }
+    }
+
+    fn frame(n: usize) -> &'static [u8] {
+        &TONE[n * FRAME..(n + 1) * FRAME]
+    }
+
+    /// An rtp packet with an sbc payload header
+    fn packet(seq: u16, header: u8, payload: &[u8]) -> Vec<u8> {
+        let mut p = vec![0x80, 0x60];
+        p.extend_from_slice(&seq.to_be_bytes());
+        p.extend_from_slice(&(seq as u32 * 128).to_be_bytes());
+        p.extend_from_slice(&[0x12, 0x34, 0x56, 0x78]);
+        p.push(header);
+        p.extend_from_slice(payload);
+        p
+    }
+
+    /// A packet of whole frames
+    fn frames(seq: u16, frames: std::ops::Range<usize>) -> Vec<u8> {
+        let payload: Vec<u8> = frames.clone().flat_map(|n| frame(n).to_vec()).collect();
+        packet(seq, frames.len() as u8, &payload)
+    }
+
+    /// What a decoder makes of the frames, in order
+    fn decoded(frames: impl IntoIterator<Item = usize>) -> Vec<i16> {
+        let mut d = sbc::Decoder::new();
+        frames
+            .into_iter()
+            .flat_map(|n| d.decode(frame(n)).unwrap().1)
+            .collect()
+    }
+
+    /// A connected pair of sockets that keep packets apart, like an l2cap transport
+    fn seqpacket_pair() -> (std::fs::File, std::fs::File) {
+        let mut fds = [0; 2];
+        let r =
+            unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_SEQPACKET, 0, fds.as_mut_ptr()) };
+        assert_eq!(r, 0);
+        unsafe {
+            (
+                std::fs::File::from(OwnedFd::from_raw_fd(fds[0])),
+                std::fs::File::from(OwnedFd::from_raw_fd(fds[1])),
+            )
+        }
+    }
+
+    #[tokio::test]
+    async fn transport() {
+        let (ours, mut phone) = seqpacket_pair();
+        let packets = vec![
+            frames(0xfff8, 0..4),
+            frames(0xfff9, 4..8),
+            // Arrives before the packet it follows, which is then too late to play
+            frames(0xfffb, 12..16),
+            frames(0xfffa, 8..12),
+            // Repeated
+            frames(0xfffb, 12..16),
+            // A frame split over three packets
+            packet(0xfffc, 0xc3, &frame(16)[..40]),
+            packet(0xfffd, 0x82, &frame(16)[40..80]),
+            packet(0xfffe, 0xa1, &frame(16)[80..]),
+            // The middle of this one is lost as the sequence number wraps, so none of it is played
+            packet(0xffff, 0xc3, &frame(17)[..40]),
+            packet(0x0001, 0xa1, &frame(17)[80..]),
+            frames(0x0002, 18..20),
+        ];
+        let mut player = Player::new(Vec::new());
+        let send = tokio::task::spawn_blocking(move || {
+            for p in packets {
+                std::io::Write::write_all(&mut phone, &p).unwrap();
+            }
+            // Hanging up ends the stream
+        });
+        play_transport(ours, 672, &mut player).await;
+        send.await.unwrap();
+        let played = (0..8).chain(12..17).chain(18..20);
+        assert_eq!(player.output, decoded(played));
+    }
+
+    #[test]
+    fn configuration() {
+        // Everything a phone can do, choosing 44.1 kHz joint stereo with 16 blocks
+        assert_eq!(
+            select_configuration(&[0xff, 0xff, 2, 53]),
+            Some(vec![0x21, 0x15, 2, 53])
+        );
+        // Only what the phone offers is picked, and the bitpool range is narrowed
+        assert_eq!(
+            select_configuration(&[0x18, 0x89, 10, 250]),
+            Some(vec![0x18, 0x89, 10, 53])
+        );
+        assert_eq!(
+            select_configuration(&[0x84, 0x46, 30, 40]),
+            Some(vec![0x84, 0x46, 30, 40])
+        );
+        // No sample rate, block count or allocation in common
+        assert_eq!(select_configuration(&[0x0f, 0xff, 2, 53]), None);
+        assert_eq!(select_configuration(&[0xf0, 0xff, 2, 53]), None);
+        assert_eq!(select_configuration(&[0xff, 0x0f, 2, 53]), None);
+        assert_eq!(select_configuration(&[0xff, 0xf0, 2, 53]), None);
+        assert_eq!(select_configuration(&[0xff, 0xfc, 2, 53]), None);
+        // Bitpools that do not overlap
+        assert_eq!(select_configuration(&[0xff, 0xff, 60, 80]), None);
+        assert_eq!(select_configuration(&[0xff, 0xff]), None);
+    }
+
+    #[test]
+    fn format() {
+        assert_eq!(configuration_format(&[0x21, 0x15, 2, 53]), (44100, 2));
+        assert_eq!(configuration_format(&[0x18, 0x15, 2, 53]), (48000, 1));
+        assert_eq!(configuration_format(&[0x42, 0x15, 2, 53]), (32000, 2));
+        assert_eq!(configuration_format(&[0x84, 0x15, 2, 53]), (16000, 2));
+        assert_eq!(configuration_format(&[]), (44100, 2));
+    }
+}
@@ -0,0 +1,187 @@
+use std::collections::HashMap;
+
+use super::CommonWindowProperties;
+use super::MessageToAsync;
+use super::Subwindow;
+use super::SubwindowTrait;
+use crate::bluetooth_backend::AdapterInfo;
+use crate::bluetooth_device::uuid_name;
+use crate::config::AdapterConfig;
+use eframe::egui;
+
+/// Choices for how long the adapter stays discoverable, 0 meaning no limit
+const TIMEOUTS: [u32; 5] = [0, 60, 180, 300, 600];
+
+fn timeout_text(secs: u32) -> String {
+    if secs == 0 {
+        "Always".to_string()
+    } else {
+        format!("{} s", secs)
+    }
+}
+
+pub struct Adapters {
+    /// The alias being typed for each adapter
+    alias: HashMap<bluer::Address, String>,
+}
+
+impl Adapters {
+    pub fn new() -> Self {
+        Self {
+            alias: HashMap::new(),
+        }
+    }
+
+    fn details(ui: &mut egui::Ui, info: &AdapterInfo) {
+        egui::CollapsingHeader::new("Details")
+            .id_source(("adapter details", info.address))
+            .show(ui, |ui| {
+                egui::Grid::new(("adapter grid", info.address))
+                    .striped(true)
+                    .show(ui, |ui| {
+                        let mut row = |name: &str, value: String| {
+                            ui.label(name);
+                            ui.label(value);
+                            ui.end_row();
+                        };
+                        row("Address", info.address.to_string());
+                        row("Address type", info.address_type.clone());
+                        row("System name", info.system_name.clone());
+                        row("Alias", info.alias.clone());
+                        row(
+                            "Modalias",
+                            info.modalias.clone().unwrap_or_else(|| "-".to_string()),
+                        );
+                        row("Powered", info.powered.to_string());
+                        row(
+                            "Discoverable",
+                            format!(
+                                "{} ({})",
+                                info.discoverable,
+                                timeout_text(info.discoverable_timeout)
+                            ),
+                        );
+                        row("Pairable", info.pairable.to_string());
+                        let or_dash =
+                            |v: &Option<String>| v.clone().unwrap_or_else(|| "-".to_string());
+                        row(
+                            "Advertising instances",
+                            match (info.active_advertising, info.supported_advertising) {
+                                (Some(a), Some(s)) => format!("{} of {}", a, s),
+                                _ => "-".to_string(),
+                            },
+                        );
+                        row("Advertising includes", or_dash(&info.advertising_includes));
+                        row(
+                            "Advertising capabilities",
+                            or_dash(&info.advertising_capabilities),
+                        );
+                        row("Advertising features", or_dash(&info.advertising_features));
+                    });
+                ui.label("Services");
+                for u in &info.uuids {
+                    match uuid_name(u) {
+                        Some(n) => ui.label(format!("{} ({})", n, u)),
+                        None => ui.label(u.to_string()),
+                    };
+                }
+            });
+    }
+
+    /// Controls for the saved settings of an adapter, returning the new settings when changed
+    fn settings(
+        &mut self,
+        ui: &mut egui::Ui,
+        info: &AdapterInfo,
+        mut c: AdapterConfig,
+    ) -> Option<AdapterConfig> {
+        let mut changed = false;
+        ui.horizontal(|ui| {
+            ui.label("Name");
+            let alias = self
+                .alias
+                .entry(info.address)
+                .or_insert_with(|| info.alias.clone());
+            ui.text_edit_singleline(alias);
+            if ui.button("Apply").clicked() {
+                let a = alias.trim().to_string();
+                c.alias = (!a.is_empty() && a != info.system_name).then_some(a);
+                changed = true;
+            }
+            if c.alias.is_some() && ui.button("Reset").clicked() {
+                c.alias = None;
+                *alias = info.system_name.clone();
+                changed = true;
+            }
+        });
+        ui.horizontal(|ui| {
+            changed |= ui.checkbox(&mut c.discoverable, "Discoverable").changed();
+            egui::ComboBox::from_id_source(("discoverable timeout", info.address))
+                .selected_text(timeout_text(c.discoverable_timeout))
+                .show_ui(ui, |ui| {
+                    for t in TIMEOUTS {
+                        changed |= ui
+                            .selectable_value(&mut c.discoverable_timeout, t, timeout_text(t))
+                            .changed();
+                    }
+                });
+            changed |= ui.checkbox(&mut c.pairable, "Pairable").changed();
+        });
+        changed.then_some(c)
+    }
+}
+
+impl SubwindowTrait for Adapters {
+    fn update(
+        &mut self,
+        ctx: &egui::Context,
+        frame: &mut eframe::Frame,
+        common: &mut CommonWindowProperties,
+    ) -> Option<Subwindow> {
+        egui::CentralPanel::default().show(ctx, |ui| {
+            ui.heading("Bluetooth adapters");
+            if common.bluetooth.adapters.is_empty() {
+                ui.label("No adapters found");
+                return;
+            }
+            let mut changed = false;
+            let infos = common.bluetooth.adapters.clone();
+            egui::ScrollArea::vertical()
+                .auto_shrink([false; 2])
+                .show(ui, |ui| {
+                    for info in &infos {
+                        let active =
+                            common.bluetooth.active_adapter.as_deref() == Some(info.name.as_str());
+                        ui.separator();
+                        ui.horizontal(|ui| {
+                            ui.label(
+                                egui::RichText::new(format!("{} {}", info.name, info.alias))
+                                    .font(egui::FontId::proportional(28.0)),
+                            );
+                            if active {
+                                ui.label("In use");
+                            } else if ui.button("Use this adapter").clicked() {
+                                common.config.set_active_adapter(info.address);
+                                changed = true;
+                            }
+                        });
+                        if active {
+                            let c = common.config.adapter_config(&info.address);
+                            if let Some(c) = self.settings(ui, info, c) {
+                                common.config.set_adapter_config(&info.address, c);
+                                changed = true;
+                            }
+                        }
+                        Self::details(ui, info);
+                    }
+                });
+            if changed {
+                common.config.save();
+                let _ = common
+                    .tx
+                    .blocking_send(MessageToAsync::AdapterSettings(common.config.clone()));
+            }
+        });
+        None
+    }
+}
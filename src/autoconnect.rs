@@ -0,0 +1,206 @@
+use std::time::Duration;
+
+use futures::StreamExt;
+
+use crate::bluetooth_backend::{BackendAdapter, BackendDevice};
+use crate::MessageFromAsync;
+
+const BACKOFF_START: Duration = Duration::from_secs(2);
+const BACKOFF_MAX: Duration = Duration::from_secs(300);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(20);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PhoneLink {
+    Idle,
+    Connecting(bluer::Address),
+    Connected(bluer::Address),
+    Waiting(Duration),
+}
+
+async fn send_status(tx: &tokio::sync::mpsc::Sender<MessageFromAsync>, s: PhoneLink) {
+    let _ = tx.send(MessageFromAsync::PhoneLink(s)).await;
+}
+
+async fn find_connected<A: BackendAdapter>(
+    adapter: &A,
+    phones: &[bluer::Address],
+) -> Option<A::Device> {
+    for a in phones {
+        if let Ok(d) = adapter.device(*a) {
+            if d.is_connected().await.unwrap_or(false) {
+                return Some(d);
+            }
+        }
+    }
+    None
+}
+
+/// Wait for a device to disconnect
+async fn wait_disconnect<D: BackendDevice>(d: &D) {
+    match d.events().await {
+        Ok(mut events) => {
+            while let Some(e) = events.next().await {
+                if let bluer::DeviceEvent::PropertyChanged(bluer::DeviceProperty::Connected(
+                    false,
+                )) = e
+                {
+                    return;
+                }
+            }
+        }
+        Err(e) => {
+            println!("Failed to watch {} for disconnect {:?}", d.address(), e);
+            while d.is_connected().await.unwrap_or(false) {
+                tokio::time::sleep(BACKOFF_START).await;
+            }
+        }
+    }
+}
+
+/// Keep the highest priority phone that is available connected
+pub async fn auto_connect<A: BackendAdapter>(
+    adapter: A,
+    mut phones: tokio::sync::watch::Receiver<Vec<bluer::Address>>,
+    tx: tokio::sync::mpsc::Sender<MessageFromAsync>,
+) {
+    let mut backoff = BACKOFF_START;
+    loop {
+        let list = phones.borrow_and_update().clone();
+        if list.is_empty() {
+            send_status(&tx, PhoneLink::Idle).await;
+            if phones.changed().await.is_err() {
+                return;
+            }
+            continue;
+        }
+        if let Some(d) = find_connected(&adapter, &list).await {
+            send_status(&tx, PhoneLink::Connected(d.address())).await;
+            backoff = BACKOFF_START;
+            tokio::select! {
+                _ = wait_disconnect(&d) => {
+                    println!("Phone {} disconnected", d.address());
+                }
+                r = phones.changed() => {
+                    if r.is_err() {
+                        return;
+                    }
+                }
+            }
+            continue;
+        }
+        let mut connected = false;
+        for a in &list {
+            let Ok(d) = adapter.device(*a) else {
+                continue;
+            };
+            send_status(&tx, PhoneLink::Connecting(*a)).await;
+            match tokio::time::timeout(CONNECT_TIMEOUT, d.connect()).await {
+                Ok(Ok(())) => {
+                    println!("Connected to phone {}", a);
+                    connected = true;
+                    break;
+                }
+                Ok(Err(e)) => println!("Failed to connect to phone {}: {:?}", a, e),
+                Err(_) => println!("Timed out connecting to phone {}", a),
+            }
+        }
+        if connected {
+            continue;
+        }
+        send_status(&tx, PhoneLink::Waiting(backoff)).await;
+        tokio::select! {
+            _ = tokio::time::sleep(backoff) => {
+                backoff = (backoff * 2).min(BACKOFF_MAX);
+            }
+            r = phones.changed() => {
+                if r.is_err() {
+                    return;
+                }
+                backoff = BACKOFF_START;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bluetooth_backend::Backend;
+    use crate::bluetooth_mock::{MockBackend, MockControl};
+    use bluer::DeviceProperty;
+
+    const FIRST: bluer::Address = bluer::Address::new([0x00, 0x11, 0x22, 0x33, 0x44, 0x01]);
+    const SECOND: bluer::Address = bluer::Address::new([0x00, 0x11, 0x22, 0x33, 0x44, 0x02]);
+    const THIRD: bluer::Address = bluer::Address::new([0x00, 0x11, 0x22, 0x33, 0x44, 0x03]);
+
+    /// Run auto_connect on a mock adapter for a list of phones
+    fn start(
+        phones: Vec<bluer::Address>,
+    ) -> (
+        MockControl,
+        tokio::sync::watch::Sender<Vec<bluer::Address>>,
+        tokio::sync::mpsc::Receiver<MessageFromAsync>,
+        tokio::task::JoinHandle<()>,
+    ) {
+        let (backend, control) = MockBackend::new();
+        control.add_adapter("hci0");
+        let adapter = backend.adapter("hci0").unwrap();
+        let (phones_tx, phones) = tokio::sync::watch::channel(phones);
+        let (tx, rx) = tokio::sync::mpsc::channel(10);
+        let task = tokio::spawn(auto_connect(adapter, phones, tx));
+        (control, phones_tx, rx, task)
+    }
+
+    async fn next_link(rx: &mut tokio::sync::mpsc::Receiver<MessageFromAsync>) -> PhoneLink {
+        loop {
+            match rx.recv().await {
+                Some(MessageFromAsync::PhoneLink(l)) => return l,
+                Some(_) => {}
+                None => panic!("Auto connect stopped"),
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn backoff() {
+        let (_control, phones_tx, mut rx, task) = start(vec![FIRST]);
+        // A phone that is not around is tried less and less often
+        let mut wait = BACKOFF_START;
+        while wait < BACKOFF_MAX {
+            assert_eq!(next_link(&mut rx).await, PhoneLink::Waiting(wait));
+            wait = (wait * 2).min(BACKOFF_MAX);
+        }
+        assert_eq!(next_link(&mut rx).await, PhoneLink::Waiting(BACKOFF_MAX));
+        assert_eq!(next_link(&mut rx).await, PhoneLink::Waiting(BACKOFF_MAX));
+        // A new list starts again from the shortest wait
+        phones_tx.send(vec![FIRST, SECOND]).unwrap();
+        assert_eq!(next_link(&mut rx).await, PhoneLink::Waiting(BACKOFF_START));
+        assert_eq!(
+            next_link(&mut rx).await,
+            PhoneLink::Waiting(BACKOFF_START * 2)
+        );
+        phones_tx.send(Vec::new()).unwrap();
+        assert_eq!(next_link(&mut rx).await, PhoneLink::Idle);
+        drop(phones_tx);
+        task.await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn priority() {
+        let (control, phones_tx, mut rx, task) = start(vec![THIRD, FIRST, SECOND]);
+        control.add_device("hci0", FIRST, Vec::new());
+        control.add_device("hci0", SECOND, vec![DeviceProperty::Connected(true)]);
+        // A phone that is already connected is kept, even if one before it is around
+        assert_eq!(next_link(&mut rx).await, PhoneLink::Connected(SECOND));
+        // Once it goes, the first phone in the list that is around is connected
+        control.set_property("hci0", SECOND, DeviceProperty::Connected(false));
+        assert_eq!(next_link(&mut rx).await, PhoneLink::Connecting(FIRST));
+        assert_eq!(next_link(&mut rx).await, PhoneLink::Connected(FIRST));
+        // And connected again when the link is lost
+        control.set_property("hci0", FIRST, DeviceProperty::Connected(false));
+        assert_eq!(next_link(&mut rx).await, PhoneLink::Connecting(FIRST));
+        assert_eq!(next_link(&mut rx).await, PhoneLink::Connected(FIRST));
+        drop(phones_tx);
+        task.await.unwrap();
+    }
+}
@@ -1,8 +1,38 @@
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::collections::VecDeque;
 use std::str::FromStr;
-use std::time::Duration;
 
+use crate::a2dp;
+use crate::adapters;
+use crate::autoconnect;
+use crate::bluetooth_backend;
+use crate::bluetooth_backend::{
+    AdapterInfo, Backend, BackendAdapter, BackendDevice, ProfileConnection,
+};
+use crate::bluetooth_device;
+#[cfg(feature = "mock")]
+use crate::bluetooth_mock;
+use crate::bluez::RETRY_DELAY;
+use crate::config;
+use crate::contacts;
+use crate::gallery;
+use crate::gatt;
+use crate::hfp;
+use crate::map;
+use crate::media;
+use crate::messages;
+use crate::obd;
+use crate::opp;
+use crate::pairing;
+use crate::pan;
+use crate::pbap;
+use crate::sco;
+use crate::sensors;
+use crate::settings;
+use crate::spp;
+use crate::terminal;
+use crate::vehicle;
 use crate::MessageFromAsync;
 
 use super::CommonWindowProperties;
@@ -12,133 +42,217 @@ use super::SubwindowTrait;
 use bluer::AdapterEvent;
 use bluer::DeviceProperty;
 use eframe::egui;
-use futures::FutureExt;
+use futures::stream::BoxStream;
 use futures::StreamExt;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DeviceAction {
+    Pair,
+    Connect,
+    Disconnect,
+    Trust,
+    Untrust,
+    Block,
+    Unblock,
+    Forget,
+}
+
+impl DeviceAction {
+    pub fn name(&self) -> &'static str {
+        match self {
+            DeviceAction::Pair => "Pair",
+            DeviceAction::Connect => "Connect",
+            DeviceAction::Disconnect => "Disconnect",
+            DeviceAction::Trust => "Trust",
+            DeviceAction::Untrust => "Untrust",
+            DeviceAction::Block => "Block",
+            DeviceAction::Unblock => "Unblock",
+            DeviceAction::Forget => "Forget",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum ActionStatus {
+    Started,
+    Done,
+    Failed(String),
+}
 
 pub struct BluetoothData {
-    scanning: bool,
+    pub scanning: bool,
     pub devices: HashMap<bluer::Address, BluetoothDeviceInfo>,
+    pub phones: HashMap<bluer::Address, hfp::HfpState>,
+    pub call_start: HashMap<bluer::Address, std::time::Instant>,
+    pub pairing: pairing::Pairing,
+    pub actions: HashMap<bluer::Address, (DeviceAction, ActionStatus)>,
+    pub link: autoconnect::PhoneLink,
+    pub status: BluetoothStatus,
+    pub adapters: Vec<AdapterInfo>,
+    /// The name of the adapter in use
+    pub active_adapter: Option<String>,
+    pub last_error: Option<BluetoothError>,
+    pub streaming: Option<a2dp::AudioStream>,
+    pub players: HashMap<bluer::Address, media::MediaState>,
+    pub phonebooks: HashMap<bluer::Address, contacts::Phonebook>,
+    pub phonebook_status: HashMap<bluer::Address, ActionStatus>,
+    /// Text messages of each phone, newest first
+    pub messages: HashMap<bluer::Address, Vec<map::Message>>,
+    pub banner: Option<messages::Banner>,
+    pub vehicle: obd::VehicleData,
+    pub terminal: spp::TerminalState,
+    pub gatt: HashMap<bluer::Address, gatt::GattState>,
+    /// Phones with call audio flowing and the codec it uses
+    pub voice: HashMap<bluer::Address, sco::Codec>,
+    /// Files phones are waiting to send, with where to send the answer
+    pub pushes: Vec<(opp::PushRequest, tokio::sync::oneshot::Sender<bool>)>,
+    /// Contacts phones sent to the radio
+    pub received_contacts: Vec<crate::vcard::VCard>,
+    /// Pictures phones sent to the radio, newest first
+    pub pictures: Vec<std::path::PathBuf>,
+    /// Network connections to phones sharing their data connection
+    pub pan: HashMap<bluer::Address, pan::PanState>,
 }
 
-async fn query_adapter(adapter: &bluer::Adapter) -> bluer::Result<()> {
-    println!(
-        "    Address:                    {}",
-        adapter.address().await?
-    );
-    println!(
-        "    Address type:               {}",
-        adapter.address_type().await?
-    );
-    println!("    Friendly name:              {}", adapter.alias().await?);
-    println!(
-        "    Modalias:                   {:?}",
-        adapter.modalias().await?
-    );
-    println!(
-        "    Powered:                    {:?}",
-        adapter.is_powered().await?
-    );
-    println!(
-        "    Discoverabe:                {:?}",
-        adapter.is_discoverable().await?
-    );
-    println!(
-        "    Pairable:                   {:?}",
-        adapter.is_pairable().await?
-    );
-    println!(
-        "    UUIDs:                      {:?}",
-        adapter.uuids().await?
-    );
-    println!();
-    println!(
-        "    Active adv. instances:      {}",
-        adapter.active_advertising_instances().await?
-    );
-    println!(
-        "    Supp.  adv. instances:      {}",
-        adapter.supported_advertising_instances().await?
-    );
-    println!(
-        "    Supp.  adv. includes:       {:?}",
-        adapter.supported_advertising_system_includes().await?
-    );
-    println!(
-        "    Adv. capabilites:           {:?}",
-        adapter.supported_advertising_capabilities().await?
-    );
-    println!(
-        "    Adv. features:              {:?}",
-        adapter.supported_advertising_features().await?
-    );
-
-    Ok(())
+async fn hfp_command(
+    handsfree: &mut HashMap<bluer::Address, tokio::sync::mpsc::Sender<hfp::HfpCommand>>,
+    addr: bluer::Address,
+    cmd: hfp::HfpCommand,
+) {
+    if let Some(s) = handsfree.get(&addr) {
+        if s.send(cmd).await.is_err() {
+            handsfree.remove(&addr);
+        }
+    } else {
+        println!("No hands free connection for {}", addr);
+    }
 }
 
-pub async fn bluetooth(
+/// Stop everything the session keeps for a device that is gone
+fn forget_device<A, D>(
+    addr: bluer::Address,
+    bluetooth_devices: &mut HashMap<bluer::Address, (A, D)>,
+    watchers: &mut HashMap<bluer::Address, tokio::task::JoinHandle<()>>,
+    seen: &mut HashMap<bluer::Address, std::time::Instant>,
+    handsfree: &mut HashMap<bluer::Address, tokio::sync::mpsc::Sender<hfp::HfpCommand>>,
+    subscriptions: &mut HashMap<(bluer::Address, gatt::AttributeId), tokio::task::JoinHandle<()>>,
+) {
+    bluetooth_devices.remove(&addr);
+    if let Some(j) = watchers.remove(&addr) {
+        j.abort();
+    }
+    seen.remove(&addr);
+    // Closing the command channel ends the hands free connection
+    handsfree.remove(&addr);
+    subscriptions.retain(|(a, _), j| {
+        if *a == addr {
+            j.abort();
+        }
+        *a != addr
+    });
+}
+
+async fn device_action<A: BackendAdapter>(
+    adapter: A,
+    dev: A::Device,
+    addr: bluer::Address,
+    action: DeviceAction,
     tx: tokio::sync::mpsc::Sender<MessageFromAsync>,
-    rx: &mut tokio::sync::mpsc::Receiver<MessageToAsync>,
 ) {
-    println!("Starting bluetooth code");
-    let bluetooth = bluer::Session::new().await.unwrap();
-    println!("Got a bluetooth session");
-
-    let mut blue_agent = bluer::agent::Agent::default();
-    blue_agent.request_default = true;
-    blue_agent.request_pin_code = Some(Box::new(move |a| {
-        async move {
-            println!("Pin requested {:?}", a);
-            Ok("1234".to_string())
-        }
-        .boxed()
-    }));
-    blue_agent.request_passkey = Some(Box::new(move |a| {
-        async move {
-            println!("passkey requested {:?}", a);
-            Ok(42)
-        }
-        .boxed()
-    }));
-    blue_agent.display_passkey = Some(Box::new(move |a| {
-        async move {
-            println!("Need to display passkey {:?}", a);
-            Ok(())
-        }
-        .boxed()
-    }));
-    blue_agent.display_pin_code = Some(Box::new(move |a| {
-        async move {
-            println!("Need to display pin code {:?}", a);
-            Ok(())
-        }
-        .boxed()
-    }));
-    blue_agent.request_confirmation = Some(Box::new(move |a| {
-        async move {
-            println!("Confirmation requested {:?}", a);
-            Ok(())
-        }
-        .boxed()
-    }));
-    blue_agent.request_authorization = Some(Box::new(move |a| {
-        async move {
-            println!("authorization requested {:?}", a);
-            Ok(())
-        }
-        .boxed()
-    }));
-    blue_agent.authorize_service = Some(Box::new(move |a| {
-        async move {
-            println!("authorize service requested {:?}", a);
-            Ok(())
-        }
-        .boxed()
-    }));
-    let blue_agent_handle = bluetooth.register_agent(blue_agent).await;
-    println!("Registered a bluetooth agent");
+    let _ = tx
+        .send(MessageFromAsync::BluetoothDeviceAction(
+            addr,
+            action,
+            ActionStatus::Started,
+        ))
+        .await;
+    let r = match action {
+        DeviceAction::Pair => dev.pair().await,
+        DeviceAction::Connect => dev.connect().await,
+        DeviceAction::Disconnect => dev.disconnect().await,
+        DeviceAction::Trust => dev.set_trusted(true).await,
+        DeviceAction::Untrust => dev.set_trusted(false).await,
+        DeviceAction::Block => dev.set_blocked(true).await,
+        DeviceAction::Unblock => dev.set_blocked(false).await,
+        DeviceAction::Forget => adapter.remove_device(addr).await,
+    };
+    // The property changes the action causes reach the gui through the device's watcher
+    let status = match r {
+        Ok(()) => ActionStatus::Done,
+        Err(e) => {
+            println!("{} {} failed {:?}", action.name(), addr, e);
+            ActionStatus::Failed(e.to_string())
+        }
+    };
+    let _ = tx
+        .send(MessageFromAsync::BluetoothDeviceAction(
+            addr, action, status,
+        ))
+        .await;
+}
+
+/// Send every property of a device, then each change as it happens
+async fn device_properties<D: BackendDevice>(
+    addr: bluer::Address,
+    dev: D,
+    tx: tokio::sync::mpsc::Sender<MessageFromAsync>,
+) {
+    let events = dev.events().await;
+    if let Ok(ps) = dev.all_properties().await {
+        for p in ps {
+            let _ = tx
+                .send(MessageFromAsync::BluetoothDeviceProperty(addr, p))
+                .await;
+        }
+    }
+    let mut events = match events {
+        Ok(e) => e,
+        Err(e) => {
+            println!("Failed to watch {} for changes {:?}", addr, e);
+            return;
+        }
+    };
+    while let Some(e) = events.next().await {
+        let bluer::DeviceEvent::PropertyChanged(p) = e;
+        if tx
+            .send(MessageFromAsync::BluetoothDeviceProperty(addr, p))
+            .await
+            .is_err()
+        {
+            break;
+        }
+    }
+}
+
+/// Run the hands free protocol on a connection to the car audio profile
+fn car_audio_connection(
+    con: ProfileConnection,
+    handsfree: &mut HashMap<bluer::Address, tokio::sync::mpsc::Sender<hfp::HfpCommand>>,
+    tx: &tokio::sync::mpsc::Sender<MessageFromAsync>,
+    voice: &tokio::sync::mpsc::Sender<MessageToAsync>,
+    msbc: bool,
+) {
+    let addr = con.device;
+    println!("Got a connection to car audio from {}", addr);
+    let (r, w) = tokio::io::split(con.stream);
+    let tx2 = tx.clone();
+    let (ctx, crx) = tokio::sync::mpsc::channel(10);
+    handsfree.insert(addr, ctx);
+    let voice = voice.clone();
+    tokio::spawn(async move {
+        if let Err(e) = hfp::handsfree(addr, r, w, tx2, crx, voice, msbc).await {
+            println!("Hands free connection to {} ended {:?}", addr, e);
+        }
+    });
+}
 
-    let profile = bluer::rfcomm::Profile {
+/// The profile phones connect to for calls, with wide band speech in the service record if offered
+fn car_audio_profile(msbc: bool) -> bluer::rfcomm::Profile {
+    let mut features = hfp::HF_FEATURES as u16 & 0x1f;
+    if msbc {
+        features |= hfp::HF_SDP_WIDEBAND_SPEECH;
+    }
+    bluer::rfcomm::Profile {
         uuid: bluer::Uuid::from_str("0000111e-0000-1000-8000-00805f9b34fb").unwrap(),
         name: Some("Car audio".to_string()),
         service: None,
@@ -150,110 +264,700 @@ pub async fn bluetooth(
         auto_connect: Some(true),
         service_record: None,
         version: None,
-        features: Some(1),
+        features: Some(features),
         ..Default::default()
+    }
+}
+
+async fn register_car_audio<B: Backend>(
+    bluetooth: &B,
+    msbc: bool,
+) -> Option<BoxStream<'static, ProfileConnection>> {
+    println!("Registering a profile");
+    match bluetooth.register_profile(car_audio_profile(msbc)).await {
+        Ok(h) => Some(h),
+        Err(e) => {
+            println!("Failed to register car audio profile {:?}", e);
+            None
+        }
+    }
+}
+
+/// Wait for the next connection on a profile, or forever if the profile is not registered
+async fn next_connection(
+    h: &mut Option<BoxStream<'static, ProfileConnection>>,
+) -> Option<ProfileConnection> {
+    match h {
+        Some(h) => h.next().await,
+        None => futures::future::pending().await,
+    }
+}
+
+type AdapterEvents<A> = futures::stream::SelectAll<BoxStream<'static, (A, AdapterEvent)>>;
+
+/// How often devices are checked for not being seen recently
+const AGING_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+
+#[derive(Clone, Debug)]
+pub enum BluetoothError {
+    Session(String),
+    Agent(String),
+    Adapters(String),
+    Adapter(String, String),
+    Discovery(String, String),
+    Lost,
+}
+
+impl std::fmt::Display for BluetoothError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BluetoothError::Session(e) => write!(f, "Unable to connect to bluez: {}", e),
+            BluetoothError::Agent(e) => write!(f, "Unable to register pairing agent: {}", e),
+            BluetoothError::Adapters(e) => write!(f, "Unable to list adapters: {}", e),
+            BluetoothError::Adapter(a, e) => write!(f, "Unable to set up adapter {}: {}", a, e),
+            BluetoothError::Discovery(a, e) => {
+                write!(f, "Unable to start discovery on {}: {}", a, e)
+            }
+            BluetoothError::Lost => write!(f, "Lost connection to bluez"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum BluetoothStatus {
+    Starting,
+    Unavailable(BluetoothError),
+    NoAdapter,
+    Ready(Vec<String>),
+}
+
+async fn scan_adapter<A: BackendAdapter>(
+    scanner: &mut AdapterEvents<A>,
+    a: &A,
+    filter: bluer::DiscoveryFilter,
+    tx: &tokio::sync::mpsc::Sender<MessageFromAsync>,
+) {
+    let r = match a.set_discovery_filter(filter).await {
+        Ok(()) => a.discover_devices().await,
+        Err(e) => Err(e),
     };
+    match r {
+        Ok(da) => {
+            let a2 = a.clone();
+            scanner.push(da.map(move |e| (a2.clone(), e)).boxed());
+        }
+        Err(e) => {
+            println!("Failed to start discovery on {}: {:?}", a.name(), e);
+            let _ = tx
+                .send(MessageFromAsync::BluetoothError(BluetoothError::Discovery(
+                    a.name().to_string(),
+                    e.to_string(),
+                )))
+                .await;
+        }
+    }
+}
 
-    let mut bluetooth_devices: HashMap<bluer::Address, (&bluer::Adapter, Option<bluer::Device>)> =
-        HashMap::new();
-    let adapter_names = bluetooth.adapter_names().await.unwrap();
-    let adapters: Vec<bluer::Adapter> = adapter_names
+/// Apply the saved settings to an adapter, returning what it reports afterwards
+async fn setup_adapter<A: BackendAdapter>(
+    adapter: &A,
+    info: &AdapterInfo,
+    c: &config::AdapterConfig,
+    active: bool,
+    tx: &tokio::sync::mpsc::Sender<MessageFromAsync>,
+) -> Option<AdapterInfo> {
+    let r = async {
+        if active {
+            adapter.set_powered(true).await?;
+            let alias = c.alias.clone().unwrap_or_default();
+            let wanted = if alias.is_empty() {
+                &info.system_name
+            } else {
+                &alias
+            };
+            if info.alias != *wanted {
+                adapter.set_alias(alias).await?;
+            }
+            adapter
+                .set_discoverable_timeout(c.discoverable_timeout)
+                .await?;
+            adapter.set_discoverable(c.discoverable).await?;
+            adapter.set_pairable(c.pairable).await?;
+        } else if info.powered {
+            // Phones should only find the adapter that is in use
+            adapter.set_discoverable(false).await?;
+            adapter.set_pairable(false).await?;
+        }
+        adapter.info().await
+    }
+    .await;
+    match r {
+        Ok(i) => Some(i),
+        Err(e) => {
+            println!("Failed to set up adapter {}: {:?}", adapter.name(), e);
+            let _ = tx
+                .send(MessageFromAsync::BluetoothError(BluetoothError::Adapter(
+                    adapter.name().to_string(),
+                    e.to_string(),
+                )))
+                .await;
+            None
+        }
+    }
+}
+
+/// Set up every adapter, dropping the ones that fail.
+/// Returns what the adapters report and the name of the one to use.
+async fn setup_adapters<A: BackendAdapter>(
+    adapters: &mut Vec<A>,
+    settings: &config::Config,
+    tx: &tokio::sync::mpsc::Sender<MessageFromAsync>,
+) -> (Vec<AdapterInfo>, Option<String>) {
+    let mut found = Vec::new();
+    for a in adapters.iter() {
+        match a.info().await {
+            Ok(i) => found.push(i),
+            Err(e) => println!("Failed to query adapter {}: {:?}", a.name(), e),
+        }
+    }
+    let active = settings
+        .active_adapter()
+        .and_then(|addr| found.iter().find(|i| i.address == addr))
+        .or_else(|| found.first())
+        .map(|i| i.name.clone());
+    let mut infos = Vec::new();
+    let mut usable = Vec::new();
+    for a in adapters.drain(..) {
+        let Some(info) = found.iter().find(|i| i.name == a.name()) else {
+            continue;
+        };
+        let c = settings.adapter_config(&info.address);
+        let is_active = active.as_deref() == Some(a.name());
+        if let Some(i) = setup_adapter(&a, info, &c, is_active, tx).await {
+            infos.push(i);
+            usable.push(a);
+        }
+    }
+    *adapters = usable;
+    (infos, active)
+}
+
+fn active_adapter<'a, A: BackendAdapter>(
+    adapters: &'a [A],
+    active: &Option<String>,
+) -> Option<&'a A> {
+    adapters
         .iter()
-        .filter_map(|n| bluetooth.adapter(n).ok())
-        .collect();
+        .find(|a| active.as_deref() == Some(a.name()))
+}
 
-    tx.send(MessageFromAsync::BluetoothPresent(!adapters.is_empty()))
+async fn send_status(
+    tx: &tokio::sync::mpsc::Sender<MessageFromAsync>,
+    infos: &[AdapterInfo],
+    active: &Option<String>,
+) {
+    let status = if infos.is_empty() {
+        BluetoothStatus::NoAdapter
+    } else {
+        BluetoothStatus::Ready(infos.iter().map(|a| a.name.clone()).collect())
+    };
+    let _ = tx.send(MessageFromAsync::BluetoothStatus(status)).await;
+    let _ = tx
+        .send(MessageFromAsync::Adapters(infos.to_vec(), active.clone()))
         .await;
+}
 
-    println!("Enabling bluetooth stuff now");
-    for adapter in &adapters {
-        adapter.set_powered(true).await.unwrap();
-        adapter.set_discoverable(true).await.unwrap();
-        adapter.set_pairable(true).await.unwrap();
+/// Keep the bluetooth code running, reconnecting to bluez whenever it is unavailable
+pub async fn bluetooth(
+    tx: tokio::sync::mpsc::Sender<MessageFromAsync>,
+    rx: &mut tokio::sync::mpsc::Receiver<MessageToAsync>,
+) {
+    let (phones_tx, _phones_rx) =
+        tokio::sync::watch::channel(config::Config::load().phone_addresses());
+    let services = Services::start(&tx);
+    #[cfg(feature = "mock")]
+    let mock = std::env::var_os("RADIO_MOCK_BLUETOOTH").map(|_| {
+        println!("Using mock bluetooth");
+        bluetooth_mock::MockBackend::demo()
+    });
+    loop {
+        let _ = tx
+            .send(MessageFromAsync::BluetoothStatus(BluetoothStatus::Starting))
+            .await;
+        let r = 'session: {
+            #[cfg(feature = "mock")]
+            if let Some((backend, _control)) = &mock {
+                break 'session bluetooth_session(backend, &tx, rx, &phones_tx, &services).await;
+            }
+            match bluetooth_backend::BluerBackend::new().await {
+                Ok(backend) => bluetooth_session(&backend, &tx, rx, &phones_tx, &services).await,
+                Err(e) => Err(BluetoothError::Session(e.to_string())),
+            }
+        };
+        let e = match r {
+            Ok(()) => return,
+            Err(e) => e,
+        };
+        println!("Bluetooth unavailable: {}", e);
+        let _ = tx
+            .send(MessageFromAsync::BluetoothStatus(
+                BluetoothStatus::Unavailable(e),
+            ))
+            .await;
+        let retry = tokio::time::sleep(RETRY_DELAY);
+        tokio::pin!(retry);
+        loop {
+            tokio::select! {
+                _ = &mut retry => break,
+                m = rx.recv() => {
+                    let Some(m) = m else {
+                        return;
+                    };
+                    let Some(m) = services.route(m).await else {
+                        continue;
+                    };
+                    match m {
+                        MessageToAsync::Quit => return,
+                        MessageToAsync::AutoConnectPhones(phones) => {
+                            let _ = phones_tx.send(phones);
+                        }
+                        MessageToAsync::BluetoothDeviceAction(addr, action) => {
+                            let _ = tx
+                                .send(MessageFromAsync::BluetoothDeviceAction(
+                                    addr,
+                                    action,
+                                    ActionStatus::Failed("Bluetooth unavailable".to_string()),
+                                ))
+                                .await;
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
     }
-    println!("Done enabling bluetooth stuff");
+}
 
-    for adapter in &adapters {
-        println!("there is an adapter");
-        query_adapter(adapter).await;
-    }
-    println!("Registering a profile");
+/// Channels to the tasks that talk to phones through bluez directly
+struct Services {
+    media: tokio::sync::mpsc::Sender<MessageToAsync>,
+    pbap: tokio::sync::mpsc::Sender<MessageToAsync>,
+    map: tokio::sync::mpsc::Sender<MessageToAsync>,
+    obd: tokio::sync::mpsc::Sender<MessageToAsync>,
+    terminal: tokio::sync::mpsc::Sender<MessageToAsync>,
+    voice: tokio::sync::mpsc::Sender<MessageToAsync>,
+    push: tokio::sync::mpsc::Sender<MessageToAsync>,
+    pan: tokio::sync::mpsc::Sender<MessageToAsync>,
+}
 
-    let mut h = bluetooth.register_profile(profile).await;
+impl Services {
+    fn start(tx: &tokio::sync::mpsc::Sender<MessageFromAsync>) -> Self {
+        let (media, media_rx) = tokio::sync::mpsc::channel(10);
+        tokio::spawn(media::media_players(tx.clone(), media_rx));
+        let (pbap, pbap_rx) = tokio::sync::mpsc::channel(10);
+        tokio::spawn(pbap::phonebooks(tx.clone(), pbap_rx));
+        let (map, map_rx) = tokio::sync::mpsc::channel(10);
+        tokio::spawn(map::messages(tx.clone(), map_rx));
+        let (obd, obd_rx) = tokio::sync::mpsc::channel(10);
+        tokio::spawn(obd::vehicle(tx.clone(), obd_rx));
+        let (terminal, terminal_rx) = tokio::sync::mpsc::channel(10);
+        tokio::spawn(spp::terminal(tx.clone(), terminal_rx));
+        let (voice, voice_rx) = tokio::sync::mpsc::channel(10);
+        tokio::spawn(sco::voice(tx.clone(), voice_rx));
+        let (push, push_rx) = tokio::sync::mpsc::channel(10);
+        tokio::spawn(opp::object_push(tx.clone(), push_rx));
+        let (pan, pan_rx) = tokio::sync::mpsc::channel(10);
+        tokio::spawn(pan::tethering(tx.clone(), pan_rx));
+        Self {
+            media,
+            pbap,
+            map,
+            obd,
+            terminal,
+            voice,
+            push,
+            pan,
+        }
+    }
 
-    for adapter in &adapters {
-        query_adapter(adapter).await;
+    /// Pass a message on to the task that handles it, giving it back if there is none
+    async fn route(&self, m: MessageToAsync) -> Option<MessageToAsync> {
+        let s = match &m {
+            MessageToAsync::MediaPlay(_)
+            | MessageToAsync::MediaPause(_)
+            | MessageToAsync::MediaNext(_)
+            | MessageToAsync::MediaPrevious(_)
+            | MessageToAsync::MediaFastForward(_)
+            | MessageToAsync::MediaRewind(_) => &self.media,
+            MessageToAsync::PhonebookDownload(_) => &self.pbap,
+            MessageToAsync::MessagesConnect(_) | MessageToAsync::MessagesDisconnect(_) => &self.map,
+            MessageToAsync::ObdConnect(_)
+            | MessageToAsync::ObdDisconnect
+            | MessageToAsync::ObdReadCodes => &self.obd,
+            MessageToAsync::TerminalConnect(_)
+            | MessageToAsync::TerminalSend(_)
+            | MessageToAsync::TerminalDisconnect => &self.terminal,
+            MessageToAsync::VoiceCodec(_, _)
+            | MessageToAsync::VoiceSettings(_)
+            | MessageToAsync::VoiceVolume(_, _) => &self.voice,
+            MessageToAsync::PushSettings(_) => &self.push,
+            MessageToAsync::PanConnect(_) | MessageToAsync::PanDisconnect(_) => &self.pan,
+            _ => return Some(m),
+        };
+        if s.send(m).await.is_err() {
+            println!("Dropped a message for a service that stopped");
+        }
+        None
     }
+}
 
-    let mut adapter_scanner = Vec::new();
-    for a in &adapters {
-        let da = a.discover_devices_with_changes().await.unwrap();
-        adapter_scanner.push((a, da));
+/// Run the bluetooth code until asked to quit or bluez goes away
+async fn bluetooth_session<B: Backend>(
+    bluetooth: &B,
+    tx: &tokio::sync::mpsc::Sender<MessageFromAsync>,
+    rx: &mut tokio::sync::mpsc::Receiver<MessageToAsync>,
+    phones_tx: &tokio::sync::watch::Sender<Vec<bluer::Address>>,
+    services: &Services,
+) -> Result<(), BluetoothError> {
+    println!("Starting bluetooth code");
+    let mut session_events = bluetooth
+        .events()
+        .await
+        .map_err(|e| BluetoothError::Session(e.to_string()))?;
+
+    let blue_agent = pairing::agent(tx.clone());
+    let _blue_agent_handle = bluetooth
+        .register_agent(blue_agent)
+        .await
+        .map_err(|e| BluetoothError::Agent(e.to_string()))?;
+    println!("Registered a bluetooth agent");
+
+    let mut bluetooth_devices: HashMap<
+        bluer::Address,
+        (B::Adapter, <B::Adapter as BackendAdapter>::Device),
+    > = HashMap::new();
+    let adapter_names = bluetooth
+        .adapter_names()
+        .await
+        .map_err(|e| BluetoothError::Adapters(e.to_string()))?;
+    let mut adapters: Vec<B::Adapter> = Vec::new();
+    println!("Enabling bluetooth stuff now");
+    for n in &adapter_names {
+        if let Ok(a) = bluetooth.adapter(n) {
+            adapters.push(a);
+        }
     }
+    let mut settings = config::Config::load();
+    let (infos, mut active) = setup_adapters(&mut adapters, &settings, tx).await;
+    println!("Done enabling bluetooth stuff");
+    send_status(tx, &infos, &active).await;
 
-    let mut quit = false;
-    let mut scan = false;
-    while !quit {
-        while let Ok(m) = rx.try_recv() {
-            match m {
-                MessageToAsync::BluetoothScan(f) => {
-                    scan = f;
+    let mut msbc = settings.voice.msbc;
+    let mut h = register_car_audio(bluetooth, msbc).await;
+
+    let mut autoconnect: Option<(String, tokio::task::JoinHandle<()>)> = None;
+    let mut scanning = false;
+    let mut scanner: AdapterEvents<B::Adapter> = futures::stream::SelectAll::new();
+    let mut handsfree: HashMap<bluer::Address, tokio::sync::mpsc::Sender<hfp::HfpCommand>> =
+        HashMap::new();
+    let mut subscriptions: HashMap<
+        (bluer::Address, gatt::AttributeId),
+        tokio::task::JoinHandle<()>,
+    > = HashMap::new();
+    // Tasks forwarding the property changes of each known device
+    let mut watchers: HashMap<bluer::Address, tokio::task::JoinHandle<()>> = HashMap::new();
+    // When discovery last reported each device
+    let mut seen: HashMap<bluer::Address, std::time::Instant> = HashMap::new();
+    let mut aging = tokio::time::interval(AGING_INTERVAL);
+    // Stale devices found to be neither paired nor connected
+    let (expired_tx, mut expired) = tokio::sync::mpsc::unbounded_channel();
+    let mut reconfigure = false;
+    // Set when scanning has to start over with the new settings
+    let mut rescan = false;
+    let r = loop {
+        if reconfigure {
+            reconfigure = false;
+            let (infos, a) = setup_adapters(&mut adapters, &settings, tx).await;
+            let gone: Vec<bluer::Address> = bluetooth_devices
+                .iter()
+                .filter(|(_, (d, _))| !adapters.iter().any(|a| a.name() == d.name()))
+                .map(|(addr, _)| *addr)
+                .collect();
+            for addr in gone {
+                forget_device(
+                    addr,
+                    &mut bluetooth_devices,
+                    &mut watchers,
+                    &mut seen,
+                    &mut handsfree,
+                    &mut subscriptions,
+                );
+                let _ = tx.send(MessageFromAsync::OldBluetoothDevice(addr)).await;
+            }
+            // Connections that ended along with their adapter
+            handsfree.retain(|_, s| !s.is_closed());
+            if a != active {
+                active = a;
+                if let Some((_, j)) = autoconnect.take() {
+                    j.abort();
                 }
-                MessageToAsync::Quit => {
-                    quit = true;
-                    println!("Exiting async code now");
+                rescan = true;
+            }
+            send_status(tx, &infos, &active).await;
+        }
+        if settings.voice.msbc != msbc {
+            msbc = settings.voice.msbc;
+            // The service record has to match the codecs the hands free side offers
+            drop(h.take());
+            h = register_car_audio(bluetooth, msbc).await;
+        }
+        if rescan {
+            rescan = false;
+            scanner = futures::stream::SelectAll::new();
+            if scanning {
+                if let Some(a) = active_adapter(&adapters, &active) {
+                    scan_adapter(&mut scanner, a, settings.discovery.filter(), tx).await;
                 }
             }
         }
-        if scan {
-            for (adapt, da) in &mut adapter_scanner {
-                if let Some(e) = da.next().await {
-                    match e {
-                        AdapterEvent::DeviceAdded(addr) => {
-                            println!("Device added {:?}", addr);
-                            bluetooth_devices.insert(addr, (adapt, None));
-                            tx.send(MessageFromAsync::NewBluetoothDevice(addr)).await;
+        if autoconnect.is_none() {
+            if let Some(a) = active_adapter(&adapters, &active) {
+                let j = tokio::spawn(autoconnect::auto_connect(
+                    a.clone(),
+                    phones_tx.subscribe(),
+                    tx.clone(),
+                ));
+                autoconnect = Some((a.name().to_string(), j));
+            }
+        }
+        tokio::select! {
+            m = rx.recv() => {
+                let Some(m) = m else {
+                    break Ok(());
+                };
+                if let MessageToAsync::VoiceSettings(v) = &m {
+                    settings.voice = v.clone();
+                }
+                let Some(m) = services.route(m).await else {
+                    continue;
+                };
+                match m {
+                    MessageToAsync::BluetoothScan(f) => {
+                        scanning = f;
+                        if f {
+                            if scanner.is_empty() {
+                                if let Some(a) = active_adapter(&adapters, &active) {
+                                    scan_adapter(&mut scanner, a, settings.discovery.filter(), tx).await;
+                                }
+                            }
+                        } else {
+                            scanner = futures::stream::SelectAll::new();
                         }
-                        AdapterEvent::DeviceRemoved(addr) => {
-                            println!("Device removed {:?}", addr);
-                            bluetooth_devices.remove_entry(&addr);
-                            tx.send(MessageFromAsync::OldBluetoothDevice(addr)).await;
+                    }
+                    MessageToAsync::Quit => {
+                        println!("Exiting async code now");
+                        break Ok(());
+                    }
+                    MessageToAsync::PhoneAnswer(addr) => {
+                        hfp_command(&mut handsfree, addr, hfp::HfpCommand::Answer).await;
+                    }
+                    MessageToAsync::PhoneHangUp(addr) => {
+                        hfp_command(&mut handsfree, addr, hfp::HfpCommand::HangUp).await;
+                    }
+                    MessageToAsync::PhoneDial(addr, number) => {
+                        hfp_command(&mut handsfree, addr, hfp::HfpCommand::Dial(number)).await;
+                    }
+                    MessageToAsync::PhoneVoiceRecognition(addr, on) => {
+                        hfp_command(&mut handsfree, addr, hfp::HfpCommand::VoiceRecognition(on))
+                            .await;
+                    }
+                    MessageToAsync::PhoneCallControl(addr, c) => {
+                        hfp_command(&mut handsfree, addr, hfp::HfpCommand::CallControl(c)).await;
+                    }
+                    MessageToAsync::PhoneDtmf(addr, c) => {
+                        hfp_command(&mut handsfree, addr, hfp::HfpCommand::Dtmf(c)).await;
+                    }
+                    MessageToAsync::PhoneSpeakerVolume(addr, v) => {
+                        hfp_command(&mut handsfree, addr, hfp::HfpCommand::SpeakerVolume(v)).await;
+                    }
+                    MessageToAsync::PhoneMicVolume(addr, v) => {
+                        hfp_command(&mut handsfree, addr, hfp::HfpCommand::MicVolume(v)).await;
+                    }
+                    MessageToAsync::AutoConnectPhones(phones) => {
+                        let _ = phones_tx.send(phones);
+                    }
+                    MessageToAsync::AdapterSettings(c) => {
+                        rescan = c.discovery != settings.discovery;
+                        settings = c;
+                        reconfigure = true;
+                    }
+                    MessageToAsync::BluetoothDeviceAction(addr, action) => {
+                        if let Some((adapter, dev)) = bluetooth_devices.get(&addr) {
+                            let adapter = adapter.clone();
+                            let dev = dev.clone();
+                            let tx2 = tx.clone();
+                            tokio::spawn(async move {
+                                device_action(adapter, dev, addr, action, tx2).await;
+                            });
+                        } else {
+                            let _ = tx
+                                .send(MessageFromAsync::BluetoothDeviceAction(
+                                    addr,
+                                    action,
+                                    ActionStatus::Failed("Unknown device".to_string()),
+                                ))
+                                .await;
                         }
-                        AdapterEvent::PropertyChanged(prop) => {
-                            println!("Property changed {:?}", prop);
+                    }
+                    MessageToAsync::GattBrowse(addr) => {
+                        if let Some((_, dev)) = bluetooth_devices.get(&addr) {
+                            tokio::spawn(gatt::browse(addr, dev.clone(), tx.clone()));
+                        } else {
+                            let _ = tx
+                                .send(MessageFromAsync::GattServices(
+                                    addr,
+                                    Err("Unknown device".to_string()),
+                                ))
+                                .await;
+                        }
+                    }
+                    MessageToAsync::GattRead(addr, id) => {
+                        if let Some((_, dev)) = bluetooth_devices.get(&addr) {
+                            tokio::spawn(gatt::read(addr, dev.clone(), id, tx.clone()));
                         }
                     }
+                    MessageToAsync::GattSubscribe(addr, id) => {
+                        if let Some((_, dev)) = bluetooth_devices.get(&addr) {
+                            let j = tokio::spawn(gatt::subscribe(addr, dev.clone(), id, tx.clone()));
+                            if let Some(old) = subscriptions.insert((addr, id), j) {
+                                old.abort();
+                            }
+                        }
+                    }
+                    MessageToAsync::GattUnsubscribe(addr, id) => {
+                        if let Some(j) = subscriptions.remove(&(addr, id)) {
+                            j.abort();
+                        }
+                    }
+                    _ => {}
                 }
             }
-        }
-        for (addr, (adapter, dev)) in &mut bluetooth_devices {
-            if dev.is_none() {
-                if let Ok(d) = adapter.device(*addr) {
-                    if let Ok(ps) = d.all_properties().await {
-                        for p in ps {
-                            tx.send(MessageFromAsync::BluetoothDeviceProperty(*addr, p))
-                                .await;
+            e = session_events.next() => {
+                match e {
+                    Some(bluer::SessionEvent::AdapterAdded(name)) => {
+                        println!("Adapter added {}", name);
+                        if adapters.iter().any(|a| a.name() == name) {
+                            continue;
                         }
+                        if let Ok(a) = bluetooth.adapter(&name) {
+                            adapters.push(a);
+                            reconfigure = true;
+                        }
+                    }
+                    Some(bluer::SessionEvent::AdapterRemoved(name)) => {
+                        println!("Adapter removed {}", name);
+                        adapters.retain(|a| a.name() != name);
+                        reconfigure = true;
                     }
-                    *dev = Some(d);
+                    None => break Err(BluetoothError::Lost),
                 }
             }
-        }
-
-        if let Ok(h) = &mut h {
-            if let Some(a) = h.next().await {
-                println!("Got a connection to car audio");
-                let con = a.accept().unwrap();
-                let (mut r, mut w) = con.into_split();
-                w.write(&vec![0_u8, 0, 0, 0]).await.unwrap();
-                match r.read_u8().await {
-                    Ok(a) => println!("Recieved bluetooth byte {:x}", a),
-                    Err(e) => println!("Error receiving bluetooth data {:?}", e),
+            Some((adapt, e)) = scanner.next(), if !scanner.is_empty() => {
+                match e {
+                    AdapterEvent::DeviceAdded(addr) => {
+                        seen.insert(addr, std::time::Instant::now());
+                        if watchers.contains_key(&addr) {
+                            let _ = tx.send(MessageFromAsync::NewBluetoothDevice(addr)).await;
+                            continue;
+                        }
+                        println!("Device added {:?}", addr);
+                        if let Ok(d) = adapt.device(addr) {
+                            let _ = tx.send(MessageFromAsync::NewBluetoothDevice(addr)).await;
+                            let j = tokio::spawn(device_properties(addr, d.clone(), tx.clone()));
+                            watchers.insert(addr, j);
+                            bluetooth_devices.insert(addr, (adapt, d));
+                        }
+                    }
+                    AdapterEvent::DeviceRemoved(addr) => {
+                        println!("Device removed {:?}", addr);
+                        forget_device(
+                            addr,
+                            &mut bluetooth_devices,
+                            &mut watchers,
+                            &mut seen,
+                            &mut handsfree,
+                            &mut subscriptions,
+                        );
+                        let _ = tx.send(MessageFromAsync::OldBluetoothDevice(addr)).await;
+                    }
+                    AdapterEvent::PropertyChanged(prop) => {
+                        println!("Property changed {:?}", prop);
+                    }
+                }
+            }
+            _ = aging.tick(), if settings.discovery.max_age > 0 => {
+                let max_age = std::time::Duration::from_secs(settings.discovery.max_age);
+                let stale: Vec<bluer::Address> = seen
+                    .iter()
+                    .filter(|(_, t)| t.elapsed() >= max_age)
+                    .map(|(a, _)| *a)
+                    .collect();
+                for addr in stale {
+                    seen.remove(&addr);
+                    let Some((_, d)) = bluetooth_devices.get(&addr) else {
+                        continue;
+                    };
+                    // A slow device must not hold up the loop while it is asked
+                    let d = d.clone();
+                    let expired_tx = expired_tx.clone();
+                    tokio::spawn(async move {
+                        let props = d.all_properties().await.unwrap_or_default();
+                        let keep = props.iter().any(|p| {
+                            matches!(
+                                p,
+                                DeviceProperty::Paired(true) | DeviceProperty::Connected(true)
+                            )
+                        });
+                        if !keep {
+                            let _ = expired_tx.send(addr);
+                        }
+                    });
+                }
+            }
+            Some(addr) = expired.recv() => {
+                // Discovery may have reported it again while it was being checked
+                if seen.contains_key(&addr) || !bluetooth_devices.contains_key(&addr) {
+                    continue;
                 }
+                println!("Forgetting {:?}, not seen for a while", addr);
+                forget_device(
+                    addr,
+                    &mut bluetooth_devices,
+                    &mut watchers,
+                    &mut seen,
+                    &mut handsfree,
+                    &mut subscriptions,
+                );
+                let _ = tx.send(MessageFromAsync::OldBluetoothDevice(addr)).await;
+            }
+            Some(req) = next_connection(&mut h) => {
+                car_audio_connection(
+                    req,
+                    &mut handsfree,
+                    tx,
+                    &services.voice,
+                    settings.voice.msbc,
+                );
             }
         }
-        tokio::time::sleep(Duration::from_millis(1)).await;
+    };
+    if let Some((_, j)) = autoconnect {
+        j.abort();
+    }
+    for j in subscriptions.values().chain(watchers.values()) {
+        j.abort();
     }
+    r
 }
 
 impl BluetoothData {
@@ -261,27 +965,81 @@ impl BluetoothData {
         Self {
             scanning: false,
             devices: HashMap::new(),
+            phones: HashMap::new(),
+            call_start: HashMap::new(),
+            pairing: pairing::Pairing::new(),
+            actions: HashMap::new(),
+            link: autoconnect::PhoneLink::Idle,
+            status: BluetoothStatus::Starting,
+            adapters: Vec::new(),
+            active_adapter: None,
+            last_error: None,
+            streaming: None,
+            players: HashMap::new(),
+            phonebooks: HashMap::new(),
+            phonebook_status: HashMap::new(),
+            messages: HashMap::new(),
+            banner: None,
+            vehicle: obd::VehicleData::default(),
+            terminal: spp::TerminalState::default(),
+            gatt: HashMap::new(),
+            voice: HashMap::new(),
+            pushes: Vec::new(),
+            received_contacts: contacts::load_received(),
+            pictures: gallery::list(),
+            pan: HashMap::new(),
+        }
+    }
+
+    pub fn update_phone(&mut self, addr: bluer::Address, state: hfp::HfpState) {
+        if state.call_active() {
+            self.call_start
+                .entry(addr)
+                .or_insert_with(std::time::Instant::now);
+        } else {
+            self.call_start.remove(&addr);
         }
+        self.phones.insert(addr, state);
+    }
+
+    pub fn phone_name(&self, addr: &bluer::Address) -> String {
+        self.devices
+            .get(addr)
+            .and_then(|d| d.alias.clone().or_else(|| d.name.clone()))
+            .unwrap_or_else(|| addr.to_string())
+    }
+
+    pub fn remove_phone(&mut self, addr: &bluer::Address) {
+        self.phones.remove(addr);
+        self.call_start.remove(addr);
     }
 }
 
+/// How many signal strength readings are kept for each device
+pub const RSSI_HISTORY: usize = 60;
+
 pub struct BluetoothDeviceInfo {
-    name: Option<String>,
-    ty: Option<bluer::AddressType>,
-    icon: Option<String>,
-    class: Option<u32>,
-    appearance: Option<u16>,
-    uuids: HashSet<bluer::Uuid>,
-    paired: bool,
-    connected: bool,
-    trusted: bool,
-    blocked: bool,
-    wake: bool,
-    alias: Option<String>,
-    legacy_pair: bool,
-    rssi: Option<i16>,
-    txpwr: Option<i16>,
-    battery: Option<u8>,
+    pub name: Option<String>,
+    pub ty: Option<bluer::AddressType>,
+    pub icon: Option<String>,
+    pub class: Option<u32>,
+    pub appearance: Option<u16>,
+    pub uuids: HashSet<bluer::Uuid>,
+    pub paired: bool,
+    pub connected: bool,
+    pub trusted: bool,
+    pub blocked: bool,
+    pub wake: bool,
+    pub alias: Option<String>,
+    pub legacy_pair: bool,
+    pub modalias: Option<bluer::Modalias>,
+    pub rssi: Option<i16>,
+    /// Recent signal strengths, oldest first
+    pub rssi_history: VecDeque<i16>,
+    pub txpwr: Option<i16>,
+    pub battery: Option<u8>,
+    pub manufacturer_data: HashMap<u16, Vec<u8>>,
+    pub service_data: HashMap<bluer::Uuid, Vec<u8>>,
 }
 
 impl BluetoothDeviceInfo {
@@ -300,9 +1058,13 @@ impl BluetoothDeviceInfo {
             wake: false,
             alias: None,
             legacy_pair: false,
+            modalias: None,
             rssi: None,
+            rssi_history: VecDeque::new(),
             txpwr: None,
             battery: None,
+            manufacturer_data: HashMap::new(),
+            service_data: HashMap::new(),
         }
     }
 
@@ -322,11 +1084,17 @@ impl BluetoothDeviceInfo {
             bluer::DeviceProperty::WakeAllowed(w) => self.wake = w,
             bluer::DeviceProperty::Alias(a) => self.alias = Some(a),
             bluer::DeviceProperty::LegacyPairing(lp) => self.legacy_pair = lp,
-            bluer::DeviceProperty::Modalias(_) => {}
-            bluer::DeviceProperty::Rssi(r) => self.rssi = Some(r),
+            bluer::DeviceProperty::Modalias(m) => self.modalias = Some(m),
+            bluer::DeviceProperty::Rssi(r) => {
+                self.rssi = Some(r);
+                if self.rssi_history.len() >= RSSI_HISTORY {
+                    self.rssi_history.pop_front();
+                }
+                self.rssi_history.push_back(r);
+            }
             bluer::DeviceProperty::TxPower(t) => self.txpwr = Some(t),
-            bluer::DeviceProperty::ManufacturerData(_) => {}
-            bluer::DeviceProperty::ServiceData(_) => {}
+            bluer::DeviceProperty::ManufacturerData(m) => self.manufacturer_data = m,
+            bluer::DeviceProperty::ServiceData(d) => self.service_data = d,
             bluer::DeviceProperty::ServicesResolved(_) => {}
             bluer::DeviceProperty::AdvertisingFlags(_) => {}
             bluer::DeviceProperty::AdvertisingData(_) => {}
@@ -336,11 +1104,196 @@ impl BluetoothDeviceInfo {
     }
 }
 
-pub struct BluetoothConfig {}
+/// Services the scan filter offers to look for
+const SCAN_SERVICES: [(u32, &str); 6] = [
+    (0x111f, "Phones"),
+    (0x110a, "Audio sources"),
+    (0x1101, "Serial port"),
+    (0x180f, "Battery"),
+    (0x181a, "Environmental sensing"),
+    (0xfcd2, "BTHome sensors"),
+];
+
+pub struct BluetoothConfig {
+    selected: Option<bluer::Address>,
+    /// The minimum signal while its slider is dragged, applied once it is let go
+    min_rssi: Option<i16>,
+}
 
 impl BluetoothConfig {
     pub fn new() -> Self {
-        Self {}
+        Self {
+            selected: None,
+            min_rssi: None,
+        }
+    }
+
+    fn auto_connect(&self, ui: &mut egui::Ui, common: &mut CommonWindowProperties) {
+        let mut phones = common.config.phone_addresses();
+        let mut changed = false;
+        egui::CollapsingHeader::new("Auto connect phones").show(ui, |ui| {
+            if phones.is_empty() {
+                ui.label("No phones selected, use the auto connect button on a device");
+            }
+            let mut i = 0;
+            while i < phones.len() {
+                let addr = phones[i];
+                let mut removed = false;
+                ui.horizontal(|ui| {
+                    ui.label(format!("{}: {}", i + 1, common.bluetooth.phone_name(&addr)));
+                    if i > 0 && ui.button("Up").clicked() {
+                        phones.swap(i - 1, i);
+                        changed = true;
+                    }
+                    if i + 1 < phones.len() && ui.button("Down").clicked() {
+                        phones.swap(i, i + 1);
+                        changed = true;
+                    }
+                    if ui.button("Remove").clicked() {
+                        removed = true;
+                    }
+                });
+                if removed {
+                    phones.remove(i);
+                    changed = true;
+                } else {
+                    i += 1;
+                }
+            }
+        });
+        if changed {
+            common.config.set_phone_addresses(&phones);
+            common.config.save();
+            let _ = common
+                .tx
+                .blocking_send(MessageToAsync::AutoConnectPhones(phones));
+        }
+    }
+
+    fn discovery_filter(&mut self, ui: &mut egui::Ui, common: &mut CommonWindowProperties) {
+        let mut d = common.config.discovery.clone();
+        egui::CollapsingHeader::new("Scan filter").show(ui, |ui| {
+            ui.horizontal(|ui| {
+                ui.selectable_value(&mut d.transport, config::Transport::Any, "Any");
+                ui.selectable_value(&mut d.transport, config::Transport::Classic, "Classic");
+                ui.selectable_value(&mut d.transport, config::Transport::LowEnergy, "Low energy");
+            });
+            ui.horizontal(|ui| {
+                let mut limit = d.min_rssi.is_some();
+                let toggled = ui.checkbox(&mut limit, "Minimum signal").changed();
+                let mut rssi = self.min_rssi.or(d.min_rssi).unwrap_or(-80);
+                let r = ui.add_enabled(
+                    limit,
+                    egui::Slider::new(&mut rssi, -100..=-30).suffix(" dBm"),
+                );
+                if toggled || settings::settled(&r) {
+                    self.min_rssi = None;
+                    d.min_rssi = limit.then_some(rssi);
+                } else if r.dragged() {
+                    self.min_rssi = Some(rssi);
+                }
+            });
+            ui.horizontal_wrapped(|ui| {
+                ui.label("Services");
+                for (short, name) in SCAN_SERVICES {
+                    let u = bluetooth_device::full_uuid(short).to_string();
+                    let mut on = d.uuids.contains(&u);
+                    if ui.checkbox(&mut on, name).changed() {
+                        if on {
+                            d.uuids.push(u);
+                        } else {
+                            d.uuids.retain(|x| *x != u);
+                        }
+                    }
+                }
+            });
+            ui.horizontal(|ui| {
+                ui.label("Forget unpaired devices after");
+                for (secs, name) in [(30, "30 s"), (120, "2 min"), (600, "10 min"), (0, "Never")] {
+                    ui.selectable_value(&mut d.max_age, secs, name);
+                }
+            });
+        });
+        if d != common.config.discovery {
+            common.config.discovery = d;
+            common.config.save();
+            let _ = common
+                .tx
+                .blocking_send(MessageToAsync::AdapterSettings(common.config.clone()));
+        }
+    }
+
+    fn add_auto_connect(common: &mut CommonWindowProperties, addr: bluer::Address) {
+        let mut phones = common.config.phone_addresses();
+        if phones.contains(&addr) {
+            return;
+        }
+        phones.push(addr);
+        common.config.set_phone_addresses(&phones);
+        common.config.save();
+        let _ = common
+            .tx
+            .blocking_send(MessageToAsync::BluetoothDeviceAction(
+                addr,
+                DeviceAction::Trust,
+            ));
+        let _ = common
+            .tx
+            .blocking_send(MessageToAsync::AutoConnectPhones(phones));
+    }
+
+    fn device_actions(
+        &self,
+        ui: &mut egui::Ui,
+        common: &CommonWindowProperties,
+        addr: bluer::Address,
+        dev: &BluetoothDeviceInfo,
+    ) {
+        let mut actions = Vec::new();
+        if !dev.paired {
+            actions.push(DeviceAction::Pair);
+        }
+        if dev.connected {
+            actions.push(DeviceAction::Disconnect);
+        } else {
+            actions.push(DeviceAction::Connect);
+        }
+        if dev.trusted {
+            actions.push(DeviceAction::Untrust);
+        } else {
+            actions.push(DeviceAction::Trust);
+        }
+        if dev.blocked {
+            actions.push(DeviceAction::Unblock);
+        } else {
+            actions.push(DeviceAction::Block);
+        }
+        actions.push(DeviceAction::Forget);
+        ui.horizontal(|ui| {
+            for a in actions {
+                if ui.button(a.name()).clicked() {
+                    let _ = common
+                        .tx
+                        .blocking_send(MessageToAsync::BluetoothDeviceAction(addr, a));
+                }
+            }
+        });
+        if let Some((a, status)) = common.bluetooth.actions.get(&addr) {
+            match status {
+                ActionStatus::Started => {
+                    ui.horizontal(|ui| {
+                        ui.spinner();
+                        ui.label(format!("{} in progress", a.name()));
+                    });
+                }
+                ActionStatus::Done => {
+                    ui.label(format!("{} complete", a.name()));
+                }
+                ActionStatus::Failed(e) => {
+                    ui.colored_label(egui::Color32::RED, format!("{} failed: {}", a.name(), e));
+                }
+            }
+        }
     }
 }
 
@@ -351,7 +1304,42 @@ impl SubwindowTrait for BluetoothConfig {
         frame: &mut eframe::Frame,
         common: &mut CommonWindowProperties,
     ) -> Option<Subwindow> {
+        let mut r = None;
         egui::CentralPanel::default().show(ctx, |ui| {
+            match &common.bluetooth.status {
+                BluetoothStatus::Starting => {
+                    ui.horizontal(|ui| {
+                        ui.spinner();
+                        ui.label("Starting bluetooth");
+                    });
+                    return;
+                }
+                BluetoothStatus::Unavailable(e) => {
+                    ui.heading("Bluetooth unavailable");
+                    ui.label(e.to_string());
+                    ui.label("Retrying in the background");
+                    return;
+                }
+                BluetoothStatus::NoAdapter => {
+                    ui.heading("No bluetooth adapter found");
+                    ui.label("Plug in a bluetooth adapter to continue");
+                    return;
+                }
+                BluetoothStatus::Ready(names) => {
+                    ui.label(format!("Adapters: {}", names.join(", ")));
+                }
+            }
+            if let Some(e) = &common.bluetooth.last_error {
+                ui.colored_label(egui::Color32::RED, e.to_string());
+            }
+            if let Some(s) = &common.bluetooth.streaming {
+                ui.label(format!(
+                    "Playing audio from {} ({} Hz, {} channels)",
+                    common.bluetooth.phone_name(&s.device),
+                    s.rate,
+                    s.channels
+                ));
+            }
             if !common.bluetooth.scanning {
                 if ui.button("Scan").clicked() {
                     common.bluetooth.scanning = true;
@@ -367,22 +1355,444 @@ impl SubwindowTrait for BluetoothConfig {
                         .blocking_send(MessageToAsync::BluetoothScan(common.bluetooth.scanning));
                 }
             }
+            if ui.button("Adapters").clicked() {
+                r = Some(Subwindow::Adapters(adapters::Adapters::new()));
+            }
+            if ui.button("Sensors").clicked() {
+                r = Some(Subwindow::Sensors(sensors::Sensors::new()));
+            }
+            self.auto_connect(ui, common);
+            self.discovery_filter(ui, common);
+            let mut auto_connect = None;
             egui::scroll_area::ScrollArea::vertical()
                 .auto_shrink([false; 2])
                 .show(ui, |ui| {
+                    let phones = common.config.phone_addresses();
+                    let spp = bluer::Uuid::from_str(spp::SPP_UUID).unwrap();
                     let mut bd: Vec<(&bluer::Address, &BluetoothDeviceInfo)> =
                         common.bluetooth.devices.iter().collect();
-                    bd.sort_by(|(_a1, a2), (_b1, b2)| b2.rssi.cmp(&a2.rssi));
+                    // Paired devices first, then by signal strength
+                    bd.sort_by(|(_a1, a2), (_b1, b2)| {
+                        b2.paired.cmp(&a2.paired).then(b2.rssi.cmp(&a2.rssi))
+                    });
                     for (a, dev) in bd {
                         let t = format!("RSSI: {:?}, icon {:?}", dev.rssi, dev.icon);
-                        if let Some(a) = &dev.alias {
-                            ui.label(format!("Device: {} {}", a, t));
+                        let text = if let Some(a) = &dev.alias {
+                            format!("Device: {} {}", a, t)
                         } else {
-                            ui.label(format!("Device {:?} {}", a, t));
+                            format!("Device {:?} {}", a, t)
+                        };
+                        let selected = self.selected == Some(*a);
+                        ui.horizontal(|ui| {
+                            if ui.selectable_label(selected, text).clicked() {
+                                self.selected = if selected { None } else { Some(*a) };
+                            }
+                            bluetooth_device::rssi_sparkline(
+                                ui,
+                                &dev.rssi_history,
+                                egui::vec2(120.0, 20.0),
+                            );
+                        });
+                        if selected {
+                            self.device_actions(ui, common, *a, dev);
+                            ui.horizontal(|ui| {
+                                if ui.button("Details").clicked() {
+                                    r = Some(Subwindow::BluetoothDevice(
+                                        bluetooth_device::BluetoothDevice::new(*a),
+                                    ));
+                                }
+                                if !phones.contains(a) && ui.button("Auto connect").clicked() {
+                                    auto_connect = Some(*a);
+                                }
+                                if dev.uuids.contains(&spp) && ui.button("Vehicle data").clicked() {
+                                    let _ = common.tx.blocking_send(MessageToAsync::ObdConnect(*a));
+                                    r = Some(Subwindow::Vehicle(vehicle::Vehicle::new()));
+                                }
+                                if dev.uuids.contains(&spp) && ui.button("Terminal").clicked() {
+                                    let _ = common
+                                        .tx
+                                        .blocking_send(MessageToAsync::TerminalConnect(*a));
+                                    r = Some(Subwindow::Terminal(terminal::Terminal::new()));
+                                }
+                            });
                         }
                     }
                 });
+            if let Some(a) = auto_connect {
+                Self::add_auto_connect(common, a);
+            }
         });
-        None
+        r
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bluetooth_mock::{MockBackend, MockControl};
+    use std::time::Duration;
+    use tokio::io::AsyncBufReadExt;
+
+    const PHONE: bluer::Address = bluer::Address::new([0x00, 0x11, 0x22, 0x33, 0x44, 0x55]);
+    const SENSOR: bluer::Address = bluer::Address::new([0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb]);
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    const BATTERY: gatt::AttributeId = gatt::AttributeId {
+        service: 0x0010,
+        characteristic: 0x0011,
+        descriptor: None,
+    };
+
+    /// Services that keep what is routed to them instead of talking to bluez
+    fn services() -> (Services, Vec<tokio::sync::mpsc::Receiver<MessageToAsync>>) {
+        let mut receivers = Vec::new();
+        let mut channel = || {
+            let (s, r) = tokio::sync::mpsc::channel(10);
+            receivers.push(r);
+            s
+        };
+        let services = Services {
+            media: channel(),
+            pbap: channel(),
+            map: channel(),
+            obd: channel(),
+            terminal: channel(),
+            voice: channel(),
+            push: channel(),
+            pan: channel(),
+        };
+        (services, receivers)
+    }
+
+    /// The ends of a session the gui would hold
+    struct Gui {
+        from: tokio::sync::mpsc::Receiver<MessageFromAsync>,
+        to: tokio::sync::mpsc::Sender<MessageToAsync>,
+        status: Option<BluetoothStatus>,
+        devices: HashMap<bluer::Address, BluetoothDeviceInfo>,
+        actions: HashMap<bluer::Address, (DeviceAction, ActionStatus)>,
+        values: HashMap<gatt::AttributeId, Vec<u8>>,
+        handsfree: HashSet<bluer::Address>,
+    }
+
+    impl Gui {
+        /// Keep track of a message the way the gui does
+        fn apply(&mut self, m: MessageFromAsync) {
+            match m {
+                MessageFromAsync::BluetoothStatus(s) => self.status = Some(s),
+                MessageFromAsync::NewBluetoothDevice(addr) => {
+                    self.devices
+                        .entry(addr)
+                        .or_insert_with(BluetoothDeviceInfo::new);
+                }
+                MessageFromAsync::OldBluetoothDevice(addr) => {
+                    self.devices.remove(&addr);
+                }
+                MessageFromAsync::BluetoothDeviceProperty(addr, prop) => {
+                    if let Some(d) = self.devices.get_mut(&addr) {
+                        d.update(prop);
+                    }
+                }
+                MessageFromAsync::BluetoothDeviceAction(addr, action, status) => {
+                    self.actions.insert(addr, (action, status));
+                }
+                MessageFromAsync::GattValue(_, id, v) => {
+                    self.values.insert(id, v);
+                }
+                MessageFromAsync::HfpConnected(addr) => {
+                    self.handsfree.insert(addr);
+                }
+                MessageFromAsync::HfpDisconnected(addr) => {
+                    self.handsfree.remove(&addr);
+                }
+                _ => {}
+            }
+        }
+
+        /// Take messages from the session until what they say passes f
+        async fn wait_for(&mut self, f: impl Fn(&Self) -> bool) {
+            while !f(self) {
+                let m = tokio::time::timeout(TIMEOUT, self.from.recv())
+                    .await
+                    .expect("The session never got there")
+                    .expect("The session stopped");
+                self.apply(m);
+            }
+        }
+
+        async fn send(&self, m: MessageToAsync) {
+            self.to.send(m).await.unwrap();
+        }
+
+        fn device(&self, addr: bluer::Address) -> Option<&BluetoothDeviceInfo> {
+            self.devices.get(&addr)
+        }
+
+        fn ready(&self, adapters: &[&str]) -> bool {
+            matches!(&self.status, Some(BluetoothStatus::Ready(a)) if *a == adapters)
+        }
+    }
+
+    /// The session side of the channels, with a gui on the other end
+    struct Session {
+        tx: tokio::sync::mpsc::Sender<MessageFromAsync>,
+        rx: tokio::sync::mpsc::Receiver<MessageToAsync>,
+        phones_tx: tokio::sync::watch::Sender<Vec<bluer::Address>>,
+        services: Services,
+        _receivers: Vec<tokio::sync::mpsc::Receiver<MessageToAsync>>,
+    }
+
+    fn session() -> (Session, Gui) {
+        let (tx, from) = tokio::sync::mpsc::channel(100);
+        let (to, rx) = tokio::sync::mpsc::channel(10);
+        let (phones_tx, _) = tokio::sync::watch::channel(Vec::new());
+        let (services, _receivers) = services();
+        let gui = Gui {
+            from,
+            to,
+            status: None,
+            devices: HashMap::new(),
+            actions: HashMap::new(),
+            values: HashMap::new(),
+            handsfree: HashSet::new(),
+        };
+        let s = Session {
+            tx,
+            rx,
+            phones_tx,
+            services,
+            _receivers,
+        };
+        (s, gui)
+    }
+
+    fn backend() -> (MockBackend, MockControl) {
+        let (b, c) = MockBackend::new();
+        c.add_adapter("hci0");
+        c.add_device(
+            "hci0",
+            PHONE,
+            vec![
+                DeviceProperty::Alias("Phone".to_string()),
+                DeviceProperty::Paired(true),
+                DeviceProperty::Rssi(-60),
+            ],
+        );
+        (b, c)
+    }
+
+    #[tokio::test]
+    async fn devices() {
+        let (backend, control) = backend();
+        let (mut s, mut gui) = session();
+        let session = bluetooth_session(&backend, &s.tx, &mut s.rx, &s.phones_tx, &s.services);
+        let script = async {
+            gui.wait_for(|g| g.ready(&["hci0"])).await;
+            gui.send(MessageToAsync::BluetoothScan(true)).await;
+            gui.wait_for(|g| {
+                g.device(PHONE)
+                    .map(|d| d.alias.as_deref() == Some("Phone") && d.paired)
+                    .unwrap_or(false)
+            })
+            .await;
+
+            // Found while scanning
+            control.add_device(
+                "hci0",
+                SENSOR,
+                vec![
+                    DeviceProperty::Alias("Sensor".to_string()),
+                    DeviceProperty::AddressType(bluer::AddressType::LeRandom),
+                    DeviceProperty::BatteryPercentage(75),
+                ],
+            );
+            gui.wait_for(|g| g.device(SENSOR).and_then(|d| d.battery) == Some(75))
+                .await;
+            assert_eq!(
+                gui.device(SENSOR).unwrap().ty,
+                Some(bluer::AddressType::LeRandom)
+            );
+
+            // Changes reported by the device itself
+            control.set_property("hci0", PHONE, DeviceProperty::Connected(true));
+            control.set_property("hci0", PHONE, DeviceProperty::Rssi(-40));
+            gui.wait_for(|g| {
+                g.device(PHONE)
+                    .map(|d| d.connected && d.rssi == Some(-40))
+                    .unwrap_or(false)
+            })
+            .await;
+            assert_eq!(gui.device(PHONE).unwrap().rssi_history.back(), Some(&-40));
+
+            // Changes made by the user
+            gui.send(MessageToAsync::BluetoothDeviceAction(
+                PHONE,
+                DeviceAction::Trust,
+            ))
+            .await;
+            gui.wait_for(|g| {
+                matches!(
+                    g.actions.get(&PHONE),
+                    Some((DeviceAction::Trust, ActionStatus::Done))
+                ) && g.device(PHONE).map(|d| d.trusted).unwrap_or(false)
+            })
+            .await;
+            let unknown = bluer::Address::new([0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc]);
+            gui.send(MessageToAsync::BluetoothDeviceAction(
+                unknown,
+                DeviceAction::Connect,
+            ))
+            .await;
+            gui.wait_for(|g| {
+                matches!(
+                    g.actions.get(&unknown),
+                    Some((DeviceAction::Connect, ActionStatus::Failed(_)))
+                )
+            })
+            .await;
+
+            control.remove_device("hci0", SENSOR);
+            gui.wait_for(|g| g.device(SENSOR).is_none()).await;
+            assert!(gui.device(PHONE).is_some());
+
+            gui.send(MessageToAsync::Quit).await;
+        };
+        let (r, ()) = tokio::join!(session, script);
+        assert!(r.is_ok());
+    }
+
+    #[tokio::test]
+    async fn adapters() {
+        let (backend, control) = backend();
+        let (mut s, mut gui) = session();
+        let session = bluetooth_session(&backend, &s.tx, &mut s.rx, &s.phones_tx, &s.services);
+        let script = async {
+            gui.wait_for(|g| g.ready(&["hci0"])).await;
+            gui.send(MessageToAsync::BluetoothScan(true)).await;
+            gui.wait_for(|g| g.device(PHONE).is_some()).await;
+            control.add_adapter("hci1");
+            gui.wait_for(|g| g.ready(&["hci0", "hci1"])).await;
+            // The devices of an adapter go with it
+            control.remove_adapter("hci0");
+            gui.wait_for(|g| g.ready(&["hci1"]) && g.device(PHONE).is_none())
+                .await;
+            control.remove_adapter("hci1");
+            gui.wait_for(|g| matches!(g.status, Some(BluetoothStatus::NoAdapter)))
+                .await;
+            gui.send(MessageToAsync::Quit).await;
+        };
+        let (r, ()) = tokio::join!(session, script);
+        assert!(r.is_ok());
+    }
+
+    #[tokio::test]
+    async fn gatt_notifications() {
+        let (backend, control) = backend();
+        control.add_device("hci0", SENSOR, vec![DeviceProperty::BatteryPercentage(75)]);
+        control.set_gatt(
+            "hci0",
+            SENSOR,
+            Vec::new(),
+            [(BATTERY, vec![75])].into_iter().collect(),
+        );
+        let (mut s, mut gui) = session();
+        let session = bluetooth_session(&backend, &s.tx, &mut s.rx, &s.phones_tx, &s.services);
+        let script = async {
+            gui.wait_for(|g| g.ready(&["hci0"])).await;
+            gui.send(MessageToAsync::BluetoothScan(true)).await;
+            gui.wait_for(|g| g.device(SENSOR).is_some()).await;
+
+            gui.send(MessageToAsync::GattRead(SENSOR, BATTERY)).await;
+            gui.wait_for(|g| g.values.get(&BATTERY).is_some_and(|v| *v == [75]))
+                .await;
+
+            gui.send(MessageToAsync::GattSubscribe(SENSOR, BATTERY))
+                .await;
+            // The subscription is made in a task of its own, so a notification may come
+            // before it is in place and has to be repeated until one gets through
+            let mut level = 74;
+            while gui.values.get(&BATTERY).is_none_or(|v| *v != [level]) {
+                level -= 1;
+                control.notify("hci0", SENSOR, BATTERY, vec![level]);
+                if let Ok(Some(m)) =
+                    tokio::time::timeout(Duration::from_millis(100), gui.from.recv()).await
+                {
+                    gui.apply(m);
+                }
+            }
+            assert!(level > 0);
+
+            gui.send(MessageToAsync::GattUnsubscribe(SENSOR, BATTERY))
+                .await;
+            gui.send(MessageToAsync::Quit).await;
+        };
+        let (r, ()) = tokio::join!(session, script);
+        assert!(r.is_ok());
+    }
+
+    #[tokio::test]
+    async fn wide_band_speech() {
+        let (backend, control) = backend();
+        let (mut s, mut gui) = session();
+        let session = bluetooth_session(&backend, &s.tx, &mut s.rx, &s.phones_tx, &s.services);
+        let script = async {
+            gui.wait_for(|g| g.ready(&["hci0"])).await;
+            for msbc in [false, true, false] {
+                gui.send(MessageToAsync::VoiceSettings(config::VoiceConfig {
+                    msbc,
+                    ..Default::default()
+                }))
+                .await;
+                // The old profile is replaced by one with a service record to match
+                tokio::time::timeout(TIMEOUT, async {
+                    loop {
+                        if let [Some(f)] = control.profile_features()[..] {
+                            if (f & hfp::HF_SDP_WIDEBAND_SPEECH != 0) == msbc {
+                                break;
+                            }
+                        }
+                        tokio::time::sleep(Duration::from_millis(10)).await;
+                    }
+                })
+                .await
+                .expect("The profile was not registered again");
+            }
+            gui.send(MessageToAsync::Quit).await;
+        };
+        let (r, ()) = tokio::join!(session, script);
+        assert!(r.is_ok());
+    }
+
+    #[tokio::test]
+    async fn hands_free_connection() {
+        let (backend, control) = backend();
+        let (mut s, mut gui) = session();
+        let session = bluetooth_session(&backend, &s.tx, &mut s.rx, &s.phones_tx, &s.services);
+        let script = async {
+            gui.wait_for(|g| g.ready(&["hci0"])).await;
+            // The profile is registered after the adapters are set up
+            let phone = loop {
+                if let Some(p) = control.connect_profile(PHONE).pop() {
+                    break p;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            };
+            let (r, w) = tokio::io::split(phone);
+            let mut r = tokio::io::BufReader::new(r);
+            let mut first = Vec::new();
+            tokio::time::timeout(TIMEOUT, r.read_until(b'\r', &mut first))
+                .await
+                .expect("No command from the hands free side")
+                .unwrap();
+            assert!(first.starts_with(b"AT+BRSF="));
+            gui.wait_for(|g| g.handsfree.contains(&PHONE)).await;
+
+            // The phone hanging up ends the connection
+            drop((r, w));
+            gui.wait_for(|g| !g.handsfree.contains(&PHONE)).await;
+            gui.send(MessageToAsync::Quit).await;
+        };
+        let (r, ()) = tokio::join!(session, script);
+        assert!(r.is_ok());
     }
 }
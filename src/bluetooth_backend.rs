@@ -0,0 +1,391 @@
+use std::any::Any;
+
+use bluer::AdapterEvent;
+use bluer::DeviceEvent;
+use bluer::DeviceProperty;
+use futures::future::BoxFuture;
+use futures::stream::BoxStream;
+use futures::FutureExt;
+use futures::StreamExt;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::gatt;
+
+#[derive(Clone, Debug)]
+pub struct BackendError(pub String);
+
+impl std::fmt::Display for BackendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl From<bluer::Error> for BackendError {
+    fn from(e: bluer::Error) -> Self {
+        BackendError(e.to_string())
+    }
+}
+
+pub type BackendResult<T> = Result<T, BackendError>;
+
+/// A connected rfcomm socket
+pub trait RfcommStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> RfcommStream for T {}
+
+/// An accepted connection to a registered profile
+pub struct ProfileConnection {
+    pub device: bluer::Address,
+    pub stream: Box<dyn RfcommStream>,
+}
+
+/// Everything bluez reports about an adapter
+#[derive(Clone, Debug)]
+pub struct AdapterInfo {
+    pub name: String,
+    pub address: bluer::Address,
+    pub address_type: String,
+    /// The name bluez picked for the adapter
+    pub system_name: String,
+    /// The name other devices see
+    pub alias: String,
+    pub modalias: Option<String>,
+    pub powered: bool,
+    pub discoverable: bool,
+    /// Seconds the adapter stays discoverable for, 0 for no limit
+    pub discoverable_timeout: u32,
+    pub pairable: bool,
+    pub uuids: Vec<bluer::Uuid>,
+    pub active_advertising: Option<u8>,
+    pub supported_advertising: Option<u8>,
+    pub advertising_includes: Option<String>,
+    pub advertising_capabilities: Option<String>,
+    pub advertising_features: Option<String>,
+}
+
+/// The bluetooth stack as a whole
+pub trait Backend: Send + Sync + 'static {
+    type Adapter: BackendAdapter;
+
+    fn events(&self) -> BoxFuture<'_, BackendResult<BoxStream<'static, bluer::SessionEvent>>>;
+    fn adapter_names(&self) -> BoxFuture<'_, BackendResult<Vec<String>>>;
+    fn adapter(&self, name: &str) -> BackendResult<Self::Adapter>;
+    fn register_agent(
+        &self,
+        agent: bluer::agent::Agent,
+    ) -> BoxFuture<'_, BackendResult<Box<dyn Any + Send>>>;
+    fn register_profile(
+        &self,
+        profile: bluer::rfcomm::Profile,
+    ) -> BoxFuture<'_, BackendResult<BoxStream<'static, ProfileConnection>>>;
+}
+
+/// A single bluetooth controller
+pub trait BackendAdapter: Clone + Send + Sync + 'static {
+    type Device: BackendDevice;
+
+    fn name(&self) -> &str;
+    fn info(&self) -> BoxFuture<'_, BackendResult<AdapterInfo>>;
+    fn set_powered(&self, v: bool) -> BoxFuture<'_, BackendResult<()>>;
+    /// Set the name other devices see, an empty name restores the system name
+    fn set_alias(&self, alias: String) -> BoxFuture<'_, BackendResult<()>>;
+    fn set_discoverable(&self, v: bool) -> BoxFuture<'_, BackendResult<()>>;
+    fn set_discoverable_timeout(&self, secs: u32) -> BoxFuture<'_, BackendResult<()>>;
+    fn set_pairable(&self, v: bool) -> BoxFuture<'_, BackendResult<()>>;
+    /// Limit the devices found by the next call to discover_devices
+    fn set_discovery_filter(
+        &self,
+        filter: bluer::DiscoveryFilter,
+    ) -> BoxFuture<'_, BackendResult<()>>;
+    fn discover_devices(&self) -> BoxFuture<'_, BackendResult<BoxStream<'static, AdapterEvent>>>;
+    fn device(&self, addr: bluer::Address) -> BackendResult<Self::Device>;
+    fn remove_device(&self, addr: bluer::Address) -> BoxFuture<'_, BackendResult<()>>;
+}
+
+/// A remote bluetooth device known to an adapter
+pub trait BackendDevice: Clone + Send + Sync + 'static {
+    fn address(&self) -> bluer::Address;
+    fn all_properties(&self) -> BoxFuture<'_, BackendResult<Vec<DeviceProperty>>>;
+    fn events(&self) -> BoxFuture<'_, BackendResult<BoxStream<'static, DeviceEvent>>>;
+    fn is_connected(&self) -> BoxFuture<'_, BackendResult<bool>>;
+    fn pair(&self) -> BoxFuture<'_, BackendResult<()>>;
+    fn connect(&self) -> BoxFuture<'_, BackendResult<()>>;
+    fn disconnect(&self) -> BoxFuture<'_, BackendResult<()>>;
+    fn set_trusted(&self, v: bool) -> BoxFuture<'_, BackendResult<()>>;
+    fn set_blocked(&self, v: bool) -> BoxFuture<'_, BackendResult<()>>;
+    fn gatt_services(&self) -> BoxFuture<'_, BackendResult<Vec<gatt::Service>>>;
+    fn gatt_read(&self, id: gatt::AttributeId) -> BoxFuture<'_, BackendResult<Vec<u8>>>;
+    fn gatt_notify(
+        &self,
+        id: gatt::AttributeId,
+    ) -> BoxFuture<'_, BackendResult<BoxStream<'static, Vec<u8>>>>;
+}
+
+/// The real bluetooth stack, through bluez
+pub struct BluerBackend {
+    session: bluer::Session,
+}
+
+impl BluerBackend {
+    pub async fn new() -> BackendResult<Self> {
+        Ok(Self {
+            session: bluer::Session::new().await?,
+        })
+    }
+}
+
+impl Backend for BluerBackend {
+    type Adapter = bluer::Adapter;
+
+    fn events(&self) -> BoxFuture<'_, BackendResult<BoxStream<'static, bluer::SessionEvent>>> {
+        async move { Ok(self.session.events().await?.boxed()) }.boxed()
+    }
+
+    fn adapter_names(&self) -> BoxFuture<'_, BackendResult<Vec<String>>> {
+        async move { Ok(self.session.adapter_names().await?) }.boxed()
+    }
+
+    fn adapter(&self, name: &str) -> BackendResult<Self::Adapter> {
+        Ok(self.session.adapter(name)?)
+    }
+
+    fn register_agent(
+        &self,
+        agent: bluer::agent::Agent,
+    ) -> BoxFuture<'_, BackendResult<Box<dyn Any + Send>>> {
+        async move {
+            let h = self.session.register_agent(agent).await?;
+            Ok(Box::new(h) as Box<dyn Any + Send>)
+        }
+        .boxed()
+    }
+
+    fn register_profile(
+        &self,
+        profile: bluer::rfcomm::Profile,
+    ) -> BoxFuture<'_, BackendResult<BoxStream<'static, ProfileConnection>>> {
+        async move {
+            let h = self.session.register_profile(profile).await?;
+            Ok(h.filter_map(|req| async move {
+                let device = req.device();
+                match req.accept() {
+                    Ok(s) => Some(ProfileConnection {
+                        device,
+                        stream: Box::new(s),
+                    }),
+                    Err(e) => {
+                        println!("Failed to accept connection from {} {:?}", device, e);
+                        None
+                    }
+                }
+            })
+            .boxed())
+        }
+        .boxed()
+    }
+}
+
+async fn query_adapter(adapter: &bluer::Adapter) -> bluer::Result<AdapterInfo> {
+    let mut uuids: Vec<bluer::Uuid> = adapter
+        .uuids()
+        .await?
+        .unwrap_or_default()
+        .into_iter()
+        .collect();
+    uuids.sort();
+    Ok(AdapterInfo {
+        name: adapter.name().to_string(),
+        address: adapter.address().await?,
+        address_type: adapter.address_type().await?.to_string(),
+        system_name: adapter.system_name().await?,
+        alias: adapter.alias().await?,
+        modalias: adapter.modalias().await?.map(|m| format!("{:?}", m)),
+        powered: adapter.is_powered().await?,
+        discoverable: adapter.is_discoverable().await?,
+        discoverable_timeout: adapter.discoverable_timeout().await?,
+        pairable: adapter.is_pairable().await?,
+        uuids,
+        // Adapters without low energy support have no advertising manager
+        active_advertising: adapter.active_advertising_instances().await.ok(),
+        supported_advertising: adapter.supported_advertising_instances().await.ok(),
+        advertising_includes: adapter
+            .supported_advertising_system_includes()
+            .await
+            .ok()
+            .map(|i| format!("{:?}", i)),
+        advertising_capabilities: adapter
+            .supported_advertising_capabilities()
+            .await
+            .ok()
+            .flatten()
+            .map(|c| format!("{:?}", c)),
+        advertising_features: adapter
+            .supported_advertising_features()
+            .await
+            .ok()
+            .flatten()
+            .map(|f| format!("{:?}", f)),
+    })
+}
+
+impl BackendAdapter for bluer::Adapter {
+    type Device = bluer::Device;
+
+    fn name(&self) -> &str {
+        bluer::Adapter::name(self)
+    }
+
+    fn info(&self) -> BoxFuture<'_, BackendResult<AdapterInfo>> {
+        async move { Ok(query_adapter(self).await?) }.boxed()
+    }
+
+    fn set_powered(&self, v: bool) -> BoxFuture<'_, BackendResult<()>> {
+        async move { Ok(bluer::Adapter::set_powered(self, v).await?) }.boxed()
+    }
+
+    fn set_alias(&self, alias: String) -> BoxFuture<'_, BackendResult<()>> {
+        async move { Ok(bluer::Adapter::set_alias(self, alias).await?) }.boxed()
+    }
+
+    fn set_discoverable(&self, v: bool) -> BoxFuture<'_, BackendResult<()>> {
+        async move { Ok(bluer::Adapter::set_discoverable(self, v).await?) }.boxed()
+    }
+
+    fn set_discoverable_timeout(&self, secs: u32) -> BoxFuture<'_, BackendResult<()>> {
+        async move { Ok(bluer::Adapter::set_discoverable_timeout(self, secs).await?) }.boxed()
+    }
+
+    fn set_pairable(&self, v: bool) -> BoxFuture<'_, BackendResult<()>> {
+        async move { Ok(bluer::Adapter::set_pairable(self, v).await?) }.boxed()
+    }
+
+    fn set_discovery_filter(
+        &self,
+        filter: bluer::DiscoveryFilter,
+    ) -> BoxFuture<'_, BackendResult<()>> {
+        async move { Ok(bluer::Adapter::set_discovery_filter(self, filter).await?) }.boxed()
+    }
+
+    fn discover_devices(&self) -> BoxFuture<'_, BackendResult<BoxStream<'static, AdapterEvent>>> {
+        async move { Ok(self.discover_devices_with_changes().await?.boxed()) }.boxed()
+    }
+
+    fn device(&self, addr: bluer::Address) -> BackendResult<Self::Device> {
+        Ok(bluer::Adapter::device(self, addr)?)
+    }
+
+    fn remove_device(&self, addr: bluer::Address) -> BoxFuture<'_, BackendResult<()>> {
+        async move { Ok(bluer::Adapter::remove_device(self, addr).await?) }.boxed()
+    }
+}
+
+impl BackendDevice for bluer::Device {
+    fn address(&self) -> bluer::Address {
+        bluer::Device::address(self)
+    }
+
+    fn all_properties(&self) -> BoxFuture<'_, BackendResult<Vec<DeviceProperty>>> {
+        async move { Ok(bluer::Device::all_properties(self).await?) }.boxed()
+    }
+
+    fn events(&self) -> BoxFuture<'_, BackendResult<BoxStream<'static, DeviceEvent>>> {
+        async move { Ok(bluer::Device::events(self).await?.boxed()) }.boxed()
+    }
+
+    fn is_connected(&self) -> BoxFuture<'_, BackendResult<bool>> {
+        async move { Ok(bluer::Device::is_connected(self).await?) }.boxed()
+    }
+
+    fn pair(&self) -> BoxFuture<'_, BackendResult<()>> {
+        async move { Ok(bluer::Device::pair(self).await?) }.boxed()
+    }
+
+    fn connect(&self) -> BoxFuture<'_, BackendResult<()>> {
+        async move { Ok(bluer::Device::connect(self).await?) }.boxed()
+    }
+
+    fn disconnect(&self) -> BoxFuture<'_, BackendResult<()>> {
+        async move { Ok(bluer::Device::disconnect(self).await?) }.boxed()
+    }
+
+    fn set_trusted(&self, v: bool) -> BoxFuture<'_, BackendResult<()>> {
+        async move { Ok(bluer::Device::set_trusted(self, v).await?) }.boxed()
+    }
+
+    fn set_blocked(&self, v: bool) -> BoxFuture<'_, BackendResult<()>> {
+        async move { Ok(bluer::Device::set_blocked(self, v).await?) }.boxed()
+    }
+
+    fn gatt_services(&self) -> BoxFuture<'_, BackendResult<Vec<gatt::Service>>> {
+        async move { Ok(gatt_services(self).await?) }.boxed()
+    }
+
+    fn gatt_read(&self, id: gatt::AttributeId) -> BoxFuture<'_, BackendResult<Vec<u8>>> {
+        async move {
+            let c = self
+                .service(id.service)
+                .await?
+                .characteristic(id.characteristic)
+                .await?;
+            Ok(match id.descriptor {
+                Some(d) => c.descriptor(d).await?.read().await?,
+                None => c.read().await?,
+            })
+        }
+        .boxed()
+    }
+
+    fn gatt_notify(
+        &self,
+        id: gatt::AttributeId,
+    ) -> BoxFuture<'_, BackendResult<BoxStream<'static, Vec<u8>>>> {
+        async move {
+            let c = self
+                .service(id.service)
+                .await?
+                .characteristic(id.characteristic)
+                .await?;
+            Ok(c.notify().await?.boxed())
+        }
+        .boxed()
+    }
+}
+
+/// How long bluez gets to discover the services of a device after connecting
+const SERVICES_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+async fn gatt_services(dev: &bluer::Device) -> bluer::Result<Vec<gatt::Service>> {
+    let start = std::time::Instant::now();
+    while !dev.is_services_resolved().await? && start.elapsed() < SERVICES_TIMEOUT {
+        tokio::time::sleep(std::time::Duration::from_millis(250)).await;
+    }
+    let mut services = Vec::new();
+    for s in dev.services().await? {
+        let mut characteristics = Vec::new();
+        for c in s.characteristics().await? {
+            let flags = c.flags().await?;
+            let mut descriptors = Vec::new();
+            for d in c.descriptors().await? {
+                descriptors.push(gatt::Descriptor {
+                    id: d.id(),
+                    uuid: d.uuid().await?,
+                });
+            }
+            characteristics.push(gatt::Characteristic {
+                id: c.id(),
+                uuid: c.uuid().await?,
+                read: flags.read,
+                notify: flags.notify || flags.indicate,
+                descriptors,
+            });
+        }
+        services.push(gatt::Service {
+            id: s.id(),
+            uuid: s.uuid().await?,
+            primary: s.primary().await?,
+            characteristics,
+        });
+    }
+    services.sort_by_key(|s| s.id);
+    Ok(services)
+}
@@ -0,0 +1,620 @@
+use std::collections::VecDeque;
+
+use super::CommonWindowProperties;
+use super::MessageToAsync;
+use super::Subwindow;
+use super::SubwindowTrait;
+use crate::bluetooth;
+use crate::gatt;
+use crate::pan;
+use eframe::egui;
+
+/// The signal strengths at the bottom and top of a sparkline, in dBm
+const SPARKLINE_RANGE: (f32, f32) = (-100.0, -30.0);
+
+/// The lower 96 bits of every uuid based on the bluetooth base uuid
+const BASE_UUID_LOW: u128 = 0x0000_1000_8000_0080_5f9b_34fb;
+
+/// Get the 16 or 32 bit short form of a uuid, if it is derived from the bluetooth base uuid
+pub fn short_uuid(u: &bluer::Uuid) -> Option<u32> {
+    let v = u.as_u128();
+    if (v & ((1u128 << 96) - 1)) == BASE_UUID_LOW {
+        Some((v >> 96) as u32)
+    } else {
+        None
+    }
+}
+
+/// Expand a 16 or 32 bit short uuid to the full uuid
+pub fn full_uuid(short: u32) -> bluer::Uuid {
+    bluer::Uuid::from_u128(((short as u128) << 96) | BASE_UUID_LOW)
+}
+
+/// A human readable name for well known service uuids
+pub fn uuid_name(u: &bluer::Uuid) -> Option<&'static str> {
+    let n = match short_uuid(u)? {
+        0x1101 => "Serial Port (SPP)",
+        0x1103 => "Dial-up Networking (DUN)",
+        0x1104 => "IrMC Sync",
+        0x1105 => "Object Push (OPP)",
+        0x1106 => "File Transfer (FTP)",
+        0x1108 => "Headset (HSP)",
+        0x110A => "Audio Source (A2DP)",
+        0x110B => "Audio Sink (A2DP)",
+        0x110C => "Remote Control Target (AVRCP)",
+        0x110D => "Advanced Audio (A2DP)",
+        0x110E => "Remote Control (AVRCP)",
+        0x110F => "Remote Control Controller (AVRCP)",
+        0x1112 => "Headset Audio Gateway (HSP)",
+        0x1115 => "Personal Area Network User (PANU)",
+        0x1116 => "Network Access Point (NAP)",
+        0x1117 => "Group Ad-hoc Network (GN)",
+        0x111E => "Hands-Free (HFP)",
+        0x111F => "Hands-Free Audio Gateway (HFP)",
+        0x112D => "SIM Access (SAP)",
+        0x112E => "Phonebook Client (PBAP)",
+        0x112F => "Phonebook Server (PBAP)",
+        0x1130 => "Phonebook Access (PBAP)",
+        0x1132 => "Message Access Server (MAP)",
+        0x1133 => "Message Notification Server (MAP)",
+        0x1134 => "Message Access (MAP)",
+        0x1200 => "PnP Information",
+        0x1203 => "Generic Audio",
+        0x1800 => "Generic Access",
+        0x1801 => "Generic Attribute",
+        0x180A => "Device Information",
+        0x180D => "Heart Rate",
+        0x180F => "Battery",
+        0x1812 => "Human Interface Device",
+        0x181A => "Environmental Sensing",
+        _ => return None,
+    };
+    Some(n)
+}
+
+/// Decode the major and minor device class fields of a class of device
+pub fn device_class(class: u32) -> (&'static str, &'static str) {
+    let major = (class >> 8) & 0x1f;
+    let minor = (class >> 2) & 0x3f;
+    match major {
+        0 => ("Miscellaneous", ""),
+        1 => (
+            "Computer",
+            match minor {
+                1 => "Desktop",
+                2 => "Server",
+                3 => "Laptop",
+                4 => "Handheld",
+                5 => "Palm-size",
+                6 => "Wearable",
+                7 => "Tablet",
+                _ => "Uncategorized",
+            },
+        ),
+        2 => (
+            "Phone",
+            match minor {
+                1 => "Cellular",
+                2 => "Cordless",
+                3 => "Smartphone",
+                4 => "Wired modem",
+                5 => "ISDN",
+                _ => "Uncategorized",
+            },
+        ),
+        3 => ("Network access point", ""),
+        4 => (
+            "Audio/Video",
+            match minor {
+                1 => "Headset",
+                2 => "Hands-free",
+                4 => "Microphone",
+                5 => "Loudspeaker",
+                6 => "Headphones",
+                7 => "Portable audio",
+                8 => "Car audio",
+                9 => "Set-top box",
+                10 => "HiFi audio",
+                11 => "VCR",
+                12 => "Video camera",
+                13 => "Camcorder",
+                14 => "Video monitor",
+                15 => "Video display and loudspeaker",
+                16 => "Video conferencing",
+                18 => "Gaming/Toy",
+                _ => "Uncategorized",
+            },
+        ),
+        5 => (
+            "Peripheral",
+            match minor >> 4 {
+                1 => "Keyboard",
+                2 => "Pointing device",
+                3 => "Keyboard and pointing device",
+                _ => match minor & 0xf {
+                    1 => "Joystick",
+                    2 => "Gamepad",
+                    3 => "Remote control",
+                    4 => "Sensing device",
+                    5 => "Digitizer tablet",
+                    6 => "Card reader",
+                    _ => "Uncategorized",
+                },
+            },
+        ),
+        6 => ("Imaging", ""),
+        7 => (
+            "Wearable",
+            match minor {
+                1 => "Wristwatch",
+                2 => "Pager",
+                3 => "Jacket",
+                4 => "Helmet",
+                5 => "Glasses",
+                _ => "Uncategorized",
+            },
+        ),
+        8 => ("Toy", ""),
+        9 => ("Health", ""),
+        _ => ("Uncategorized", ""),
+    }
+}
+
+/// The service class bits of a class of device
+pub fn service_classes(class: u32) -> Vec<&'static str> {
+    [
+        (13, "Limited discoverable"),
+        (16, "Positioning"),
+        (17, "Networking"),
+        (18, "Rendering"),
+        (19, "Capturing"),
+        (20, "Object transfer"),
+        (21, "Audio"),
+        (22, "Telephony"),
+        (23, "Information"),
+    ]
+    .iter()
+    .filter(|(bit, _)| (class & (1 << bit)) != 0)
+    .map(|(_, n)| *n)
+    .collect()
+}
+
+/// The category of a bluetooth low energy appearance value
+pub fn appearance_name(appearance: u16) -> &'static str {
+    match appearance >> 6 {
+        1 => "Phone",
+        2 => "Computer",
+        3 => "Watch",
+        4 => "Clock",
+        5 => "Display",
+        6 => "Remote control",
+        7 => "Eye-glasses",
+        8 => "Tag",
+        9 => "Keyring",
+        10 => "Media player",
+        11 => "Barcode scanner",
+        12 => "Thermometer",
+        13 => "Heart rate sensor",
+        14 => "Blood pressure",
+        15 => "Human interface device",
+        16 => "Glucose meter",
+        17 => "Running walking sensor",
+        18 => "Cycling",
+        49 => "Pulse oximeter",
+        81 => "Outdoor sports",
+        _ => "Unknown",
+    }
+}
+
+/// A few of the company identifiers used in manufacturer data
+pub fn company_name(id: u16) -> Option<&'static str> {
+    match id {
+        0x0006 => Some("Microsoft"),
+        0x004C => Some("Apple"),
+        0x0075 => Some("Samsung"),
+        0x0087 => Some("Garmin"),
+        0x00E0 => Some("Google"),
+        0x0157 => Some("Huami"),
+        0x038F => Some("Xiaomi"),
+        _ => None,
+    }
+}
+
+/// Draw recent signal strengths as a small line, the newest on the right
+pub fn rssi_sparkline(ui: &mut egui::Ui, history: &VecDeque<i16>, size: egui::Vec2) {
+    let (rect, _) = ui.allocate_exact_size(size, egui::Sense::hover());
+    if history.len() < 2 {
+        return;
+    }
+    let (low, high) = SPARKLINE_RANGE;
+    let step = rect.width() / (bluetooth::RSSI_HISTORY - 1) as f32;
+    let points = history
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(i, r)| {
+            let v = ((*r as f32 - low) / (high - low)).clamp(0.0, 1.0);
+            egui::pos2(
+                rect.right() - i as f32 * step,
+                rect.bottom() - v * rect.height(),
+            )
+        })
+        .collect();
+    ui.painter().add(egui::Shape::line(
+        points,
+        egui::Stroke::new(1.5, ui.visuals().text_color()),
+    ));
+}
+
+pub fn hex(data: &[u8]) -> String {
+    data.iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+pub struct BluetoothDevice {
+    addr: bluer::Address,
+}
+
+impl BluetoothDevice {
+    pub fn new(addr: bluer::Address) -> Self {
+        Self { addr }
+    }
+}
+
+/// Connect to the network a phone shares and show how the connection is doing
+fn tethering(ui: &mut egui::Ui, common: &CommonWindowProperties, addr: bluer::Address) {
+    let shares = common
+        .bluetooth
+        .devices
+        .get(&addr)
+        .map(|d| d.uuids.contains(&full_uuid(0x1116)))
+        .unwrap_or(false);
+    let state = common.bluetooth.pan.get(&addr).cloned().unwrap_or_default();
+    if !shares && state == pan::PanState::default() {
+        return;
+    }
+    ui.horizontal(|ui| {
+        ui.heading("Network sharing");
+        match &state.status {
+            pan::PanStatus::Connecting | pan::PanStatus::Disconnecting => {
+                ui.spinner();
+            }
+            pan::PanStatus::Connected => {
+                if ui.button("Disconnect").clicked() {
+                    let _ = common.tx.blocking_send(MessageToAsync::PanDisconnect(addr));
+                }
+            }
+            pan::PanStatus::Disconnected | pan::PanStatus::Failed(_) => {
+                if ui.button("Connect").clicked() {
+                    let _ = common.tx.blocking_send(MessageToAsync::PanConnect(addr));
+                }
+            }
+        }
+    });
+    if let pan::PanStatus::Failed(e) = &state.status {
+        ui.colored_label(egui::Color32::RED, e);
+    }
+    let Some(interface) = &state.interface else {
+        return;
+    };
+    egui::Grid::new("network sharing")
+        .num_columns(2)
+        .striped(true)
+        .show(ui, |ui| {
+            ui.label("Interface");
+            ui.label(interface);
+            ui.end_row();
+            ui.label("Link");
+            ui.label(state.link.as_deref().unwrap_or("unknown"));
+            ui.end_row();
+            ui.label("Address");
+            if state.addresses.is_empty() {
+                ui.label("None yet");
+            } else {
+                ui.vertical(|ui| {
+                    for a in &state.addresses {
+                        ui.label(a.to_string());
+                    }
+                });
+            }
+            ui.end_row();
+        });
+}
+
+/// The attribute tree of a device, with buttons to read and subscribe to values
+fn gatt_explorer(ui: &mut egui::Ui, common: &mut CommonWindowProperties, addr: bluer::Address) {
+    ui.horizontal(|ui| {
+        ui.heading("Attributes");
+        if ui.button("Browse").clicked() {
+            common.bluetooth.gatt.insert(addr, gatt::GattState::new());
+            let _ = common.tx.blocking_send(MessageToAsync::GattBrowse(addr));
+        }
+    });
+    let Some(g) = common.bluetooth.gatt.get_mut(&addr) else {
+        return;
+    };
+    match &g.status {
+        bluetooth::ActionStatus::Started => {
+            ui.horizontal(|ui| {
+                ui.spinner();
+                ui.label("Connecting and reading services");
+            });
+            return;
+        }
+        bluetooth::ActionStatus::Failed(e) => {
+            ui.colored_label(egui::Color32::RED, e);
+            return;
+        }
+        bluetooth::ActionStatus::Done => {}
+    }
+    let mut messages = Vec::new();
+    for s in &g.services {
+        let name = uuid_name(&s.uuid)
+            .map(|n| n.to_string())
+            .unwrap_or_else(|| s.uuid.to_string());
+        let name = if s.primary {
+            name
+        } else {
+            format!("{} (secondary)", name)
+        };
+        egui::CollapsingHeader::new(name)
+            .id_source(("service", s.id))
+            .show(ui, |ui| {
+                for c in &s.characteristics {
+                    let id = gatt::AttributeId {
+                        service: s.id,
+                        characteristic: c.id,
+                        descriptor: None,
+                    };
+                    ui.horizontal(|ui| {
+                        ui.label(
+                            gatt::attribute_name(&c.uuid)
+                                .map(|n| n.to_string())
+                                .unwrap_or_else(|| c.uuid.to_string()),
+                        );
+                        if c.read && ui.button("Read").clicked() {
+                            messages.push(MessageToAsync::GattRead(addr, id));
+                        }
+                        if c.notify {
+                            let mut on = g.subscribed.contains(&id);
+                            if ui.checkbox(&mut on, "Subscribe").changed() {
+                                if on {
+                                    g.subscribed.insert(id);
+                                    messages.push(MessageToAsync::GattSubscribe(addr, id));
+                                } else {
+                                    g.subscribed.remove(&id);
+                                    messages.push(MessageToAsync::GattUnsubscribe(addr, id));
+                                }
+                            }
+                        }
+                        if let Some(v) = g.values.get(&id) {
+                            match gatt::decode(&c.uuid, v) {
+                                Some(d) => ui.label(format!("{} ({})", d, hex(v))),
+                                None => ui.label(hex(v)),
+                            };
+                        }
+                    });
+                    for d in &c.descriptors {
+                        let id = gatt::AttributeId {
+                            descriptor: Some(d.id),
+                            ..id
+                        };
+                        ui.horizontal(|ui| {
+                            ui.add_space(32.0);
+                            ui.label(
+                                gatt::attribute_name(&d.uuid)
+                                    .map(|n| n.to_string())
+                                    .unwrap_or_else(|| d.uuid.to_string()),
+                            );
+                            if ui.button("Read").clicked() {
+                                messages.push(MessageToAsync::GattRead(addr, id));
+                            }
+                            if let Some(v) = g.values.get(&id) {
+                                ui.label(hex(v));
+                            }
+                        });
+                    }
+                }
+            });
+    }
+    for m in messages {
+        let _ = common.tx.blocking_send(m);
+    }
+}
+
+fn flag(ui: &mut egui::Ui, name: &str, v: bool) {
+    ui.label(name);
+    ui.label(if v { "Yes" } else { "No" });
+    ui.end_row();
+}
+
+impl SubwindowTrait for BluetoothDevice {
+    fn update(
+        &mut self,
+        ctx: &egui::Context,
+        frame: &mut eframe::Frame,
+        common: &mut CommonWindowProperties,
+    ) -> Option<Subwindow> {
+        let mut r = None;
+        egui::CentralPanel::default().show(ctx, |ui| {
+            if ui.button("Back").clicked() {
+                r = Some(Subwindow::BluetoothConfig(bluetooth::BluetoothConfig::new()));
+            }
+            ui.heading(common.bluetooth.phone_name(&self.addr));
+            if !common.bluetooth.devices.contains_key(&self.addr) {
+                ui.label("Device is no longer available");
+                return;
+            }
+            egui::ScrollArea::vertical()
+                .auto_shrink([false; 2])
+                .show(ui, |ui| {
+                    let Some(dev) = common.bluetooth.devices.get(&self.addr) else {
+                        return;
+                    };
+                    egui::Grid::new("device properties")
+                        .num_columns(2)
+                        .striped(true)
+                        .show(ui, |ui| {
+                            ui.label("Address");
+                            ui.label(self.addr.to_string());
+                            ui.end_row();
+                            if let Some(n) = &dev.name {
+                                ui.label("Name");
+                                ui.label(n);
+                                ui.end_row();
+                            }
+                            if let Some(t) = &dev.ty {
+                                ui.label("Address type");
+                                ui.label(format!("{:?}", t));
+                                ui.end_row();
+                            }
+                            if let Some(i) = &dev.icon {
+                                ui.label("Icon");
+                                ui.label(i);
+                                ui.end_row();
+                            }
+                            if let Some(c) = dev.class {
+                                let (major, minor) = device_class(c);
+                                ui.label("Class");
+                                ui.label(format!("{:06X} {} {}", c, major, minor));
+                                ui.end_row();
+                                ui.label("Services");
+                                ui.label(service_classes(c).join(", "));
+                                ui.end_row();
+                            }
+                            if let Some(a) = dev.appearance {
+                                ui.label("Appearance");
+                                ui.label(format!("{:04X} {}", a, appearance_name(a)));
+                                ui.end_row();
+                            }
+                            if let Some(m) = &dev.modalias {
+                                ui.label("Modalias");
+                                ui.label(format!("{:?}", m));
+                                ui.end_row();
+                            }
+                            flag(ui, "Paired", dev.paired);
+                            flag(ui, "Connected", dev.connected);
+                            flag(ui, "Trusted", dev.trusted);
+                            flag(ui, "Blocked", dev.blocked);
+                            flag(ui, "Wake allowed", dev.wake);
+                            flag(ui, "Legacy pairing", dev.legacy_pair);
+                            if let Some(rssi) = dev.rssi {
+                                ui.label("RSSI");
+                                ui.horizontal(|ui| {
+                                    ui.label(format!("{} dBm", rssi));
+                                    rssi_sparkline(ui, &dev.rssi_history, egui::vec2(240.0, 40.0));
+                                });
+                                ui.end_row();
+                            }
+                            if let Some(t) = dev.txpwr {
+                                ui.label("TX power");
+                                ui.label(format!("{} dBm", t));
+                                ui.end_row();
+                            }
+                            if let Some(b) = dev.battery {
+                                ui.label("Battery");
+                                ui.add(
+                                    egui::ProgressBar::new(b as f32 / 100.0)
+                                        .text(format!("{}%", b)),
+                                );
+                                ui.end_row();
+                            }
+                        });
+                    if !dev.uuids.is_empty() {
+                        ui.heading("Profiles");
+                        let mut uuids: Vec<&bluer::Uuid> = dev.uuids.iter().collect();
+                        uuids.sort();
+                        for u in uuids {
+                            if let Some(n) = uuid_name(u) {
+                                ui.label(n);
+                            } else {
+                                ui.label(u.to_string());
+                            }
+                        }
+                    }
+                    if !dev.manufacturer_data.is_empty() {
+                        ui.heading("Manufacturer data");
+                        for (id, data) in &dev.manufacturer_data {
+                            let company = company_name(*id).unwrap_or("Unknown");
+                            ui.label(format!("{:04X} {}: {}", id, company, hex(data)));
+                        }
+                    }
+                    if !dev.service_data.is_empty() {
+                        ui.heading("Service data");
+                        for (u, data) in &dev.service_data {
+                            let name = uuid_name(u)
+                                .map(|n| n.to_string())
+                                .unwrap_or_else(|| u.to_string());
+                            ui.label(format!("{}: {}", name, hex(data)));
+                        }
+                    }
+                    tethering(ui, common, self.addr);
+                    gatt_explorer(ui, common, self.addr);
+                });
+        });
+        r
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn uuids() {
+        let hfp = bluer::Uuid::from_str("0000111f-0000-1000-8000-00805f9b34fb").unwrap();
+        assert_eq!(short_uuid(&hfp), Some(0x111f));
+        assert_eq!(full_uuid(0x111f), hfp);
+        assert_eq!(uuid_name(&hfp), Some("Hands-Free Audio Gateway (HFP)"));
+        assert_eq!(uuid_name(&full_uuid(0x181a)), Some("Environmental Sensing"));
+        // 32 bit short uuids keep all their bits
+        assert_eq!(short_uuid(&full_uuid(0x1234_5678)), Some(0x1234_5678));
+        assert_eq!(uuid_name(&full_uuid(0x1234_5678)), None);
+        assert_eq!(uuid_name(&full_uuid(0xfcd2)), None);
+        // A vendor uuid has no short form
+        let vendor = bluer::Uuid::from_str("6e400001-b5a3-f393-e0a9-e50e24dcca9e").unwrap();
+        assert_eq!(short_uuid(&vendor), None);
+        assert_eq!(uuid_name(&vendor), None);
+    }
+
+    #[test]
+    fn class_of_device() {
+        assert_eq!(device_class(0x5a020c), ("Phone", "Smartphone"));
+        assert_eq!(
+            service_classes(0x5a020c),
+            vec!["Networking", "Capturing", "Object transfer", "Telephony"]
+        );
+        assert_eq!(device_class(0x240420), ("Audio/Video", "Car audio"));
+        assert_eq!(service_classes(0x240420), vec!["Rendering", "Audio"]);
+        assert_eq!(device_class(0x00010c), ("Computer", "Laptop"));
+        assert_eq!(device_class(0x000200), ("Phone", "Uncategorized"));
+        // Peripherals split the minor class into a keyboard and pointer part and a type
+        assert_eq!(device_class(0x000540), ("Peripheral", "Keyboard"));
+        assert_eq!(device_class(0x000580), ("Peripheral", "Pointing device"));
+        assert_eq!(
+            device_class(0x0005c0),
+            ("Peripheral", "Keyboard and pointing device")
+        );
+        assert_eq!(device_class(0x000508), ("Peripheral", "Gamepad"));
+        assert_eq!(device_class(0x000704), ("Wearable", "Wristwatch"));
+        assert_eq!(device_class(0), ("Miscellaneous", ""));
+        assert_eq!(device_class(0x001f00), ("Uncategorized", ""));
+        assert!(service_classes(0).is_empty());
+        assert_eq!(service_classes(0x2000), vec!["Limited discoverable"]);
+    }
+
+    #[test]
+    fn appearance() {
+        assert_eq!(appearance_name(0x0040), "Phone");
+        // The low six bits are the subcategory, like a sports watch
+        assert_eq!(appearance_name(0x00c1), "Watch");
+        assert_eq!(appearance_name(0x0341), "Heart rate sensor");
+        assert_eq!(appearance_name(0x0c40), "Pulse oximeter");
+        assert_eq!(appearance_name(0x1440), "Outdoor sports");
+        assert_eq!(appearance_name(0x0000), "Unknown");
+        assert_eq!(appearance_name(0xffff), "Unknown");
+    }
+}
@@ -0,0 +1,629 @@
+use std::any::Any;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use bluer::AdapterEvent;
+use bluer::DeviceEvent;
+use bluer::DeviceProperty;
+use futures::future::BoxFuture;
+use futures::stream::BoxStream;
+use futures::FutureExt;
+use futures::StreamExt;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::bluetooth_backend::{
+    AdapterInfo, Backend, BackendAdapter, BackendDevice, BackendError, BackendResult,
+    ProfileConnection,
+};
+use crate::gatt;
+
+fn receiver_stream<T: Send + 'static>(rx: UnboundedReceiver<T>) -> BoxStream<'static, T> {
+    futures::stream::unfold(rx, |mut rx| async move { rx.recv().await.map(|e| (e, rx)) }).boxed()
+}
+
+/// Send to every subscriber, forgetting the ones that went away
+fn broadcast<T: Clone>(subscribers: &mut Vec<UnboundedSender<T>>, e: T) {
+    subscribers.retain(|s| s.send(e.clone()).is_ok());
+}
+
+struct MockDeviceState {
+    properties: Vec<DeviceProperty>,
+    events: Vec<UnboundedSender<DeviceEvent>>,
+    services: Vec<gatt::Service>,
+    values: HashMap<gatt::AttributeId, Vec<u8>>,
+    notify: HashMap<gatt::AttributeId, Vec<UnboundedSender<Vec<u8>>>>,
+}
+
+impl MockDeviceState {
+    fn set(&mut self, prop: DeviceProperty) {
+        let d = std::mem::discriminant(&prop);
+        self.properties.retain(|p| std::mem::discriminant(p) != d);
+        self.properties.push(prop.clone());
+        broadcast(&mut self.events, DeviceEvent::PropertyChanged(prop));
+    }
+
+    fn flag(&self, f: impl Fn(&DeviceProperty) -> Option<bool>) -> bool {
+        self.properties.iter().find_map(f).unwrap_or(false)
+    }
+
+    /// Whether discovery with a filter would report the device
+    fn matches(&self, filter: &bluer::DiscoveryFilter) -> bool {
+        let rssi = self.properties.iter().find_map(|p| match p {
+            DeviceProperty::Rssi(r) => Some(*r),
+            _ => None,
+        });
+        if let (Some(min), Some(rssi)) = (filter.rssi, rssi) {
+            if rssi < min {
+                return false;
+            }
+        }
+        filter.uuids.is_empty()
+            || self.properties.iter().any(|p| match p {
+                DeviceProperty::Uuids(u) => !u.is_disjoint(&filter.uuids),
+                DeviceProperty::ServiceData(d) => d.keys().any(|u| filter.uuids.contains(u)),
+                _ => false,
+            })
+    }
+}
+
+/// A scripted remote device
+#[derive(Clone)]
+pub struct MockDevice {
+    addr: bluer::Address,
+    state: Arc<Mutex<MockDeviceState>>,
+}
+
+impl MockDevice {
+    fn update(&self, prop: DeviceProperty) -> BackendResult<()> {
+        self.state.lock().unwrap().set(prop);
+        Ok(())
+    }
+}
+
+impl BackendDevice for MockDevice {
+    fn address(&self) -> bluer::Address {
+        self.addr
+    }
+
+    fn all_properties(&self) -> BoxFuture<'_, BackendResult<Vec<DeviceProperty>>> {
+        let p = self.state.lock().unwrap().properties.clone();
+        async move { Ok(p) }.boxed()
+    }
+
+    fn events(&self) -> BoxFuture<'_, BackendResult<BoxStream<'static, DeviceEvent>>> {
+        let (s, r) = tokio::sync::mpsc::unbounded_channel();
+        self.state.lock().unwrap().events.push(s);
+        async move { Ok(receiver_stream(r)) }.boxed()
+    }
+
+    fn is_connected(&self) -> BoxFuture<'_, BackendResult<bool>> {
+        let c = self.state.lock().unwrap().flag(|p| match p {
+            DeviceProperty::Connected(c) => Some(*c),
+            _ => None,
+        });
+        async move { Ok(c) }.boxed()
+    }
+
+    fn pair(&self) -> BoxFuture<'_, BackendResult<()>> {
+        async move { self.update(DeviceProperty::Paired(true)) }.boxed()
+    }
+
+    fn connect(&self) -> BoxFuture<'_, BackendResult<()>> {
+        async move { self.update(DeviceProperty::Connected(true)) }.boxed()
+    }
+
+    fn disconnect(&self) -> BoxFuture<'_, BackendResult<()>> {
+        async move { self.update(DeviceProperty::Connected(false)) }.boxed()
+    }
+
+    fn set_trusted(&self, v: bool) -> BoxFuture<'_, BackendResult<()>> {
+        async move { self.update(DeviceProperty::Trusted(v)) }.boxed()
+    }
+
+    fn set_blocked(&self, v: bool) -> BoxFuture<'_, BackendResult<()>> {
+        async move { self.update(DeviceProperty::Blocked(v)) }.boxed()
+    }
+
+    fn gatt_services(&self) -> BoxFuture<'_, BackendResult<Vec<gatt::Service>>> {
+        let s = self.state.lock().unwrap().services.clone();
+        async move { Ok(s) }.boxed()
+    }
+
+    fn gatt_read(&self, id: gatt::AttributeId) -> BoxFuture<'_, BackendResult<Vec<u8>>> {
+        let v = self
+            .state
+            .lock()
+            .unwrap()
+            .values
+            .get(&id)
+            .cloned()
+            .ok_or_else(|| BackendError(format!("No attribute {:?}", id)));
+        async move { v }.boxed()
+    }
+
+    fn gatt_notify(
+        &self,
+        id: gatt::AttributeId,
+    ) -> BoxFuture<'_, BackendResult<BoxStream<'static, Vec<u8>>>> {
+        let (s, r) = tokio::sync::mpsc::unbounded_channel();
+        self.state
+            .lock()
+            .unwrap()
+            .notify
+            .entry(id)
+            .or_default()
+            .push(s);
+        async move { Ok(receiver_stream(r)) }.boxed()
+    }
+}
+
+struct MockAdapterState {
+    devices: HashMap<bluer::Address, MockDevice>,
+    events: Vec<UnboundedSender<AdapterEvent>>,
+    alias: String,
+    powered: bool,
+    discoverable: bool,
+    discoverable_timeout: u32,
+    pairable: bool,
+    filter: bluer::DiscoveryFilter,
+}
+
+/// A scripted bluetooth controller
+#[derive(Clone)]
+pub struct MockAdapter {
+    name: String,
+    address: bluer::Address,
+    state: Arc<Mutex<MockAdapterState>>,
+}
+
+impl BackendAdapter for MockAdapter {
+    type Device = MockDevice;
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn info(&self) -> BoxFuture<'_, BackendResult<AdapterInfo>> {
+        let state = self.state.lock().unwrap();
+        let info = AdapterInfo {
+            name: self.name.clone(),
+            address: self.address,
+            address_type: "public".to_string(),
+            system_name: "mock".to_string(),
+            alias: state.alias.clone(),
+            modalias: None,
+            powered: state.powered,
+            discoverable: state.discoverable,
+            discoverable_timeout: state.discoverable_timeout,
+            pairable: state.pairable,
+            uuids: Vec::new(),
+            active_advertising: None,
+            supported_advertising: None,
+            advertising_includes: None,
+            advertising_capabilities: None,
+            advertising_features: None,
+        };
+        async move { Ok(info) }.boxed()
+    }
+
+    fn set_powered(&self, v: bool) -> BoxFuture<'_, BackendResult<()>> {
+        self.state.lock().unwrap().powered = v;
+        async move { Ok(()) }.boxed()
+    }
+
+    fn set_alias(&self, alias: String) -> BoxFuture<'_, BackendResult<()>> {
+        let mut state = self.state.lock().unwrap();
+        state.alias = if alias.is_empty() {
+            "mock".to_string()
+        } else {
+            alias
+        };
+        async move { Ok(()) }.boxed()
+    }
+
+    fn set_discoverable(&self, v: bool) -> BoxFuture<'_, BackendResult<()>> {
+        self.state.lock().unwrap().discoverable = v;
+        async move { Ok(()) }.boxed()
+    }
+
+    fn set_discoverable_timeout(&self, secs: u32) -> BoxFuture<'_, BackendResult<()>> {
+        self.state.lock().unwrap().discoverable_timeout = secs;
+        async move { Ok(()) }.boxed()
+    }
+
+    fn set_pairable(&self, v: bool) -> BoxFuture<'_, BackendResult<()>> {
+        self.state.lock().unwrap().pairable = v;
+        async move { Ok(()) }.boxed()
+    }
+
+    fn set_discovery_filter(
+        &self,
+        filter: bluer::DiscoveryFilter,
+    ) -> BoxFuture<'_, BackendResult<()>> {
+        self.state.lock().unwrap().filter = filter;
+        async move { Ok(()) }.boxed()
+    }
+
+    fn discover_devices(&self) -> BoxFuture<'_, BackendResult<BoxStream<'static, AdapterEvent>>> {
+        let (s, r) = tokio::sync::mpsc::unbounded_channel();
+        let mut state = self.state.lock().unwrap();
+        for (addr, d) in &state.devices {
+            if d.state.lock().unwrap().matches(&state.filter) {
+                let _ = s.send(AdapterEvent::DeviceAdded(*addr));
+            }
+        }
+        state.events.push(s);
+        async move { Ok(receiver_stream(r)) }.boxed()
+    }
+
+    fn device(&self, addr: bluer::Address) -> BackendResult<Self::Device> {
+        self.state
+            .lock()
+            .unwrap()
+            .devices
+            .get(&addr)
+            .cloned()
+            .ok_or_else(|| BackendError(format!("No device {}", addr)))
+    }
+
+    fn remove_device(&self, addr: bluer::Address) -> BoxFuture<'_, BackendResult<()>> {
+        let mut state = self.state.lock().unwrap();
+        let r = match state.devices.remove(&addr) {
+            Some(_) => {
+                broadcast(&mut state.events, AdapterEvent::DeviceRemoved(addr));
+                Ok(())
+            }
+            None => Err(BackendError(format!("No device {}", addr))),
+        };
+        async move { r }.boxed()
+    }
+}
+
+struct MockState {
+    adapters: HashMap<String, MockAdapter>,
+    events: Vec<UnboundedSender<bluer::SessionEvent>>,
+    /// The features of each registered profile and where its connections go
+    profiles: Vec<(Option<u16>, UnboundedSender<ProfileConnection>)>,
+}
+
+/// A bluetooth stack that only exists in memory, driven by a [MockControl]
+pub struct MockBackend {
+    state: Arc<Mutex<MockState>>,
+}
+
+/// Scripts the behavior of a [MockBackend]
+#[derive(Clone)]
+pub struct MockControl {
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockBackend {
+    pub fn new() -> (Self, MockControl) {
+        let state = Arc::new(Mutex::new(MockState {
+            adapters: HashMap::new(),
+            events: Vec::new(),
+            profiles: Vec::new(),
+        }));
+        (
+            Self {
+                state: state.clone(),
+            },
+            MockControl { state },
+        )
+    }
+}
+
+#[cfg(feature = "mock")]
+impl MockBackend {
+    /// A backend with an adapter, a paired phone and a low energy sensor
+    pub fn demo() -> (Self, MockControl) {
+        use crate::bluetooth_device::full_uuid;
+        use std::str::FromStr;
+
+        let (b, c) = Self::new();
+        c.add_adapter("hci0");
+        let phone = bluer::Address::new([0x00, 0x11, 0x22, 0x33, 0x44, 0x55]);
+        c.add_device(
+            "hci0",
+            phone,
+            vec![
+                DeviceProperty::Alias("Mock phone".to_string()),
+                DeviceProperty::Class(0x5a020c),
+                DeviceProperty::Paired(true),
+                DeviceProperty::Trusted(true),
+                DeviceProperty::Rssi(-50),
+                DeviceProperty::Uuids(
+                    [
+                        "0000111f-0000-1000-8000-00805f9b34fb",
+                        "0000110a-0000-1000-8000-00805f9b34fb",
+                        "0000112f-0000-1000-8000-00805f9b34fb",
+                    ]
+                    .iter()
+                    .filter_map(|u| bluer::Uuid::from_str(u).ok())
+                    .collect(),
+                ),
+            ],
+        );
+        let sensor = bluer::Address::new([0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb]);
+        c.add_device(
+            "hci0",
+            sensor,
+            vec![
+                DeviceProperty::Alias("Mock sensor".to_string()),
+                DeviceProperty::AddressType(bluer::AddressType::LeRandom),
+                DeviceProperty::Rssi(-80),
+                DeviceProperty::BatteryPercentage(75),
+                DeviceProperty::ServiceData(
+                    [(
+                        full_uuid(0xfcd2),
+                        vec![0x40, 0x02, 0xca, 0x08, 0x03, 0xbf, 0x13],
+                    )]
+                    .into_iter()
+                    .collect(),
+                ),
+            ],
+        );
+        let battery = gatt::AttributeId {
+            service: 0x0010,
+            characteristic: 0x0011,
+            descriptor: None,
+        };
+        let temperature = gatt::AttributeId {
+            service: 0x0020,
+            characteristic: 0x0021,
+            descriptor: None,
+        };
+        let characteristic = |id: u16, uuid: u32| gatt::Characteristic {
+            id,
+            uuid: full_uuid(uuid),
+            read: true,
+            notify: true,
+            descriptors: vec![gatt::Descriptor {
+                id: id + 1,
+                uuid: full_uuid(0x2902),
+            }],
+        };
+        c.set_gatt(
+            "hci0",
+            sensor,
+            vec![
+                gatt::Service {
+                    id: battery.service,
+                    uuid: full_uuid(0x180f),
+                    primary: true,
+                    characteristics: vec![characteristic(battery.characteristic, 0x2a19)],
+                },
+                gatt::Service {
+                    id: temperature.service,
+                    uuid: full_uuid(0x181a),
+                    primary: true,
+                    characteristics: vec![characteristic(temperature.characteristic, 0x2a6e)],
+                },
+            ],
+            [(battery, vec![75]), (temperature, vec![0xca, 0x08])]
+                .into_iter()
+                .collect(),
+        );
+        c.add_device(
+            "hci0",
+            bluer::Address::new([0x80, 0xea, 0xca, 0x10, 0x8a, 0x78]),
+            vec![
+                DeviceProperty::Alias("TPMS1_10CA8A".to_string()),
+                DeviceProperty::AddressType(bluer::AddressType::LePublic),
+                DeviceProperty::Rssi(-70),
+                DeviceProperty::ManufacturerData(
+                    [(
+                        0x0100,
+                        vec![
+                            0x80, 0xea, 0xca, 0x10, 0x8a, 0x78, 0x40, 0x1f, 0x03, 0x00, 0xe6, 0x0a,
+                            0x00, 0x00, 0x5b, 0x00,
+                        ],
+                    )]
+                    .into_iter()
+                    .collect(),
+                ),
+            ],
+        );
+        (b, c)
+    }
+}
+
+impl MockControl {
+    pub fn add_adapter(&self, name: &str) {
+        let mut state = self.state.lock().unwrap();
+        let index = state.adapters.len() as u8;
+        state.adapters.insert(
+            name.to_string(),
+            MockAdapter {
+                name: name.to_string(),
+                address: bluer::Address::new([0x00, 0x1a, 0x7d, 0xda, 0x71, index]),
+                state: Arc::new(Mutex::new(MockAdapterState {
+                    devices: HashMap::new(),
+                    events: Vec::new(),
+                    alias: "mock".to_string(),
+                    powered: false,
+                    discoverable: false,
+                    discoverable_timeout: 180,
+                    pairable: false,
+                    filter: bluer::DiscoveryFilter::default(),
+                })),
+            },
+        );
+        broadcast(
+            &mut state.events,
+            bluer::SessionEvent::AdapterAdded(name.to_string()),
+        );
+    }
+
+    pub fn remove_adapter(&self, name: &str) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(a) = state.adapters.remove(name) {
+            a.state.lock().unwrap().events.clear();
+            broadcast(
+                &mut state.events,
+                bluer::SessionEvent::AdapterRemoved(name.to_string()),
+            );
+        }
+    }
+
+    pub fn add_device(&self, adapter: &str, addr: bluer::Address, properties: Vec<DeviceProperty>) {
+        let state = self.state.lock().unwrap();
+        let Some(a) = state.adapters.get(adapter) else {
+            return;
+        };
+        let mut astate = a.state.lock().unwrap();
+        astate.devices.insert(
+            addr,
+            MockDevice {
+                addr,
+                state: Arc::new(Mutex::new(MockDeviceState {
+                    properties,
+                    events: Vec::new(),
+                    services: Vec::new(),
+                    values: HashMap::new(),
+                    notify: HashMap::new(),
+                })),
+            },
+        );
+        broadcast(&mut astate.events, AdapterEvent::DeviceAdded(addr));
+    }
+
+    pub fn remove_device(&self, adapter: &str, addr: bluer::Address) {
+        let state = self.state.lock().unwrap();
+        if let Some(a) = state.adapters.get(adapter) {
+            let mut astate = a.state.lock().unwrap();
+            if astate.devices.remove(&addr).is_some() {
+                broadcast(&mut astate.events, AdapterEvent::DeviceRemoved(addr));
+            }
+        }
+    }
+
+    /// Change a property of a device, as if the device reported it
+    pub fn set_property(&self, adapter: &str, addr: bluer::Address, prop: DeviceProperty) {
+        let state = self.state.lock().unwrap();
+        if let Some(d) = state
+            .adapters
+            .get(adapter)
+            .and_then(|a| a.state.lock().unwrap().devices.get(&addr).cloned())
+        {
+            d.state.lock().unwrap().set(prop);
+        }
+    }
+
+    fn device(&self, adapter: &str, addr: bluer::Address) -> Option<MockDevice> {
+        let a = self.state.lock().unwrap().adapters.get(adapter).cloned()?;
+        a.device(addr).ok()
+    }
+
+    /// Give a device an attribute tree with initial values
+    pub fn set_gatt(
+        &self,
+        adapter: &str,
+        addr: bluer::Address,
+        services: Vec<gatt::Service>,
+        values: HashMap<gatt::AttributeId, Vec<u8>>,
+    ) {
+        if let Some(d) = self.device(adapter, addr) {
+            let mut s = d.state.lock().unwrap();
+            s.services = services;
+            s.values = values;
+        }
+    }
+
+    /// Change the value of a characteristic, notifying subscribers
+    pub fn notify(&self, adapter: &str, addr: bluer::Address, id: gatt::AttributeId, v: Vec<u8>) {
+        if let Some(d) = self.device(adapter, addr) {
+            let mut s = d.state.lock().unwrap();
+            s.values.insert(id, v.clone());
+            if let Some(subscribers) = s.notify.get_mut(&id) {
+                broadcast(subscribers, v);
+            }
+        }
+    }
+
+    /// The features of the profiles that are still registered
+    pub fn profile_features(&self) -> Vec<Option<u16>> {
+        let state = self.state.lock().unwrap();
+        state
+            .profiles
+            .iter()
+            .filter(|(_, p)| !p.is_closed())
+            .map(|(f, _)| *f)
+            .collect()
+    }
+
+    /// Connect to every registered profile, returning the remote end of each connection
+    pub fn connect_profile(&self, addr: bluer::Address) -> Vec<tokio::io::DuplexStream> {
+        let mut state = self.state.lock().unwrap();
+        let mut remote = Vec::new();
+        state.profiles.retain(|(_, p)| {
+            let (a, b) = tokio::io::duplex(1024);
+            let r = p
+                .send(ProfileConnection {
+                    device: addr,
+                    stream: Box::new(a),
+                })
+                .is_ok();
+            if r {
+                remote.push(b);
+            }
+            r
+        });
+        remote
+    }
+}
+
+impl Backend for MockBackend {
+    type Adapter = MockAdapter;
+
+    fn events(&self) -> BoxFuture<'_, BackendResult<BoxStream<'static, bluer::SessionEvent>>> {
+        let (s, r) = tokio::sync::mpsc::unbounded_channel();
+        self.state.lock().unwrap().events.push(s);
+        async move { Ok(receiver_stream(r)) }.boxed()
+    }
+
+    fn adapter_names(&self) -> BoxFuture<'_, BackendResult<Vec<String>>> {
+        let mut names: Vec<String> = self
+            .state
+            .lock()
+            .unwrap()
+            .adapters
+            .keys()
+            .cloned()
+            .collect();
+        names.sort();
+        async move { Ok(names) }.boxed()
+    }
+
+    fn adapter(&self, name: &str) -> BackendResult<Self::Adapter> {
+        self.state
+            .lock()
+            .unwrap()
+            .adapters
+            .get(name)
+            .cloned()
+            .ok_or_else(|| BackendError(format!("No adapter {}", name)))
+    }
+
+    fn register_agent(
+        &self,
+        _agent: bluer::agent::Agent,
+    ) -> BoxFuture<'_, BackendResult<Box<dyn Any + Send>>> {
+        async move { Ok(Box::new(()) as Box<dyn Any + Send>) }.boxed()
+    }
+
+    fn register_profile(
+        &self,
+        profile: bluer::rfcomm::Profile,
+    ) -> BoxFuture<'_, BackendResult<BoxStream<'static, ProfileConnection>>> {
+        let (s, r) = tokio::sync::mpsc::unbounded_channel();
+        self.state
+            .lock()
+            .unwrap()
+            .profiles
+            .push((profile.features, s));
+        async move { Ok(receiver_stream(r)) }.boxed()
+    }
+}
@@ -0,0 +1,32 @@
+use std::time::Duration;
+
+/// How long bluez gets to answer a method call
+pub const DBUS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long to wait before trying to reach a bluez service again
+pub const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Get the address of a device from its bluez object path
+pub fn path_address(p: &dbus::Path) -> Option<bluer::Address> {
+    let dev = p.split('/').find(|s| s.starts_with("dev_"))?;
+    dev[4..].replace('_', ":").parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn address() {
+        let addr = bluer::Address::new([0x00, 0x11, 0x22, 0x33, 0xaa, 0xbb]);
+        let path = dbus::Path::from("/org/bluez/hci0/dev_00_11_22_33_AA_BB");
+        assert_eq!(path_address(&path), Some(addr));
+        let path = dbus::Path::from("/org/bluez/hci0/dev_00_11_22_33_AA_BB/player0");
+        assert_eq!(path_address(&path), Some(addr));
+        assert_eq!(path_address(&dbus::Path::from("/org/bluez/hci0")), None);
+        assert_eq!(
+            path_address(&dbus::Path::from("/org/bluez/hci0/dev_00_11")),
+            None
+        );
+    }
+}
@@ -0,0 +1,128 @@
+use crate::vcard::{self, VCard};
+
+/// A message in the bMessage format used by the message access profile
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BMessage {
+    pub read: bool,
+    /// The kind of message, such as SMS_GSM, MMS or EMAIL
+    pub kind: String,
+    pub folder: String,
+    pub originator: Option<VCard>,
+    pub recipients: Vec<VCard>,
+    pub body: String,
+}
+
+/// Split a line into its property name and value
+fn property(line: &str) -> (String, &str) {
+    let (name, value) = line.split_once(':').unwrap_or((line, ""));
+    (name.trim().to_uppercase(), value.trim())
+}
+
+/// Parse a bMessage as sent by a phone, returning None if there is no message in it
+pub fn parse(text: &str) -> Option<BMessage> {
+    let mut lines = text.lines().map(|l| l.strip_suffix('\r').unwrap_or(l));
+    let mut msg: Option<BMessage> = None;
+    let mut envelopes = 0;
+    while let Some(line) = lines.next() {
+        let (name, value) = property(line);
+        if name == "BEGIN" && value.eq_ignore_ascii_case("BMSG") {
+            msg = Some(BMessage::default());
+            continue;
+        }
+        let Some(m) = msg.as_mut() else {
+            continue;
+        };
+        match (name.as_str(), value.to_uppercase().as_str()) {
+            ("BEGIN", "VCARD") => {
+                let mut card = String::from("BEGIN:VCARD\r\n");
+                for l in lines.by_ref() {
+                    card.push_str(l);
+                    card.push_str("\r\n");
+                    let (name, value) = property(l);
+                    if name == "END" && value.eq_ignore_ascii_case("VCARD") {
+                        break;
+                    }
+                }
+                let Some(c) = vcard::parse(&card).into_iter().next() else {
+                    continue;
+                };
+                // The sender comes before the envelope, recipients are inside it
+                if envelopes == 0 {
+                    m.originator = Some(c);
+                } else {
+                    m.recipients.push(c);
+                }
+            }
+            ("BEGIN", "BENV") => envelopes += 1,
+            ("END", "BENV") => envelopes -= 1,
+            ("BEGIN", "MSG") => {
+                let mut body: Vec<&str> = Vec::new();
+                for l in lines.by_ref() {
+                    if l == "END:MSG" {
+                        break;
+                    }
+                    body.push(l);
+                }
+                if !m.body.is_empty() {
+                    m.body.push('\n');
+                }
+                m.body.push_str(body.join("\n").trim_end());
+            }
+            ("END", "BMSG") => return msg,
+            ("STATUS", s) => m.read = s == "READ",
+            ("TYPE", _) => m.kind = value.to_string(),
+            ("FOLDER", _) => m.folder = value.to_string(),
+            _ => {}
+        }
+    }
+    msg
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inbox() {
+        let m = parse(include_str!("../testdata/inbox.bmsg")).unwrap();
+        assert!(!m.read);
+        assert_eq!(m.kind, "SMS_GSM");
+        assert_eq!(m.folder, "telecom/msg/inbox");
+        let from = m.originator.unwrap();
+        assert_eq!(from.name, "Alice Anderson");
+        assert_eq!(from.numbers[0].number, "+15551234567");
+        assert_eq!(m.recipients.len(), 1);
+        assert_eq!(m.recipients[0].display_name(), "Unknown");
+        assert_eq!(m.body, "Running 10 minutes late, order me a coffee");
+    }
+
+    #[test]
+    fn multiline() {
+        let m = parse(include_str!("../testdata/multiline.bmsg")).unwrap();
+        assert!(m.read);
+        let from = m.originator.unwrap();
+        assert_eq!(from.name, "");
+        assert_eq!(from.display_name(), "+447700900123");
+        assert_eq!(
+            m.body,
+            "Your code is 4821.\nDo not share it.\nTime: 10:15\nBEGIN:VCARD is not a card"
+        );
+    }
+
+    #[test]
+    fn sent() {
+        let m = parse(include_str!("../testdata/sent.bmsg")).unwrap();
+        assert_eq!(m.folder, "telecom/msg/sent");
+        assert_eq!(m.originator.unwrap().name, "");
+        assert_eq!(m.recipients.len(), 2);
+        assert_eq!(m.recipients[0].name, "Bob Brown");
+        assert_eq!(m.recipients[1].numbers[0].number, "5550199");
+        assert_eq!(m.body, "On my way");
+    }
+
+    #[test]
+    fn not_a_message() {
+        assert_eq!(parse(""), None);
+        assert_eq!(parse("BEGIN:VCARD\r\nFN:Alice\r\nEND:VCARD\r\n"), None);
+    }
+}
@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+/// Settings that are kept between runs of the program
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Phones to connect to automatically, highest priority first
+    pub phones: Vec<String>,
+    /// The address of the adapter to use, the first one found when not set
+    pub adapter: Option<String>,
+    /// Settings for each adapter, by adapter address
+    pub adapters: HashMap<String, AdapterConfig>,
+    pub discovery: DiscoveryConfig,
+    pub voice: VoiceConfig,
+    /// The key a steering wheel button sends to start the phone's voice assistant, like F9
+    pub voice_assistant_key: Option<String>,
+    pub object_push: PushConfig,
+    /// A received picture shown behind the main page
+    pub wallpaper: Option<PathBuf>,
+}
+
+/// How an adapter presents itself to other devices
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct AdapterConfig {
+    /// The name other devices see, the system name when not set
+    pub alias: Option<String>,
+    pub discoverable: bool,
+    /// Seconds to stay discoverable for, 0 for no limit
+    pub discoverable_timeout: u32,
+    pub pairable: bool,
+}
+
+impl Default for AdapterConfig {
+    fn default() -> Self {
+        Self {
+            alias: None,
+            discoverable: true,
+            discoverable_timeout: 0,
+            pairable: true,
+        }
+    }
+}
+
+/// Where the audio of hands free calls goes
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct VoiceConfig {
+    /// Offer wide band speech to phones that support it
+    pub msbc: bool,
+    /// The alsa device the caller is heard on
+    pub output: String,
+    /// The alsa device of the microphone
+    pub input: String,
+    pub speaker_gain: f32,
+    pub mic_gain: f32,
+}
+
+impl Default for VoiceConfig {
+    fn default() -> Self {
+        Self {
+            msbc: true,
+            output: "default".to_string(),
+            input: "default".to_string(),
+            speaker_gain: 1.0,
+            mic_gain: 1.0,
+        }
+    }
+}
+
+/// What phones may send to the radio over object push
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct PushConfig {
+    /// The largest file that is accepted, in bytes
+    pub max_size: u64,
+}
+
+impl Default for PushConfig {
+    fn default() -> Self {
+        Self {
+            max_size: 20 * 1024 * 1024,
+        }
+    }
+}
+
+/// The directory settings and cached data are kept in
+pub fn dir() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".config")))?;
+    Some(base.join("radio-gui"))
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum Transport {
+    #[default]
+    Any,
+    Classic,
+    LowEnergy,
+}
+
+/// Which devices scanning looks for and how long they stay listed
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct DiscoveryConfig {
+    pub transport: Transport,
+    /// Ignore devices with a weaker signal than this, in dBm
+    pub min_rssi: Option<i16>,
+    /// Only find devices with one of these services, any device when empty
+    pub uuids: Vec<String>,
+    /// Seconds an unpaired device stays listed after it was last seen, 0 to keep them
+    pub max_age: u64,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            transport: Transport::Any,
+            min_rssi: None,
+            uuids: Vec::new(),
+            max_age: 120,
+        }
+    }
+}
+
+impl DiscoveryConfig {
+    pub fn filter(&self) -> bluer::DiscoveryFilter {
+        bluer::DiscoveryFilter {
+            uuids: self.uuids.iter().filter_map(|u| u.parse().ok()).collect(),
+            rssi: self.min_rssi,
+            transport: match self.transport {
+                Transport::Any => bluer::DiscoveryTransport::Auto,
+                Transport::Classic => bluer::DiscoveryTransport::BrEdr,
+                Transport::LowEnergy => bluer::DiscoveryTransport::Le,
+            },
+            ..Default::default()
+        }
+    }
+}
+
+impl Config {
+    fn path() -> Option<PathBuf> {
+        Some(dir()?.join("config.toml"))
+    }
+
+    pub fn load() -> Self {
+        let Some(p) = Self::path() else {
+            return Self::default();
+        };
+        match std::fs::read_to_string(&p) {
+            Ok(s) => toml::from_str(&s).unwrap_or_else(|e| {
+                println!("Failed to parse {}: {}", p.display(), e);
+                Self::default()
+            }),
+            Err(_) => Self::default(),
+        }
+    }
+
+    pub fn save(&self) {
+        let Some(p) = Self::path() else {
+            return;
+        };
+        if let Some(d) = p.parent() {
+            let _ = std::fs::create_dir_all(d);
+        }
+        match toml::to_string(self) {
+            Ok(s) => {
+                if let Err(e) = std::fs::write(&p, s) {
+                    println!("Failed to save {}: {}", p.display(), e);
+                }
+            }
+            Err(e) => println!("Failed to serialize config: {}", e),
+        }
+    }
+
+    pub fn phone_addresses(&self) -> Vec<bluer::Address> {
+        self.phones.iter().filter_map(|p| p.parse().ok()).collect()
+    }
+
+    pub fn set_phone_addresses(&mut self, phones: &[bluer::Address]) {
+        self.phones = phones.iter().map(|a| a.to_string()).collect();
+    }
+
+    pub fn active_adapter(&self) -> Option<bluer::Address> {
+        self.adapter.as_ref().and_then(|a| a.parse().ok())
+    }
+
+    pub fn set_active_adapter(&mut self, addr: bluer::Address) {
+        self.adapter = Some(addr.to_string());
+    }
+
+    pub fn adapter_config(&self, addr: &bluer::Address) -> AdapterConfig {
+        self.adapters
+            .get(&addr.to_string())
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub fn set_adapter_config(&mut self, addr: &bluer::Address, c: AdapterConfig) {
+        self.adapters.insert(addr.to_string(), c);
+    }
+}
@@ -0,0 +1,328 @@
+use std::path::PathBuf;
+
+use super::CommonWindowProperties;
+use super::MessageToAsync;
+use super::Subwindow;
+use super::SubwindowTrait;
+use crate::bluetooth::ActionStatus;
+use crate::config;
+use crate::phone;
+use crate::vcard::{CallKind, VCard};
+use eframe::egui;
+
+/// The contacts and recent calls of a phone
+#[derive(Clone, Debug, Default)]
+pub struct Phonebook {
+    /// Contacts with at least one number, in alphabetical order
+    pub contacts: Vec<VCard>,
+    /// Calls, most recent first
+    pub history: Vec<VCard>,
+}
+
+impl Phonebook {
+    pub fn parse(pb: &str, cch: &str) -> Self {
+        let mut contacts: Vec<VCard> = crate::vcard::parse(pb)
+            .into_iter()
+            .filter(|c| !c.numbers.is_empty())
+            .collect();
+        contacts.sort_by_key(|c| c.sort_key());
+        Self {
+            contacts,
+            history: crate::vcard::parse(cch),
+        }
+    }
+}
+
+fn cache_files(addr: bluer::Address) -> Option<(PathBuf, PathBuf)> {
+    let d = config::dir()?.join("contacts");
+    let name = addr.to_string().replace(':', "");
+    Some((
+        d.join(format!("{}-pb.vcf", name)),
+        d.join(format!("{}-cch.vcf", name)),
+    ))
+}
+
+/// Keep the downloaded phonebook of a phone for the next time it connects
+pub fn save_cache(addr: bluer::Address, pb: &str, cch: &str) {
+    let Some((pbf, cchf)) = cache_files(addr) else {
+        return;
+    };
+    if let Some(d) = pbf.parent() {
+        let _ = std::fs::create_dir_all(d);
+    }
+    for (f, data) in [(pbf, pb), (cchf, cch)] {
+        if let Err(e) = std::fs::write(&f, data) {
+            println!("Failed to save {}: {}", f.display(), e);
+        }
+    }
+}
+
+pub fn load_cache(addr: bluer::Address) -> Option<Phonebook> {
+    let (pbf, cchf) = cache_files(addr)?;
+    let pb = std::fs::read_to_string(pbf).ok()?;
+    let cch = std::fs::read_to_string(cchf).unwrap_or_default();
+    Some(Phonebook::parse(&pb, &cch))
+}
+
+fn received_file() -> Option<PathBuf> {
+    Some(config::dir()?.join("contacts").join("received.vcf"))
+}
+
+/// Contacts that were sent to the radio, in alphabetical order
+pub fn load_received() -> Vec<VCard> {
+    let Some(f) = received_file() else {
+        return Vec::new();
+    };
+    let vcf = std::fs::read_to_string(f).unwrap_or_default();
+    let mut contacts: Vec<VCard> = crate::vcard::parse(&vcf)
+        .into_iter()
+        .filter(|c| !c.numbers.is_empty())
+        .collect();
+    contacts.sort_by_key(|c| c.sort_key());
+    contacts
+}
+
+/// Add contact cards that were sent to the radio to the ones kept from before
+pub fn save_received(vcf: &str) {
+    let Some(f) = received_file() else {
+        return;
+    };
+    if let Some(d) = f.parent() {
+        let _ = std::fs::create_dir_all(d);
+    }
+    let mut data = std::fs::read_to_string(&f).unwrap_or_default();
+    if !data.is_empty() && !data.ends_with('\n') {
+        data.push_str("\r\n");
+    }
+    data.push_str(vcf);
+    if let Err(e) = std::fs::write(&f, data) {
+        println!("Failed to save {}: {}", f.display(), e);
+    }
+}
+
+/// Turn a vcard time like 20240102T101500 into 2024-01-02 10:15
+pub fn format_time(t: &str) -> String {
+    if t.len() >= 13 && t.is_char_boundary(8) && t.is_char_boundary(13) {
+        format!(
+            "{}-{}-{} {}:{}",
+            &t[0..4],
+            &t[4..6],
+            &t[6..8],
+            &t[9..11],
+            &t[11..13]
+        )
+    } else {
+        t.to_string()
+    }
+}
+
+fn initial(c: &VCard) -> char {
+    c.display_name()
+        .chars()
+        .next()
+        .map(|c| {
+            if c.is_alphabetic() {
+                c.to_uppercase().next().unwrap_or(c)
+            } else {
+                '#'
+            }
+        })
+        .unwrap_or('#')
+}
+
+fn matches(c: &VCard, search: &str) -> bool {
+    if search.is_empty() {
+        return true;
+    }
+    let search = search.to_lowercase();
+    c.sort_key().contains(&search) || c.numbers.iter().any(|n| n.number.contains(&search))
+}
+
+pub struct Contacts {
+    selected: Option<bluer::Address>,
+    search: String,
+    history: bool,
+    /// The letter to scroll to on the next frame
+    jump: Option<char>,
+}
+
+impl Contacts {
+    pub fn new() -> Self {
+        Self {
+            selected: None,
+            search: String::new(),
+            history: false,
+            jump: None,
+        }
+    }
+
+    /// Buttons to call each number of a contact, returning true if a call was started
+    fn numbers(
+        ui: &mut egui::Ui,
+        common: &CommonWindowProperties,
+        addr: bluer::Address,
+        c: &VCard,
+    ) -> bool {
+        let mut called = false;
+        for n in &c.numbers {
+            let text = match &n.kind {
+                Some(k) => format!("{} {}", k, n.number),
+                None => n.number.clone(),
+            };
+            if ui.button(text).clicked() {
+                let _ = common
+                    .tx
+                    .blocking_send(MessageToAsync::PhoneDial(addr, n.number.clone()));
+                called = true;
+            }
+        }
+        called
+    }
+}
+
+impl SubwindowTrait for Contacts {
+    fn update(
+        &mut self,
+        ctx: &egui::Context,
+        frame: &mut eframe::Frame,
+        common: &mut CommonWindowProperties,
+    ) -> Option<Subwindow> {
+        let mut r = None;
+        egui::CentralPanel::default().show(ctx, |ui| {
+            if self
+                .selected
+                .map(|a| !common.bluetooth.phones.contains_key(&a))
+                .unwrap_or(true)
+            {
+                self.selected = common.bluetooth.phones.keys().next().copied();
+            }
+            let Some(addr) = self.selected else {
+                ui.heading("No phone connected");
+                return;
+            };
+            ui.horizontal(|ui| {
+                ui.label(common.bluetooth.phone_name(&addr));
+                ui.selectable_value(&mut self.history, false, "Contacts");
+                ui.selectable_value(&mut self.history, true, "Recent calls");
+                if ui.button("Refresh").clicked() {
+                    let _ = common
+                        .tx
+                        .blocking_send(MessageToAsync::PhonebookDownload(addr));
+                }
+                match common.bluetooth.phonebook_status.get(&addr) {
+                    Some(ActionStatus::Started) => {
+                        ui.spinner();
+                    }
+                    Some(ActionStatus::Failed(e)) => {
+                        ui.colored_label(egui::Color32::RED, e);
+                    }
+                    _ => {}
+                }
+            });
+            ui.horizontal(|ui| {
+                ui.label("Search");
+                ui.add(
+                    egui::TextEdit::singleline(&mut self.search)
+                        .font(egui::FontId::proportional(32.0)),
+                );
+                if ui.button("Clear").clicked() {
+                    self.search.clear();
+                }
+            });
+            let received = &common.bluetooth.received_contacts;
+            let book = common.bluetooth.phonebooks.get(&addr);
+            if book.is_none() && (self.history || received.is_empty()) {
+                ui.label("No contacts downloaded yet");
+                return;
+            }
+            let empty = Phonebook::default();
+            let book = book.unwrap_or(&empty);
+            if self.history {
+                egui::ScrollArea::vertical()
+                    .auto_shrink([false; 2])
+                    .show(ui, |ui| {
+                        egui::Grid::new("recent calls")
+                            .striped(true)
+                            .show(ui, |ui| {
+                                for c in book.history.iter().filter(|c| matches(c, &self.search)) {
+                                    let (kind, color) = match c.call.as_ref().map(|c| c.kind) {
+                                        Some(CallKind::Missed) => ("Missed", egui::Color32::RED),
+                                        Some(CallKind::Received) => {
+                                            ("Received", ui.visuals().text_color())
+                                        }
+                                        Some(CallKind::Dialed) => {
+                                            ("Dialed", ui.visuals().text_color())
+                                        }
+                                        None => ("", ui.visuals().text_color()),
+                                    };
+                                    ui.colored_label(color, kind);
+                                    ui.label(c.display_name());
+                                    ui.label(
+                                        c.call
+                                            .as_ref()
+                                            .and_then(|c| c.time.as_deref())
+                                            .map(format_time)
+                                            .unwrap_or_default(),
+                                    );
+                                    if Self::numbers(ui, common, addr, c) {
+                                        r = Some(Subwindow::Phone(phone::Phone::new()));
+                                    }
+                                    ui.end_row();
+                                }
+                            });
+                    });
+                return;
+            }
+            let mut contacts: Vec<&VCard> = book
+                .contacts
+                .iter()
+                .chain(received)
+                .filter(|c| matches(c, &self.search))
+                .collect();
+            if !received.is_empty() {
+                contacts.sort_by_cached_key(|c| c.sort_key());
+            }
+            let mut letters: Vec<char> = contacts.iter().map(|c| initial(c)).collect();
+            letters.dedup();
+            ui.horizontal_wrapped(|ui| {
+                for l in &letters {
+                    if ui
+                        .button(
+                            egui::RichText::new(l.to_string())
+                                .font(egui::FontId::proportional(28.0)),
+                        )
+                        .clicked()
+                    {
+                        self.jump = Some(*l);
+                    }
+                }
+            });
+            egui::ScrollArea::vertical()
+                .auto_shrink([false; 2])
+                .show(ui, |ui| {
+                    let mut last = None;
+                    for c in contacts {
+                        let l = initial(c);
+                        if last != Some(l) {
+                            last = Some(l);
+                            let h = ui.heading(l.to_string());
+                            if self.jump == Some(l) {
+                                h.scroll_to_me(Some(egui::Align::TOP));
+                                self.jump = None;
+                            }
+                        }
+                        ui.horizontal(|ui| {
+                            ui.label(
+                                egui::RichText::new(c.display_name())
+                                    .font(egui::FontId::proportional(28.0)),
+                            );
+                            if Self::numbers(ui, common, addr, c) {
+                                r = Some(Subwindow::Phone(phone::Phone::new()));
+                            }
+                        });
+                    }
+                });
+        });
+        r
+    }
+}
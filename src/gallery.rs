@@ -0,0 +1,154 @@
+use std::path::{Path, PathBuf};
+
+use super::CommonWindowProperties;
+use super::Subwindow;
+use super::SubwindowTrait;
+use crate::config;
+use eframe::egui;
+
+const THUMBNAIL_SIZE: egui::Vec2 = egui::Vec2 { x: 240.0, y: 180.0 };
+
+fn pictures_dir() -> Option<PathBuf> {
+    Some(config::dir()?.join("pictures"))
+}
+
+/// Pictures that were sent to the radio, newest first
+pub fn list() -> Vec<PathBuf> {
+    let Some(d) = pictures_dir() else {
+        return Vec::new();
+    };
+    let Ok(entries) = std::fs::read_dir(d) else {
+        return Vec::new();
+    };
+    let mut pictures: Vec<PathBuf> = entries
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.is_file())
+        .collect();
+    // Names start with the time they arrived
+    pictures.sort();
+    pictures.reverse();
+    pictures
+}
+
+/// Move a received picture into the gallery, returning where it now is
+pub fn keep(file: &Path, name: &str) -> std::io::Result<PathBuf> {
+    let d = pictures_dir()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "No config directory"))?;
+    std::fs::create_dir_all(&d)?;
+    let time = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let name = Path::new(name)
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "picture".to_string());
+    let dest = d.join(format!("{}-{}", time, name));
+    // The temporary directory may be on another file system
+    if std::fs::rename(file, &dest).is_err() {
+        std::fs::copy(file, &dest)?;
+        let _ = std::fs::remove_file(file);
+    }
+    Ok(dest)
+}
+
+pub fn uri(p: &Path) -> String {
+    format!("file://{}", p.display())
+}
+
+/// Draw the wallpaper over the whole of a ui, keeping its shape
+pub fn wallpaper(ui: &mut egui::Ui, common: &CommonWindowProperties) {
+    let Some(w) = &common.config.wallpaper else {
+        return;
+    };
+    let rect = ui.max_rect();
+    let image = egui::Image::new(uri(w)).fit_to_exact_size(rect.size());
+    if let Some(size) = image.load_and_calc_size(ui, rect.size()) {
+        image.paint_at(ui, egui::Rect::from_center_size(rect.center(), size));
+    }
+}
+
+pub struct Gallery {
+    selected: Option<PathBuf>,
+}
+
+impl Gallery {
+    pub fn new() -> Self {
+        Self { selected: None }
+    }
+
+    /// One picture at full size, with what can be done with it
+    fn picture(&mut self, ui: &mut egui::Ui, common: &mut CommonWindowProperties, p: PathBuf) {
+        ui.horizontal(|ui| {
+            if ui.button("Back").clicked() {
+                self.selected = None;
+            }
+            if common.config.wallpaper.as_ref() == Some(&p) {
+                ui.label("Wallpaper");
+            } else if ui.button("Use as wallpaper").clicked() {
+                common.config.wallpaper = Some(p.clone());
+                common.config.save();
+            }
+            if ui.button("Delete").clicked() {
+                if let Err(e) = std::fs::remove_file(&p) {
+                    println!("Failed to delete {}: {}", p.display(), e);
+                }
+                common.bluetooth.pictures.retain(|q| *q != p);
+                if common.config.wallpaper.as_ref() == Some(&p) {
+                    common.config.wallpaper = None;
+                    common.config.save();
+                }
+                ui.ctx().forget_image(&uri(&p));
+                self.selected = None;
+            }
+        });
+        ui.add(egui::Image::new(uri(&p)).shrink_to_fit());
+    }
+}
+
+impl SubwindowTrait for Gallery {
+    fn update(
+        &mut self,
+        ctx: &egui::Context,
+        frame: &mut eframe::Frame,
+        common: &mut CommonWindowProperties,
+    ) -> Option<Subwindow> {
+        egui::CentralPanel::default().show(ctx, |ui| {
+            if let Some(p) = self.selected.clone() {
+                self.picture(ui, common, p);
+                return;
+            }
+            ui.horizontal(|ui| {
+                ui.heading("Pictures");
+                if common.config.wallpaper.is_some() && ui.button("Clear wallpaper").clicked() {
+                    common.config.wallpaper = None;
+                    common.config.save();
+                }
+            });
+            if common.bluetooth.pictures.is_empty() {
+                ui.label("No pictures yet, send one from a phone over bluetooth");
+                return;
+            }
+            egui::ScrollArea::vertical()
+                .auto_shrink([false; 2])
+                .show(ui, |ui| {
+                    ui.horizontal_wrapped(|ui| {
+                        for p in &common.bluetooth.pictures {
+                            if ui
+                                .add(
+                                    egui::Image::new(uri(p))
+                                        .fit_to_exact_size(THUMBNAIL_SIZE)
+                                        .sense(egui::Sense::click()),
+                                )
+                                .clicked()
+                            {
+                                self.selected = Some(p.clone());
+                            }
+                        }
+                    });
+                });
+        });
+        None
+    }
+}
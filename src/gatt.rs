@@ -0,0 +1,338 @@
+use std::collections::{HashMap, HashSet};
+
+use futures::StreamExt;
+
+use crate::bluetooth::{ActionStatus, BluetoothDeviceInfo};
+use crate::bluetooth_backend::BackendDevice;
+use crate::bluetooth_device::short_uuid;
+use crate::MessageFromAsync;
+
+/// Where an attribute lives in the attribute tree of a device
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct AttributeId {
+    pub service: u16,
+    pub characteristic: u16,
+    pub descriptor: Option<u16>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Descriptor {
+    pub id: u16,
+    pub uuid: bluer::Uuid,
+}
+
+#[derive(Clone, Debug)]
+pub struct Characteristic {
+    pub id: u16,
+    pub uuid: bluer::Uuid,
+    pub read: bool,
+    /// Supports notifications or indications
+    pub notify: bool,
+    pub descriptors: Vec<Descriptor>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Service {
+    pub id: u16,
+    pub uuid: bluer::Uuid,
+    pub primary: bool,
+    pub characteristics: Vec<Characteristic>,
+}
+
+/// What the gui knows about the attributes of a device
+#[derive(Clone, Debug)]
+pub struct GattState {
+    pub status: ActionStatus,
+    pub services: Vec<Service>,
+    pub values: HashMap<AttributeId, Vec<u8>>,
+    pub subscribed: HashSet<AttributeId>,
+}
+
+impl GattState {
+    pub fn new() -> Self {
+        Self {
+            status: ActionStatus::Started,
+            services: Vec::new(),
+            values: HashMap::new(),
+            subscribed: HashSet::new(),
+        }
+    }
+}
+
+/// A human readable name for well known characteristic and descriptor uuids
+pub fn attribute_name(u: &bluer::Uuid) -> Option<&'static str> {
+    let n = match short_uuid(u)? {
+        0x2900 => "Extended Properties",
+        0x2901 => "User Description",
+        0x2902 => "Client Configuration",
+        0x2904 => "Presentation Format",
+        0x2A00 => "Device Name",
+        0x2A01 => "Appearance",
+        0x2A05 => "Service Changed",
+        0x2A19 => "Battery Level",
+        0x2A24 => "Model Number",
+        0x2A25 => "Serial Number",
+        0x2A26 => "Firmware Revision",
+        0x2A27 => "Hardware Revision",
+        0x2A28 => "Software Revision",
+        0x2A29 => "Manufacturer Name",
+        0x2A37 => "Heart Rate Measurement",
+        0x2A38 => "Body Sensor Location",
+        0x2A6D => "Pressure",
+        0x2A6E => "Temperature",
+        0x2A6F => "Humidity",
+        _ => return None,
+    };
+    Some(n)
+}
+
+fn u16_le(d: &[u8], i: usize) -> Option<u16> {
+    Some(u16::from_le_bytes([*d.get(i)?, *d.get(i + 1)?]))
+}
+
+/// Decode the value of a well known characteristic into something readable
+pub fn decode(u: &bluer::Uuid, d: &[u8]) -> Option<String> {
+    match short_uuid(u)? {
+        0x2A00 | 0x2A24..=0x2A29 => Some(String::from_utf8_lossy(d).into_owned()),
+        0x2A19 => Some(format!("{}%", d.first()?)),
+        0x2A37 => {
+            let flags = *d.first()?;
+            let (bpm, mut i) = if flags & 1 != 0 {
+                (u16_le(d, 1)?, 3)
+            } else {
+                (*d.get(1)? as u16, 2)
+            };
+            let mut s = format!("{} bpm", bpm);
+            if flags & 0x08 != 0 {
+                s.push_str(&format!(", {} kJ", u16_le(d, i)?));
+                i += 2;
+            }
+            if flags & 0x10 != 0 {
+                let rr: Vec<String> = (i..d.len().saturating_sub(1))
+                    .step_by(2)
+                    .filter_map(|i| u16_le(d, i))
+                    .map(|r| format!("{} ms", r as u32 * 1000 / 1024))
+                    .collect();
+                s.push_str(&format!(", RR {}", rr.join(" ")));
+            }
+            Some(s)
+        }
+        0x2A38 => Some(
+            match d.first()? {
+                0 => "Other",
+                1 => "Chest",
+                2 => "Wrist",
+                3 => "Finger",
+                4 => "Hand",
+                5 => "Ear lobe",
+                6 => "Foot",
+                _ => "Unknown",
+            }
+            .to_string(),
+        ),
+        0x2A6D => {
+            let p = u32::from_le_bytes(d.get(0..4)?.try_into().ok()?);
+            Some(format!("{:.1} hPa", p as f32 / 1000.0))
+        }
+        0x2A6E => Some(format!("{:.2} °C", u16_le(d, 0)? as i16 as f32 / 100.0)),
+        0x2A6F => Some(format!("{:.2} %", u16_le(d, 0)? as f32 / 100.0)),
+        _ => None,
+    }
+}
+
+/// A sensor value found in the advertisements of a device
+#[derive(Clone, Debug, PartialEq)]
+pub enum Reading {
+    /// A tire, numbered from front left, front right, rear left, rear right
+    Tire {
+        wheel: u8,
+        /// Pressure in kPa
+        pressure: f32,
+        temperature: f32,
+        battery: u8,
+        alarm: bool,
+    },
+    Temperature(f32),
+    Humidity(f32),
+    Battery(u8),
+}
+
+/// The company id used by the common 16 byte TPMS sensors
+const TPMS_COMPANY: u16 = 0x0100;
+
+/// The BTHome service used by many cabin thermometers
+const BTHOME_UUID: u32 = 0xFCD2;
+
+/// Decode the manufacturer data of a TPMS sensor
+pub fn tpms(d: &[u8]) -> Option<Reading> {
+    if d.len() < 16 || d[0] & 0xf0 != 0x80 {
+        return None;
+    }
+    let pressure = u32::from_le_bytes(d[6..10].try_into().ok()?);
+    let temperature = i32::from_le_bytes(d[10..14].try_into().ok()?);
+    Some(Reading::Tire {
+        wheel: d[0] & 0x0f,
+        pressure: pressure as f32 / 1000.0,
+        temperature: temperature as f32 / 100.0,
+        battery: d[14],
+        alarm: d[15] != 0,
+    })
+}
+
+/// Decode unencrypted BTHome version 2 service data
+pub fn bthome(d: &[u8]) -> Vec<Reading> {
+    let mut readings = Vec::new();
+    let Some(info) = d.first() else {
+        return readings;
+    };
+    // Encrypted data or another version
+    if info & 0x01 != 0 || info >> 5 != 2 {
+        return readings;
+    }
+    let mut i = 1;
+    while let Some(id) = d.get(i) {
+        let (len, r) = match id {
+            0x00 => (1, None),
+            0x01 => (1, d.get(i + 1).map(|b| Reading::Battery(*b))),
+            0x02 => (
+                2,
+                u16_le(d, i + 1).map(|t| Reading::Temperature(t as i16 as f32 / 100.0)),
+            ),
+            0x03 => (
+                2,
+                u16_le(d, i + 1).map(|h| Reading::Humidity(h as f32 / 100.0)),
+            ),
+            // Without the length of an unknown object the rest can not be read
+            _ => break,
+        };
+        readings.extend(r);
+        i += 1 + len;
+    }
+    readings
+}
+
+/// Every sensor reading a device is advertising
+pub fn advertised(dev: &BluetoothDeviceInfo) -> Vec<Reading> {
+    let mut readings = Vec::new();
+    if let Some(r) = dev
+        .manufacturer_data
+        .get(&TPMS_COMPANY)
+        .and_then(|d| tpms(d))
+    {
+        readings.push(r);
+    }
+    for (u, d) in &dev.service_data {
+        if short_uuid(u) == Some(BTHOME_UUID) {
+            readings.extend(bthome(d));
+        }
+    }
+    readings
+}
+
+/// Connect to a device and send its attribute tree to the gui
+pub async fn browse<D: BackendDevice>(
+    addr: bluer::Address,
+    dev: D,
+    tx: tokio::sync::mpsc::Sender<MessageFromAsync>,
+) {
+    let r = async {
+        if !dev.is_connected().await? {
+            dev.connect().await?;
+        }
+        dev.gatt_services().await
+    }
+    .await;
+    let r = r.map_err(|e| {
+        println!("Reading attributes of {} failed {}", addr, e);
+        e.to_string()
+    });
+    let _ = tx.send(MessageFromAsync::GattServices(addr, r)).await;
+}
+
+pub async fn read<D: BackendDevice>(
+    addr: bluer::Address,
+    dev: D,
+    id: AttributeId,
+    tx: tokio::sync::mpsc::Sender<MessageFromAsync>,
+) {
+    match dev.gatt_read(id).await {
+        Ok(v) => {
+            let _ = tx.send(MessageFromAsync::GattValue(addr, id, v)).await;
+        }
+        Err(e) => println!("Reading {:?} of {} failed {}", id, addr, e),
+    }
+}
+
+/// Pass notifications of a characteristic to the gui until the task is aborted
+pub async fn subscribe<D: BackendDevice>(
+    addr: bluer::Address,
+    dev: D,
+    id: AttributeId,
+    tx: tokio::sync::mpsc::Sender<MessageFromAsync>,
+) {
+    let mut values = match dev.gatt_notify(id).await {
+        Ok(v) => v,
+        Err(e) => {
+            println!("Subscribing to {:?} of {} failed {}", id, addr, e);
+            return;
+        }
+    };
+    while let Some(v) = values.next().await {
+        let _ = tx.send(MessageFromAsync::GattValue(addr, id, v)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bluetooth_device::full_uuid as uuid;
+
+    #[test]
+    fn characteristics() {
+        assert_eq!(decode(&uuid(0x2A19), &[75]).unwrap(), "75%");
+        assert_eq!(decode(&uuid(0x2A37), &[0x00, 72]).unwrap(), "72 bpm");
+        assert_eq!(
+            decode(&uuid(0x2A37), &[0x11, 0x2c, 0x01, 0x00, 0x04]).unwrap(),
+            "300 bpm, RR 1000 ms"
+        );
+        assert_eq!(decode(&uuid(0x2A6E), &[0x0c, 0xfe]).unwrap(), "-5.00 °C");
+        assert_eq!(decode(&uuid(0x2A6F), &[0x88, 0x13]).unwrap(), "50.00 %");
+        assert_eq!(
+            decode(&uuid(0x2A6D), &[0xa0, 0x86, 0x01, 0x00]).unwrap(),
+            "100.0 hPa"
+        );
+        assert_eq!(decode(&uuid(0x1234), &[1]), None);
+    }
+
+    #[test]
+    fn tires() {
+        let d = [
+            0x81, 0xea, 0xca, 0x10, 0x8a, 0x78, 0x40, 0x1f, 0x03, 0x00, 0xe6, 0x0a, 0x00, 0x00,
+            0x5b, 0x00,
+        ];
+        assert_eq!(
+            tpms(&d),
+            Some(Reading::Tire {
+                wheel: 1,
+                pressure: 204.608,
+                temperature: 27.9,
+                battery: 91,
+                alarm: false,
+            })
+        );
+        assert_eq!(tpms(&d[..10]), None);
+    }
+
+    #[test]
+    fn cabin() {
+        assert_eq!(
+            bthome(&[0x40, 0x01, 0x61, 0x02, 0xca, 0x09, 0x03, 0xbf, 0x13]),
+            vec![
+                Reading::Battery(97),
+                Reading::Temperature(25.06),
+                Reading::Humidity(50.55),
+            ]
+        );
+        assert!(bthome(&[0x41, 0x01, 0x61]).is_empty());
+    }
+}
@@ -0,0 +1,1227 @@
+use std::collections::VecDeque;
+
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, Lines};
+
+use crate::sco::{Codec, Volume, MAX_VOLUME};
+use crate::MessageFromAsync;
+use crate::MessageToAsync;
+
+/// Asks for the list of calls, which ends at its OK
+const LIST_CALLS: &str = "AT+CLCC";
+
+/// Hands free feature bits sent with AT+BRSF
+pub const HF_FEATURE_EC_NR: u32 = 1 << 0;
+pub const HF_FEATURE_THREE_WAY: u32 = 1 << 1;
+pub const HF_FEATURE_CLI: u32 = 1 << 2;
+pub const HF_FEATURE_VOICE_RECOGNITION: u32 = 1 << 3;
+pub const HF_FEATURE_REMOTE_VOLUME: u32 = 1 << 4;
+pub const HF_FEATURE_ENHANCED_CALL_STATUS: u32 = 1 << 5;
+pub const HF_FEATURE_CODEC_NEGOTIATION: u32 = 1 << 7;
+
+/// The sdp feature bit saying mSBC is supported
+pub const HF_SDP_WIDEBAND_SPEECH: u16 = 1 << 5;
+
+/// Audio gateway feature bits received with +BRSF
+pub const AG_FEATURE_THREE_WAY: u32 = 1 << 0;
+pub const AG_FEATURE_VOICE_RECOGNITION: u32 = 1 << 2;
+pub const AG_FEATURE_INBAND_RING: u32 = 1 << 3;
+pub const AG_FEATURE_ENHANCED_CALL_STATUS: u32 = 1 << 6;
+pub const AG_FEATURE_CODEC_NEGOTIATION: u32 = 1 << 9;
+
+/// The features this hands free unit supports
+pub const HF_FEATURES: u32 = HF_FEATURE_THREE_WAY
+    | HF_FEATURE_CLI
+    | HF_FEATURE_VOICE_RECOGNITION
+    | HF_FEATURE_REMOTE_VOLUME
+    | HF_FEATURE_ENHANCED_CALL_STATUS
+    | HF_FEATURE_CODEC_NEGOTIATION;
+
+#[derive(Debug)]
+pub enum HfpError {
+    Io(std::io::Error),
+    Disconnected,
+    Error(String),
+}
+
+impl From<std::io::Error> for HfpError {
+    fn from(e: std::io::Error) -> Self {
+        HfpError::Io(e)
+    }
+}
+
+/// The value of the callsetup indicator
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CallSetup {
+    Idle,
+    Incoming,
+    Outgoing,
+    Alerting,
+}
+
+impl CallSetup {
+    fn from_indicator(v: u8) -> Self {
+        match v {
+            1 => CallSetup::Incoming,
+            2 => CallSetup::Outgoing,
+            3 => CallSetup::Alerting,
+            _ => CallSetup::Idle,
+        }
+    }
+}
+
+/// The overall state of calls on the phone
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CallStatus {
+    Idle,
+    Incoming(Option<String>),
+    Dialing,
+    Alerting,
+    Active,
+}
+
+/// The state of one call in the list from AT+CLCC
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CallState {
+    Active,
+    Held,
+    Dialing,
+    Alerting,
+    Incoming,
+    Waiting,
+}
+
+impl CallState {
+    fn from_clcc(v: u8) -> Option<Self> {
+        match v {
+            0 => Some(CallState::Active),
+            1 => Some(CallState::Held),
+            2 => Some(CallState::Dialing),
+            3 => Some(CallState::Alerting),
+            4 => Some(CallState::Incoming),
+            5 => Some(CallState::Waiting),
+            _ => None,
+        }
+    }
+}
+
+/// A call on the phone, as listed by AT+CLCC
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Call {
+    /// The number the audio gateway uses for the call in AT+CHLD
+    pub index: u8,
+    pub outgoing: bool,
+    pub state: CallState,
+    /// The call is part of a conference
+    pub multiparty: bool,
+    pub number: Option<String>,
+}
+
+/// Three way calling actions, sent with AT+CHLD
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CallControl {
+    /// Release held calls, or reject a waiting call
+    ReleaseHeld,
+    /// End the active calls and answer the other call
+    ReleaseActive,
+    /// Hold the active calls and answer the other call
+    Swap,
+    /// Join the held calls to the active ones
+    Merge,
+    /// End one call
+    Release(u8),
+    /// Talk to one call of a conference alone, holding the rest
+    Private(u8),
+}
+
+impl CallControl {
+    /// The option listed by +CHLD when the audio gateway supports this
+    fn option(&self) -> &str {
+        match self {
+            CallControl::ReleaseHeld => "0",
+            CallControl::ReleaseActive => "1",
+            CallControl::Swap => "2",
+            CallControl::Merge => "3",
+            CallControl::Release(_) => "1x",
+            CallControl::Private(_) => "2x",
+        }
+    }
+
+    fn code(&self) -> String {
+        match self {
+            CallControl::Release(i) => format!("1{}", i),
+            CallControl::Private(i) => format!("2{}", i),
+            c => c.option().to_string(),
+        }
+    }
+}
+
+/// A single indicator reported by the audio gateway
+#[derive(Clone, Debug)]
+pub struct Indicator {
+    pub name: String,
+    pub min: u8,
+    pub max: u8,
+    pub value: u8,
+}
+
+/// Everything known about the audio gateway on the other end of a hands free connection
+#[derive(Clone, Debug, Default)]
+pub struct HfpState {
+    pub connected: bool,
+    pub ag_features: u32,
+    pub indicators: Vec<Indicator>,
+    pub caller: Option<String>,
+    pub inband_ring: bool,
+    pub ringing: bool,
+    pub hold_options: Vec<String>,
+    /// The codec call audio will use
+    pub codec: Option<Codec>,
+    /// The name of the network the phone is on
+    pub operator: Option<String>,
+    /// The number of a call waiting behind the active call
+    pub waiting: Option<String>,
+    /// The phone's voice assistant is listening
+    pub voice_recognition: bool,
+    /// Every call, when the audio gateway can list them
+    pub calls: Vec<Call>,
+    pub volume: Volume,
+}
+
+impl HfpState {
+    pub fn indicator(&self, name: &str) -> Option<u8> {
+        self.indicators
+            .iter()
+            .find(|i| i.name == name)
+            .map(|i| i.value)
+    }
+
+    pub fn call_setup(&self) -> CallSetup {
+        CallSetup::from_indicator(self.indicator("callsetup").unwrap_or(0))
+    }
+
+    pub fn call_active(&self) -> bool {
+        self.indicator("call").unwrap_or(0) != 0
+    }
+
+    /// An indicator as a fraction of its range
+    fn level(&self, name: &str) -> Option<f32> {
+        let i = self.indicators.iter().find(|i| i.name == name)?;
+        if i.max > i.min {
+            Some(i.value.saturating_sub(i.min).min(i.max - i.min) as f32 / (i.max - i.min) as f32)
+        } else {
+            None
+        }
+    }
+
+    /// A call is waiting behind the active call
+    pub fn call_waiting(&self) -> bool {
+        self.call_setup() == CallSetup::Incoming && self.call_active()
+    }
+
+    /// The value of the callheld indicator, 1 with active and held calls and 2 with only held calls
+    pub fn call_held(&self) -> u8 {
+        self.indicator("callheld").unwrap_or(0)
+    }
+
+    pub fn supports(&self, c: CallControl) -> bool {
+        self.hold_options.iter().any(|o| o == c.option())
+    }
+
+    pub fn voice_recognition_supported(&self) -> bool {
+        (self.ag_features & AG_FEATURE_VOICE_RECOGNITION) != 0
+    }
+
+    /// Whether the phone has a network, true when it does not say
+    pub fn service(&self) -> bool {
+        self.indicator("service").unwrap_or(1) != 0
+    }
+
+    pub fn roaming(&self) -> bool {
+        self.indicator("roam").unwrap_or(0) != 0
+    }
+
+    /// The signal strength from 0 to 1
+    pub fn signal(&self) -> Option<f32> {
+        self.level("signal")
+    }
+
+    /// The battery charge in percent
+    pub fn battery(&self) -> Option<u8> {
+        self.level("battchg").map(|l| (l * 100.0).round() as u8)
+    }
+
+    pub fn call_status(&self) -> CallStatus {
+        match self.call_setup() {
+            CallSetup::Incoming if self.call_active() => CallStatus::Incoming(self.waiting.clone()),
+            CallSetup::Incoming => CallStatus::Incoming(self.caller.clone()),
+            CallSetup::Outgoing => CallStatus::Dialing,
+            CallSetup::Alerting => CallStatus::Alerting,
+            CallSetup::Idle => {
+                if self.call_active() {
+                    CallStatus::Active
+                } else {
+                    CallStatus::Idle
+                }
+            }
+        }
+    }
+
+    fn set_indicator(&mut self, index: usize, value: u8) {
+        if let Some(i) = self.indicators.get_mut(index) {
+            i.value = value;
+        }
+        if self.call_setup() != CallSetup::Incoming {
+            self.ringing = false;
+            self.waiting = None;
+            if !self.call_active() {
+                self.caller = None;
+            }
+        }
+    }
+}
+
+/// A result code from the audio gateway
+#[derive(Debug, PartialEq, Eq)]
+pub enum AtResult {
+    Ok,
+    Error,
+    CmeError(u32),
+    Ring,
+    Brsf(u32),
+    CindSupported(Vec<(String, u8, u8)>),
+    CindValues(Vec<u8>),
+    Ciev(usize, u8),
+    Clip(String),
+    Bsir(bool),
+    Chld(Vec<String>),
+    /// The codec id the audio gateway wants to use
+    Bcs(u8),
+    /// The network operator, if the phone is registered on one
+    Cops(Option<String>),
+    /// The number of a call waiting behind the active call
+    Ccwa(Option<String>),
+    Clcc(Call),
+    Bvra(bool),
+    /// The speaker volume set on the phone
+    Vgs(u8),
+    /// The microphone volume set on the phone
+    Vgm(u8),
+    Unknown(String),
+}
+
+/// Split the parameters of a result code at commas that are not in quotes or parentheses
+fn split_params(s: &str) -> Vec<String> {
+    let mut params = Vec::new();
+    let mut cur = String::new();
+    let mut depth = 0;
+    let mut quoted = false;
+    for c in s.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                cur.push(c);
+            }
+            '(' if !quoted => {
+                depth += 1;
+                cur.push(c);
+            }
+            ')' if !quoted => {
+                depth -= 1;
+                cur.push(c);
+            }
+            ',' if !quoted && depth == 0 => {
+                params.push(cur.trim().to_string());
+                cur.clear();
+            }
+            _ => cur.push(c),
+        }
+    }
+    if !cur.trim().is_empty() || !params.is_empty() {
+        params.push(cur.trim().to_string());
+    }
+    params
+}
+
+fn unquote(s: &str) -> String {
+    s.trim().trim_matches('"').to_string()
+}
+
+fn strip_parens(s: &str) -> &str {
+    s.trim().trim_start_matches('(').trim_end_matches(')')
+}
+
+/// Parse one line of the ("name",(min-max)) list returned by AT+CIND=?
+fn parse_cind_supported(s: &str) -> Option<Vec<(String, u8, u8)>> {
+    let mut list = Vec::new();
+    for entry in split_params(s) {
+        let inner = entry.trim().strip_prefix('(')?.strip_suffix(')')?;
+        let parts = split_params(inner);
+        let name = unquote(parts.first()?);
+        let range = strip_parens(parts.get(1)?);
+        let vals: Vec<u8> = range
+            .split([',', '-'])
+            .filter_map(|v| v.trim().parse().ok())
+            .collect();
+        let min = vals.iter().copied().min().unwrap_or(0);
+        let max = vals.iter().copied().max().unwrap_or(0);
+        list.push((name, min, max));
+    }
+    Some(list)
+}
+
+/// Parse a single line received from the audio gateway
+pub fn parse_result(line: &str) -> AtResult {
+    let line = line.trim();
+    if line == "OK" {
+        return AtResult::Ok;
+    }
+    if line == "ERROR" {
+        return AtResult::Error;
+    }
+    if line == "RING" {
+        return AtResult::Ring;
+    }
+    let Some((code, params)) = line.split_once(':') else {
+        return AtResult::Unknown(line.to_string());
+    };
+    let params = params.trim();
+    let r = match code.trim() {
+        "+CME ERROR" => params.parse().ok().map(AtResult::CmeError),
+        "+BRSF" => params.parse().ok().map(AtResult::Brsf),
+        "+CIND" => {
+            if params.starts_with('(') {
+                parse_cind_supported(params).map(AtResult::CindSupported)
+            } else {
+                params
+                    .split(',')
+                    .map(|v| v.trim().parse().ok())
+                    .collect::<Option<Vec<u8>>>()
+                    .map(AtResult::CindValues)
+            }
+        }
+        "+CIEV" => {
+            let p = split_params(params);
+            match (
+                p.first().and_then(|i| i.parse::<usize>().ok()),
+                p.get(1).and_then(|v| v.parse::<u8>().ok()),
+            ) {
+                (Some(i), Some(v)) if i > 0 => Some(AtResult::Ciev(i - 1, v)),
+                _ => None,
+            }
+        }
+        "+CLIP" => split_params(params)
+            .first()
+            .map(|n| AtResult::Clip(unquote(n))),
+        "+BSIR" => params.parse::<u8>().ok().map(|v| AtResult::Bsir(v != 0)),
+        "+CHLD" => Some(AtResult::Chld(
+            strip_parens(params)
+                .split(',')
+                .map(|s| s.trim().to_string())
+                .collect(),
+        )),
+        "+BCS" => params.parse().ok().map(AtResult::Bcs),
+        "+CCWA" => Some(AtResult::Ccwa(
+            split_params(params)
+                .first()
+                .map(|n| unquote(n))
+                .filter(|n| !n.is_empty()),
+        )),
+        "+CLCC" => {
+            let p = split_params(params);
+            let num = |i: usize| p.get(i).and_then(|v| v.parse::<u8>().ok());
+            match (
+                num(0),
+                num(1),
+                num(2).and_then(CallState::from_clcc),
+                num(4),
+            ) {
+                (Some(index), Some(dir), Some(state), Some(mpty)) => Some(AtResult::Clcc(Call {
+                    index,
+                    outgoing: dir == 0,
+                    state,
+                    multiparty: mpty != 0,
+                    number: p.get(5).map(|n| unquote(n)).filter(|n| !n.is_empty()),
+                })),
+                _ => None,
+            }
+        }
+        "+BVRA" => params.parse::<u8>().ok().map(|v| AtResult::Bvra(v != 0)),
+        "+VGS" => params.parse().ok().map(AtResult::Vgs),
+        "+VGM" => params.parse().ok().map(AtResult::Vgm),
+        "+COPS" => Some(AtResult::Cops(
+            split_params(params)
+                .get(2)
+                .map(|o| unquote(o))
+                .filter(|o| !o.is_empty()),
+        )),
+        _ => None,
+    };
+    r.unwrap_or_else(|| AtResult::Unknown(line.to_string()))
+}
+
+/// A request from the gui for the audio gateway
+#[derive(Clone, Debug)]
+pub enum HfpCommand {
+    Answer,
+    HangUp,
+    Dial(String),
+    VoiceRecognition(bool),
+    CallControl(CallControl),
+    /// Send a touch tone during a call
+    Dtmf(char),
+    SpeakerVolume(u8),
+    MicVolume(u8),
+}
+
+impl HfpCommand {
+    /// The AT command to send, or None when there is nothing valid to send
+    fn at(&self) -> Option<String> {
+        Some(match self {
+            HfpCommand::Answer => "ATA".to_string(),
+            HfpCommand::HangUp => "AT+CHUP".to_string(),
+            HfpCommand::Dial(n) => {
+                let n: String = n
+                    .chars()
+                    .filter(|c| c.is_ascii_digit() || matches!(c, '+' | '*' | '#'))
+                    .collect();
+                // A bare ATD; would redial or fail depending on the phone
+                if n.is_empty() {
+                    return None;
+                }
+                format!("ATD{};", n)
+            }
+            HfpCommand::VoiceRecognition(on) => format!("AT+BVRA={}", *on as u8),
+            HfpCommand::CallControl(c) => format!("AT+CHLD={}", c.code()),
+            HfpCommand::Dtmf(c @ ('0'..='9' | '*' | '#' | 'A'..='D')) => format!("AT+VTS={}", c),
+            HfpCommand::Dtmf(_) => return None,
+            HfpCommand::SpeakerVolume(v) => format!("AT+VGS={}", (*v).min(MAX_VOLUME)),
+            HfpCommand::MicVolume(v) => format!("AT+VGM={}", (*v).min(MAX_VOLUME)),
+        })
+    }
+
+    /// The volumes after this command, if it changes them
+    fn volume(&self, current: Volume) -> Option<Volume> {
+        match self {
+            HfpCommand::SpeakerVolume(v) => Some(Volume {
+                speaker: (*v).min(MAX_VOLUME),
+                ..current
+            }),
+            HfpCommand::MicVolume(v) => Some(Volume {
+                mic: (*v).min(MAX_VOLUME),
+                ..current
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// A hands free protocol connection to an audio gateway
+pub struct HandsFree<R, W> {
+    addr: bluer::Address,
+    lines: Lines<BufReader<R>>,
+    w: W,
+    state: HfpState,
+    tx: tokio::sync::mpsc::Sender<MessageFromAsync>,
+    voice: tokio::sync::mpsc::Sender<MessageToAsync>,
+    /// The codecs offered to the audio gateway
+    codecs: Vec<Codec>,
+    /// Calls received since the last AT+CLCC, until its OK
+    listing: Option<Vec<Call>>,
+    /// Commands waiting for the outstanding one to get its final result code
+    queue: VecDeque<String>,
+    /// The command the next OK or ERROR belongs to
+    outstanding: Option<String>,
+}
+
+impl<R: AsyncRead + Unpin, W: AsyncWrite + Unpin> HandsFree<R, W> {
+    pub fn new(
+        addr: bluer::Address,
+        r: R,
+        w: W,
+        tx: tokio::sync::mpsc::Sender<MessageFromAsync>,
+        voice: tokio::sync::mpsc::Sender<MessageToAsync>,
+        msbc: bool,
+    ) -> Self {
+        let codecs = if msbc {
+            vec![Codec::Cvsd, Codec::Msbc]
+        } else {
+            vec![Codec::Cvsd]
+        };
+        Self {
+            addr,
+            lines: BufReader::new(r).lines(),
+            w,
+            state: HfpState::default(),
+            tx,
+            voice,
+            codecs,
+            listing: None,
+            queue: VecDeque::new(),
+            outstanding: None,
+        }
+    }
+
+    async fn send_state(&self) {
+        let _ = self
+            .tx
+            .send(MessageFromAsync::HfpState(self.addr, self.state.clone()))
+            .await;
+    }
+
+    /// Keep the volumes, passing them on to the call audio
+    fn set_volume(&mut self, v: Volume) {
+        self.state.volume = v;
+        let _ = self
+            .voice
+            .try_send(MessageToAsync::VoiceVolume(self.addr, v));
+    }
+
+    async fn set_codec(&mut self, c: Codec) {
+        self.state.codec = Some(c);
+        let _ = self
+            .voice
+            .send(MessageToAsync::VoiceCodec(self.addr, Some(c)))
+            .await;
+        self.send_state().await;
+    }
+
+    fn available_codecs(&self) -> String {
+        let ids: Vec<String> = self.codecs.iter().map(|c| c.id().to_string()).collect();
+        format!("AT+BAC={}", ids.join(","))
+    }
+
+    /// Confirm the codec the audio gateway chose, or offer ours again if it is not one of them
+    async fn select_codec(&mut self, id: u8) -> Result<(), HfpError> {
+        match Codec::from_id(id).filter(|c| self.codecs.contains(c)) {
+            Some(c) => {
+                self.set_codec(c).await;
+                self.queue_command(format!("AT+BCS={}", id)).await
+            }
+            None => self.queue_command(self.available_codecs()).await,
+        }
+    }
+
+    /// Send a command once every command before it got its final result code
+    async fn queue_command(&mut self, cmd: String) -> Result<(), HfpError> {
+        self.queue.push_back(cmd);
+        self.send_next().await
+    }
+
+    /// Send the next queued command, unless one is still waiting for its result
+    async fn send_next(&mut self) -> Result<(), HfpError> {
+        if self.outstanding.is_some() {
+            return Ok(());
+        }
+        let Some(cmd) = self.queue.pop_front() else {
+            return Ok(());
+        };
+        if cmd == LIST_CALLS {
+            self.listing = Some(Vec::new());
+        }
+        self.write_command(&cmd).await?;
+        self.outstanding = Some(cmd);
+        Ok(())
+    }
+
+    /// End the outstanding command with its final result code and send the next one,
+    /// returns the command that ended
+    async fn complete(&mut self, ok: bool) -> Result<Option<String>, HfpError> {
+        let done = self.outstanding.take();
+        if done.as_deref() == Some(LIST_CALLS) {
+            if ok {
+                self.finish_listing().await;
+            } else {
+                self.listing = None;
+            }
+        }
+        self.send_next().await?;
+        Ok(done)
+    }
+
+    async fn write_command(&mut self, cmd: &str) -> Result<(), HfpError> {
+        println!("HFP send: {}", cmd);
+        self.w.write_all(format!("{}\r", cmd).as_bytes()).await?;
+        self.w.flush().await?;
+        Ok(())
+    }
+
+    async fn read_result(&mut self) -> Result<AtResult, HfpError> {
+        loop {
+            match self.lines.next_line().await? {
+                Some(l) if l.trim().is_empty() => continue,
+                Some(l) => {
+                    println!("HFP recv: {}", l.trim());
+                    return Ok(parse_result(&l));
+                }
+                None => return Err(HfpError::Disconnected),
+            }
+        }
+    }
+
+    /// Update the state from a result code, returns true if the state changed
+    fn handle_result(&mut self, r: AtResult) -> bool {
+        match r {
+            AtResult::Ok | AtResult::Error | AtResult::CmeError(_) => false,
+            AtResult::Ring => {
+                self.state.ringing = true;
+                true
+            }
+            AtResult::Brsf(f) => {
+                self.state.ag_features = f;
+                self.state.inband_ring = (f & AG_FEATURE_INBAND_RING) != 0;
+                true
+            }
+            AtResult::CindSupported(list) => {
+                self.state.indicators = list
+                    .into_iter()
+                    .map(|(name, min, max)| Indicator {
+                        name,
+                        min,
+                        max,
+                        value: min,
+                    })
+                    .collect();
+                true
+            }
+            AtResult::CindValues(vals) => {
+                for (i, v) in vals.into_iter().enumerate() {
+                    self.state.set_indicator(i, v);
+                }
+                true
+            }
+            AtResult::Ciev(i, v) => {
+                self.state.set_indicator(i, v);
+                true
+            }
+            AtResult::Clip(n) => {
+                self.state.caller = Some(n);
+                true
+            }
+            AtResult::Bsir(b) => {
+                self.state.inband_ring = b;
+                true
+            }
+            AtResult::Chld(o) => {
+                self.state.hold_options = o;
+                true
+            }
+            AtResult::Bcs(_) => false,
+            AtResult::Cops(o) => {
+                self.state.operator = o;
+                true
+            }
+            AtResult::Ccwa(n) => {
+                self.state.waiting = n;
+                true
+            }
+            AtResult::Clcc(c) => {
+                self.listing.get_or_insert_with(Vec::new).push(c);
+                false
+            }
+            AtResult::Bvra(b) => {
+                self.state.voice_recognition = b;
+                true
+            }
+            AtResult::Vgs(v) => {
+                self.set_volume(Volume {
+                    speaker: v.min(MAX_VOLUME),
+                    ..self.state.volume
+                });
+                true
+            }
+            AtResult::Vgm(v) => {
+                self.set_volume(Volume {
+                    mic: v.min(MAX_VOLUME),
+                    ..self.state.volume
+                });
+                true
+            }
+            AtResult::Unknown(l) => {
+                println!("Unhandled HFP result: {}", l);
+                false
+            }
+        }
+    }
+
+    /// Send a command and process everything received until its final result code
+    pub async fn command(&mut self, cmd: &str) -> Result<(), HfpError> {
+        self.queue_command(cmd.to_string()).await?;
+        loop {
+            let r = self.read_result().await?;
+            let ok = match r {
+                AtResult::Ok => true,
+                AtResult::Error | AtResult::CmeError(_) => false,
+                AtResult::Bcs(id) => {
+                    self.select_codec(id).await?;
+                    continue;
+                }
+                r => {
+                    if self.handle_result(r) {
+                        self.send_state().await;
+                    }
+                    continue;
+                }
+            };
+            // A command queued before this one may still have been waiting
+            if self.complete(ok).await?.as_deref() != Some(cmd) {
+                continue;
+            }
+            return match r {
+                AtResult::Ok => Ok(()),
+                AtResult::CmeError(e) => Err(HfpError::Error(format!("{}: CME ERROR {}", cmd, e))),
+                _ => Err(HfpError::Error(cmd.to_string())),
+            };
+        }
+    }
+
+    /// Establish the service level connection
+    pub async fn connect(&mut self) -> Result<(), HfpError> {
+        self.command(&format!("AT+BRSF={}", HF_FEATURES)).await?;
+        if (self.state.ag_features & AG_FEATURE_CODEC_NEGOTIATION) != 0
+            && (HF_FEATURES & HF_FEATURE_CODEC_NEGOTIATION) != 0
+        {
+            self.command(&self.available_codecs()).await?;
+        }
+        self.command("AT+CIND=?").await?;
+        self.command("AT+CIND?").await?;
+        self.command("AT+CMER=3,0,0,1").await?;
+        if (self.state.ag_features & AG_FEATURE_THREE_WAY) != 0
+            && (HF_FEATURES & HF_FEATURE_THREE_WAY) != 0
+        {
+            self.command("AT+CHLD=?").await?;
+        }
+        self.state.connected = true;
+        // Calls use cvsd until the audio gateway picks a codec
+        self.set_codec(Codec::Cvsd).await;
+        if (HF_FEATURES & HF_FEATURE_CLI) != 0 {
+            if let Err(e) = self.command("AT+CLIP=1").await {
+                println!("Failed to enable caller id {:?}", e);
+            }
+        }
+        // Operator names are asked for as long text
+        if let Err(e) = self.command("AT+COPS=3,0").await {
+            println!("Failed to set the operator name format {:?}", e);
+        } else if let Err(e) = self.command("AT+COPS?").await {
+            println!("Failed to get the operator name {:?}", e);
+        }
+        if (self.state.ag_features & AG_FEATURE_THREE_WAY) != 0
+            && (HF_FEATURES & HF_FEATURE_THREE_WAY) != 0
+        {
+            if let Err(e) = self.command("AT+CCWA=1").await {
+                println!("Failed to enable call waiting {:?}", e);
+            }
+        }
+        if self.lists_calls() {
+            if let Err(e) = self.command(LIST_CALLS).await {
+                println!("Failed to list calls {:?}", e);
+            }
+        }
+        Ok(())
+    }
+
+    fn lists_calls(&self) -> bool {
+        (self.state.ag_features & AG_FEATURE_ENHANCED_CALL_STATUS) != 0
+            && (HF_FEATURES & HF_FEATURE_ENHANCED_CALL_STATUS) != 0
+    }
+
+    /// Ask for the list of calls, unless that is already waiting to be sent
+    async fn refresh_calls(&mut self) -> Result<(), HfpError> {
+        if self.lists_calls() && !self.queue.iter().any(|c| c == LIST_CALLS) {
+            self.queue_command(LIST_CALLS.to_string()).await?;
+        }
+        Ok(())
+    }
+
+    /// Replace the calls with the finished list from AT+CLCC
+    async fn finish_listing(&mut self) {
+        if let Some(calls) = self.listing.take() {
+            self.state.calls = calls;
+            self.send_state().await;
+        }
+    }
+
+    /// Whether a result code could change the list of calls
+    fn calls_changed(&self, r: &AtResult) -> bool {
+        match r {
+            AtResult::Ciev(i, _) => self
+                .state
+                .indicators
+                .get(*i)
+                .map(|i| matches!(i.name.as_str(), "call" | "callsetup" | "callheld"))
+                .unwrap_or(false),
+            AtResult::Ccwa(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Whether a result code says the phone may have moved to another network
+    fn network_changed(&self, r: &AtResult) -> bool {
+        match r {
+            AtResult::Ciev(i, _) => self
+                .state
+                .indicators
+                .get(*i)
+                .map(|i| i.name == "service" || i.name == "roam")
+                .unwrap_or(false),
+            _ => false,
+        }
+    }
+
+    /// Process unsolicited result codes and commands until the connection closes
+    pub async fn run(
+        &mut self,
+        cmd: &mut tokio::sync::mpsc::Receiver<HfpCommand>,
+    ) -> Result<(), HfpError> {
+        loop {
+            tokio::select! {
+                r = self.read_result() => {
+                    match r? {
+                        AtResult::Ok => {
+                            self.complete(true).await?;
+                        }
+                        AtResult::Error => {
+                            let c = self.complete(false).await?;
+                            println!("HFP command {:?} failed", c);
+                        }
+                        AtResult::CmeError(e) => {
+                            let c = self.complete(false).await?;
+                            println!("HFP command {:?} failed: CME ERROR {}", c, e);
+                        }
+                        AtResult::Bcs(id) => self.select_codec(id).await?,
+                        r => {
+                            let network = self.network_changed(&r);
+                            let calls = self.calls_changed(&r);
+                            if self.handle_result(r) {
+                                self.send_state().await;
+                            }
+                            if network && self.state.service() {
+                                self.queue_command("AT+COPS?".to_string()).await?;
+                            }
+                            if calls {
+                                self.refresh_calls().await?;
+                            }
+                        }
+                    }
+                }
+                c = cmd.recv() => {
+                    match c {
+                        Some(c) => {
+                            let Some(at) = c.at() else {
+                                println!("Not sending the HFP command {:?}", c);
+                                continue;
+                            };
+                            self.queue_command(at).await?;
+                            if let Some(v) = c.volume(self.state.volume) {
+                                self.set_volume(v);
+                                self.send_state().await;
+                            }
+                        }
+                        None => return Ok(()),
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Run the hands free protocol on an accepted connection until it closes,
+/// offering wideband speech when msbc is set
+pub async fn handsfree<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+    addr: bluer::Address,
+    r: R,
+    w: W,
+    tx: tokio::sync::mpsc::Sender<MessageFromAsync>,
+    mut cmd: tokio::sync::mpsc::Receiver<HfpCommand>,
+    voice: tokio::sync::mpsc::Sender<MessageToAsync>,
+    msbc: bool,
+) -> Result<(), HfpError> {
+    let mut hf = HandsFree::new(addr, r, w, tx.clone(), voice.clone(), msbc);
+    let _ = tx.send(MessageFromAsync::HfpConnected(addr)).await;
+    let r = match hf.connect().await {
+        Ok(()) => hf.run(&mut cmd).await,
+        Err(e) => Err(e),
+    };
+    let _ = voice.send(MessageToAsync::VoiceCodec(addr, None)).await;
+    let _ = tx.send(MessageFromAsync::HfpDisconnected(addr)).await;
+    r
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::io::{AsyncBufReadExt, DuplexStream, ReadHalf, WriteHalf};
+
+    const ADDR: bluer::Address = bluer::Address::new([0x00, 0x11, 0x22, 0x33, 0x44, 0x55]);
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Three way calling, in band ringing, call listing and codec negotiation
+    const AG_FEATURES: u32 = AG_FEATURE_THREE_WAY
+        | AG_FEATURE_INBAND_RING
+        | AG_FEATURE_ENHANCED_CALL_STATUS
+        | AG_FEATURE_CODEC_NEGOTIATION;
+
+    const INDICATORS: &str = "+CIND: (\"service\",(0,1)),(\"call\",(0,1)),(\"callsetup\",(0-3)),\
+        (\"callheld\",(0-2)),(\"signal\",(0-5)),(\"roam\",(0,1)),(\"battchg\",(0-5))";
+
+    /// The test side of a hands free connection, playing the phone
+    struct Gateway {
+        r: BufReader<ReadHalf<DuplexStream>>,
+        w: WriteHalf<DuplexStream>,
+        rx: tokio::sync::mpsc::Receiver<MessageFromAsync>,
+        voice: tokio::sync::mpsc::Receiver<MessageToAsync>,
+        cmd: tokio::sync::mpsc::Sender<HfpCommand>,
+    }
+
+    impl Gateway {
+        /// Start the hands free side on one end of a pipe, returning the other end
+        fn start(msbc: bool) -> (Self, tokio::task::JoinHandle<Result<(), HfpError>>) {
+            let (hf, ag) = tokio::io::duplex(1024);
+            let (tx, rx) = tokio::sync::mpsc::channel(100);
+            let (voice_tx, voice) = tokio::sync::mpsc::channel(100);
+            let (cmd, crx) = tokio::sync::mpsc::channel(10);
+            let (r, w) = tokio::io::split(hf);
+            let j = tokio::spawn(handsfree(ADDR, r, w, tx, crx, voice_tx, msbc));
+            let (r, w) = tokio::io::split(ag);
+            let ag = Self {
+                r: BufReader::new(r),
+                w,
+                rx,
+                voice,
+                cmd,
+            };
+            (ag, j)
+        }
+
+        /// Read the next command, which must be cmd, and answer it with the lines of reply
+        async fn expect(&mut self, cmd: &str, reply: &[&str]) {
+            let mut line = Vec::new();
+            tokio::time::timeout(TIMEOUT, self.r.read_until(b'\r', &mut line))
+                .await
+                .expect("No command from the hands free side")
+                .unwrap();
+            assert_eq!(String::from_utf8_lossy(&line).trim(), cmd);
+            for l in reply {
+                self.send(l).await;
+            }
+        }
+
+        async fn send(&mut self, line: &str) {
+            self.w
+                .write_all(format!("\r\n{}\r\n", line).as_bytes())
+                .await
+                .unwrap();
+        }
+
+        /// Wait for the hands free side to report a state that passes f
+        async fn wait_for(&mut self, f: impl Fn(&HfpState) -> bool) -> HfpState {
+            loop {
+                let m = tokio::time::timeout(TIMEOUT, self.rx.recv())
+                    .await
+                    .expect("The state never got there")
+                    .expect("The hands free side stopped");
+                if let MessageFromAsync::HfpState(_, s) = m {
+                    if f(&s) {
+                        return s;
+                    }
+                }
+            }
+        }
+
+        /// Go through the service level connection setup with a phone that has no calls
+        async fn connect(&mut self) -> HfpState {
+            let brsf = format!("+BRSF: {}", AG_FEATURES);
+            self.expect(&format!("AT+BRSF={}", HF_FEATURES), &[&brsf, "OK"])
+                .await;
+            self.expect("AT+BAC=1,2", &["OK"]).await;
+            self.expect("AT+CIND=?", &[INDICATORS, "OK"]).await;
+            self.expect("AT+CIND?", &["+CIND: 1,0,0,0,4,0,3", "OK"])
+                .await;
+            self.expect("AT+CMER=3,0,0,1", &["OK"]).await;
+            self.expect("AT+CHLD=?", &["+CHLD: (0,1,1x,2,2x,3)", "OK"])
+                .await;
+            self.expect("AT+CLIP=1", &["OK"]).await;
+            self.expect("AT+COPS=3,0", &["OK"]).await;
+            self.expect("AT+COPS?", &["+COPS: 0,0,\"Carrier\"", "OK"])
+                .await;
+            // Call waiting is optional, a failure must not end the connection
+            self.expect("AT+CCWA=1", &["ERROR"]).await;
+            self.expect("AT+CLCC", &["OK"]).await;
+            self.wait_for(|s| s.connected && s.operator.is_some()).await
+        }
+    }
+
+    #[tokio::test]
+    async fn service_level_connection() {
+        let (mut ag, j) = Gateway::start(true);
+        let s = ag.connect().await;
+        assert_eq!(s.ag_features, AG_FEATURES);
+        assert!(s.inband_ring);
+        assert_eq!(s.indicators.len(), 7);
+        assert!(s.service());
+        assert_eq!(s.signal(), Some(0.8));
+        assert_eq!(s.battery(), Some(60));
+        assert_eq!(s.operator.as_deref(), Some("Carrier"));
+        assert!(s.supports(CallControl::Swap));
+        assert!(s.supports(CallControl::Release(1)));
+        assert_eq!(s.codec, Some(Codec::Cvsd));
+        assert_eq!(s.call_status(), CallStatus::Idle);
+        assert!(s.calls.is_empty());
+
+        // The phone switches to wideband speech before connecting call audio
+        ag.send("+BCS: 2").await;
+        ag.expect("AT+BCS=2", &["OK"]).await;
+        loop {
+            match ag.voice.recv().await {
+                Some(MessageToAsync::VoiceCodec(_, Some(Codec::Msbc))) => break,
+                Some(_) => {}
+                None => panic!("No codec change"),
+            }
+        }
+
+        drop(ag.cmd);
+        assert!(j.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn incoming_call() {
+        let (mut ag, j) = Gateway::start(true);
+        ag.connect().await;
+
+        ag.send("+CIEV: 3,1").await;
+        ag.wait_for(|s| s.call_setup() == CallSetup::Incoming).await;
+        ag.expect("AT+CLCC", &["+CLCC: 1,1,4,0,0,\"5551234\",129", "OK"])
+            .await;
+        let s = ag.wait_for(|s| !s.calls.is_empty()).await;
+        assert_eq!(s.calls[0].state, CallState::Incoming);
+        assert!(!s.calls[0].outgoing);
+
+        ag.send("RING").await;
+        ag.wait_for(|s| s.ringing).await;
+        ag.send("+CLIP: \"5551234\",129").await;
+        let s = ag.wait_for(|s| s.caller.is_some()).await;
+        assert_eq!(
+            s.call_status(),
+            CallStatus::Incoming(Some("5551234".to_string()))
+        );
+        ag.send("+BSIR: 0").await;
+        let s = ag.wait_for(|s| !s.inband_ring).await;
+        assert!(s.ringing);
+
+        // Both indicator changes ask for the calls again, but the list only goes out
+        // once ATA got its OK, and that OK must not end the list
+        ag.cmd.send(HfpCommand::Answer).await.unwrap();
+        ag.expect("ATA", &["+CIEV: 2,1", "+CIEV: 3,0"]).await;
+        let s = ag.wait_for(|s| s.call_setup() == CallSetup::Idle).await;
+        assert!(!s.ringing);
+        assert_eq!(s.call_status(), CallStatus::Active);
+        ag.send("OK").await;
+        ag.expect("AT+CLCC", &["+CLCC: 1,1,0,0,0,\"5551234\",129", "OK"])
+            .await;
+        let s = ag
+            .wait_for(|s| s.calls.first().map(|c| c.state) == Some(CallState::Active))
+            .await;
+        assert_eq!(s.calls.len(), 1);
+        assert_eq!(s.caller.as_deref(), Some("5551234"));
+
+        // A failed command leaves the connection up
+        ag.cmd
+            .send(HfpCommand::Dial("555-0000".to_string()))
+            .await
+            .unwrap();
+        ag.expect("ATD5550000;", &["ERROR"]).await;
+
+        // A call list that fails keeps the calls from before
+        ag.send("+CIEV: 2,0").await;
+        let s = ag.wait_for(|s| !s.call_active()).await;
+        assert!(s.caller.is_none());
+        ag.expect("AT+CLCC", &["+CME ERROR: 30"]).await;
+        // Nothing is sent for a tone that does not exist or a number without digits
+        ag.cmd.send(HfpCommand::Dtmf('x')).await.unwrap();
+        ag.cmd
+            .send(HfpCommand::Dial("call me".to_string()))
+            .await
+            .unwrap();
+        ag.cmd.send(HfpCommand::Dtmf('5')).await.unwrap();
+        ag.expect("AT+VTS=5", &["OK", "+CIEV: 5,2"]).await;
+        let s = ag.wait_for(|s| s.signal() == Some(0.4)).await;
+        assert_eq!(s.calls.len(), 1);
+
+        drop(ag.cmd);
+        assert!(j.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn setup_error() {
+        let (mut ag, j) = Gateway::start(false);
+        let brsf = format!("+BRSF: {}", AG_FEATURES);
+        ag.expect(&format!("AT+BRSF={}", HF_FEATURES), &[&brsf, "OK"])
+            .await;
+        // Only cvsd is offered without wideband speech
+        ag.expect("AT+BAC=1", &["OK"]).await;
+        ag.expect("AT+CIND=?", &[INDICATORS, "OK"]).await;
+        ag.expect("AT+CIND?", &["+CIND: 1,0,0,0,4,0,3", "OK"]).await;
+        ag.expect("AT+CMER=3,0,0,1", &["ERROR"]).await;
+        assert!(matches!(
+            j.await.unwrap(),
+            Err(HfpError::Error(c)) if c == "AT+CMER=3,0,0,1"
+        ));
+    }
+
+    #[test]
+    fn commands() {
+        assert_eq!(
+            HfpCommand::Dial("+1 (555) 123-4567".to_string())
+                .at()
+                .as_deref(),
+            Some("ATD+15551234567;")
+        );
+        assert_eq!(HfpCommand::Dial("-".to_string()).at(), None);
+        assert_eq!(HfpCommand::Dial(String::new()).at(), None);
+        for c in ['0', '9', '*', '#', 'A', 'D'] {
+            assert_eq!(HfpCommand::Dtmf(c).at(), Some(format!("AT+VTS={}", c)));
+        }
+        for c in ['E', 'a', '+', ' ', ';'] {
+            assert_eq!(HfpCommand::Dtmf(c).at(), None);
+        }
+    }
+
+    #[test]
+    fn results() {
+        assert_eq!(parse_result("OK"), AtResult::Ok);
+        assert_eq!(parse_result("ERROR"), AtResult::Error);
+        assert_eq!(parse_result("+CME ERROR: 30"), AtResult::CmeError(30));
+        assert_eq!(parse_result("RING"), AtResult::Ring);
+        assert_eq!(parse_result("+CIEV: 2,1"), AtResult::Ciev(1, 1));
+        assert_eq!(
+            parse_result("+CIEV: 0,1"),
+            AtResult::Unknown("+CIEV: 0,1".to_string())
+        );
+        assert_eq!(
+            parse_result("+CLIP: \"+15551234\",145,,,\"Alice\""),
+            AtResult::Clip("+15551234".to_string())
+        );
+        assert_eq!(parse_result("+BSIR: 1"), AtResult::Bsir(true));
+        assert_eq!(parse_result("+BSIR: 0"), AtResult::Bsir(false));
+        assert_eq!(
+            parse_result("+CIND: (\"call\",(0,1)),(\"callsetup\",(0-3))"),
+            AtResult::CindSupported(vec![
+                ("call".to_string(), 0, 1),
+                ("callsetup".to_string(), 0, 3)
+            ])
+        );
+        assert_eq!(
+            parse_result("+CHLD: (0,1,1x,2,2x,3,4)"),
+            AtResult::Chld(
+                ["0", "1", "1x", "2", "2x", "3", "4"]
+                    .map(String::from)
+                    .to_vec()
+            )
+        );
+        assert_eq!(parse_result("+COPS: 0"), AtResult::Cops(None));
+        assert_eq!(
+            parse_result("+XAPL=iPhone,2"),
+            AtResult::Unknown("+XAPL=iPhone,2".to_string())
+        );
+    }
+}
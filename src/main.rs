@@ -1,5 +1,37 @@
+mod a2dp;
+mod adapters;
+mod autoconnect;
 mod bluetooth;
+mod bluetooth_backend;
+mod bluetooth_device;
+#[cfg(any(test, feature = "mock"))]
+mod bluetooth_mock;
+mod bluez;
+mod bmessage;
+mod config;
+mod contacts;
+mod gallery;
+mod gatt;
+mod hfp;
+mod map;
+mod media;
+mod messages;
+mod msbc;
+mod obd;
+mod obex;
+mod opp;
+mod pairing;
+mod pan;
+mod pbap;
+mod phone;
+mod sbc;
+mod sco;
+mod sensors;
 mod settings;
+mod spp;
+mod terminal;
+mod vcard;
+mod vehicle;
 mod video;
 
 use eframe::egui::{self, Vec2};
@@ -18,11 +50,85 @@ enum MessageFromAsync {
     NewBluetoothDevice(bluer::Address),
     OldBluetoothDevice(bluer::Address),
     BluetoothDeviceProperty(bluer::Address, bluer::DeviceProperty),
-    BluetoothPresent(bool),
+    BluetoothStatus(bluetooth::BluetoothStatus),
+    /// What each adapter reports and the name of the one in use
+    Adapters(Vec<bluetooth_backend::AdapterInfo>, Option<String>),
+    BluetoothError(bluetooth::BluetoothError),
+    BluetoothDeviceAction(
+        bluer::Address,
+        bluetooth::DeviceAction,
+        bluetooth::ActionStatus,
+    ),
+    PhoneLink(autoconnect::PhoneLink),
+    HfpConnected(bluer::Address),
+    HfpDisconnected(bluer::Address),
+    HfpState(bluer::Address, hfp::HfpState),
+    /// The codec of the call audio of a phone, none when the audio link closed
+    Voice(bluer::Address, Option<sco::Codec>),
+    PairingRequest(
+        pairing::PairingRequest,
+        tokio::sync::oneshot::Sender<pairing::PairingResponse>,
+    ),
+    AudioStream(Option<a2dp::AudioStream>),
+    MediaPlayer(bluer::Address, media::MediaState),
+    MediaPlayerGone(bluer::Address),
+    Phonebook(bluer::Address, contacts::Phonebook),
+    PhonebookStatus(bluer::Address, bluetooth::ActionStatus),
+    Messages(bluer::Address, Vec<map::Message>),
+    NewMessage(bluer::Address, map::Message),
+    Vehicle(obd::VehicleData),
+    TerminalStatus(spp::TerminalStatus),
+    TerminalLine(spp::TerminalLine),
+    GattServices(bluer::Address, Result<Vec<gatt::Service>, String>),
+    GattValue(bluer::Address, gatt::AttributeId, Vec<u8>),
+    /// A phone wants to send a file, answered with whether to accept it
+    PushRequest(opp::PushRequest, tokio::sync::oneshot::Sender<bool>),
+    ReceivedContacts(Vec<vcard::VCard>),
+    ReceivedPicture(std::path::PathBuf),
+    Pan(bluer::Address, pan::PanState),
 }
 
 enum MessageToAsync {
     BluetoothScan(bool),
+    PhoneAnswer(bluer::Address),
+    PhoneHangUp(bluer::Address),
+    PhoneDial(bluer::Address, String),
+    PhoneVoiceRecognition(bluer::Address, bool),
+    PhoneCallControl(bluer::Address, hfp::CallControl),
+    PhoneDtmf(bluer::Address, char),
+    PhoneSpeakerVolume(bluer::Address, u8),
+    PhoneMicVolume(bluer::Address, u8),
+    BluetoothDeviceAction(bluer::Address, bluetooth::DeviceAction),
+    AutoConnectPhones(Vec<bluer::Address>),
+    AdapterSettings(config::Config),
+    /// The codec a phone's call audio will use, none when its hands free connection closed
+    VoiceCodec(bluer::Address, Option<sco::Codec>),
+    VoiceSettings(config::VoiceConfig),
+    VoiceVolume(bluer::Address, sco::Volume),
+    PushSettings(config::PushConfig),
+    MediaPlay(bluer::Address),
+    MediaPause(bluer::Address),
+    MediaNext(bluer::Address),
+    MediaPrevious(bluer::Address),
+    MediaFastForward(bluer::Address),
+    MediaRewind(bluer::Address),
+    PhonebookDownload(bluer::Address),
+    MessagesConnect(bluer::Address),
+    MessagesDisconnect(bluer::Address),
+    ObdConnect(bluer::Address),
+    ObdDisconnect,
+    ObdReadCodes,
+    TerminalConnect(bluer::Address),
+    /// Text for the serial terminal to send, line ending included
+    TerminalSend(String),
+    TerminalDisconnect,
+    GattBrowse(bluer::Address),
+    GattRead(bluer::Address, gatt::AttributeId),
+    GattSubscribe(bluer::Address, gatt::AttributeId),
+    GattUnsubscribe(bluer::Address, gatt::AttributeId),
+    /// Use the data connection a phone shares over bluetooth
+    PanConnect(bluer::Address),
+    PanDisconnect(bluer::Address),
     Quit,
 }
 
@@ -37,6 +143,7 @@ impl SubwindowTrait for MainPage {
     ) -> Option<Subwindow> {
         let r = None;
         egui::CentralPanel::default().show(ctx, |ui| {
+            gallery::wallpaper(ui, common);
             ui.heading("Hello World!");
             if ui.button("quit").clicked() {
                 ui.ctx().send_viewport_cmd(egui::ViewportCommand::Close);
@@ -50,8 +157,18 @@ impl SubwindowTrait for MainPage {
 enum Subwindow {
     MainPage(MainPage),
     BluetoothConfig(bluetooth::BluetoothConfig),
+    Adapters(adapters::Adapters),
+    BluetoothDevice(bluetooth_device::BluetoothDevice),
     Video(video::Video),
     Settings(settings::Settings),
+    Phone(phone::Phone),
+    Media(media::Media),
+    Contacts(contacts::Contacts),
+    Messages(messages::Messages),
+    Vehicle(vehicle::Vehicle),
+    Terminal(terminal::Terminal),
+    Sensors(sensors::Sensors),
+    Gallery(gallery::Gallery),
 }
 
 impl Default for Subwindow {
@@ -82,8 +199,10 @@ fn main() {
 }
 
 struct CommonWindowProperties {
+    config: config::Config,
     bluetooth: bluetooth::BluetoothData,
     video_sources: Vec<video::VideoSource>,
+    video_events: std::sync::mpsc::Receiver<video::Hotplug>,
     rx: tokio::sync::mpsc::Receiver<MessageFromAsync>,
     tx: tokio::sync::mpsc::Sender<MessageToAsync>,
 }
@@ -93,13 +212,26 @@ impl CommonWindowProperties {
         rx: tokio::sync::mpsc::Receiver<MessageFromAsync>,
         tx: tokio::sync::mpsc::Sender<MessageToAsync>,
     ) -> Self {
-        let mut vs = Vec::new();
-        if let Ok(d) = v4l::Device::new(0) {
-            vs.push(video::Video::video_start(d));
-        }
+        // Watch before listing so that no camera plugged in meanwhile is missed
+        let video_events = video::watch_devices();
+        let vs = video::video_devices()
+            .into_iter()
+            .filter_map(|p| match video::Video::video_start(&p) {
+                Ok(v) => {
+                    println!("Found camera {} at {}", v.label(), p.display());
+                    Some(v)
+                }
+                Err(e) => {
+                    println!("Not using {} as a camera: {}", p.display(), e);
+                    None
+                }
+            })
+            .collect();
         Self {
+            config: config::Config::load(),
             bluetooth: bluetooth::BluetoothData::new(),
             video_sources: vs,
+            video_events,
             rx,
             tx,
         }
@@ -116,6 +248,7 @@ async fn async_main(
     tx: tokio::sync::mpsc::Sender<MessageFromAsync>,
     mut rx: tokio::sync::mpsc::Receiver<MessageToAsync>,
 ) {
+    tokio::spawn(a2dp::a2dp_sink(tx.clone()));
     bluetooth::bluetooth(tx, &mut rx).await;
 }
 
@@ -149,13 +282,15 @@ impl eframe::App for MyEguiApp {
         while let Ok(m) = self.common.rx.try_recv() {
             match m {
                 MessageFromAsync::NewBluetoothDevice(addr) => {
+                    // Discovery reports known devices again whenever they change
                     self.common
                         .bluetooth
                         .devices
-                        .insert(addr, bluetooth::BluetoothDeviceInfo::new());
+                        .entry(addr)
+                        .or_insert_with(bluetooth::BluetoothDeviceInfo::new);
                 }
                 MessageFromAsync::OldBluetoothDevice(addr) => {
-                    //self.common.bluetooth_devices.remove_entry(&addr);
+                    self.common.bluetooth.devices.remove(&addr);
                 }
                 MessageFromAsync::BluetoothDeviceProperty(addr, prop) => {
                     println!("Received bluetooth device property: {:?}: {:?}", addr, prop);
@@ -163,17 +298,187 @@ impl eframe::App for MyEguiApp {
                         d.update(prop);
                     }
                 }
-                MessageFromAsync::BluetoothPresent(p) => {
-                    println!("Bluetooth presence: {}", p);
+                MessageFromAsync::BluetoothStatus(s) => {
+                    println!("Bluetooth status: {:?}", s);
+                    match &s {
+                        bluetooth::BluetoothStatus::Ready(_) => {}
+                        bluetooth::BluetoothStatus::Unavailable(_) => {
+                            self.common.bluetooth.scanning = false;
+                            self.common.bluetooth.last_error = None;
+                            self.common.bluetooth.devices.clear();
+                            self.common.bluetooth.adapters.clear();
+                            self.common.bluetooth.phones.clear();
+                            self.common.bluetooth.call_start.clear();
+                        }
+                        _ => {
+                            self.common.bluetooth.scanning = false;
+                            self.common.bluetooth.last_error = None;
+                        }
+                    }
+                    self.common.bluetooth.status = s;
+                }
+                MessageFromAsync::Adapters(infos, active) => {
+                    self.common.bluetooth.adapters = infos;
+                    self.common.bluetooth.active_adapter = active;
+                }
+                MessageFromAsync::BluetoothError(e) => {
+                    println!("Bluetooth error: {}", e);
+                    self.common.bluetooth.last_error = Some(e);
+                }
+                MessageFromAsync::BluetoothDeviceAction(addr, action, status) => {
+                    if let (bluetooth::DeviceAction::Forget, bluetooth::ActionStatus::Done) =
+                        (action, &status)
+                    {
+                        self.common.bluetooth.devices.remove(&addr);
+                    }
+                    self.common.bluetooth.actions.insert(addr, (action, status));
+                }
+                MessageFromAsync::PhoneLink(l) => {
+                    self.common.bluetooth.link = l;
+                }
+                MessageFromAsync::HfpConnected(addr) => {
+                    println!("Hands free connected to {}", addr);
+                    self.common
+                        .bluetooth
+                        .phones
+                        .insert(addr, hfp::HfpState::default());
+                    if !self.common.bluetooth.phonebooks.contains_key(&addr) {
+                        if let Some(book) = contacts::load_cache(addr) {
+                            self.common.bluetooth.phonebooks.insert(addr, book);
+                        }
+                    }
+                    let _ = self
+                        .common
+                        .tx
+                        .blocking_send(MessageToAsync::PhonebookDownload(addr));
+                    let _ = self
+                        .common
+                        .tx
+                        .blocking_send(MessageToAsync::MessagesConnect(addr));
+                }
+                MessageFromAsync::HfpDisconnected(addr) => {
+                    println!("Hands free disconnected from {}", addr);
+                    self.common.bluetooth.remove_phone(&addr);
+                    let _ = self
+                        .common
+                        .tx
+                        .blocking_send(MessageToAsync::MessagesDisconnect(addr));
+                }
+                MessageFromAsync::HfpState(addr, state) => {
+                    self.common.bluetooth.update_phone(addr, state);
+                }
+                MessageFromAsync::Voice(addr, codec) => match codec {
+                    Some(c) => {
+                        self.common.bluetooth.voice.insert(addr, c);
+                    }
+                    None => {
+                        self.common.bluetooth.voice.remove(&addr);
+                    }
+                },
+                MessageFromAsync::PairingRequest(req, s) => {
+                    self.common.bluetooth.pairing.add(req, s);
+                }
+                MessageFromAsync::AudioStream(s) => {
+                    self.common.bluetooth.streaming = s;
+                }
+                MessageFromAsync::MediaPlayer(addr, state) => {
+                    self.common.bluetooth.players.insert(addr, state);
+                }
+                MessageFromAsync::MediaPlayerGone(addr) => {
+                    self.common.bluetooth.players.remove(&addr);
+                }
+                MessageFromAsync::Phonebook(addr, book) => {
+                    self.common.bluetooth.phonebooks.insert(addr, book);
+                }
+                MessageFromAsync::PhonebookStatus(addr, status) => {
+                    self.common.bluetooth.phonebook_status.insert(addr, status);
+                }
+                MessageFromAsync::Messages(addr, list) => {
+                    self.common.bluetooth.messages.insert(addr, list);
+                }
+                MessageFromAsync::NewMessage(addr, message) => {
+                    self.common
+                        .bluetooth
+                        .messages
+                        .entry(addr)
+                        .or_default()
+                        .insert(0, message.clone());
+                    self.common.bluetooth.banner = Some(messages::Banner {
+                        addr,
+                        message,
+                        shown: std::time::Instant::now(),
+                    });
+                }
+                MessageFromAsync::Vehicle(data) => {
+                    self.common.bluetooth.vehicle = data;
+                }
+                MessageFromAsync::TerminalStatus(s) => {
+                    if let spp::TerminalStatus::Connecting(_) = s {
+                        self.common.bluetooth.terminal.lines.clear();
+                    }
+                    self.common.bluetooth.terminal.status = s;
+                }
+                MessageFromAsync::TerminalLine(l) => {
+                    self.common.bluetooth.terminal.add(l);
+                }
+                MessageFromAsync::GattServices(addr, r) => {
+                    let g = self
+                        .common
+                        .bluetooth
+                        .gatt
+                        .entry(addr)
+                        .or_insert_with(gatt::GattState::new);
+                    match r {
+                        Ok(s) => {
+                            g.services = s;
+                            g.status = bluetooth::ActionStatus::Done;
+                        }
+                        Err(e) => g.status = bluetooth::ActionStatus::Failed(e),
+                    }
+                }
+                MessageFromAsync::GattValue(addr, id, v) => {
+                    if let Some(g) = self.common.bluetooth.gatt.get_mut(&addr) {
+                        g.values.insert(id, v);
+                    }
+                }
+                MessageFromAsync::PushRequest(req, s) => {
+                    self.common.bluetooth.pushes.push((req, s));
+                }
+                MessageFromAsync::ReceivedContacts(cards) => {
+                    let received = &mut self.common.bluetooth.received_contacts;
+                    received.extend(cards);
+                    received.sort_by_key(|c| c.sort_key());
+                }
+                MessageFromAsync::ReceivedPicture(p) => {
+                    self.common.bluetooth.pictures.insert(0, p);
+                }
+                MessageFromAsync::Pan(addr, state) => {
+                    if state == pan::PanState::default() {
+                        self.common.bluetooth.pan.remove(&addr);
+                    } else {
+                        self.common.bluetooth.pan.insert(addr, state);
+                    }
                 }
             }
         }
+        video::hotplug(&mut self.common);
+        let voice_key = self
+            .common
+            .config
+            .voice_assistant_key
+            .as_deref()
+            .and_then(egui::Key::from_name);
+        if let Some(k) = voice_key {
+            if ctx.input(|i| i.key_pressed(k)) {
+                phone::voice_assistant(&self.common);
+            }
+        }
         egui::TopBottomPanel::bottom("Bottom Icons")
             .min_height(74.0)
             .max_height(74.0)
             .show(ctx, |ui| {
                 ui.horizontal(|ui| {
-                    if !self.common.video_sources.is_empty() {
+                    if let Some(v) = self.common.video_sources.first() {
                         if ui
                             .button(
                                 eframe::egui::RichText::new("V")
@@ -181,7 +486,7 @@ impl eframe::App for MyEguiApp {
                             )
                             .clicked()
                         {
-                            self.subwindow = Subwindow::Video(video::Video::new());
+                            self.subwindow = Subwindow::Video(video::Video::new(v.path.clone()));
                         }
                     }
                     if ui
@@ -194,6 +499,61 @@ impl eframe::App for MyEguiApp {
                         self.subwindow =
                             Subwindow::BluetoothConfig(bluetooth::BluetoothConfig::new());
                     }
+                    if ui
+                        .button(
+                            eframe::egui::RichText::new("P")
+                                .font(eframe::egui::FontId::proportional(64.0)),
+                        )
+                        .clicked()
+                    {
+                        self.subwindow = Subwindow::Phone(phone::Phone::new());
+                    }
+                    if ui
+                        .button(
+                            eframe::egui::RichText::new("M")
+                                .font(eframe::egui::FontId::proportional(64.0)),
+                        )
+                        .clicked()
+                    {
+                        self.subwindow = Subwindow::Media(media::Media::new());
+                    }
+                    let unread = messages::unread(&self.common);
+                    let text = if unread > 0 {
+                        format!("T {}", unread)
+                    } else {
+                        "T".to_string()
+                    };
+                    if ui
+                        .button(
+                            eframe::egui::RichText::new(text)
+                                .font(eframe::egui::FontId::proportional(64.0)),
+                        )
+                        .clicked()
+                    {
+                        self.subwindow = Subwindow::Messages(messages::Messages::new());
+                    }
+                    if self.common.bluetooth.vehicle.status != obd::ObdStatus::Disconnected {
+                        if ui
+                            .button(
+                                eframe::egui::RichText::new("O")
+                                    .font(eframe::egui::FontId::proportional(64.0)),
+                            )
+                            .clicked()
+                        {
+                            self.subwindow = Subwindow::Vehicle(vehicle::Vehicle::new());
+                        }
+                    }
+                    if !self.common.bluetooth.pictures.is_empty() {
+                        if ui
+                            .button(
+                                eframe::egui::RichText::new("G")
+                                    .font(eframe::egui::FontId::proportional(64.0)),
+                            )
+                            .clicked()
+                        {
+                            self.subwindow = Subwindow::Gallery(gallery::Gallery::new());
+                        }
+                    }
                     if ui
                         .add(
                             egui::Image::new(egui::include_image!("../refresh.png"))
@@ -215,6 +575,21 @@ impl eframe::App for MyEguiApp {
                     {
                         self.subwindow = Subwindow::Settings(settings::Settings::new());
                     }
+                    match &self.common.bluetooth.link {
+                        autoconnect::PhoneLink::Idle => {}
+                        autoconnect::PhoneLink::Connecting(a) => {
+                            ui.label(format!(
+                                "Connecting to {}",
+                                self.common.bluetooth.phone_name(a)
+                            ));
+                        }
+                        autoconnect::PhoneLink::Connected(_) => {}
+                        autoconnect::PhoneLink::Waiting(_) => {
+                            ui.label("No phone");
+                        }
+                    }
+                    phone::status_bar(ui, &self.common);
+                    media::now_playing(ui, &self.common);
                     ui.label(format!("Focus: {:?}", ui.input(|r| r.viewport().focused)));
                     if self.check {
                         ui.label("LABEL");
@@ -228,5 +603,11 @@ impl eframe::App for MyEguiApp {
         if let Some(sub) = self.subwindow.update(ctx, frame, &mut self.common) {
             self.subwindow = sub;
         }
+        if let Some(sub) = messages::banner(ctx, &mut self.common) {
+            self.subwindow = sub;
+        }
+        phone::incoming_call(ctx, &mut self.common);
+        pairing::pairing_dialog(ctx, &mut self.common);
+        opp::push_dialog(ctx, &mut self.common);
     }
 }
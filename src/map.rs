@@ -0,0 +1,250 @@
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use dbus::arg::{PropMap, RefArg, Variant};
+use dbus::message::MatchRule;
+use dbus::nonblock::SyncConnection;
+use futures::StreamExt;
+
+use crate::bluez::RETRY_DELAY;
+use crate::bmessage;
+use crate::obex::{self, ObexError, OBEX_SERVICE, OBEX_TIMEOUT};
+use crate::MessageFromAsync;
+use crate::MessageToAsync;
+
+const MESSAGE_IFACE: &str = "org.bluez.obex.Message1";
+
+const ACCESS_IFACE: &str = "org.bluez.obex.MessageAccess1";
+
+/// How many messages of the inbox to list when a phone connects
+const LIST_COUNT: u16 = 20;
+
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A text message on a phone
+#[derive(Clone, Debug)]
+pub struct Message {
+    pub sender: String,
+    pub number: Option<String>,
+    /// The time of the message as sent by the phone, like 20240102T101500
+    pub time: Option<String>,
+    pub text: String,
+    pub read: bool,
+}
+
+impl Message {
+    /// Make a message from the properties of a listed message object
+    fn from_listing(props: &PropMap) -> Self {
+        let get = |k: &str| dbus::arg::prop_cast::<String>(props, k).cloned();
+        let number = get("SenderAddress").filter(|n| !n.is_empty());
+        Self {
+            sender: get("Sender")
+                .filter(|s| !s.is_empty())
+                .or_else(|| number.clone())
+                .unwrap_or_else(|| "Unknown".to_string()),
+            number,
+            time: get("Timestamp"),
+            text: get("Subject").unwrap_or_default(),
+            read: dbus::arg::prop_cast::<bool>(props, "Read")
+                .copied()
+                .unwrap_or(false),
+        }
+    }
+}
+
+/// A connection to the message access server of a phone
+struct Session {
+    path: dbus::Path<'static>,
+    /// Message objects that are already known, so they are not reported as new
+    known: HashSet<dbus::Path<'static>>,
+}
+
+fn list_filter() -> PropMap {
+    let mut filter: PropMap = HashMap::new();
+    filter.insert(
+        "MaxCount".to_string(),
+        Variant(Box::new(LIST_COUNT) as Box<dyn RefArg>),
+    );
+    filter
+}
+
+/// Turn the result of ListMessages into messages, newest first
+fn listed_messages(
+    list: HashMap<dbus::Path<'static>, PropMap>,
+) -> Vec<(dbus::Path<'static>, Message)> {
+    let mut list: Vec<(dbus::Path<'static>, Message)> = list
+        .into_iter()
+        .map(|(p, props)| (p, Message::from_listing(&props)))
+        .collect();
+    list.sort_by(|a, b| b.1.time.cmp(&a.1.time));
+    list
+}
+
+/// Read a message object added to a session from an InterfacesAdded signal
+fn added_message(msg: &dbus::Message) -> Option<(dbus::Path<'static>, Option<String>)> {
+    let (path, ifaces): (dbus::Path<'static>, HashMap<String, PropMap>) = msg.read2().ok()?;
+    let props = ifaces.get(MESSAGE_IFACE)?;
+    let folder = dbus::arg::prop_cast::<String>(props, "Folder").cloned();
+    Some((path, folder))
+}
+
+/// Open a session to a phone and list its inbox
+async fn connect(
+    conn: &std::sync::Arc<SyncConnection>,
+    addr: bluer::Address,
+) -> Result<(Session, Vec<Message>), ObexError> {
+    let client =
+        dbus::nonblock::Proxy::new(OBEX_SERVICE, "/org/bluez/obex", OBEX_TIMEOUT, conn.clone());
+    let (path,): (dbus::Path<'static>,) = client
+        .method_call(
+            "org.bluez.obex.Client1",
+            "CreateSession",
+            (addr.to_string(), obex::session_args("map")),
+        )
+        .await?;
+    let proxy = dbus::nonblock::Proxy::new(OBEX_SERVICE, path.clone(), OBEX_TIMEOUT, conn.clone());
+    proxy
+        .method_call::<(), _, _, _>(ACCESS_IFACE, "SetFolder", ("telecom/msg",))
+        .await?;
+    let (list,): (HashMap<dbus::Path<'static>, PropMap>,) = proxy
+        .method_call(ACCESS_IFACE, "ListMessages", ("inbox", list_filter()))
+        .await?;
+    let list = listed_messages(list);
+    let session = Session {
+        path,
+        known: list.iter().map(|(p, _)| p.clone()).collect(),
+    };
+    Ok((session, list.into_iter().map(|(_, m)| m).collect()))
+}
+
+/// Download a new message and read who sent it
+async fn fetch(
+    conn: &std::sync::Arc<SyncConnection>,
+    changes: &mut futures::channel::mpsc::UnboundedReceiver<dbus::Message>,
+    addr: bluer::Address,
+    path: &dbus::Path<'static>,
+) -> Result<Message, ObexError> {
+    let proxy = dbus::nonblock::Proxy::new(OBEX_SERVICE, path.clone(), OBEX_TIMEOUT, conn.clone());
+    let target = obex::temp_file(addr, "message.bmsg");
+    let (transfer, _): (dbus::Path<'static>, PropMap) = proxy
+        .method_call(
+            MESSAGE_IFACE,
+            "Get",
+            (target.to_string_lossy().into_owned(), false),
+        )
+        .await?;
+    let text = obex::finish_transfer(changes, &transfer, &target, TRANSFER_TIMEOUT).await?;
+    let m = bmessage::parse(&text).ok_or(ObexError::Transfer("Not a bMessage".to_string()))?;
+    let from = m.originator.unwrap_or_default();
+    Ok(Message {
+        sender: from.display_name().to_string(),
+        number: from.numbers.first().map(|n| n.number.clone()),
+        time: None,
+        text: m.body,
+        read: m.read,
+    })
+}
+
+/// Follow the messages of connected phones until the connection to dbus is lost
+async fn map_session(
+    tx: &tokio::sync::mpsc::Sender<MessageFromAsync>,
+    rx: &mut tokio::sync::mpsc::Receiver<MessageToAsync>,
+) -> Result<(), dbus::Error> {
+    let (resource, conn) = dbus_tokio::connection::new_session_sync()?;
+    let mut lost = tokio::spawn(resource);
+
+    let (_added_match, mut added) = conn
+        .add_match(MatchRule::new_signal(
+            "org.freedesktop.DBus.ObjectManager",
+            "InterfacesAdded",
+        ))
+        .await?
+        .msg_stream();
+    let (_changes_match, mut changes) = conn
+        .add_match(MatchRule::new_signal(
+            "org.freedesktop.DBus.Properties",
+            "PropertiesChanged",
+        ))
+        .await?
+        .msg_stream();
+
+    let mut sessions: HashMap<bluer::Address, Session> = HashMap::new();
+    let r = loop {
+        tokio::select! {
+            m = rx.recv() => match m {
+                None => break Ok(()),
+                Some(MessageToAsync::MessagesConnect(addr)) => {
+                    if sessions.contains_key(&addr) {
+                        continue;
+                    }
+                    match connect(&conn, addr).await {
+                        Ok((session, list)) => {
+                            println!("Message access connected to {}", addr);
+                            sessions.insert(addr, session);
+                            let _ = tx.send(MessageFromAsync::Messages(addr, list)).await;
+                        }
+                        Err(e) => println!("Message access to {} failed {}", addr, e),
+                    }
+                }
+                Some(MessageToAsync::MessagesDisconnect(addr)) => {
+                    if let Some(s) = sessions.remove(&addr) {
+                        let client = dbus::nonblock::Proxy::new(
+                            OBEX_SERVICE,
+                            "/org/bluez/obex",
+                            OBEX_TIMEOUT,
+                            conn.clone(),
+                        );
+                        let _: Result<(), dbus::Error> = client
+                            .method_call("org.bluez.obex.Client1", "RemoveSession", (s.path,))
+                            .await;
+                    }
+                }
+                Some(_) => {}
+            },
+            Some(msg) = added.next() => {
+                let Some((path, folder)) = added_message(&msg) else {
+                    continue;
+                };
+                if folder.is_some_and(|f| !f.ends_with("inbox")) {
+                    continue;
+                }
+                let Some((addr, s)) = sessions
+                    .iter_mut()
+                    .find(|(_, s)| path.starts_with(&format!("{}/", s.path)))
+                else {
+                    continue;
+                };
+                if !s.known.insert(path.clone()) {
+                    continue;
+                }
+                let addr = *addr;
+                match fetch(&conn, &mut changes, addr, &path).await {
+                    Ok(m) => {
+                        println!("New message from {}", m.sender);
+                        let _ = tx.send(MessageFromAsync::NewMessage(addr, m)).await;
+                    }
+                    Err(e) => println!("Fetching message {} failed {}", path, e),
+                }
+            }
+            _ = &mut lost => {
+                break Err(dbus::Error::new_failed("Connection to dbus lost"));
+            }
+        }
+    };
+    lost.abort();
+    r
+}
+
+/// Report new text messages on connected phones to the gui
+pub async fn messages(
+    tx: tokio::sync::mpsc::Sender<MessageFromAsync>,
+    mut rx: tokio::sync::mpsc::Receiver<MessageToAsync>,
+) {
+    loop {
+        match map_session(&tx, &mut rx).await {
+            Ok(()) => return,
+            Err(e) => println!("Message access unavailable {}", e),
+        }
+        tokio::time::sleep(RETRY_DELAY).await;
+    }
+}
@@ -0,0 +1,559 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use dbus::arg::{PropMap, RefArg};
+use dbus::message::MatchRule;
+use dbus::nonblock::stdintf::org_freedesktop_dbus::ObjectManager;
+use dbus::nonblock::SyncConnection;
+use futures::StreamExt;
+
+use super::CommonWindowProperties;
+use super::MessageToAsync;
+use super::Subwindow;
+use super::SubwindowTrait;
+use crate::bluez::{self, DBUS_TIMEOUT, RETRY_DELAY};
+use crate::phone::{big_button, format_duration};
+use crate::MessageFromAsync;
+use eframe::egui;
+
+const PLAYER_IFACE: &str = "org.bluez.MediaPlayer1";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PlaybackStatus {
+    Playing,
+    #[default]
+    Stopped,
+    Paused,
+    ForwardSeek,
+    ReverseSeek,
+    Error,
+}
+
+impl PlaybackStatus {
+    fn parse(s: &str) -> Self {
+        match s {
+            "playing" => PlaybackStatus::Playing,
+            "paused" => PlaybackStatus::Paused,
+            "forward-seek" => PlaybackStatus::ForwardSeek,
+            "reverse-seek" => PlaybackStatus::ReverseSeek,
+            "error" => PlaybackStatus::Error,
+            _ => PlaybackStatus::Stopped,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Track {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub number: Option<u32>,
+    pub tracks: Option<u32>,
+    pub duration: Option<Duration>,
+}
+
+impl Track {
+    /// Read the track dictionary of a media player
+    fn parse(v: &dyn RefArg) -> Self {
+        let mut t = Self::default();
+        let Some(mut it) = v.as_iter() else {
+            return t;
+        };
+        while let (Some(k), Some(val)) = (it.next(), it.next()) {
+            match k.as_str().unwrap_or_default() {
+                "Title" => t.title = val.as_str().map(|s| s.to_string()),
+                "Artist" => t.artist = val.as_str().map(|s| s.to_string()),
+                "Album" => t.album = val.as_str().map(|s| s.to_string()),
+                "TrackNumber" => t.number = val.as_u64().map(|n| n as u32),
+                "NumberOfTracks" => t.tracks = val.as_u64().map(|n| n as u32),
+                "Duration" => t.duration = val.as_u64().map(Duration::from_millis),
+                _ => {}
+            }
+        }
+        t
+    }
+}
+
+/// The now playing information of a phone
+#[derive(Clone, Debug)]
+pub struct MediaState {
+    pub name: Option<String>,
+    pub status: PlaybackStatus,
+    pub position: Duration,
+    /// When the position was reported
+    pub updated: Instant,
+    pub track: Track,
+}
+
+impl MediaState {
+    fn new() -> Self {
+        Self {
+            name: None,
+            status: PlaybackStatus::Stopped,
+            position: Duration::ZERO,
+            updated: Instant::now(),
+            track: Track::default(),
+        }
+    }
+
+    fn update(&mut self, props: &PropMap) {
+        if let Some(n) = dbus::arg::prop_cast::<String>(props, "Name") {
+            self.name = Some(n.clone());
+        }
+        if let Some(s) = dbus::arg::prop_cast::<String>(props, "Status") {
+            self.position = self.position();
+            self.updated = Instant::now();
+            self.status = PlaybackStatus::parse(s);
+        }
+        if let Some(p) = props.get("Position").and_then(|v| v.0.as_u64()) {
+            self.position = Duration::from_millis(p);
+            self.updated = Instant::now();
+        }
+        if let Some(t) = props.get("Track") {
+            self.track = Track::parse(&*t.0);
+        }
+    }
+
+    /// The current position in the track, counting time since the phone last reported it
+    pub fn position(&self) -> Duration {
+        if self.status != PlaybackStatus::Playing {
+            return self.position;
+        }
+        let p = self.position + self.updated.elapsed();
+        match self.track.duration {
+            Some(d) => p.min(d),
+            None => p,
+        }
+    }
+}
+
+/// A media player exported by bluez for a connected phone
+struct Player {
+    path: dbus::Path<'static>,
+    state: MediaState,
+}
+
+/// Read a newly exported media player from an InterfacesAdded signal
+fn added_player(msg: &dbus::Message) -> Option<(dbus::Path<'static>, MediaState)> {
+    let (path, ifaces): (dbus::Path<'static>, HashMap<String, PropMap>) = msg.read2().ok()?;
+    let mut state = MediaState::new();
+    state.update(ifaces.get(PLAYER_IFACE)?);
+    Some((path, state))
+}
+
+/// Read the path of a removed media player from an InterfacesRemoved signal
+fn removed_player(msg: &dbus::Message) -> Option<dbus::Path<'static>> {
+    let (path, ifaces): (dbus::Path<'static>, Vec<String>) = msg.read2().ok()?;
+    ifaces.iter().any(|i| i == PLAYER_IFACE).then_some(path)
+}
+
+/// Apply a PropertiesChanged signal to the player it is for
+fn player_changed(
+    msg: &dbus::Message,
+    players: &mut HashMap<bluer::Address, Player>,
+) -> Option<(bluer::Address, MediaState)> {
+    let (iface, props, _): (String, PropMap, Vec<String>) = msg.read3().ok()?;
+    if iface != PLAYER_IFACE {
+        return None;
+    }
+    let path = msg.path()?;
+    let (a, p) = players.iter_mut().find(|(_, p)| p.path == path)?;
+    p.state.update(&props);
+    Some((*a, p.state.clone()))
+}
+
+async fn add_player(
+    players: &mut HashMap<bluer::Address, Player>,
+    path: dbus::Path<'static>,
+    state: MediaState,
+    tx: &tokio::sync::mpsc::Sender<MessageFromAsync>,
+) {
+    let Some(addr) = bluez::path_address(&path) else {
+        return;
+    };
+    println!("Media player {} for {}", path, addr);
+    let _ = tx
+        .send(MessageFromAsync::MediaPlayer(addr, state.clone()))
+        .await;
+    players.insert(addr, Player { path, state });
+}
+
+/// Send a transport command to the player of a phone
+async fn command(
+    conn: &std::sync::Arc<SyncConnection>,
+    players: &HashMap<bluer::Address, Player>,
+    m: MessageToAsync,
+) {
+    let (addr, method) = match m {
+        MessageToAsync::MediaPlay(a) => (a, "Play"),
+        MessageToAsync::MediaPause(a) => (a, "Pause"),
+        MessageToAsync::MediaNext(a) => (a, "Next"),
+        MessageToAsync::MediaPrevious(a) => (a, "Previous"),
+        MessageToAsync::MediaFastForward(a) => (a, "FastForward"),
+        MessageToAsync::MediaRewind(a) => (a, "Rewind"),
+        _ => return,
+    };
+    let Some(p) = players.get(&addr) else {
+        return;
+    };
+    let proxy = dbus::nonblock::Proxy::new("org.bluez", p.path.clone(), DBUS_TIMEOUT, conn.clone());
+    let r: Result<(), dbus::Error> = proxy.method_call(PLAYER_IFACE, method, ()).await;
+    if let Err(e) = r {
+        println!("Media {} on {} failed {}", method, addr, e);
+    }
+}
+
+/// Follow the media players of connected phones until the connection to dbus is lost
+async fn media_session(
+    tx: &tokio::sync::mpsc::Sender<MessageFromAsync>,
+    rx: &mut tokio::sync::mpsc::Receiver<MessageToAsync>,
+) -> Result<(), dbus::Error> {
+    let (resource, conn) = dbus_tokio::connection::new_system_sync()?;
+    let mut lost = tokio::spawn(resource);
+
+    let (_added_match, mut added) = conn
+        .add_match(MatchRule::new_signal(
+            "org.freedesktop.DBus.ObjectManager",
+            "InterfacesAdded",
+        ))
+        .await?
+        .msg_stream();
+    let (_removed_match, mut removed) = conn
+        .add_match(MatchRule::new_signal(
+            "org.freedesktop.DBus.ObjectManager",
+            "InterfacesRemoved",
+        ))
+        .await?
+        .msg_stream();
+    let (_changes_match, mut changes) = conn
+        .add_match(MatchRule::new_signal(
+            "org.freedesktop.DBus.Properties",
+            "PropertiesChanged",
+        ))
+        .await?
+        .msg_stream();
+
+    let mut players: HashMap<bluer::Address, Player> = HashMap::new();
+    let root = dbus::nonblock::Proxy::new("org.bluez", "/", DBUS_TIMEOUT, conn.clone());
+    let found: Vec<(dbus::Path<'static>, MediaState)> = root
+        .get_managed_objects()
+        .await?
+        .into_iter()
+        .filter_map(|(path, ifaces)| {
+            let mut state = MediaState::new();
+            state.update(ifaces.get(PLAYER_IFACE)?);
+            Some((path, state))
+        })
+        .collect();
+    for (path, state) in found {
+        add_player(&mut players, path, state, tx).await;
+    }
+
+    let r = loop {
+        tokio::select! {
+            m = rx.recv() => {
+                let Some(m) = m else {
+                    break Ok(());
+                };
+                command(&conn, &players, m).await;
+            }
+            Some(msg) = added.next() => {
+                if let Some((path, state)) = added_player(&msg) {
+                    add_player(&mut players, path, state, tx).await;
+                }
+            }
+            Some(msg) = removed.next() => {
+                let Some(path) = removed_player(&msg) else {
+                    continue;
+                };
+                let gone: Vec<bluer::Address> = players
+                    .iter()
+                    .filter(|(_, p)| p.path == path)
+                    .map(|(a, _)| *a)
+                    .collect();
+                for a in gone {
+                    players.remove(&a);
+                    let _ = tx.send(MessageFromAsync::MediaPlayerGone(a)).await;
+                }
+            }
+            Some(msg) = changes.next() => {
+                if let Some((a, state)) = player_changed(&msg, &mut players) {
+                    let _ = tx.send(MessageFromAsync::MediaPlayer(a, state)).await;
+                }
+            }
+            _ = &mut lost => {
+                break Err(dbus::Error::new_failed("Connection to dbus lost"));
+            }
+        }
+    };
+    lost.abort();
+    for a in players.keys() {
+        let _ = tx.send(MessageFromAsync::MediaPlayerGone(*a)).await;
+    }
+    r
+}
+
+/// Pass now playing changes to the gui and media commands to the phones
+pub async fn media_players(
+    tx: tokio::sync::mpsc::Sender<MessageFromAsync>,
+    mut rx: tokio::sync::mpsc::Receiver<MessageToAsync>,
+) {
+    loop {
+        match media_session(&tx, &mut rx).await {
+            Ok(()) => return,
+            Err(e) => println!("Media players unavailable {}", e),
+        }
+        tokio::time::sleep(RETRY_DELAY).await;
+    }
+}
+
+/// A short now playing line for the bottom bar
+pub fn now_playing(ui: &mut egui::Ui, common: &CommonWindowProperties) {
+    let Some((addr, state)) = common
+        .bluetooth
+        .players
+        .iter()
+        .find(|(_, s)| s.status == PlaybackStatus::Playing)
+        .or_else(|| common.bluetooth.players.iter().next())
+    else {
+        return;
+    };
+    let title = state.track.title.as_deref().unwrap_or("Unknown track");
+    match &state.track.artist {
+        Some(a) => ui.label(format!("{} - {}", title, a)),
+        None => ui.label(title),
+    };
+    let (text, m) = if state.status == PlaybackStatus::Playing {
+        ("Pause", MessageToAsync::MediaPause(*addr))
+    } else {
+        ("Play", MessageToAsync::MediaPlay(*addr))
+    };
+    if ui.button(text).clicked() {
+        let _ = common.tx.blocking_send(m);
+    }
+}
+
+pub struct Media {
+    selected: Option<bluer::Address>,
+    /// The seek button being held down, true for forward
+    seeking: Option<bool>,
+}
+
+impl Media {
+    pub fn new() -> Self {
+        Self {
+            selected: None,
+            seeking: None,
+        }
+    }
+
+    /// A button that seeks for as long as it is held down
+    fn seek_button(
+        &mut self,
+        ui: &mut egui::Ui,
+        common: &CommonWindowProperties,
+        addr: bluer::Address,
+        forward: bool,
+    ) {
+        let text = if forward { ">>" } else { "<<" };
+        let held = ui
+            .add_sized(
+                [96.0, 72.0],
+                egui::Button::new(egui::RichText::new(text).font(egui::FontId::proportional(48.0))),
+            )
+            .is_pointer_button_down_on();
+        if held && self.seeking.is_none() {
+            self.seeking = Some(forward);
+            let m = if forward {
+                MessageToAsync::MediaFastForward(addr)
+            } else {
+                MessageToAsync::MediaRewind(addr)
+            };
+            let _ = common.tx.blocking_send(m);
+        } else if !held && self.seeking == Some(forward) {
+            self.seeking = None;
+            let _ = common.tx.blocking_send(MessageToAsync::MediaPlay(addr));
+        }
+    }
+}
+
+impl SubwindowTrait for Media {
+    fn update(
+        &mut self,
+        ctx: &egui::Context,
+        frame: &mut eframe::Frame,
+        common: &mut CommonWindowProperties,
+    ) -> Option<Subwindow> {
+        egui::CentralPanel::default().show(ctx, |ui| {
+            if self
+                .selected
+                .map(|a| !common.bluetooth.players.contains_key(&a))
+                .unwrap_or(true)
+            {
+                self.selected = common.bluetooth.players.keys().next().copied();
+            }
+            let Some(addr) = self.selected else {
+                ui.heading("No media player connected");
+                return;
+            };
+            if common.bluetooth.players.len() > 1 {
+                egui::ComboBox::from_label("Phone")
+                    .selected_text(common.bluetooth.phone_name(&addr))
+                    .show_ui(ui, |ui| {
+                        for a in common.bluetooth.players.keys() {
+                            ui.selectable_value(
+                                &mut self.selected,
+                                Some(*a),
+                                common.bluetooth.phone_name(a),
+                            );
+                        }
+                    });
+            } else {
+                ui.label(common.bluetooth.phone_name(&addr));
+            }
+            let Some(state) = common.bluetooth.players.get(&addr).cloned() else {
+                return;
+            };
+            if let Some(n) = &state.name {
+                ui.label(n);
+            }
+            ui.heading(state.track.title.as_deref().unwrap_or("Unknown track"));
+            if let Some(a) = &state.track.artist {
+                ui.label(egui::RichText::new(a).font(egui::FontId::proportional(32.0)));
+            }
+            if let Some(a) = &state.track.album {
+                ui.label(a);
+            }
+            if let (Some(n), Some(t)) = (state.track.number, state.track.tracks) {
+                ui.label(format!("Track {} of {}", n, t));
+            }
+            let position = state.position();
+            match state.track.duration {
+                Some(d) if !d.is_zero() => {
+                    ui.add(
+                        egui::ProgressBar::new(position.as_secs_f32() / d.as_secs_f32()).text(
+                            format!("{} / {}", format_duration(position), format_duration(d)),
+                        ),
+                    );
+                }
+                _ => {
+                    ui.label(format_duration(position));
+                }
+            }
+            ui.label(format!("{:?}", state.status));
+            ui.horizontal(|ui| {
+                if big_button(ui, "|<") {
+                    let _ = common.tx.blocking_send(MessageToAsync::MediaPrevious(addr));
+                }
+                self.seek_button(ui, common, addr, false);
+                if state.status == PlaybackStatus::Playing {
+                    if big_button(ui, "Pause") {
+                        let _ = common.tx.blocking_send(MessageToAsync::MediaPause(addr));
+                    }
+                } else if big_button(ui, "Play") {
+                    let _ = common.tx.blocking_send(MessageToAsync::MediaPlay(addr));
+                }
+                self.seek_button(ui, common, addr, true);
+                if big_button(ui, ">|") {
+                    let _ = common.tx.blocking_send(MessageToAsync::MediaNext(addr));
+                }
+            });
+        });
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dbus::arg::Variant;
+
+    fn insert(props: &mut PropMap, name: &str, value: impl RefArg + 'static) {
+        props.insert(name.to_string(), Variant(Box::new(value)));
+    }
+
+    #[test]
+    fn status() {
+        assert_eq!(PlaybackStatus::parse("playing"), PlaybackStatus::Playing);
+        assert_eq!(PlaybackStatus::parse("paused"), PlaybackStatus::Paused);
+        assert_eq!(
+            PlaybackStatus::parse("forward-seek"),
+            PlaybackStatus::ForwardSeek
+        );
+        assert_eq!(
+            PlaybackStatus::parse("reverse-seek"),
+            PlaybackStatus::ReverseSeek
+        );
+        assert_eq!(PlaybackStatus::parse("error"), PlaybackStatus::Error);
+        assert_eq!(PlaybackStatus::parse("stopped"), PlaybackStatus::Stopped);
+        assert_eq!(PlaybackStatus::parse("Playing"), PlaybackStatus::Stopped);
+        assert_eq!(PlaybackStatus::parse(""), PlaybackStatus::Stopped);
+    }
+
+    #[test]
+    fn track() {
+        let mut track = PropMap::new();
+        insert(&mut track, "Title", "Drive".to_string());
+        insert(&mut track, "Artist", "The Cars".to_string());
+        insert(&mut track, "TrackNumber", 3u32);
+        insert(&mut track, "NumberOfTracks", 12u32);
+        insert(&mut track, "Duration", 235_000u32);
+        insert(&mut track, "Genre", "Rock".to_string());
+        let t = Track::parse(&track);
+        assert_eq!(t.title.as_deref(), Some("Drive"));
+        assert_eq!(t.artist.as_deref(), Some("The Cars"));
+        assert_eq!(t.album, None);
+        assert_eq!(t.number, Some(3));
+        assert_eq!(t.tracks, Some(12));
+        assert_eq!(t.duration, Some(Duration::from_secs(235)));
+
+        // Values of the wrong type are left out
+        let mut track = PropMap::new();
+        insert(&mut track, "Title", 5u32);
+        insert(&mut track, "Duration", "long".to_string());
+        let t = Track::parse(&track);
+        assert_eq!(t.title, None);
+        assert_eq!(t.duration, None);
+        assert_eq!(Track::parse(&5u32).title, None);
+
+        // The track arrives as a dictionary inside a variant
+        let mut track = PropMap::new();
+        insert(&mut track, "Album", "Heartbeat City".to_string());
+        let mut props = PropMap::new();
+        insert(&mut props, "Name", "Music".to_string());
+        insert(&mut props, "Track", track);
+        let mut state = MediaState::new();
+        state.update(&props);
+        assert_eq!(state.name.as_deref(), Some("Music"));
+        assert_eq!(state.track.album.as_deref(), Some("Heartbeat City"));
+    }
+
+    #[test]
+    fn position() {
+        let mut state = MediaState::new();
+        state.position = Duration::from_secs(10);
+        state.updated = Instant::now() - Duration::from_secs(5);
+        // Only a playing track moves on
+        assert_eq!(state.position(), Duration::from_secs(10));
+        state.status = PlaybackStatus::Playing;
+        let p = state.position();
+        assert!(p >= Duration::from_secs(15) && p < Duration::from_secs(16));
+        // But not past its end
+        state.track.duration = Some(Duration::from_secs(12));
+        assert_eq!(state.position(), Duration::from_secs(12));
+
+        // Pausing keeps the position reached so far
+        state.track.duration = None;
+        let mut props = PropMap::new();
+        insert(&mut props, "Status", "paused".to_string());
+        state.update(&props);
+        assert_eq!(state.status, PlaybackStatus::Paused);
+        assert!(state.position() >= Duration::from_secs(15));
+
+        // A reported position replaces the counted one
+        let mut props = PropMap::new();
+        insert(&mut props, "Position", 2_500u32);
+        state.update(&props);
+        assert_eq!(state.position(), Duration::from_millis(2_500));
+    }
+}
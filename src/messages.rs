@@ -0,0 +1,153 @@
+use std::time::{Duration, Instant};
+
+use super::CommonWindowProperties;
+use super::MessageToAsync;
+use super::Subwindow;
+use super::SubwindowTrait;
+use crate::contacts::format_time;
+use crate::map::Message;
+use crate::phone;
+use eframe::egui;
+
+/// How long a new message is shown over the current page
+const BANNER_TIME: Duration = Duration::from_secs(8);
+
+/// The newest message, shown until it is dismissed or times out
+pub struct Banner {
+    pub addr: bluer::Address,
+    pub message: Message,
+    pub shown: Instant,
+}
+
+/// The number of unread messages on all phones
+pub fn unread(common: &CommonWindowProperties) -> usize {
+    common
+        .bluetooth
+        .messages
+        .values()
+        .flatten()
+        .filter(|m| !m.read)
+        .count()
+}
+
+/// Show a new message at the top of the screen without taking over the page
+pub fn banner(ctx: &egui::Context, common: &mut CommonWindowProperties) -> Option<Subwindow> {
+    let b = common.bluetooth.banner.as_ref()?;
+    if b.shown.elapsed() > BANNER_TIME {
+        common.bluetooth.banner = None;
+        return None;
+    }
+    let mut r = None;
+    let mut dismiss = false;
+    egui::Area::new(egui::Id::new("message banner"))
+        .anchor(egui::Align2::CENTER_TOP, [0.0, 8.0])
+        .order(egui::Order::Foreground)
+        .show(ctx, |ui| {
+            egui::Frame::popup(ui.style()).show(ui, |ui| {
+                ui.set_max_width(600.0);
+                ui.horizontal(|ui| {
+                    ui.vertical(|ui| {
+                        ui.strong(&b.message.sender);
+                        ui.add(egui::Label::new(&b.message.text).truncate());
+                    });
+                    if ui.button("Open").clicked() {
+                        r = Some(Subwindow::Messages(Messages {
+                            selected: Some(b.addr),
+                        }));
+                        dismiss = true;
+                    }
+                    if ui.button("X").clicked() {
+                        dismiss = true;
+                    }
+                });
+            });
+        });
+    if dismiss {
+        common.bluetooth.banner = None;
+    }
+    r
+}
+
+pub struct Messages {
+    selected: Option<bluer::Address>,
+}
+
+impl Messages {
+    pub fn new() -> Self {
+        Self { selected: None }
+    }
+}
+
+impl SubwindowTrait for Messages {
+    fn update(
+        &mut self,
+        ctx: &egui::Context,
+        frame: &mut eframe::Frame,
+        common: &mut CommonWindowProperties,
+    ) -> Option<Subwindow> {
+        let mut r = None;
+        egui::CentralPanel::default().show(ctx, |ui| {
+            if self
+                .selected
+                .map(|a| !common.bluetooth.messages.contains_key(&a))
+                .unwrap_or(true)
+            {
+                self.selected = common.bluetooth.messages.keys().next().copied();
+            }
+            let Some(addr) = self.selected else {
+                ui.heading("No messages");
+                return;
+            };
+            ui.horizontal(|ui| {
+                for a in common.bluetooth.messages.keys() {
+                    ui.selectable_value(
+                        &mut self.selected,
+                        Some(*a),
+                        common.bluetooth.phone_name(a),
+                    );
+                }
+            });
+            let Some(messages) = common.bluetooth.messages.get(&addr) else {
+                return;
+            };
+            egui::ScrollArea::vertical()
+                .auto_shrink([false; 2])
+                .show(ui, |ui| {
+                    for m in messages {
+                        ui.separator();
+                        ui.horizontal(|ui| {
+                            let sender = egui::RichText::new(&m.sender)
+                                .font(egui::FontId::proportional(28.0));
+                            if m.read {
+                                ui.label(sender);
+                            } else {
+                                ui.label(sender.strong());
+                            }
+                            if let Some(t) = &m.time {
+                                ui.label(format_time(t));
+                            }
+                            if let Some(n) = &m.number {
+                                if ui.button("Call").clicked() {
+                                    let _ = common
+                                        .tx
+                                        .blocking_send(MessageToAsync::PhoneDial(addr, n.clone()));
+                                    r = Some(Subwindow::Phone(phone::Phone::new()));
+                                }
+                            }
+                        });
+                        ui.label(&m.text);
+                    }
+                });
+        });
+        // Everything on the page has now been seen
+        if let Some(m) = self
+            .selected
+            .and_then(|a| common.bluetooth.messages.get_mut(&a))
+        {
+            for m in m.iter_mut() {
+                m.read = true;
+            }
+        }
+        r
+    }
+}
@@ -0,0 +1,309 @@
+use crate::sbc::{self, MSBC_FRAME_LENGTH, MSBC_SAMPLES};
+
+/// The first byte of the H2 header in front of every mSBC frame on an SCO link
+const H2_SYNC: u8 = 0x01;
+
+/// The second byte of the H2 header, for each value of the 2 bit sequence number
+const H2_SEQUENCE: [u8; 4] = [0x08, 0x38, 0xc8, 0xf8];
+
+/// The length of an mSBC frame on an SCO link, with its H2 header and a padding byte
+pub const MSBC_PACKET: usize = 2 + MSBC_FRAME_LENGTH + 1;
+
+/// The shortest and longest pitch period concealment repeats, 400 Hz down to 133 Hz
+const MIN_PERIOD: usize = 40;
+const MAX_PERIOD: usize = 120;
+
+/// How many samples are compared when looking for the pitch period
+const PITCH_WINDOW: usize = 64;
+
+/// Concealed audio fades to silence over this many samples
+const FADE_SAMPLES: usize = 4 * MSBC_SAMPLES;
+
+/// Samples blended from concealed audio into the first good frame after a loss
+const OVERLAP: usize = 32;
+
+/// Hides lost frames by repeating the last pitch period of the audio, fading out over long losses
+pub struct Plc {
+    /// The most recent audio, oldest first
+    history: Vec<i16>,
+    period: usize,
+    /// Samples concealed since the last good frame
+    concealed: usize,
+}
+
+impl Plc {
+    pub fn new() -> Self {
+        Self {
+            history: vec![0; MAX_PERIOD + PITCH_WINDOW + MSBC_SAMPLES],
+            period: MAX_PERIOD,
+            concealed: 0,
+        }
+    }
+
+    fn remember(&mut self, frame: &[i16]) {
+        self.history.drain(..frame.len());
+        self.history.extend_from_slice(frame);
+    }
+
+    /// Find the period that best repeats the end of the history
+    fn pitch(&self) -> usize {
+        let h: Vec<f32> = self.history.iter().map(|s| *s as f32).collect();
+        let end = h.len();
+        let recent = &h[end - PITCH_WINDOW..];
+        let mut best = (MAX_PERIOD, 0.0);
+        for p in MIN_PERIOD..=MAX_PERIOD {
+            let past = &h[end - PITCH_WINDOW - p..end - p];
+            let corr: f32 = recent.iter().zip(past).map(|(a, b)| a * b).sum();
+            let energy: f32 = past.iter().map(|b| b * b).sum();
+            if energy > 0.0 {
+                let score = corr / energy.sqrt();
+                if score > best.1 {
+                    best = (p, score);
+                }
+            }
+        }
+        best.0
+    }
+
+    /// The audio that would follow the history if it kept repeating, faded for the loss so far
+    fn predict(&self, n: usize) -> Vec<f32> {
+        let end = self.history.len();
+        (0..n)
+            .map(|i| {
+                let s = self.history[end - self.period + i % self.period] as f32;
+                let fade = 1.0 - (self.concealed + i) as f32 / FADE_SAMPLES as f32;
+                s * fade.max(0.0)
+            })
+            .collect()
+    }
+
+    /// Pass on a good frame, smoothing the change back from concealed audio
+    pub fn good(&mut self, mut frame: Vec<i16>) -> Vec<i16> {
+        if self.concealed > 0 {
+            let c = self.predict(OVERLAP);
+            for (i, (s, c)) in frame.iter_mut().zip(c).enumerate() {
+                let w = (i + 1) as f32 / (OVERLAP + 1) as f32;
+                *s = (c * (1.0 - w) + *s as f32 * w).round() as i16;
+            }
+            self.concealed = 0;
+        }
+        self.remember(&frame);
+        frame
+    }
+
+    /// Make up a frame of audio in place of one that was lost
+    pub fn lost(&mut self) -> Vec<i16> {
+        if self.concealed == 0 {
+            self.period = self.pitch();
+        }
+        let out: Vec<i16> = self
+            .predict(MSBC_SAMPLES)
+            .into_iter()
+            .map(|s| s.round() as i16)
+            .collect();
+        // The history keeps the unfaded audio so later frames continue the same waveform
+        let end = self.history.len();
+        let repeated: Vec<i16> = (0..MSBC_SAMPLES)
+            .map(|i| self.history[end - self.period + i % self.period])
+            .collect();
+        self.remember(&repeated);
+        self.concealed += MSBC_SAMPLES;
+        out
+    }
+}
+
+/// The sequence number of an H2 header, if the bytes are one
+fn h2_sequence(h: &[u8]) -> Option<u8> {
+    if h.first() != Some(&H2_SYNC) {
+        return None;
+    }
+    H2_SEQUENCE
+        .iter()
+        .position(|s| h.get(1) == Some(s))
+        .map(|p| p as u8)
+}
+
+/// Turns the bytes read from a transparent SCO link into audio, concealing lost frames
+pub struct MsbcReader {
+    buf: Vec<u8>,
+    decoder: sbc::Decoder,
+    plc: Plc,
+    sequence: Option<u8>,
+    /// The number of frames that were concealed
+    pub lost: usize,
+}
+
+impl MsbcReader {
+    pub fn new() -> Self {
+        Self {
+            buf: Vec::new(),
+            decoder: sbc::Decoder::new(),
+            plc: Plc::new(),
+            sequence: None,
+            lost: 0,
+        }
+    }
+
+    /// Add the contents of an SCO packet, returning the 16 kHz audio of every frame it completes
+    pub fn push(&mut self, data: &[u8]) -> Vec<i16> {
+        self.buf.extend_from_slice(data);
+        let mut out = Vec::new();
+        loop {
+            let start = (0..self.buf.len().saturating_sub(2))
+                .find(|&i| h2_sequence(&self.buf[i..]).is_some() && self.buf[i + 2] == 0xad);
+            let Some(start) = start else {
+                // Keep a partial header that may be completed by the next packet
+                let keep = self.buf.len().min(2);
+                self.buf.drain(..self.buf.len() - keep);
+                break;
+            };
+            self.buf.drain(..start);
+            if self.buf.len() < 2 + MSBC_FRAME_LENGTH {
+                break;
+            }
+            let seq = h2_sequence(&self.buf).unwrap_or(0);
+            if let Some(prev) = self.sequence {
+                let missing = (seq + 3 - prev) % 4;
+                for _ in 0..missing {
+                    self.lost += 1;
+                    out.extend(self.plc.lost());
+                }
+            }
+            self.sequence = Some(seq);
+            match self.decoder.decode(&self.buf[2..2 + MSBC_FRAME_LENGTH]) {
+                Ok((_, samples)) => out.extend(self.plc.good(samples)),
+                Err(e) => {
+                    println!("Concealing a bad mSBC frame {}", e);
+                    self.lost += 1;
+                    out.extend(self.plc.lost());
+                }
+            }
+            self.buf.drain(..2 + MSBC_FRAME_LENGTH);
+        }
+        out
+    }
+}
+
+/// Turns 16 kHz audio into mSBC frames with H2 headers, ready to write to an SCO link
+pub struct MsbcWriter {
+    encoder: sbc::MsbcEncoder,
+    pending: Vec<i16>,
+    sequence: u8,
+}
+
+impl MsbcWriter {
+    pub fn new() -> Self {
+        Self {
+            encoder: sbc::MsbcEncoder::new(),
+            pending: Vec::new(),
+            sequence: 0,
+        }
+    }
+
+    /// Add audio, returning the bytes of every frame it completes
+    pub fn push(&mut self, pcm: &[i16]) -> Vec<u8> {
+        self.pending.extend_from_slice(pcm);
+        let mut out = Vec::new();
+        while self.pending.len() >= MSBC_SAMPLES {
+            let frame: Vec<i16> = self.pending.drain(..MSBC_SAMPLES).collect();
+            out.push(H2_SYNC);
+            out.push(H2_SEQUENCE[self.sequence as usize]);
+            out.extend(self.encoder.encode(&frame));
+            out.push(0);
+            self.sequence = (self.sequence + 1) % 4;
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TONE_FRAMES: usize = 100;
+    const TONE_AMPLITUDE: f32 = 8000.0;
+
+    /// Decode a stream in pieces the size of the packets of a typical USB controller
+    fn read(stream: &[u8]) -> (Vec<i16>, usize) {
+        let mut r = MsbcReader::new();
+        let mut out = Vec::new();
+        for p in stream.chunks(24) {
+            out.extend(r.push(p));
+        }
+        (out, r.lost)
+    }
+
+    fn zero_crossings(s: &[i16]) -> usize {
+        s.windows(2).filter(|w| (w[0] < 0) != (w[1] < 0)).count()
+    }
+
+    fn peak(s: &[i16]) -> f32 {
+        s.iter().map(|s| (*s as f32).abs()).fold(0.0, f32::max)
+    }
+
+    #[test]
+    fn tone() {
+        let (out, lost) = read(include_bytes!("../testdata/msbc_tone.sco"));
+        assert_eq!(lost, 0);
+        assert_eq!(out.len(), TONE_FRAMES * MSBC_SAMPLES);
+        // One second at 16 kHz holds 2000 crossings of a 1 kHz tone
+        let steady = &out[MSBC_SAMPLES..];
+        let expected = 2000.0 * steady.len() as f32 / 16000.0;
+        assert!((zero_crossings(steady) as f32 - expected).abs() <= 2.0);
+        assert!((peak(steady) - TONE_AMPLITUDE).abs() < TONE_AMPLITUDE * 0.05);
+    }
+
+    #[test]
+    fn concealment() {
+        // The tone with frame 30 missing, frame 60 damaged and noise before frame 80
+        let (out, lost) = read(include_bytes!("../testdata/msbc_loss.sco"));
+        assert_eq!(lost, 2);
+        assert_eq!(out.len(), TONE_FRAMES * MSBC_SAMPLES);
+        for frame in [30, 60] {
+            let start = frame * MSBC_SAMPLES;
+            let concealed = &out[start - OVERLAP..start + MSBC_SAMPLES + OVERLAP];
+            assert!(peak(concealed) > TONE_AMPLITUDE * 0.5);
+            // The largest step of a clean 1 kHz tone is about 3100
+            let jump = concealed
+                .windows(2)
+                .map(|w| (w[1] as i32 - w[0] as i32).abs())
+                .max()
+                .unwrap();
+            assert!(jump < 4000, "click of {} around frame {}", jump, frame);
+        }
+    }
+
+    #[test]
+    fn long_loss_fades_out() {
+        let mut plc = Plc::new();
+        let tone: Vec<i16> = (0..MSBC_SAMPLES * 2)
+            .map(|i| (TONE_AMPLITUDE * (i as f32 * std::f32::consts::PI / 8.0).sin()) as i16)
+            .collect();
+        plc.good(tone[..MSBC_SAMPLES].to_vec());
+        plc.good(tone[MSBC_SAMPLES..].to_vec());
+        let first = plc.lost();
+        assert!(peak(&first) > TONE_AMPLITUDE * 0.7);
+        for _ in 0..4 {
+            plc.lost();
+        }
+        assert_eq!(peak(&plc.lost()), 0.0);
+    }
+
+    #[test]
+    fn writer() {
+        let mut w = MsbcWriter::new();
+        let pcm: Vec<i16> = (0..MSBC_SAMPLES * 5 + 10)
+            .map(|i| (i % 100) as i16)
+            .collect();
+        let stream = w.push(&pcm);
+        assert_eq!(stream.len(), 5 * MSBC_PACKET);
+        let seqs: Vec<u8> = stream
+            .chunks(MSBC_PACKET)
+            .map(|p| h2_sequence(p).unwrap())
+            .collect();
+        assert_eq!(seqs, vec![0, 1, 2, 3, 0]);
+        let (out, lost) = read(&stream);
+        assert_eq!(lost, 0);
+        assert_eq!(out.len(), 5 * MSBC_SAMPLES);
+    }
+}
@@ -0,0 +1,606 @@
+use std::collections::HashSet;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+
+use crate::bluetooth_backend::RfcommStream;
+use crate::spp;
+use crate::MessageFromAsync;
+use crate::MessageToAsync;
+
+/// How long the adapter gets to answer a command, ATZ and the first query are slow
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Debug)]
+pub enum ObdError {
+    Io(std::io::Error),
+    Timeout,
+    Disconnected,
+    /// The vehicle did not answer the request
+    NoData,
+    /// The adapter did not understand the command
+    Unsupported(String),
+    /// The adapter could not talk to the vehicle
+    Bus(String),
+}
+
+impl std::fmt::Display for ObdError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ObdError::Io(e) => write!(f, "{}", e),
+            ObdError::Timeout => write!(f, "Adapter did not respond"),
+            ObdError::Disconnected => write!(f, "Adapter disconnected"),
+            ObdError::NoData => write!(f, "No data"),
+            ObdError::Unsupported(c) => write!(f, "Unsupported command {}", c),
+            ObdError::Bus(e) => write!(f, "Vehicle bus error: {}", e),
+        }
+    }
+}
+
+impl From<std::io::Error> for ObdError {
+    fn from(e: std::io::Error) -> Self {
+        ObdError::Io(e)
+    }
+}
+
+/// The mode 01 parameters that are polled
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Pid {
+    Rpm,
+    Speed,
+    Coolant,
+    FuelLevel,
+    Maf,
+}
+
+impl Pid {
+    pub const ALL: [Pid; 5] = [Pid::Rpm, Pid::Speed, Pid::Coolant, Pid::FuelLevel, Pid::Maf];
+
+    fn code(self) -> u8 {
+        match self {
+            Pid::Rpm => 0x0c,
+            Pid::Speed => 0x0d,
+            Pid::Coolant => 0x05,
+            Pid::FuelLevel => 0x2f,
+            Pid::Maf => 0x10,
+        }
+    }
+
+    /// Turn the data bytes of a response into a value
+    pub fn decode(self, d: &[u8]) -> Option<f32> {
+        let a = *d.first()? as f32;
+        let ab = || Some(a * 256.0 + *d.get(1)? as f32);
+        match self {
+            Pid::Rpm => Some(ab()? / 4.0),
+            Pid::Speed => Some(a),
+            Pid::Coolant => Some(a - 40.0),
+            Pid::FuelLevel => Some(a * 100.0 / 255.0),
+            Pid::Maf => Some(ab()? / 100.0),
+        }
+    }
+}
+
+/// Decode the trouble codes in a mode 03 response, like P0133
+pub fn decode_dtcs(d: &[u8]) -> Vec<String> {
+    // CAN adapters put the number of codes first
+    let d = if d.len() % 2 == 1 { &d[1..] } else { d };
+    d.chunks_exact(2)
+        .filter(|c| c[0] != 0 || c[1] != 0)
+        .map(|c| {
+            let system = ['P', 'C', 'B', 'U'][(c[0] >> 6) as usize];
+            format!(
+                "{}{}{:01X}{:02X}",
+                system,
+                (c[0] >> 4) & 3,
+                c[0] & 0xf,
+                c[1]
+            )
+        })
+        .collect()
+}
+
+/// Read the hex bytes of a response line, None if it is not hex data
+fn hex_bytes(s: &str) -> Option<Vec<u8>> {
+    let s: String = s.chars().filter(|c| !c.is_whitespace()).collect();
+    if s.is_empty() || !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Find the answer to a request in the lines the adapter sent, returning the data after the header
+pub fn parse_response(lines: &[String], mode: u8, pid: Option<u8>) -> Result<Vec<u8>, ObdError> {
+    let mut frames: Vec<Vec<u8>> = Vec::new();
+    let mut multi: Vec<u8> = Vec::new();
+    for l in lines {
+        match l.as_str() {
+            "NO DATA" => return Err(ObdError::NoData),
+            "?" => return Err(ObdError::Unsupported(format!("{:02X}", mode))),
+            _ => {}
+        }
+        if l.contains("ERROR") || l.starts_with("UNABLE") || l == "STOPPED" {
+            return Err(ObdError::Bus(l.clone()));
+        }
+        // Long responses come in numbered frames like 0: 43 03 01 33
+        if let Some((_, data)) = l.split_once(':') {
+            multi.extend(hex_bytes(data).unwrap_or_default());
+        } else if let Some(b) = hex_bytes(l) {
+            frames.push(b);
+        }
+    }
+    if !multi.is_empty() {
+        frames.push(multi);
+    }
+    let header = match pid {
+        Some(_) => 2,
+        None => 1,
+    };
+    frames
+        .into_iter()
+        .find(|f| {
+            f.len() >= header && f[0] == mode + 0x40 && (pid.is_none() || f.get(1) == pid.as_ref())
+        })
+        .map(|f| f[header..].to_vec())
+        .ok_or(ObdError::NoData)
+}
+
+/// What the adapter is doing
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum ObdStatus {
+    #[default]
+    Disconnected,
+    Connecting(bluer::Address),
+    /// Connected to an adapter, with the version it reported
+    Connected(bluer::Address, String),
+    Failed(String),
+}
+
+/// Everything read from the vehicle
+#[derive(Clone, Debug, Default)]
+pub struct VehicleData {
+    pub status: ObdStatus,
+    pub rpm: Option<f32>,
+    /// Speed in km/h
+    pub speed: Option<f32>,
+    /// Coolant temperature in degrees C
+    pub coolant: Option<f32>,
+    /// Fuel level in percent
+    pub fuel: Option<f32>,
+    /// Mass air flow in g/s
+    pub maf: Option<f32>,
+    /// Trouble codes, None until they have been read
+    pub dtcs: Option<Vec<String>>,
+    pub updated: Option<Instant>,
+}
+
+impl VehicleData {
+    pub fn value(&self, pid: Pid) -> Option<f32> {
+        match pid {
+            Pid::Rpm => self.rpm,
+            Pid::Speed => self.speed,
+            Pid::Coolant => self.coolant,
+            Pid::FuelLevel => self.fuel,
+            Pid::Maf => self.maf,
+        }
+    }
+
+    fn set(&mut self, pid: Pid, v: Option<f32>) {
+        let p = match pid {
+            Pid::Rpm => &mut self.rpm,
+            Pid::Speed => &mut self.speed,
+            Pid::Coolant => &mut self.coolant,
+            Pid::FuelLevel => &mut self.fuel,
+            Pid::Maf => &mut self.maf,
+        };
+        *p = v;
+        self.updated = Some(Instant::now());
+    }
+}
+
+/// A connection to an ELM327 OBD-II adapter
+pub struct Elm327<R, W> {
+    r: BufReader<R>,
+    w: W,
+}
+
+impl<R: AsyncRead + Unpin, W: AsyncWrite + Unpin> Elm327<R, W> {
+    pub fn new(r: R, w: W) -> Self {
+        Self {
+            r: BufReader::new(r),
+            w,
+        }
+    }
+
+    /// Send a command and collect the lines of the response up to the prompt
+    pub async fn command(&mut self, cmd: &str) -> Result<Vec<String>, ObdError> {
+        println!("OBD send: {}", cmd);
+        self.w.write_all(format!("{}\r", cmd).as_bytes()).await?;
+        self.w.flush().await?;
+        let mut buf = Vec::new();
+        let n = tokio::time::timeout(RESPONSE_TIMEOUT, self.r.read_until(b'>', &mut buf))
+            .await
+            .map_err(|_| ObdError::Timeout)??;
+        if n == 0 || buf.last() != Some(&b'>') {
+            return Err(ObdError::Disconnected);
+        }
+        let text = String::from_utf8_lossy(&buf[..buf.len() - 1]).into_owned();
+        let lines: Vec<String> = text
+            .split(['\r', '\n'])
+            .map(|l| l.trim().to_string())
+            .filter(|l| !l.is_empty() && l != cmd && !l.starts_with("SEARCHING"))
+            .collect();
+        println!("OBD recv: {}", lines.join(" | "));
+        Ok(lines)
+    }
+
+    /// An AT command that should answer OK
+    async fn setting(&mut self, cmd: &str) -> Result<(), ObdError> {
+        let lines = self.command(cmd).await?;
+        if lines.iter().any(|l| l == "OK") {
+            Ok(())
+        } else {
+            Err(ObdError::Unsupported(cmd.to_string()))
+        }
+    }
+
+    /// Reset the adapter and let it find the vehicle protocol, returning its version
+    pub async fn init(&mut self) -> Result<String, ObdError> {
+        let lines = self.command("ATZ").await?;
+        let version = lines
+            .iter()
+            .rev()
+            .find(|l| l.starts_with("ELM"))
+            .cloned()
+            .unwrap_or_else(|| "Unknown adapter".to_string());
+        self.setting("ATE0").await?;
+        self.setting("ATSP0").await?;
+        Ok(version)
+    }
+
+    pub async fn query(&mut self, mode: u8, pid: Option<u8>) -> Result<Vec<u8>, ObdError> {
+        let cmd = match pid {
+            Some(p) => format!("{:02X}{:02X}", mode, p),
+            None => format!("{:02X}", mode),
+        };
+        let lines = self.command(&cmd).await?;
+        parse_response(&lines, mode, pid)
+    }
+
+    pub async fn read_pid(&mut self, pid: Pid) -> Result<Option<f32>, ObdError> {
+        Ok(pid.decode(&self.query(0x01, Some(pid.code())).await?))
+    }
+
+    pub async fn read_dtcs(&mut self) -> Result<Vec<String>, ObdError> {
+        match self.query(0x03, None).await {
+            Ok(d) => Ok(decode_dtcs(&d)),
+            Err(ObdError::NoData) => Ok(Vec::new()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Open a serial device such as a bound rfcomm tty or a simulator pty
+pub fn open_serial(path: &Path) -> std::io::Result<tokio::fs::File> {
+    use std::os::fd::AsRawFd;
+    let f = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)?;
+    // The adapter ends lines with a bare carriage return, which a tty would translate
+    unsafe {
+        let mut t: libc::termios = std::mem::zeroed();
+        if libc::tcgetattr(f.as_raw_fd(), &mut t) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        libc::cfmakeraw(&mut t);
+        if libc::tcsetattr(f.as_raw_fd(), libc::TCSANOW, &t) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(tokio::fs::File::from_std(f))
+}
+
+/// Reach the adapter through a serial device when one is given, otherwise over bluetooth
+async fn open(
+    addr: bluer::Address,
+    serial: Option<&Path>,
+) -> std::io::Result<Box<dyn RfcommStream>> {
+    match serial {
+        Some(p) => Ok(Box::new(open_serial(p)?)),
+        None => Ok(Box::new(spp::connect(addr).await?)),
+    }
+}
+
+/// Poll the vehicle until asked to disconnect or the adapter goes away,
+/// giving the adapter to connect to next when asked to switch to another one
+async fn obd_session(
+    addr: bluer::Address,
+    serial: Option<&Path>,
+    data: &mut VehicleData,
+    tx: &tokio::sync::mpsc::Sender<MessageFromAsync>,
+    rx: &mut tokio::sync::mpsc::Receiver<MessageToAsync>,
+) -> Result<Option<bluer::Address>, ObdError> {
+    let (r, w) = tokio::io::split(open(addr, serial).await?);
+    let mut elm = Elm327::new(r, w);
+    let version = elm.init().await?;
+    println!("Connected to {} at {}", version, addr);
+    data.status = ObdStatus::Connected(addr, version);
+    data.dtcs = Some(elm.read_dtcs().await?);
+    let _ = tx.send(MessageFromAsync::Vehicle(data.clone())).await;
+    let mut unsupported: HashSet<Pid> = HashSet::new();
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            m = rx.recv() => match m {
+                None | Some(MessageToAsync::ObdDisconnect) => return Ok(None),
+                Some(MessageToAsync::ObdReadCodes) => {
+                    data.dtcs = Some(elm.read_dtcs().await?);
+                }
+                Some(MessageToAsync::ObdConnect(a)) if a != addr => return Ok(Some(a)),
+                Some(_) => continue,
+            },
+            _ = interval.tick() => {
+                for pid in Pid::ALL {
+                    if unsupported.contains(&pid) {
+                        continue;
+                    }
+                    match elm.read_pid(pid).await {
+                        Ok(v) => data.set(pid, v),
+                        Err(ObdError::NoData) | Err(ObdError::Unsupported(_)) => {
+                            println!("Vehicle does not report {:?}", pid);
+                            unsupported.insert(pid);
+                        }
+                        Err(e) => return Err(e),
+                    }
+                }
+            }
+        }
+        let _ = tx.send(MessageFromAsync::Vehicle(data.clone())).await;
+    }
+}
+
+/// Connect to OBD-II adapters when asked and report vehicle data to the gui
+pub async fn vehicle(
+    tx: tokio::sync::mpsc::Sender<MessageFromAsync>,
+    rx: tokio::sync::mpsc::Receiver<MessageToAsync>,
+) {
+    serve(tx, rx, None).await;
+}
+
+/// Run the adapter connections asked for, through the serial device if there is one
+async fn serve(
+    tx: tokio::sync::mpsc::Sender<MessageFromAsync>,
+    mut rx: tokio::sync::mpsc::Receiver<MessageToAsync>,
+    serial: Option<&Path>,
+) {
+    let mut next = None;
+    loop {
+        let addr = match next.take() {
+            Some(a) => a,
+            None => match rx.recv().await {
+                Some(MessageToAsync::ObdConnect(a)) => a,
+                Some(_) => continue,
+                None => return,
+            },
+        };
+        let mut data = VehicleData {
+            status: ObdStatus::Connecting(addr),
+            ..Default::default()
+        };
+        let _ = tx.send(MessageFromAsync::Vehicle(data.clone())).await;
+        data.status = match obd_session(addr, serial, &mut data, &tx, &mut rx).await {
+            Ok(n) => {
+                next = n;
+                ObdStatus::Disconnected
+            }
+            Err(e) => {
+                println!("OBD connection to {} failed {}", addr, e);
+                ObdStatus::Failed(e.to_string())
+            }
+        };
+        let _ = tx.send(MessageFromAsync::Vehicle(data)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::os::fd::FromRawFd;
+
+    /// Answer like an ELM327 on a car that does not report its fuel level
+    fn simulate(mut port: std::fs::File) {
+        let mut echo = true;
+        let mut cmd = Vec::new();
+        let mut b = [0u8; 1];
+        while let Ok(1) = port.read(&mut b) {
+            if b[0] != b'\r' {
+                cmd.push(b[0]);
+                continue;
+            }
+            let c = String::from_utf8_lossy(&cmd).to_uppercase();
+            cmd.clear();
+            let answer = match c.as_str() {
+                "ATZ" => {
+                    echo = true;
+                    "\r\rELM327 v1.5"
+                }
+                "ATE0" => {
+                    echo = false;
+                    "OK"
+                }
+                "ATSP0" => "OK",
+                "010C" => "SEARCHING...\r41 0C 1A F8",
+                "010D" => "41 0D 3C",
+                "0105" => "41 05 5A",
+                "012F" => "NO DATA",
+                "0110" => "41 10 01 F4",
+                "03" => "43 02 01 33 C1 23",
+                _ => "?",
+            };
+            let mut out = String::new();
+            if echo {
+                out.push_str(&c);
+                out.push('\r');
+            }
+            out.push_str(answer);
+            out.push_str("\r\r>");
+            if port.write_all(out.as_bytes()).is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Open a pty, returning the master side and the path of the slave
+    fn pty() -> (std::fs::File, std::path::PathBuf) {
+        unsafe {
+            let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+            assert!(fd >= 0);
+            assert_eq!(libc::grantpt(fd), 0);
+            assert_eq!(libc::unlockpt(fd), 0);
+            let mut name = [0 as libc::c_char; 128];
+            assert_eq!(libc::ptsname_r(fd, name.as_mut_ptr(), name.len()), 0);
+            let path = std::ffi::CStr::from_ptr(name.as_ptr())
+                .to_string_lossy()
+                .into_owned();
+            (std::fs::File::from_raw_fd(fd), path.into())
+        }
+    }
+
+    #[tokio::test]
+    async fn simulator() {
+        let (master, path) = pty();
+        let port = open_serial(&path).unwrap();
+        std::thread::spawn(move || simulate(master));
+        let (r, w) = tokio::io::split(port);
+        let mut elm = Elm327::new(r, w);
+        assert_eq!(elm.init().await.unwrap(), "ELM327 v1.5");
+        assert_eq!(elm.read_pid(Pid::Rpm).await.unwrap(), Some(1726.0));
+        assert_eq!(elm.read_pid(Pid::Speed).await.unwrap(), Some(60.0));
+        assert_eq!(elm.read_pid(Pid::Coolant).await.unwrap(), Some(50.0));
+        assert!(matches!(
+            elm.read_pid(Pid::FuelLevel).await,
+            Err(ObdError::NoData)
+        ));
+        assert_eq!(elm.read_pid(Pid::Maf).await.unwrap(), Some(5.0));
+        assert_eq!(elm.read_dtcs().await.unwrap(), vec!["P0133", "U0123"]);
+        assert!(matches!(
+            elm.command("ATXYZ").await.unwrap().as_slice(),
+            [q] if q == "?"
+        ));
+    }
+
+    const ADAPTER: bluer::Address = bluer::Address::new([0x00, 0x1d, 0xa5, 0x68, 0x98, 0x8b]);
+
+    /// Take messages until the vehicle data passes f
+    async fn wait_for(
+        rx: &mut tokio::sync::mpsc::Receiver<MessageFromAsync>,
+        f: impl Fn(&VehicleData) -> bool,
+    ) -> VehicleData {
+        loop {
+            let m = tokio::time::timeout(Duration::from_secs(10), rx.recv())
+                .await
+                .expect("The vehicle data never got there")
+                .expect("The vehicle task stopped");
+            if let MessageFromAsync::Vehicle(d) = m {
+                if f(&d) {
+                    return d;
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn session() {
+        let (master, path) = pty();
+        std::thread::spawn(move || simulate(master));
+        // Holding the port open lets the simulator carry on between connections
+        let _port = std::fs::File::open(&path).unwrap();
+        let (tx, mut from) = tokio::sync::mpsc::channel(10);
+        let (to, rx) = tokio::sync::mpsc::channel(10);
+        let task = tokio::spawn(async move { serve(tx, rx, Some(&path)).await });
+        to.send(MessageToAsync::ObdConnect(ADAPTER)).await.unwrap();
+        let d = wait_for(&mut from, |d| d.rpm.is_some()).await;
+        assert_eq!(
+            d.status,
+            ObdStatus::Connected(ADAPTER, "ELM327 v1.5".to_string())
+        );
+        assert_eq!(d.rpm, Some(1726.0));
+        assert_eq!(d.speed, Some(60.0));
+        assert_eq!(d.fuel, None);
+        assert_eq!(d.dtcs, Some(vec!["P0133".to_string(), "U0123".to_string()]));
+
+        // Connecting to another adapter closes the first connection
+        let other = bluer::Address::new([0x00, 0x1d, 0xa5, 0x00, 0x00, 0x01]);
+        to.send(MessageToAsync::ObdConnect(other)).await.unwrap();
+        wait_for(&mut from, |d| d.status == ObdStatus::Disconnected).await;
+        wait_for(&mut from, |d| d.status == ObdStatus::Connecting(other)).await;
+        let d = wait_for(&mut from, |d| d.rpm.is_some()).await;
+        assert_eq!(
+            d.status,
+            ObdStatus::Connected(other, "ELM327 v1.5".to_string())
+        );
+
+        to.send(MessageToAsync::ObdDisconnect).await.unwrap();
+        wait_for(&mut from, |d| d.status == ObdStatus::Disconnected).await;
+        drop(to);
+        task.await.unwrap();
+    }
+
+    #[test]
+    fn responses() {
+        let lines = |l: &[&str]| l.iter().map(|s| s.to_string()).collect::<Vec<String>>();
+        assert_eq!(
+            parse_response(&lines(&["410C1AF8"]), 0x01, Some(0x0c)).unwrap(),
+            vec![0x1a, 0xf8]
+        );
+        // Older cars report the bus init before the data
+        assert_eq!(
+            parse_response(&lines(&["BUS INIT: ...OK", "41 0D 3C"]), 0x01, Some(0x0d)).unwrap(),
+            vec![0x3c]
+        );
+        assert_eq!(
+            parse_response(
+                &lines(&["00A", "0: 43 04 01 33 02", "1: 44 C1 23 00 05 00 00"]),
+                0x03,
+                None
+            )
+            .unwrap(),
+            vec![0x04, 0x01, 0x33, 0x02, 0x44, 0xc1, 0x23, 0x00, 0x05, 0x00, 0x00]
+        );
+        assert!(matches!(
+            parse_response(&lines(&["UNABLE TO CONNECT"]), 0x01, Some(0x0c)),
+            Err(ObdError::Bus(_))
+        ));
+        assert!(matches!(
+            parse_response(&lines(&["NO DATA"]), 0x01, Some(0x0c)),
+            Err(ObdError::NoData)
+        ));
+    }
+
+    #[test]
+    fn dtcs() {
+        assert_eq!(decode_dtcs(&[0x01, 0x33, 0x00, 0x00]), vec!["P0133"]);
+        assert_eq!(
+            decode_dtcs(&[0x03, 0x01, 0x33, 0x42, 0x10, 0x9a, 0xbc]),
+            vec!["P0133", "C0210", "B1ABC"]
+        );
+        assert!(decode_dtcs(&[0x00]).is_empty());
+    }
+
+    #[test]
+    fn pids() {
+        assert_eq!(Pid::Rpm.decode(&[0x1a, 0xf8]), Some(1726.0));
+        assert_eq!(Pid::Rpm.decode(&[0x1a]), None);
+        assert_eq!(Pid::Coolant.decode(&[0x00]), Some(-40.0));
+        assert_eq!(Pid::FuelLevel.decode(&[0xff]), Some(100.0));
+        assert_eq!(Pid::Maf.decode(&[0x01, 0xf4]), Some(5.0));
+    }
+}
@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use dbus::arg::{PropMap, RefArg, Variant};
+use futures::StreamExt;
+
+pub const OBEX_SERVICE: &str = "org.bluez.obex";
+
+/// How long obexd gets to answer, longer than bluez as it may have to reach the phone first
+pub const OBEX_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub enum ObexError {
+    Dbus(String),
+    Transfer(String),
+    /// More was sent than the limit allows
+    TooLarge(u64),
+    Timeout,
+}
+
+impl std::fmt::Display for ObexError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ObexError::Dbus(e) => write!(f, "Dbus error: {}", e),
+            ObexError::Transfer(e) => write!(f, "Transfer failed: {}", e),
+            ObexError::TooLarge(n) => write!(f, "Transfer is over {} bytes", n),
+            ObexError::Timeout => write!(f, "Transfer timed out"),
+        }
+    }
+}
+
+impl From<dbus::Error> for ObexError {
+    fn from(e: dbus::Error) -> Self {
+        ObexError::Dbus(e.to_string())
+    }
+}
+
+/// The arguments for Client1.CreateSession, target is a profile like pbap or map
+pub fn session_args(target: &str) -> PropMap {
+    let mut args: PropMap = HashMap::new();
+    args.insert(
+        "Target".to_string(),
+        Variant(Box::new(target.to_string()) as Box<dyn RefArg>),
+    );
+    args
+}
+
+/// A temporary file for obexd to write a transfer into
+pub fn temp_file(addr: bluer::Address, name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!(
+        "radio-{}-{}",
+        addr.to_string().replace(':', ""),
+        name
+    ))
+}
+
+/// Read the changed properties of a transfer from a PropertiesChanged signal
+fn transfer_changes(msg: &dbus::Message, transfer: &dbus::Path) -> Option<PropMap> {
+    let (iface, props, _): (String, PropMap, Vec<String>) = msg.read3().ok()?;
+    if iface != "org.bluez.obex.Transfer1" || msg.path()? != *transfer {
+        return None;
+    }
+    Some(props)
+}
+
+/// Wait for a transfer to finish writing to its file, failing once more than limit bytes arrived
+pub async fn wait_transfer(
+    changes: &mut futures::channel::mpsc::UnboundedReceiver<dbus::Message>,
+    transfer: &dbus::Path<'static>,
+    target: &Path,
+    timeout: Duration,
+    limit: Option<u64>,
+) -> Result<(), ObexError> {
+    let timeout = tokio::time::sleep(timeout);
+    tokio::pin!(timeout);
+    loop {
+        tokio::select! {
+            _ = &mut timeout => return Err(ObexError::Timeout),
+            m = changes.next() => {
+                let Some(m) = m else {
+                    return Err(ObexError::Dbus("Connection lost".to_string()));
+                };
+                let Some(props) = transfer_changes(&m, transfer) else {
+                    continue;
+                };
+                let sent = dbus::arg::prop_cast::<u64>(&props, "Transferred");
+                if let (Some(sent), Some(limit)) = (sent, limit) {
+                    if *sent > limit {
+                        return Err(ObexError::TooLarge(limit));
+                    }
+                }
+                match dbus::arg::prop_cast::<String>(&props, "Status").map(|s| s.as_str()) {
+                    Some("complete") => return Ok(()),
+                    Some("error") => {
+                        return Err(ObexError::Transfer(target.display().to_string()))
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+/// Wait for a transfer to finish, then read and remove the file it was written to
+pub async fn finish_transfer(
+    changes: &mut futures::channel::mpsc::UnboundedReceiver<dbus::Message>,
+    transfer: &dbus::Path<'static>,
+    target: &Path,
+    timeout: Duration,
+) -> Result<String, ObexError> {
+    wait_transfer(changes, transfer, target, timeout, None).await?;
+    let data = tokio::fs::read(target)
+        .await
+        .map_err(|e| ObexError::Transfer(e.to_string()))?;
+    let _ = tokio::fs::remove_file(target).await;
+    Ok(String::from_utf8_lossy(&data).into_owned())
+}
@@ -0,0 +1,544 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use dbus::arg::PropMap;
+use dbus::message::MatchRule;
+use dbus::nonblock::stdintf::org_freedesktop_dbus::Properties;
+use dbus::nonblock::SyncConnection;
+use dbus_crossroads::Crossroads;
+
+use super::CommonWindowProperties;
+use crate::bluez::RETRY_DELAY;
+use crate::config;
+use crate::contacts;
+use crate::gallery;
+use crate::obex::{self, ObexError, OBEX_SERVICE, OBEX_TIMEOUT};
+use crate::MessageFromAsync;
+use crate::MessageToAsync;
+use eframe::egui;
+
+pub const OBJECT_PUSH_UUID: bluer::Uuid =
+    bluer::Uuid::from_u128(0x00001105_0000_1000_8000_00805f9b34fb);
+
+/// Where the object push agent lives on the session bus
+const AGENT_PATH: &str = "/radio/obex/agent";
+
+/// How long the user has to accept a file before it is rejected
+const PROMPT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a phone gets to send a file once it was accepted
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// The kinds of file phones may send to the radio
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PushKind {
+    Contact,
+    Picture,
+}
+
+impl PushKind {
+    /// Work out what a file is from its mime type, or its name when the phone leaves that out
+    fn of(mime: &str, name: &str) -> Option<Self> {
+        match mime.to_lowercase().as_str() {
+            "text/x-vcard" | "text/vcard" => return Some(PushKind::Contact),
+            "image/jpeg" | "image/png" => return Some(PushKind::Picture),
+            "" => {}
+            _ => return None,
+        }
+        let ext = Path::new(name).extension()?.to_str()?.to_lowercase();
+        match ext.as_str() {
+            "vcf" | "vcard" => Some(PushKind::Contact),
+            "jpg" | "jpeg" | "png" => Some(PushKind::Picture),
+            _ => None,
+        }
+    }
+}
+
+/// A file a phone wants to send to the radio
+#[derive(Clone, Debug)]
+pub struct PushRequest {
+    pub device: bluer::Address,
+    pub name: String,
+    pub kind: PushKind,
+    /// The size in bytes, 0 when the phone did not say
+    pub size: u64,
+}
+
+type PushReply = tokio::sync::oneshot::Sender<Result<String, dbus::MethodErr>>;
+
+fn rejected() -> dbus::MethodErr {
+    dbus::MethodErr::from(("org.bluez.obex.Error.Rejected", "Rejected"))
+}
+
+/// Serve the obex agent interface, passing each file a phone offers on to the push task
+fn agent(
+    conn: &Arc<SyncConnection>,
+    events: tokio::sync::mpsc::UnboundedSender<(dbus::Path<'static>, PushReply)>,
+) {
+    let mut cr = Crossroads::new();
+    cr.set_async_support(Some((
+        conn.clone(),
+        Box::new(|x| {
+            tokio::spawn(x);
+        }),
+    )));
+    let iface = cr.register(
+        "org.bluez.obex.Agent1",
+        |b: &mut dbus_crossroads::IfaceBuilder<
+            tokio::sync::mpsc::UnboundedSender<(dbus::Path<'static>, PushReply)>,
+        >| {
+            b.method_with_cr_async(
+                "AuthorizePush",
+                ("transfer",),
+                ("filename",),
+                |mut ctx, cr, (transfer,): (dbus::Path<'static>,)| {
+                    let events = cr
+                        .data_mut::<tokio::sync::mpsc::UnboundedSender<(
+                            dbus::Path<'static>,
+                            PushReply,
+                        )>>(ctx.path())
+                        .cloned();
+                    async move {
+                        let (s, r) = tokio::sync::oneshot::channel();
+                        if let Some(events) = events {
+                            let _ = events.send((transfer, s));
+                        }
+                        let reply = r.await.unwrap_or_else(|_| Err(rejected()));
+                        ctx.reply(reply.map(|f| (f,)))
+                    }
+                },
+            );
+            b.method("Cancel", (), (), |_, _, _: ()| Ok(()));
+            b.method("Release", (), (), |_, _, _: ()| Ok(()));
+        },
+    );
+    cr.insert(AGENT_PATH, &[iface], events);
+    conn.start_receive(
+        MatchRule::new_method_call(),
+        Box::new(move |msg, conn| {
+            let _ = cr.handle_message(msg, conn);
+            true
+        }),
+    );
+}
+
+/// Find out who is sending a file and what it is
+async fn push_request(
+    conn: &Arc<SyncConnection>,
+    transfer: &dbus::Path<'static>,
+) -> Result<Option<PushRequest>, ObexError> {
+    let proxy =
+        dbus::nonblock::Proxy::new(OBEX_SERVICE, transfer.clone(), OBEX_TIMEOUT, conn.clone());
+    let props: PropMap = proxy.get_all("org.bluez.obex.Transfer1").await?;
+    let name = dbus::arg::prop_cast::<String>(&props, "Name")
+        .cloned()
+        .unwrap_or_default();
+    let mime = dbus::arg::prop_cast::<String>(&props, "Type")
+        .cloned()
+        .unwrap_or_default();
+    let size = dbus::arg::prop_cast::<u64>(&props, "Size")
+        .copied()
+        .unwrap_or(0);
+    let session = dbus::arg::prop_cast::<dbus::Path<'static>>(&props, "Session")
+        .cloned()
+        .ok_or_else(|| ObexError::Dbus("Transfer has no session".to_string()))?;
+    let proxy = dbus::nonblock::Proxy::new(OBEX_SERVICE, session, OBEX_TIMEOUT, conn.clone());
+    let destination: String = proxy.get("org.bluez.obex.Session1", "Destination").await?;
+    let device = destination
+        .parse()
+        .map_err(|_| ObexError::Dbus(format!("Bad address {}", destination)))?;
+    let Some(kind) = PushKind::of(&mime, &name) else {
+        println!(
+            "{} offered {} of type {}, which is not supported",
+            device, name, mime
+        );
+        return Ok(None);
+    };
+    Ok(Some(PushRequest {
+        device,
+        name,
+        kind,
+        size,
+    }))
+}
+
+/// Send a file offer to the gui and wait for the user to accept or reject it
+async fn ask_user(tx: &tokio::sync::mpsc::Sender<MessageFromAsync>, req: PushRequest) -> bool {
+    let (s, r) = tokio::sync::oneshot::channel();
+    if tx
+        .send(MessageFromAsync::PushRequest(req, s))
+        .await
+        .is_err()
+    {
+        return false;
+    }
+    match tokio::time::timeout(PROMPT_TIMEOUT, r).await {
+        Ok(Ok(accept)) => accept,
+        Ok(Err(_)) => false,
+        Err(_) => {
+            println!("File offer timed out");
+            false
+        }
+    }
+}
+
+/// Keep a file that finished arriving, in the contact store or the gallery
+async fn store(tx: &tokio::sync::mpsc::Sender<MessageFromAsync>, req: &PushRequest, file: &Path) {
+    match req.kind {
+        PushKind::Contact => {
+            let data = match tokio::fs::read(file).await {
+                Ok(d) => d,
+                Err(e) => {
+                    println!("Failed to read {}: {}", file.display(), e);
+                    return;
+                }
+            };
+            let _ = tokio::fs::remove_file(file).await;
+            let vcf = String::from_utf8_lossy(&data);
+            contacts::save_received(&vcf);
+            let cards: Vec<_> = crate::vcard::parse(&vcf)
+                .into_iter()
+                .filter(|c| !c.numbers.is_empty())
+                .collect();
+            println!("Received {} contacts from {}", cards.len(), req.device);
+            let _ = tx.send(MessageFromAsync::ReceivedContacts(cards)).await;
+        }
+        PushKind::Picture => match gallery::keep(file, &req.name) {
+            Ok(p) => {
+                println!("Received picture {} from {}", p.display(), req.device);
+                let _ = tx.send(MessageFromAsync::ReceivedPicture(p)).await;
+            }
+            Err(e) => {
+                println!("Failed to keep {}: {}", req.name, e);
+                let _ = tokio::fs::remove_file(file).await;
+            }
+        },
+    }
+}
+
+/// Decide whether to take a file a phone offers, returning where obexd should write it
+async fn authorize(
+    tx: &tokio::sync::mpsc::Sender<MessageFromAsync>,
+    settings: &config::PushConfig,
+    req: &PushRequest,
+) -> Option<PathBuf> {
+    if req.size > settings.max_size {
+        println!(
+            "{} offered {} of {} bytes, more than the limit of {}",
+            req.device, req.name, req.size, settings.max_size
+        );
+        return None;
+    }
+    if !ask_user(tx, req.clone()).await {
+        return None;
+    }
+    // Only the last part of the name, so a phone cannot choose where the file goes
+    let name = Path::new(&req.name)
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "file".to_string());
+    Some(obex::temp_file(req.device, &name))
+}
+
+/// Stop obexd taking any more of a file
+async fn cancel(conn: &Arc<SyncConnection>, transfer: &dbus::Path<'static>) {
+    let proxy =
+        dbus::nonblock::Proxy::new(OBEX_SERVICE, transfer.clone(), OBEX_TIMEOUT, conn.clone());
+    if let Err(e) = proxy
+        .method_call::<(), _, _, _>("org.bluez.obex.Transfer1", "Cancel", ())
+        .await
+    {
+        println!("Unable to cancel file transfer {}", e);
+    }
+}
+
+/// Handle one file a phone offers, from asking the user to storing it
+async fn receive(
+    conn: Arc<SyncConnection>,
+    tx: tokio::sync::mpsc::Sender<MessageFromAsync>,
+    settings: config::PushConfig,
+    transfer: dbus::Path<'static>,
+    reply: PushReply,
+) {
+    // Watch the transfer before accepting it so that its end cannot be missed
+    let rule = MatchRule::new_signal("org.freedesktop.DBus.Properties", "PropertiesChanged");
+    let (_changes_match, mut changes) = match conn.add_match(rule).await {
+        Ok(m) => m.msg_stream(),
+        Err(e) => {
+            println!("Unable to watch file transfer {}", e);
+            let _ = reply.send(Err(rejected()));
+            return;
+        }
+    };
+    let offer = match push_request(&conn, &transfer).await {
+        Ok(Some(req)) => authorize(&tx, &settings, &req).await.map(|t| (req, t)),
+        Ok(None) => None,
+        Err(e) => {
+            println!("Unable to read file offer {}", e);
+            None
+        }
+    };
+    let Some((req, target)) = offer else {
+        let _ = reply.send(Err(rejected()));
+        return;
+    };
+    println!("Receiving {} from {}", req.name, req.device);
+    let _ = reply.send(Ok(target.to_string_lossy().into_owned()));
+    // A phone that did not say how big the file is may send more than the limit
+    let limit = Some(settings.max_size);
+    match obex::wait_transfer(&mut changes, &transfer, &target, TRANSFER_TIMEOUT, limit).await {
+        Ok(()) => store(&tx, &req, &target).await,
+        Err(e) => {
+            if let ObexError::TooLarge(_) = e {
+                cancel(&conn, &transfer).await;
+            }
+            println!("Receiving {} from {} failed {}", req.name, req.device, e);
+            let _ = tokio::fs::remove_file(&target).await;
+        }
+    }
+}
+
+/// Take files from phones until asked to quit or the connection to obexd is lost
+async fn push_session(
+    tx: &tokio::sync::mpsc::Sender<MessageFromAsync>,
+    rx: &mut tokio::sync::mpsc::Receiver<MessageToAsync>,
+    settings: &mut config::PushConfig,
+) -> Result<(), ObexError> {
+    let (resource, conn) = dbus_tokio::connection::new_session_sync()?;
+    let mut lost = tokio::spawn(resource);
+
+    let (events_tx, mut events) = tokio::sync::mpsc::unbounded_channel();
+    agent(&conn, events_tx);
+
+    let r = async {
+        let manager =
+            dbus::nonblock::Proxy::new(OBEX_SERVICE, "/org/bluez/obex", OBEX_TIMEOUT, conn.clone());
+        manager
+            .method_call::<(), _, _, _>(
+                "org.bluez.obex.AgentManager1",
+                "RegisterAgent",
+                (dbus::Path::from(AGENT_PATH),),
+            )
+            .await?;
+        println!("Registered the object push agent");
+        loop {
+            tokio::select! {
+                Some((transfer, reply)) = events.recv() => {
+                    tokio::spawn(receive(
+                        conn.clone(),
+                        tx.clone(),
+                        settings.clone(),
+                        transfer,
+                        reply,
+                    ));
+                }
+                m = rx.recv() => match m {
+                    Some(MessageToAsync::PushSettings(s)) => *settings = s,
+                    Some(_) => {}
+                    None => return Ok(()),
+                },
+                _ = &mut lost => {
+                    return Err(ObexError::Dbus("Connection lost".to_string()));
+                }
+            }
+        }
+    }
+    .await;
+    lost.abort();
+    r
+}
+
+/// Receive contacts and pictures that phones send to the radio
+pub async fn object_push(
+    tx: tokio::sync::mpsc::Sender<MessageFromAsync>,
+    mut rx: tokio::sync::mpsc::Receiver<MessageToAsync>,
+) {
+    let mut settings = config::Config::load().object_push;
+    loop {
+        match push_session(&tx, &mut rx, &mut settings).await {
+            Ok(()) => return,
+            Err(e) => println!("Object push stopped {}", e),
+        }
+        tokio::time::sleep(RETRY_DELAY).await;
+    }
+}
+
+/// A file size like 1.5 MB
+fn format_size(bytes: u64) -> String {
+    if bytes >= 1024 * 1024 {
+        format!("{:.1} MB", bytes as f32 / (1024.0 * 1024.0))
+    } else {
+        format!("{} kB", bytes.div_ceil(1024))
+    }
+}
+
+/// Show a dialog for the oldest file offer that is still waiting
+pub fn push_dialog(ctx: &egui::Context, common: &mut CommonWindowProperties) {
+    common.bluetooth.pushes.retain(|(_, s)| !s.is_closed());
+    let Some((req, _)) = common.bluetooth.pushes.first() else {
+        return;
+    };
+    let name = common.bluetooth.phone_name(&req.device);
+    let mut response = None;
+    egui::Window::new("Receive file")
+        .collapsible(false)
+        .resizable(false)
+        .anchor(egui::Align2::CENTER_CENTER, [0.0, 0.0])
+        .show(ctx, |ui| {
+            ui.heading(name);
+            let what = match req.kind {
+                PushKind::Contact => "a contact",
+                PushKind::Picture => "a picture",
+            };
+            ui.label(format!("Wants to send {}", what));
+            if req.size > 0 {
+                ui.label(format!("{} ({})", req.name, format_size(req.size)));
+            } else {
+                ui.label(&req.name);
+            }
+            ui.horizontal(|ui| {
+                if ui.button("Accept").clicked() {
+                    response = Some(true);
+                }
+                if ui.button("Reject").clicked() {
+                    response = Some(false);
+                }
+            });
+        });
+    if let Some(r) = response {
+        let (_req, s) = common.bluetooth.pushes.remove(0);
+        let _ = s.send(r);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dbus::arg::{RefArg, Variant};
+
+    #[test]
+    fn kind() {
+        assert_eq!(
+            PushKind::of("text/x-vcard", "a.vcf"),
+            Some(PushKind::Contact)
+        );
+        assert_eq!(PushKind::of("image/png", "a.png"), Some(PushKind::Picture));
+        // The mime type wins over the extension
+        assert_eq!(PushKind::of("image/jpeg", "a.vcf"), Some(PushKind::Picture));
+        assert_eq!(PushKind::of("text/vcard", "a.jpg"), Some(PushKind::Contact));
+        assert_eq!(PushKind::of("text/plain", "a.jpg"), None);
+        assert_eq!(PushKind::of("application/pdf", "a.vcf"), None);
+        // Without a mime type the extension decides
+        assert_eq!(PushKind::of("", "a.vcard"), Some(PushKind::Contact));
+        assert_eq!(PushKind::of("", "a.jpeg"), Some(PushKind::Picture));
+        assert_eq!(PushKind::of("", "a.txt"), None);
+        assert_eq!(PushKind::of("", "vcf"), None);
+        assert_eq!(PushKind::of("", ""), None);
+        // Case does not matter
+        assert_eq!(PushKind::of("", "CAR.JPG"), Some(PushKind::Picture));
+        assert_eq!(PushKind::of("", "Card.VCF"), Some(PushKind::Contact));
+        assert_eq!(PushKind::of("IMAGE/PNG", "a"), Some(PushKind::Picture));
+    }
+
+    fn request(size: u64) -> PushRequest {
+        PushRequest {
+            device: bluer::Address::new([0x00, 0x11, 0x22, 0x33, 0x44, 0x55]),
+            name: "../photos/car.jpg".to_string(),
+            kind: PushKind::Picture,
+            size,
+        }
+    }
+
+    /// Answer the next file offer sent to the gui
+    async fn answer(
+        rx: &mut tokio::sync::mpsc::Receiver<MessageFromAsync>,
+        size: u64,
+        accept: bool,
+    ) {
+        let Some(MessageFromAsync::PushRequest(req, reply)) = rx.recv().await else {
+            panic!("No file offer");
+        };
+        assert_eq!(req.size, size);
+        reply.send(accept).unwrap();
+    }
+
+    #[tokio::test]
+    async fn max_size() {
+        let settings = config::PushConfig { max_size: 1000 };
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        // Too large is rejected without asking
+        assert_eq!(authorize(&tx, &settings, &request(1001)).await, None);
+        assert!(rx.try_recv().is_err());
+        // At the limit, or of unknown size, the user decides
+        let target = obex::temp_file(request(0).device, "car.jpg");
+        for size in [1000, 0] {
+            let (r, ()) = tokio::join!(
+                authorize(&tx, &settings, &request(size)),
+                answer(&mut rx, size, true)
+            );
+            assert_eq!(r.as_ref(), Some(&target));
+        }
+        let (r, ()) = tokio::join!(
+            authorize(&tx, &settings, &request(10)),
+            answer(&mut rx, 10, false)
+        );
+        assert_eq!(r, None);
+    }
+
+    /// A PropertiesChanged signal for one property of a transfer
+    fn changed(
+        transfer: &dbus::Path<'static>,
+        name: &str,
+        value: impl RefArg + 'static,
+    ) -> dbus::Message {
+        let mut props = PropMap::new();
+        props.insert(name.to_string(), Variant(Box::new(value)));
+        dbus::Message::signal(
+            transfer,
+            &"org.freedesktop.DBus.Properties".into(),
+            &"PropertiesChanged".into(),
+        )
+        .append3("org.bluez.obex.Transfer1", props, Vec::<String>::new())
+    }
+
+    #[tokio::test]
+    async fn too_large() {
+        let settings = config::PushConfig { max_size: 1000 };
+        let transfer = dbus::Path::from("/org/bluez/obex/server/session1/transfer1");
+        let other = dbus::Path::from("/org/bluez/obex/server/session2/transfer2");
+        let target = Path::new("/tmp/radio-test");
+        let (s, mut changes) = futures::channel::mpsc::unbounded();
+        // Only the transfer being received counts
+        s.unbounded_send(changed(&other, "Transferred", 5000u64))
+            .unwrap();
+        s.unbounded_send(changed(&transfer, "Transferred", 1000u64))
+            .unwrap();
+        s.unbounded_send(changed(&transfer, "Transferred", 1001u64))
+            .unwrap();
+        let r = obex::wait_transfer(
+            &mut changes,
+            &transfer,
+            target,
+            TRANSFER_TIMEOUT,
+            Some(settings.max_size),
+        )
+        .await;
+        assert!(matches!(r, Err(ObexError::TooLarge(1000))));
+
+        // A transfer that stays under the limit completes
+        s.unbounded_send(changed(&transfer, "Transferred", 900u64))
+            .unwrap();
+        s.unbounded_send(changed(&transfer, "Status", "complete".to_string()))
+            .unwrap();
+        let r = obex::wait_transfer(
+            &mut changes,
+            &transfer,
+            target,
+            TRANSFER_TIMEOUT,
+            Some(settings.max_size),
+        )
+        .await;
+        assert!(r.is_ok());
+    }
+}
@@ -0,0 +1,299 @@
+use std::time::Duration;
+
+use bluer::agent::ReqError;
+use futures::FutureExt;
+
+use super::CommonWindowProperties;
+use crate::opp;
+use crate::MessageFromAsync;
+use eframe::egui;
+
+/// How long the user has to answer a pairing request before it is canceled
+const PAIRING_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Debug)]
+pub enum PairingRequest {
+    PinCode(bluer::Address),
+    Passkey(bluer::Address),
+    DisplayPinCode(bluer::Address, String),
+    DisplayPasskey(bluer::Address, u32, u16),
+    Confirmation(bluer::Address, u32),
+    Authorization(bluer::Address),
+    AuthorizeService(bluer::Address, bluer::Uuid),
+}
+
+impl PairingRequest {
+    pub fn device(&self) -> bluer::Address {
+        match self {
+            PairingRequest::PinCode(a) => *a,
+            PairingRequest::Passkey(a) => *a,
+            PairingRequest::DisplayPinCode(a, _) => *a,
+            PairingRequest::DisplayPasskey(a, _, _) => *a,
+            PairingRequest::Confirmation(a, _) => *a,
+            PairingRequest::Authorization(a) => *a,
+            PairingRequest::AuthorizeService(a, _) => *a,
+        }
+    }
+
+    fn is_display(&self) -> bool {
+        matches!(
+            self,
+            PairingRequest::DisplayPinCode(_, _) | PairingRequest::DisplayPasskey(_, _, _)
+        )
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum PairingResponse {
+    Accept,
+    Reject,
+    PinCode(String),
+    Passkey(u32),
+}
+
+/// Send a request to the gui and wait for the user to answer it
+async fn ask_user(
+    tx: tokio::sync::mpsc::Sender<MessageFromAsync>,
+    req: PairingRequest,
+) -> bluer::agent::ReqResult<PairingResponse> {
+    println!("Pairing request {:?}", req);
+    let (s, r) = tokio::sync::oneshot::channel();
+    tx.send(MessageFromAsync::PairingRequest(req, s))
+        .await
+        .map_err(|_| ReqError::Canceled)?;
+    match tokio::time::timeout(PAIRING_TIMEOUT, r).await {
+        Ok(Ok(PairingResponse::Reject)) => Err(ReqError::Rejected),
+        Ok(Ok(r)) => Ok(r),
+        Ok(Err(_)) => Err(ReqError::Canceled),
+        Err(_) => {
+            println!("Pairing request timed out");
+            Err(ReqError::Canceled)
+        }
+    }
+}
+
+/// Show a request to the user without waiting for an answer
+async fn tell_user(tx: tokio::sync::mpsc::Sender<MessageFromAsync>, req: PairingRequest) {
+    println!("Pairing display {:?}", req);
+    let (s, _r) = tokio::sync::oneshot::channel();
+    let _ = tx.send(MessageFromAsync::PairingRequest(req, s)).await;
+}
+
+/// Build an agent that passes every request through to the gui
+pub fn agent(tx: tokio::sync::mpsc::Sender<MessageFromAsync>) -> bluer::agent::Agent {
+    let mut blue_agent = bluer::agent::Agent::default();
+    blue_agent.request_default = true;
+    let t = tx.clone();
+    blue_agent.request_pin_code = Some(Box::new(move |a| {
+        let tx = t.clone();
+        async move {
+            match ask_user(tx, PairingRequest::PinCode(a.device)).await? {
+                PairingResponse::PinCode(p) => Ok(p),
+                _ => Err(ReqError::Rejected),
+            }
+        }
+        .boxed()
+    }));
+    let t = tx.clone();
+    blue_agent.request_passkey = Some(Box::new(move |a| {
+        let tx = t.clone();
+        async move {
+            match ask_user(tx, PairingRequest::Passkey(a.device)).await? {
+                PairingResponse::Passkey(p) => Ok(p),
+                _ => Err(ReqError::Rejected),
+            }
+        }
+        .boxed()
+    }));
+    let t = tx.clone();
+    blue_agent.display_passkey = Some(Box::new(move |a| {
+        let tx = t.clone();
+        async move {
+            tell_user(
+                tx,
+                PairingRequest::DisplayPasskey(a.device, a.passkey, a.entered),
+            )
+            .await;
+            Ok(())
+        }
+        .boxed()
+    }));
+    let t = tx.clone();
+    blue_agent.display_pin_code = Some(Box::new(move |a| {
+        let tx = t.clone();
+        async move {
+            tell_user(tx, PairingRequest::DisplayPinCode(a.device, a.pincode)).await;
+            Ok(())
+        }
+        .boxed()
+    }));
+    let t = tx.clone();
+    blue_agent.request_confirmation = Some(Box::new(move |a| {
+        let tx = t.clone();
+        async move {
+            ask_user(tx, PairingRequest::Confirmation(a.device, a.passkey)).await?;
+            Ok(())
+        }
+        .boxed()
+    }));
+    let t = tx.clone();
+    blue_agent.request_authorization = Some(Box::new(move |a| {
+        let tx = t.clone();
+        async move {
+            ask_user(tx, PairingRequest::Authorization(a.device)).await?;
+            Ok(())
+        }
+        .boxed()
+    }));
+    let t = tx;
+    blue_agent.authorize_service = Some(Box::new(move |a| {
+        let tx = t.clone();
+        async move {
+            // Each file is offered to the user on its own as it arrives
+            if a.service == opp::OBJECT_PUSH_UUID {
+                return Ok(());
+            }
+            ask_user(tx, PairingRequest::AuthorizeService(a.device, a.service)).await?;
+            Ok(())
+        }
+        .boxed()
+    }));
+    blue_agent
+}
+
+/// The pairing requests waiting on the user
+pub struct Pairing {
+    requests: Vec<(
+        PairingRequest,
+        tokio::sync::oneshot::Sender<PairingResponse>,
+    )>,
+    input: String,
+}
+
+impl Pairing {
+    pub fn new() -> Self {
+        Self {
+            requests: Vec::new(),
+            input: String::new(),
+        }
+    }
+
+    pub fn add(&mut self, req: PairingRequest, s: tokio::sync::oneshot::Sender<PairingResponse>) {
+        if req.is_display() {
+            let dev = req.device();
+            self.requests
+                .retain(|(r, _)| !(r.is_display() && r.device() == dev));
+        }
+        self.requests.push((req, s));
+    }
+}
+
+/// Show a dialog for the oldest pairing request that is still waiting
+pub fn pairing_dialog(ctx: &egui::Context, common: &mut CommonWindowProperties) {
+    let pairing = &mut common.bluetooth.pairing;
+    pairing
+        .requests
+        .retain(|(r, s)| r.is_display() || !s.is_closed());
+    if pairing.requests.is_empty() {
+        return;
+    }
+    let dev = pairing.requests[0].0.device();
+    let name = common.bluetooth.phone_name(&dev);
+    let pairing = &mut common.bluetooth.pairing;
+    let mut response = None;
+    egui::Window::new("Bluetooth pairing")
+        .collapsible(false)
+        .resizable(false)
+        .anchor(egui::Align2::CENTER_CENTER, [0.0, 0.0])
+        .show(ctx, |ui| {
+            ui.heading(name);
+            match &pairing.requests[0].0 {
+                PairingRequest::PinCode(_) => {
+                    ui.label("Enter the PIN code for the device");
+                    ui.text_edit_singleline(&mut pairing.input);
+                    ui.horizontal(|ui| {
+                        if ui.button("Pair").clicked() && !pairing.input.is_empty() {
+                            response = Some(PairingResponse::PinCode(pairing.input.clone()));
+                        }
+                        if ui.button("Cancel").clicked() {
+                            response = Some(PairingResponse::Reject);
+                        }
+                    });
+                }
+                PairingRequest::Passkey(_) => {
+                    ui.label("Enter the passkey shown on the device");
+                    ui.text_edit_singleline(&mut pairing.input);
+                    ui.horizontal(|ui| {
+                        if ui.button("Pair").clicked() {
+                            if let Ok(p) = pairing.input.trim().parse::<u32>() {
+                                response = Some(PairingResponse::Passkey(p));
+                            }
+                        }
+                        if ui.button("Cancel").clicked() {
+                            response = Some(PairingResponse::Reject);
+                        }
+                    });
+                }
+                PairingRequest::DisplayPinCode(_, pin) => {
+                    ui.label("Enter this PIN code on the device");
+                    ui.label(egui::RichText::new(pin).font(egui::FontId::proportional(48.0)));
+                    if ui.button("Dismiss").clicked() {
+                        response = Some(PairingResponse::Accept);
+                    }
+                }
+                PairingRequest::DisplayPasskey(_, passkey, entered) => {
+                    ui.label("Enter this passkey on the device");
+                    ui.label(
+                        egui::RichText::new(format!("{:06}", passkey))
+                            .font(egui::FontId::proportional(48.0)),
+                    );
+                    ui.label(format!("{} digits entered", entered));
+                    if ui.button("Dismiss").clicked() {
+                        response = Some(PairingResponse::Accept);
+                    }
+                }
+                PairingRequest::Confirmation(_, passkey) => {
+                    ui.label("Confirm the passkey matches the one shown on the device");
+                    ui.label(
+                        egui::RichText::new(format!("{:06}", passkey))
+                            .font(egui::FontId::proportional(48.0)),
+                    );
+                    ui.horizontal(|ui| {
+                        if ui.button("Confirm").clicked() {
+                            response = Some(PairingResponse::Accept);
+                        }
+                        if ui.button("Reject").clicked() {
+                            response = Some(PairingResponse::Reject);
+                        }
+                    });
+                }
+                PairingRequest::Authorization(_) => {
+                    ui.label("Allow this device to pair?");
+                    ui.horizontal(|ui| {
+                        if ui.button("Allow").clicked() {
+                            response = Some(PairingResponse::Accept);
+                        }
+                        if ui.button("Reject").clicked() {
+                            response = Some(PairingResponse::Reject);
+                        }
+                    });
+                }
+                PairingRequest::AuthorizeService(_, uuid) => {
+                    ui.label(format!("Allow this device to use service {}?", uuid));
+                    ui.horizontal(|ui| {
+                        if ui.button("Allow").clicked() {
+                            response = Some(PairingResponse::Accept);
+                        }
+                        if ui.button("Reject").clicked() {
+                            response = Some(PairingResponse::Reject);
+                        }
+                    });
+                }
+            }
+        });
+    if let Some(r) = response {
+        let (_req, s) = pairing.requests.remove(0);
+        let _ = s.send(r);
+        pairing.input.clear();
+    }
+}
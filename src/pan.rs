@@ -0,0 +1,293 @@
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use dbus::arg::PropMap;
+use dbus::message::MatchRule;
+use dbus::nonblock::stdintf::org_freedesktop_dbus::ObjectManager;
+use dbus::nonblock::SyncConnection;
+use futures::StreamExt;
+
+use crate::bluez::{self, RETRY_DELAY};
+use crate::MessageFromAsync;
+use crate::MessageToAsync;
+
+const NETWORK_IFACE: &str = "org.bluez.Network1";
+
+/// Connecting sets up the bnep link, which can take a while on a busy phone
+const DBUS_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often the address and link state of connected interfaces are checked
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum PanStatus {
+    #[default]
+    Disconnected,
+    Connecting,
+    Connected,
+    Disconnecting,
+    Failed(String),
+}
+
+/// The network connection to a phone sharing its data connection
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PanState {
+    pub status: PanStatus,
+    /// The network interface bluez made for the connection, like bnep0
+    pub interface: Option<String>,
+    /// The addresses the system gave the interface
+    pub addresses: Vec<IpAddr>,
+    /// The operational state of the interface, like up or down
+    pub link: Option<String>,
+}
+
+impl PanState {
+    /// Read the addresses and link state of the interface again
+    fn refresh(&mut self) {
+        match (&self.status, &self.interface) {
+            (PanStatus::Connected, Some(i)) => {
+                self.addresses = addresses(i);
+                self.link = link_state(i);
+            }
+            _ => {
+                self.addresses.clear();
+                self.link = None;
+            }
+        }
+    }
+}
+
+/// The addresses the system has given a network interface
+fn addresses(interface: &str) -> Vec<IpAddr> {
+    let mut out = Vec::new();
+    let mut ifap: *mut libc::ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut ifap) } != 0 {
+        return out;
+    }
+    let mut p = ifap;
+    while !p.is_null() {
+        let ifa = unsafe { &*p };
+        p = ifa.ifa_next;
+        if ifa.ifa_addr.is_null() || ifa.ifa_name.is_null() {
+            continue;
+        }
+        let name = unsafe { CStr::from_ptr(ifa.ifa_name) };
+        if name.to_bytes() != interface.as_bytes() {
+            continue;
+        }
+        match unsafe { (*ifa.ifa_addr).sa_family } as i32 {
+            libc::AF_INET => {
+                let sa = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in) };
+                out.push(IpAddr::V4(Ipv4Addr::from(u32::from_be(sa.sin_addr.s_addr))));
+            }
+            libc::AF_INET6 => {
+                let sa = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in6) };
+                out.push(IpAddr::V6(Ipv6Addr::from(sa.sin6_addr.s6_addr)));
+            }
+            _ => {}
+        }
+    }
+    unsafe { libc::freeifaddrs(ifap) };
+    out
+}
+
+fn link_state(interface: &str) -> Option<String> {
+    let s = std::fs::read_to_string(format!("/sys/class/net/{}/operstate", interface)).ok()?;
+    Some(s.trim().to_string())
+}
+
+/// Apply the network properties of a device to its state
+fn update(state: &mut PanState, props: &PropMap) {
+    if let Some(c) = dbus::arg::prop_cast::<bool>(props, "Connected") {
+        state.status = if *c {
+            PanStatus::Connected
+        } else {
+            PanStatus::Disconnected
+        };
+        if !*c {
+            state.interface = None;
+        }
+    }
+    if let Some(i) = dbus::arg::prop_cast::<String>(props, "Interface") {
+        if !i.is_empty() {
+            state.interface = Some(i.clone());
+        }
+    }
+    state.refresh();
+}
+
+/// Read the new network properties of a device from a PropertiesChanged signal
+fn network_changed(msg: &dbus::Message) -> Option<(bluer::Address, PropMap)> {
+    let (iface, props, _): (String, PropMap, Vec<String>) = msg.read3().ok()?;
+    if iface != NETWORK_IFACE {
+        return None;
+    }
+    Some((bluez::path_address(&msg.path()?)?, props))
+}
+
+/// The object path of a device that can share its network
+async fn network_path(
+    conn: &Arc<SyncConnection>,
+    addr: bluer::Address,
+) -> Result<dbus::Path<'static>, String> {
+    let root = dbus::nonblock::Proxy::new("org.bluez", "/", DBUS_TIMEOUT, conn.clone());
+    let objects = root
+        .get_managed_objects()
+        .await
+        .map_err(|e| e.to_string())?;
+    objects
+        .into_iter()
+        .find(|(path, ifaces)| {
+            ifaces.contains_key(NETWORK_IFACE) && bluez::path_address(path) == Some(addr)
+        })
+        .map(|(path, _)| path)
+        .ok_or_else(|| "The device does not share a network".to_string())
+}
+
+/// Connect to or disconnect from the network of a device, giving the interface on connecting
+async fn network_call(
+    conn: Arc<SyncConnection>,
+    addr: bluer::Address,
+    connect: bool,
+) -> Result<Option<String>, String> {
+    let path = network_path(&conn, addr).await?;
+    let proxy = dbus::nonblock::Proxy::new("org.bluez", path, DBUS_TIMEOUT, conn.clone());
+    if connect {
+        let (interface,): (String,) = proxy
+            .method_call(NETWORK_IFACE, "Connect", ("nap",))
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(Some(interface))
+    } else {
+        proxy
+            .method_call::<(), _, _, _>(NETWORK_IFACE, "Disconnect", ())
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(None)
+    }
+}
+
+/// Follow the network connections to phones until the connection to dbus is lost
+async fn pan_session(
+    tx: &tokio::sync::mpsc::Sender<MessageFromAsync>,
+    rx: &mut tokio::sync::mpsc::Receiver<MessageToAsync>,
+) -> Result<(), dbus::Error> {
+    let (resource, conn) = dbus_tokio::connection::new_system_sync()?;
+    let mut lost = tokio::spawn(resource);
+
+    let (_changes_match, mut changes) = conn
+        .add_match(MatchRule::new_signal(
+            "org.freedesktop.DBus.Properties",
+            "PropertiesChanged",
+        ))
+        .await?
+        .msg_stream();
+
+    // Phones that were connected before the radio started
+    let mut networks: HashMap<bluer::Address, PanState> = HashMap::new();
+    let root = dbus::nonblock::Proxy::new("org.bluez", "/", DBUS_TIMEOUT, conn.clone());
+    for (path, ifaces) in root.get_managed_objects().await? {
+        let (Some(addr), Some(props)) = (bluez::path_address(&path), ifaces.get(NETWORK_IFACE))
+        else {
+            continue;
+        };
+        let mut state = PanState::default();
+        update(&mut state, props);
+        if state.status == PanStatus::Connected {
+            let _ = tx.send(MessageFromAsync::Pan(addr, state.clone())).await;
+            networks.insert(addr, state);
+        }
+    }
+
+    let (done_tx, mut done) = tokio::sync::mpsc::unbounded_channel();
+    let mut poll = tokio::time::interval(POLL_INTERVAL);
+    let r = loop {
+        tokio::select! {
+            m = rx.recv() => {
+                let (addr, connect) = match m {
+                    Some(MessageToAsync::PanConnect(a)) => (a, true),
+                    Some(MessageToAsync::PanDisconnect(a)) => (a, false),
+                    Some(_) => continue,
+                    None => break Ok(()),
+                };
+                let state = networks.entry(addr).or_default();
+                state.status = if connect {
+                    PanStatus::Connecting
+                } else {
+                    PanStatus::Disconnecting
+                };
+                let _ = tx.send(MessageFromAsync::Pan(addr, state.clone())).await;
+                let conn = conn.clone();
+                let done_tx = done_tx.clone();
+                tokio::spawn(async move {
+                    let r = network_call(conn, addr, connect).await;
+                    let _ = done_tx.send((addr, r));
+                });
+            }
+            Some((addr, r)) = done.recv() => {
+                let state = networks.entry(addr).or_default();
+                match r {
+                    Ok(Some(interface)) => {
+                        println!("Using the network of {} on {}", addr, interface);
+                        state.status = PanStatus::Connected;
+                        state.interface = Some(interface);
+                    }
+                    Ok(None) => {
+                        state.status = PanStatus::Disconnected;
+                        state.interface = None;
+                    }
+                    Err(e) => {
+                        println!("Network connection to {} failed {}", addr, e);
+                        state.status = PanStatus::Failed(e);
+                    }
+                }
+                state.refresh();
+                let _ = tx.send(MessageFromAsync::Pan(addr, state.clone())).await;
+            }
+            Some(msg) = changes.next() => {
+                let Some((addr, props)) = network_changed(&msg) else {
+                    continue;
+                };
+                let state = networks.entry(addr).or_default();
+                update(state, &props);
+                let _ = tx.send(MessageFromAsync::Pan(addr, state.clone())).await;
+            }
+            _ = poll.tick() => {
+                for (addr, state) in networks.iter_mut() {
+                    let old = state.clone();
+                    state.refresh();
+                    if *state != old {
+                        let _ = tx.send(MessageFromAsync::Pan(*addr, state.clone())).await;
+                    }
+                }
+            }
+            _ = &mut lost => {
+                break Err(dbus::Error::new_failed("Connection to dbus lost"));
+            }
+        }
+    };
+    lost.abort();
+    for a in networks.keys() {
+        let _ = tx
+            .send(MessageFromAsync::Pan(*a, PanState::default()))
+            .await;
+    }
+    r
+}
+
+/// Use the data connection of phones that share it over bluetooth
+pub async fn tethering(
+    tx: tokio::sync::mpsc::Sender<MessageFromAsync>,
+    mut rx: tokio::sync::mpsc::Receiver<MessageToAsync>,
+) {
+    loop {
+        match pan_session(&tx, &mut rx).await {
+            Ok(()) => return,
+            Err(e) => println!("Network sharing stopped {}", e),
+        }
+        tokio::time::sleep(RETRY_DELAY).await;
+    }
+}
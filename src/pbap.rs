@@ -0,0 +1,115 @@
+use std::time::Duration;
+
+use dbus::arg::PropMap;
+use dbus::message::MatchRule;
+use dbus::nonblock::SyncConnection;
+
+use crate::bluetooth::ActionStatus;
+use crate::contacts;
+use crate::obex::{self, ObexError, OBEX_SERVICE, OBEX_TIMEOUT};
+use crate::MessageFromAsync;
+use crate::MessageToAsync;
+
+/// How long a phone gets to send a whole phonebook
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Pull one phonebook object from the phone as vcard text
+async fn pull(
+    conn: &std::sync::Arc<SyncConnection>,
+    session: &dbus::Path<'static>,
+    changes: &mut futures::channel::mpsc::UnboundedReceiver<dbus::Message>,
+    addr: bluer::Address,
+    book: &str,
+) -> Result<String, ObexError> {
+    let proxy =
+        dbus::nonblock::Proxy::new(OBEX_SERVICE, session.clone(), OBEX_TIMEOUT, conn.clone());
+    proxy
+        .method_call::<(), _, _, _>("org.bluez.obex.PhonebookAccess1", "Select", ("int", book))
+        .await?;
+    let target = obex::temp_file(addr, &format!("{}.vcf", book));
+    let (transfer, _): (dbus::Path<'static>, PropMap) = proxy
+        .method_call(
+            "org.bluez.obex.PhonebookAccess1",
+            "PullAll",
+            (target.to_string_lossy().into_owned(), PropMap::new()),
+        )
+        .await?;
+    obex::finish_transfer(changes, &transfer, &target, TRANSFER_TIMEOUT).await
+}
+
+/// Download the phonebook and the combined call history of a phone
+async fn download(addr: bluer::Address) -> Result<(String, String), ObexError> {
+    let (resource, conn) = dbus_tokio::connection::new_session_sync()?;
+    let lost = tokio::spawn(resource);
+    let r = async {
+        let (_changes_match, mut changes) = conn
+            .add_match(MatchRule::new_signal(
+                "org.freedesktop.DBus.Properties",
+                "PropertiesChanged",
+            ))
+            .await?
+            .msg_stream();
+        let client =
+            dbus::nonblock::Proxy::new(OBEX_SERVICE, "/org/bluez/obex", OBEX_TIMEOUT, conn.clone());
+        let (session,): (dbus::Path<'static>,) = client
+            .method_call(
+                "org.bluez.obex.Client1",
+                "CreateSession",
+                (addr.to_string(), obex::session_args("pbap")),
+            )
+            .await?;
+        let r = async {
+            let pb = pull(&conn, &session, &mut changes, addr, "pb").await?;
+            let cch = pull(&conn, &session, &mut changes, addr, "cch").await?;
+            Ok((pb, cch))
+        }
+        .await;
+        let _: Result<(), dbus::Error> = client
+            .method_call("org.bluez.obex.Client1", "RemoveSession", (session,))
+            .await;
+        r
+    }
+    .await;
+    lost.abort();
+    r
+}
+
+/// Download phonebooks when asked, caching them for the next time the phone connects
+pub async fn phonebooks(
+    tx: tokio::sync::mpsc::Sender<MessageFromAsync>,
+    mut rx: tokio::sync::mpsc::Receiver<MessageToAsync>,
+) {
+    while let Some(m) = rx.recv().await {
+        let MessageToAsync::PhonebookDownload(addr) = m else {
+            continue;
+        };
+        println!("Downloading phonebook from {}", addr);
+        let _ = tx
+            .send(MessageFromAsync::PhonebookStatus(
+                addr,
+                ActionStatus::Started,
+            ))
+            .await;
+        let status = match download(addr).await {
+            Ok((pb, cch)) => {
+                contacts::save_cache(addr, &pb, &cch);
+                let book = contacts::Phonebook::parse(&pb, &cch);
+                println!(
+                    "Got {} contacts and {} calls from {}",
+                    book.contacts.len(),
+                    book.history.len(),
+                    addr
+                );
+                let _ = tx.send(MessageFromAsync::Phonebook(addr, book)).await;
+                ActionStatus::Done
+            }
+            Err(e) => {
+                println!("Phonebook download from {} failed {}", addr, e);
+                ActionStatus::Failed(e.to_string())
+            }
+        };
+        let _ = tx
+            .send(MessageFromAsync::PhonebookStatus(addr, status))
+            .await;
+    }
+}
@@ -0,0 +1,499 @@
+use super::CommonWindowProperties;
+use super::MessageToAsync;
+use super::Subwindow;
+use super::SubwindowTrait;
+use crate::autoconnect::PhoneLink;
+use crate::contacts;
+use crate::hfp::{Call, CallControl, CallState, CallStatus, HfpState};
+use crate::sco::MAX_VOLUME;
+use eframe::egui;
+
+const KEYPAD: [[&str; 3]; 4] = [
+    ["1", "2", "3"],
+    ["4", "5", "6"],
+    ["7", "8", "9"],
+    ["*", "0", "#"],
+];
+
+pub fn big_button(ui: &mut egui::Ui, text: &str) -> bool {
+    ui.add_sized(
+        [96.0, 72.0],
+        egui::Button::new(egui::RichText::new(text).font(egui::FontId::proportional(48.0))),
+    )
+    .clicked()
+}
+
+pub fn format_duration(d: std::time::Duration) -> String {
+    let s = d.as_secs();
+    if s >= 3600 {
+        format!("{}:{:02}:{:02}", s / 3600, (s / 60) % 60, s % 60)
+    } else {
+        format!("{}:{:02}", s / 60, s % 60)
+    }
+}
+
+/// Draw signal strength as five bars of rising height
+fn signal_bars(ui: &mut egui::Ui, signal: f32) {
+    let (rect, _) = ui.allocate_exact_size(egui::vec2(40.0, 32.0), egui::Sense::hover());
+    let lit = (signal * 5.0).round() as usize;
+    let p = ui.painter();
+    for i in 0..5 {
+        let x = rect.left() + i as f32 * 8.0;
+        let h = rect.height() * (i + 1) as f32 / 5.0;
+        let bar = egui::Rect::from_min_max(
+            egui::pos2(x, rect.bottom() - h),
+            egui::pos2(x + 6.0, rect.bottom()),
+        );
+        let color = if i < lit {
+            ui.visuals().strong_text_color()
+        } else {
+            ui.visuals().weak_text_color()
+        };
+        p.rect_filled(bar, 1.0, color);
+    }
+}
+
+fn call_button(ui: &mut egui::Ui, text: &str) -> bool {
+    ui.button(egui::RichText::new(text).font(egui::FontId::proportional(28.0)))
+        .clicked()
+}
+
+/// The phone the car is using, the automatically connected one if there is one
+pub fn current_phone(common: &CommonWindowProperties) -> Option<bluer::Address> {
+    match &common.bluetooth.link {
+        PhoneLink::Connected(a) => Some(*a),
+        _ => None,
+    }
+    .or_else(|| common.bluetooth.phones.keys().next().copied())
+}
+
+fn toggle_assistant(common: &CommonWindowProperties, addr: bluer::Address) {
+    if let Some(state) = common.bluetooth.phones.get(&addr) {
+        let _ = common
+            .tx
+            .blocking_send(MessageToAsync::PhoneVoiceRecognition(
+                addr,
+                !state.voice_recognition,
+            ));
+    }
+}
+
+/// Start the voice assistant of the current phone, or stop it if it is listening
+pub fn voice_assistant(common: &CommonWindowProperties) {
+    if let Some(addr) = current_phone(common) {
+        toggle_assistant(common, addr);
+    }
+}
+
+/// The contact name for a number, or the number itself
+fn caller_name(common: &CommonWindowProperties, addr: bluer::Address, number: &str) -> String {
+    let digits = |n: &str| -> String { n.chars().filter(|c| c.is_ascii_digit()).collect() };
+    let d = digits(number);
+    common
+        .bluetooth
+        .phonebooks
+        .get(&addr)
+        .map(|b| b.contacts.as_slice())
+        .unwrap_or_default()
+        .iter()
+        .chain(&common.bluetooth.received_contacts)
+        .find(|c| !d.is_empty() && c.numbers.iter().any(|n| digits(&n.number) == d))
+        .map(|c| c.display_name().to_string())
+        .unwrap_or_else(|| number.to_string())
+}
+
+fn call_control(common: &CommonWindowProperties, addr: bluer::Address, c: CallControl) {
+    let _ = common
+        .tx
+        .blocking_send(MessageToAsync::PhoneCallControl(addr, c));
+}
+
+fn call_state_text(c: &Call) -> String {
+    let s = match c.state {
+        CallState::Active => "Active",
+        CallState::Held => "On hold",
+        CallState::Dialing => "Dialing",
+        CallState::Alerting => "Ringing",
+        CallState::Incoming => "Incoming",
+        CallState::Waiting => "Waiting",
+    };
+    if c.multiparty {
+        format!("{} in conference", s)
+    } else {
+        s.to_string()
+    }
+}
+
+/// Where the call audio is going, and with which codec
+fn audio_label(ui: &mut egui::Ui, common: &CommonWindowProperties, addr: bluer::Address) {
+    match common.bluetooth.voice.get(&addr) {
+        Some(codec) => ui.label(format!("Audio: {}", codec)),
+        None => ui.label("Audio on the phone"),
+    };
+}
+
+/// Buttons to hold, swap and merge calls, as far as the phone allows
+fn hold_controls(
+    ui: &mut egui::Ui,
+    common: &CommonWindowProperties,
+    addr: bluer::Address,
+    state: &HfpState,
+) {
+    ui.horizontal(|ui| match state.call_held() {
+        0 if state.call_active() && state.supports(CallControl::Swap) => {
+            if call_button(ui, "Hold") {
+                call_control(common, addr, CallControl::Swap);
+            }
+        }
+        1 => {
+            if state.supports(CallControl::Swap) && call_button(ui, "Swap") {
+                call_control(common, addr, CallControl::Swap);
+            }
+            if state.supports(CallControl::Merge) && call_button(ui, "Merge") {
+                call_control(common, addr, CallControl::Merge);
+            }
+        }
+        2 if state.supports(CallControl::Swap) => {
+            if call_button(ui, "Resume") {
+                call_control(common, addr, CallControl::Swap);
+            }
+        }
+        _ => {}
+    });
+}
+
+/// A card for each call listed by the phone, with what can be done to it
+fn call_cards(
+    ui: &mut egui::Ui,
+    common: &CommonWindowProperties,
+    addr: bluer::Address,
+    state: &HfpState,
+) {
+    for c in &state.calls {
+        ui.group(|ui| {
+            ui.horizontal(|ui| {
+                ui.vertical(|ui| {
+                    ui.heading(
+                        c.number
+                            .as_deref()
+                            .map(|n| caller_name(common, addr, n))
+                            .unwrap_or_else(|| "Unknown caller".to_string()),
+                    );
+                    ui.label(call_state_text(c));
+                });
+                if matches!(c.state, CallState::Incoming | CallState::Waiting) {
+                    return;
+                }
+                if c.state == CallState::Active
+                    && c.multiparty
+                    && state.supports(CallControl::Private(c.index))
+                    && call_button(ui, "Private")
+                {
+                    call_control(common, addr, CallControl::Private(c.index));
+                }
+                if state.supports(CallControl::Release(c.index)) && call_button(ui, "End") {
+                    call_control(common, addr, CallControl::Release(c.index));
+                }
+            });
+        });
+    }
+}
+
+/// The name, signal, battery and network of the connected phone, for the bottom panel
+pub fn status_bar(ui: &mut egui::Ui, common: &CommonWindowProperties) {
+    let Some(addr) = current_phone(common) else {
+        return;
+    };
+    ui.label(common.bluetooth.phone_name(&addr));
+    let Some(state) = common.bluetooth.phones.get(&addr) else {
+        return;
+    };
+    if !state.service() {
+        ui.colored_label(egui::Color32::RED, "No service");
+    } else {
+        if let Some(s) = state.signal() {
+            signal_bars(ui, s);
+        }
+        if let Some(o) = &state.operator {
+            ui.label(o);
+        }
+        if state.roaming() {
+            ui.colored_label(egui::Color32::YELLOW, "R");
+        }
+    }
+    let battery = common
+        .bluetooth
+        .devices
+        .get(&addr)
+        .and_then(|d| d.battery)
+        .or_else(|| state.battery());
+    if let Some(b) = battery {
+        ui.label(format!("{}%", b));
+    }
+}
+
+/// Shows a window for any phone with a call ringing, over the top of the current subwindow
+pub fn incoming_call(ctx: &egui::Context, common: &mut CommonWindowProperties) {
+    let mut calls: Vec<(bluer::Address, Option<String>, bool)> = Vec::new();
+    for (addr, state) in &common.bluetooth.phones {
+        if let CallStatus::Incoming(caller) = state.call_status() {
+            calls.push((*addr, caller, state.call_waiting()));
+        }
+    }
+    for (addr, caller, waiting) in calls {
+        egui::Window::new(if waiting {
+            "Call waiting"
+        } else {
+            "Incoming call"
+        })
+        .id(egui::Id::new(("incoming call", addr)))
+        .collapsible(false)
+        .resizable(false)
+        .anchor(egui::Align2::CENTER_CENTER, [0.0, 0.0])
+        .show(ctx, |ui| {
+            ui.heading(
+                caller
+                    .map(|c| caller_name(common, addr, &c))
+                    .unwrap_or_else(|| "Unknown caller".to_string()),
+            );
+            ui.label(common.bluetooth.phone_name(&addr));
+            if waiting {
+                // A waiting call is answered by deciding what happens to the current one
+                ui.horizontal(|ui| {
+                    if call_button(ui, "Hold and answer") {
+                        call_control(common, addr, CallControl::Swap);
+                    }
+                    if call_button(ui, "End and answer") {
+                        call_control(common, addr, CallControl::ReleaseActive);
+                    }
+                    if call_button(ui, "Reject") {
+                        call_control(common, addr, CallControl::ReleaseHeld);
+                    }
+                });
+                return;
+            }
+            ui.horizontal(|ui| {
+                if big_button(ui, "Answer") {
+                    let _ = common.tx.blocking_send(MessageToAsync::PhoneAnswer(addr));
+                }
+                if big_button(ui, "Reject") {
+                    let _ = common.tx.blocking_send(MessageToAsync::PhoneHangUp(addr));
+                }
+            });
+        });
+    }
+}
+
+/// A volume slider with large steps for the touch screen, returning the volume when changed
+fn volume_row(ui: &mut egui::Ui, label: &str, volume: u8) -> Option<u8> {
+    let mut v = volume;
+    let mut send = false;
+    ui.horizontal(|ui| {
+        ui.label(label);
+        if call_button(ui, "-") && v > 0 {
+            v -= 1;
+            send = true;
+        }
+        let r = ui.add(egui::Slider::new(&mut v, 0..=MAX_VOLUME));
+        // Only the final value of a drag goes to the phone
+        send |= r.drag_stopped() || (r.changed() && !r.dragged());
+        if call_button(ui, "+") && v < MAX_VOLUME {
+            v += 1;
+            send = true;
+        }
+    });
+    send.then_some(v)
+}
+
+pub struct Phone {
+    selected: Option<bluer::Address>,
+    number: String,
+    /// Show the keypad for sending tones during a call
+    keypad: bool,
+    /// The tones sent during the current call
+    tones: String,
+}
+
+impl Phone {
+    pub fn new() -> Self {
+        Self {
+            selected: None,
+            number: String::new(),
+            keypad: false,
+            tones: String::new(),
+        }
+    }
+
+    /// The touch tone keypad and call volumes
+    fn in_call(
+        &mut self,
+        ui: &mut egui::Ui,
+        common: &CommonWindowProperties,
+        addr: bluer::Address,
+        state: &HfpState,
+    ) {
+        if let Some(v) = volume_row(ui, "Speaker", state.volume.speaker) {
+            let _ = common
+                .tx
+                .blocking_send(MessageToAsync::PhoneSpeakerVolume(addr, v));
+        }
+        if let Some(v) = volume_row(ui, "Microphone", state.volume.mic) {
+            let _ = common
+                .tx
+                .blocking_send(MessageToAsync::PhoneMicVolume(addr, v));
+        }
+        ui.checkbox(&mut self.keypad, "Keypad");
+        if !self.keypad {
+            return;
+        }
+        ui.label(egui::RichText::new(&self.tones).font(egui::FontId::proportional(48.0)));
+        egui::Grid::new("tone keypad").show(ui, |ui| {
+            for row in KEYPAD {
+                for key in row {
+                    if big_button(ui, key) {
+                        self.tones.push_str(key);
+                        for c in key.chars() {
+                            let _ = common.tx.blocking_send(MessageToAsync::PhoneDtmf(addr, c));
+                        }
+                    }
+                }
+                ui.end_row();
+            }
+        });
+    }
+
+    fn dialer(
+        &mut self,
+        ui: &mut egui::Ui,
+        common: &mut CommonWindowProperties,
+        addr: bluer::Address,
+    ) {
+        ui.horizontal(|ui| {
+            ui.add(
+                egui::TextEdit::singleline(&mut self.number)
+                    .font(egui::FontId::proportional(48.0))
+                    .desired_width(300.0),
+            );
+            if big_button(ui, "<") {
+                self.number.pop();
+            }
+        });
+        egui::Grid::new("dial keypad").show(ui, |ui| {
+            for row in KEYPAD {
+                for key in row {
+                    if big_button(ui, key) {
+                        self.number.push_str(key);
+                    }
+                }
+                ui.end_row();
+            }
+        });
+        if !self.number.is_empty() && big_button(ui, "Call") {
+            let _ = common
+                .tx
+                .blocking_send(MessageToAsync::PhoneDial(addr, self.number.clone()));
+        }
+    }
+}
+
+impl SubwindowTrait for Phone {
+    fn update(
+        &mut self,
+        ctx: &egui::Context,
+        frame: &mut eframe::Frame,
+        common: &mut CommonWindowProperties,
+    ) -> Option<Subwindow> {
+        let mut r = None;
+        egui::CentralPanel::default().show(ctx, |ui| {
+            if self
+                .selected
+                .map(|a| !common.bluetooth.phones.contains_key(&a))
+                .unwrap_or(true)
+            {
+                self.selected = common.bluetooth.phones.keys().next().copied();
+            }
+            let Some(addr) = self.selected else {
+                ui.heading("No phone connected");
+                return;
+            };
+            if common.bluetooth.phones.len() > 1 {
+                egui::ComboBox::from_label("Phone")
+                    .selected_text(common.bluetooth.phone_name(&addr))
+                    .show_ui(ui, |ui| {
+                        for a in common.bluetooth.phones.keys() {
+                            ui.selectable_value(
+                                &mut self.selected,
+                                Some(*a),
+                                common.bluetooth.phone_name(a),
+                            );
+                        }
+                    });
+            } else {
+                ui.heading(common.bluetooth.phone_name(&addr));
+            }
+            let Some(state) = common.bluetooth.phones.get(&addr).cloned() else {
+                return;
+            };
+            match state.call_status() {
+                CallStatus::Idle | CallStatus::Incoming(_) if !state.call_active() => {
+                    self.tones.clear();
+                    ui.horizontal(|ui| {
+                        if ui.button("Contacts").clicked() {
+                            r = Some(Subwindow::Contacts(contacts::Contacts::new()));
+                        }
+                        if state.voice_recognition_supported() {
+                            let text = if state.voice_recognition {
+                                "Stop voice assistant"
+                            } else {
+                                "Voice assistant"
+                            };
+                            if ui.button(text).clicked() {
+                                toggle_assistant(common, addr);
+                            }
+                        }
+                    });
+                    self.dialer(ui, common, addr);
+                }
+                _ if !state.calls.is_empty() => {
+                    if let Some(start) = common.bluetooth.call_start.get(&addr) {
+                        ui.label(
+                            egui::RichText::new(format_duration(start.elapsed()))
+                                .font(egui::FontId::proportional(48.0)),
+                        );
+                    }
+                    audio_label(ui, common, addr);
+                    call_cards(ui, common, addr, &state);
+                    hold_controls(ui, common, addr, &state);
+                    self.in_call(ui, common, addr, &state);
+                    if big_button(ui, "Hang up") {
+                        let _ = common.tx.blocking_send(MessageToAsync::PhoneHangUp(addr));
+                    }
+                }
+                CallStatus::Dialing | CallStatus::Alerting => {
+                    ui.heading(format!("Calling {}", self.number));
+                    if big_button(ui, "Hang up") {
+                        let _ = common.tx.blocking_send(MessageToAsync::PhoneHangUp(addr));
+                    }
+                }
+                _ => {
+                    if let Some(c) = &state.caller {
+                        ui.heading(caller_name(common, addr, c));
+                    }
+                    if let Some(start) = common.bluetooth.call_start.get(&addr) {
+                        ui.label(
+                            egui::RichText::new(format_duration(start.elapsed()))
+                                .font(egui::FontId::proportional(48.0)),
+                        );
+                    }
+                    audio_label(ui, common, addr);
+                    hold_controls(ui, common, addr, &state);
+                    self.in_call(ui, common, addr, &state);
+                    if big_button(ui, "Hang up") {
+                        let _ = common.tx.blocking_send(MessageToAsync::PhoneHangUp(addr));
+                    }
+                }
+            }
+        });
+        r
+    }
+}
@@ -0,0 +1,499 @@
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+use tokio::io::unix::AsyncFd;
+
+use crate::config::VoiceConfig;
+use crate::msbc::{MsbcReader, MsbcWriter};
+use crate::sbc::MSBC_SAMPLES;
+use crate::MessageFromAsync;
+use crate::MessageToAsync;
+
+const BTPROTO_SCO: libc::c_int = 2;
+const SOL_BLUETOOTH: libc::c_int = 274;
+const SOL_SCO: libc::c_int = 17;
+const SCO_OPTIONS: libc::c_int = 1;
+const BT_DEFER_SETUP: libc::c_int = 7;
+const BT_VOICE: libc::c_int = 11;
+const BT_VOICE_TRANSPARENT: u16 = 0x0003;
+const BT_VOICE_CVSD_16BIT: u16 = 0x0060;
+
+/// How much audio the sound card buffers, in microseconds
+const BUFFER_TIME: u32 = 60000;
+
+/// The highest volume of AT+VGS and AT+VGM
+pub const MAX_VOLUME: u8 = 15;
+
+/// Each volume step below the highest lowers the level by this much
+const VOLUME_STEP_DB: f32 = 2.0;
+
+#[repr(C)]
+struct SockaddrSco {
+    family: libc::sa_family_t,
+    /// The address of the device, least significant byte first
+    bdaddr: [u8; 6],
+}
+
+#[repr(C)]
+struct BtVoice {
+    setting: u16,
+}
+
+#[repr(C)]
+struct ScoOptions {
+    mtu: u16,
+}
+
+/// The audio codecs of hands free calls, with their ids from codec negotiation
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Codec {
+    Cvsd,
+    Msbc,
+}
+
+impl Codec {
+    pub fn id(&self) -> u8 {
+        match self {
+            Codec::Cvsd => 1,
+            Codec::Msbc => 2,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            1 => Some(Codec::Cvsd),
+            2 => Some(Codec::Msbc),
+            _ => None,
+        }
+    }
+
+    /// The sample rate of the audio carried by the codec
+    pub fn rate(&self) -> u32 {
+        match self {
+            Codec::Cvsd => 8000,
+            Codec::Msbc => 16000,
+        }
+    }
+
+    /// How the controller handles the audio, converting cvsd itself or passing frames through
+    fn voice_setting(&self) -> u16 {
+        match self {
+            Codec::Cvsd => BT_VOICE_CVSD_16BIT,
+            Codec::Msbc => BT_VOICE_TRANSPARENT,
+        }
+    }
+}
+
+impl std::fmt::Display for Codec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Codec::Cvsd => write!(f, "CVSD"),
+            Codec::Msbc => write!(f, "mSBC"),
+        }
+    }
+}
+
+/// The call volumes the phone and the car agree on, from 0 to MAX_VOLUME
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Volume {
+    pub speaker: u8,
+    pub mic: u8,
+}
+
+impl Default for Volume {
+    fn default() -> Self {
+        Self {
+            speaker: MAX_VOLUME,
+            mic: MAX_VOLUME,
+        }
+    }
+}
+
+/// The gain for a volume step, 0 being silent
+fn volume_gain(v: u8) -> f32 {
+    if v == 0 {
+        0.0
+    } else {
+        let db = -VOLUME_STEP_DB * MAX_VOLUME.saturating_sub(v) as f32;
+        10f32.powf(db / 20.0)
+    }
+}
+
+/// The gains of a call, from the settings and the volume
+#[derive(Clone)]
+struct Levels {
+    settings: tokio::sync::watch::Receiver<VoiceConfig>,
+    volume: tokio::sync::watch::Receiver<Volume>,
+}
+
+impl Levels {
+    fn speaker(&self) -> f32 {
+        self.settings.borrow().speaker_gain * volume_gain(self.volume.borrow().speaker)
+    }
+
+    fn mic(&self) -> f32 {
+        self.settings.borrow().mic_gain * volume_gain(self.volume.borrow().mic)
+    }
+}
+
+/// What is known about the hands free connection of a phone
+struct Link {
+    codec: Codec,
+    volume: tokio::sync::watch::Sender<Volume>,
+}
+
+fn check(r: libc::c_int) -> std::io::Result<libc::c_int> {
+    if r < 0 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(r)
+    }
+}
+
+fn setsockopt<T>(
+    fd: &impl AsRawFd,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: &T,
+) -> std::io::Result<()> {
+    check(unsafe {
+        libc::setsockopt(
+            fd.as_raw_fd(),
+            level,
+            name,
+            value as *const T as *const libc::c_void,
+            std::mem::size_of::<T>() as libc::socklen_t,
+        )
+    })?;
+    Ok(())
+}
+
+/// Waits for audio gateways to open sco links
+struct Listener {
+    fd: AsyncFd<OwnedFd>,
+}
+
+impl Listener {
+    fn bind() -> std::io::Result<Self> {
+        let fd = check(unsafe {
+            libc::socket(
+                libc::AF_BLUETOOTH,
+                libc::SOCK_SEQPACKET | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                BTPROTO_SCO,
+            )
+        })?;
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let addr = SockaddrSco {
+            family: libc::AF_BLUETOOTH as libc::sa_family_t,
+            bdaddr: [0; 6],
+        };
+        check(unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &addr as *const SockaddrSco as *const libc::sockaddr,
+                std::mem::size_of::<SockaddrSco>() as libc::socklen_t,
+            )
+        })?;
+        // Links are only set up once the codec for them has been chosen
+        setsockopt(&fd, SOL_BLUETOOTH, BT_DEFER_SETUP, &1u32)?;
+        check(unsafe { libc::listen(fd.as_raw_fd(), 1) })?;
+        Ok(Self {
+            fd: AsyncFd::new(fd)?,
+        })
+    }
+
+    async fn accept(&self) -> std::io::Result<Incoming> {
+        loop {
+            let mut guard = self.fd.readable().await?;
+            let r = guard.try_io(|fd| {
+                let mut addr = SockaddrSco {
+                    family: 0,
+                    bdaddr: [0; 6],
+                };
+                let mut len = std::mem::size_of::<SockaddrSco>() as libc::socklen_t;
+                let r = check(unsafe {
+                    libc::accept4(
+                        fd.as_raw_fd(),
+                        &mut addr as *mut SockaddrSco as *mut libc::sockaddr,
+                        &mut len,
+                        libc::SOCK_CLOEXEC,
+                    )
+                })?;
+                Ok((r, addr))
+            });
+            if let Ok(r) = r {
+                let (fd, addr) = r?;
+                let mut bdaddr = addr.bdaddr;
+                bdaddr.reverse();
+                return Ok(Incoming {
+                    fd: unsafe { OwnedFd::from_raw_fd(fd) },
+                    addr: bluer::Address::new(bdaddr),
+                });
+            }
+        }
+    }
+}
+
+/// A sco link that has been asked for but not yet set up
+struct Incoming {
+    fd: OwnedFd,
+    addr: bluer::Address,
+}
+
+impl Incoming {
+    /// Set up the link for a codec, returning the socket and its packet size
+    fn accept(self, codec: Codec) -> std::io::Result<(std::fs::File, usize)> {
+        setsockopt(
+            &self.fd,
+            SOL_BLUETOOTH,
+            BT_VOICE,
+            &BtVoice {
+                setting: codec.voice_setting(),
+            },
+        )?;
+        // The first read on a deferred socket lets the link go ahead
+        let mut b = [0u8; 1];
+        check(unsafe {
+            libc::recv(
+                self.fd.as_raw_fd(),
+                b.as_mut_ptr() as *mut libc::c_void,
+                1,
+                0,
+            ) as libc::c_int
+        })?;
+        let mut opts = ScoOptions { mtu: 0 };
+        let mut len = std::mem::size_of::<ScoOptions>() as libc::socklen_t;
+        check(unsafe {
+            libc::getsockopt(
+                self.fd.as_raw_fd(),
+                SOL_SCO,
+                SCO_OPTIONS,
+                &mut opts as *mut ScoOptions as *mut libc::c_void,
+                &mut len,
+            )
+        })?;
+        Ok((std::fs::File::from(self.fd), opts.mtu.max(1) as usize))
+    }
+}
+
+fn open_pcm(device: &str, dir: alsa::Direction, rate: u32) -> Result<alsa::PCM, alsa::Error> {
+    let pcm = alsa::PCM::new(device, dir, false)?;
+    {
+        let hwp = alsa::pcm::HwParams::any(&pcm)?;
+        hwp.set_channels(1)?;
+        hwp.set_rate(rate, alsa::ValueOr::Nearest)?;
+        hwp.set_format(alsa::pcm::Format::s16())?;
+        hwp.set_access(alsa::pcm::Access::RWInterleaved)?;
+        hwp.set_buffer_time_near(BUFFER_TIME, alsa::ValueOr::Nearest)?;
+        pcm.hw_params(&hwp)?;
+    }
+    Ok(pcm)
+}
+
+fn apply_gain(samples: &mut [i16], gain: f32) {
+    if gain != 1.0 {
+        for s in samples {
+            *s = (*s as f32 * gain).clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+        }
+    }
+}
+
+/// Play the voice of the call until the link closes
+fn play(sock: &mut std::fs::File, codec: Codec, levels: &Levels) -> Result<(), alsa::Error> {
+    let device = levels.settings.borrow().output.clone();
+    let pcm = open_pcm(&device, alsa::Direction::Playback, codec.rate())?;
+    let io = pcm.io_i16()?;
+    let mut reader = MsbcReader::new();
+    let mut buf = [0u8; 512];
+    loop {
+        let n = match sock.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) => {
+                println!("Call audio ended {}", e);
+                break;
+            }
+        };
+        let mut samples: Vec<i16> = match codec {
+            Codec::Cvsd => buf[..n]
+                .chunks_exact(2)
+                .map(|b| i16::from_le_bytes([b[0], b[1]]))
+                .collect(),
+            Codec::Msbc => reader.push(&buf[..n]),
+        };
+        if samples.is_empty() {
+            continue;
+        }
+        apply_gain(&mut samples, levels.speaker());
+        if let Err(e) = io.writei(&samples) {
+            pcm.try_recover(e, true)?;
+            io.writei(&samples)?;
+        }
+    }
+    if reader.lost > 0 {
+        println!("Concealed {} lost frames of call audio", reader.lost);
+    }
+    Ok(())
+}
+
+/// Send the microphone to the phone until writing to the link fails
+fn record(
+    mut sock: std::fs::File,
+    mtu: usize,
+    codec: Codec,
+    levels: Levels,
+) -> Result<(), alsa::Error> {
+    let device = levels.settings.borrow().input.clone();
+    let pcm = open_pcm(&device, alsa::Direction::Capture, codec.rate())?;
+    pcm.start()?;
+    let io = pcm.io_i16()?;
+    let mut samples = vec![
+        0i16;
+        match codec {
+            Codec::Cvsd => (mtu / 2).max(1),
+            Codec::Msbc => MSBC_SAMPLES,
+        }
+    ];
+    let mut writer = MsbcWriter::new();
+    let mut out: Vec<u8> = Vec::new();
+    loop {
+        let n = match io.readi(&mut samples) {
+            Ok(n) => n,
+            Err(e) => {
+                pcm.try_recover(e, true)?;
+                continue;
+            }
+        };
+        let s = &mut samples[..n];
+        apply_gain(s, levels.mic());
+        match codec {
+            Codec::Cvsd => out.extend(s.iter().flat_map(|s| s.to_le_bytes())),
+            Codec::Msbc => out.extend(writer.push(s)),
+        }
+        while out.len() >= mtu {
+            let packet: Vec<u8> = out.drain(..mtu).collect();
+            if sock.write(&packet).is_err() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Connect a sco link to the speaker and microphone for the length of a call
+fn bridge(mut sock: std::fs::File, mtu: usize, codec: Codec, levels: Levels) {
+    let recorder = match sock.try_clone() {
+        Ok(s) => {
+            let levels = levels.clone();
+            Some(std::thread::spawn(move || {
+                if let Err(e) = record(s, mtu, codec, levels) {
+                    println!("Failed to record call audio {}", e);
+                }
+            }))
+        }
+        Err(e) => {
+            println!("Unable to send call audio {}", e);
+            None
+        }
+    };
+    if let Err(e) = play(&mut sock, codec, &levels) {
+        println!("Failed to play call audio {}", e);
+    }
+    // Stop the recorder when the link is gone or the speaker failed
+    unsafe {
+        libc::shutdown(sock.as_raw_fd(), libc::SHUT_RDWR);
+    }
+    if let Some(r) = recorder {
+        let _ = r.join();
+    }
+}
+
+/// Wait for the next sco link, or forever if there is no listener
+async fn next_link(l: &Option<Listener>) -> std::io::Result<Incoming> {
+    match l {
+        Some(l) => l.accept().await,
+        None => futures::future::pending().await,
+    }
+}
+
+/// Carry the audio of calls for phones with a hands free connection
+pub async fn voice(
+    tx: tokio::sync::mpsc::Sender<MessageFromAsync>,
+    mut rx: tokio::sync::mpsc::Receiver<MessageToAsync>,
+) {
+    let (settings, settings_rx) = tokio::sync::watch::channel(crate::config::Config::load().voice);
+    let listener = match Listener::bind() {
+        Ok(l) => Some(l),
+        Err(e) => {
+            println!("Unable to listen for call audio {}", e);
+            None
+        }
+    };
+    let mut links: HashMap<bluer::Address, Link> = HashMap::new();
+    let (ended_tx, mut ended) = tokio::sync::mpsc::channel(1);
+    loop {
+        tokio::select! {
+            m = rx.recv() => match m {
+                None => return,
+                Some(MessageToAsync::VoiceCodec(addr, Some(codec))) => match links.get_mut(&addr) {
+                    Some(l) => l.codec = codec,
+                    None => {
+                        let (volume, _) = tokio::sync::watch::channel(Volume::default());
+                        links.insert(addr, Link { codec, volume });
+                    }
+                },
+                Some(MessageToAsync::VoiceCodec(addr, None)) => {
+                    links.remove(&addr);
+                }
+                Some(MessageToAsync::VoiceVolume(addr, v)) => {
+                    if let Some(l) = links.get(&addr) {
+                        l.volume.send_replace(v);
+                    }
+                }
+                Some(MessageToAsync::VoiceSettings(s)) => {
+                    let _ = settings.send(s);
+                }
+                Some(_) => {}
+            },
+            l = next_link(&listener) => {
+                let l = match l {
+                    Ok(l) => l,
+                    Err(e) => {
+                        println!("Failed to accept call audio {}", e);
+                        continue;
+                    }
+                };
+                let addr = l.addr;
+                let Some(link) = links.get(&addr) else {
+                    println!("Refusing call audio from {} without a hands free connection", addr);
+                    continue;
+                };
+                let codec = link.codec;
+                let levels = Levels {
+                    settings: settings_rx.clone(),
+                    volume: link.volume.subscribe(),
+                };
+                let (sock, mtu) = match l.accept(codec) {
+                    Ok(s) => s,
+                    Err(e) => {
+                        println!("Failed to set up call audio from {} {}", addr, e);
+                        continue;
+                    }
+                };
+                println!("Call audio from {} using {} with {} byte packets", addr, codec, mtu);
+                let _ = tx.send(MessageFromAsync::Voice(addr, Some(codec))).await;
+                let ended_tx = ended_tx.clone();
+                tokio::spawn(async move {
+                    let _ =
+                        tokio::task::spawn_blocking(move || bridge(sock, mtu, codec, levels))
+                            .await;
+                    let _ = ended_tx.send(addr).await;
+                });
+            }
+            Some(addr) = ended.recv() => {
+                println!("Call audio from {} closed", addr);
+                let _ = tx.send(MessageFromAsync::Voice(addr, None)).await;
+            }
+        }
+    }
+}
@@ -0,0 +1,139 @@
+use super::CommonWindowProperties;
+use super::MessageToAsync;
+use super::Subwindow;
+use super::SubwindowTrait;
+use crate::gatt::{self, Reading};
+use eframe::egui;
+
+const WHEELS: [&str; 4] = ["Front left", "Front right", "Rear left", "Rear right"];
+
+/// Tire readings below this pressure in kPa are shown as a warning
+const LOW_PRESSURE: f32 = 180.0;
+
+pub struct Sensors {}
+
+impl Sensors {
+    pub fn new() -> Self {
+        Self {}
+    }
+
+    fn tire(ui: &mut egui::Ui, name: &str, reading: Option<&Reading>) {
+        ui.vertical(|ui| {
+            ui.label(name);
+            let Some(Reading::Tire {
+                pressure,
+                temperature,
+                battery,
+                alarm,
+                ..
+            }) = reading
+            else {
+                ui.label(egui::RichText::new("--").font(egui::FontId::proportional(48.0)));
+                return;
+            };
+            let text = egui::RichText::new(format!("{:.0} kPa", pressure))
+                .font(egui::FontId::proportional(48.0));
+            if *alarm || *pressure < LOW_PRESSURE {
+                ui.label(text.color(egui::Color32::RED));
+            } else {
+                ui.label(text);
+            }
+            ui.label(format!("{:.0} °C, battery {}%", temperature, battery));
+        });
+    }
+}
+
+impl SubwindowTrait for Sensors {
+    fn update(
+        &mut self,
+        ctx: &egui::Context,
+        frame: &mut eframe::Frame,
+        common: &mut CommonWindowProperties,
+    ) -> Option<Subwindow> {
+        egui::CentralPanel::default().show(ctx, |ui| {
+            if !common.bluetooth.scanning {
+                ui.horizontal(|ui| {
+                    ui.label("Sensors are only updated while scanning");
+                    if ui.button("Scan").clicked() {
+                        common.bluetooth.scanning = true;
+                        let _ = common.tx.blocking_send(MessageToAsync::BluetoothScan(true));
+                    }
+                });
+            }
+            let mut tires: Vec<Option<Reading>> = vec![None; WHEELS.len()];
+            let mut others: Vec<(String, Vec<Reading>)> = Vec::new();
+            for (addr, dev) in &common.bluetooth.devices {
+                let mut readings = Vec::new();
+                for r in gatt::advertised(dev) {
+                    match r {
+                        Reading::Tire { wheel, .. } if (wheel as usize) < WHEELS.len() => {
+                            tires[wheel as usize] = Some(r);
+                        }
+                        r => readings.push(r),
+                    }
+                }
+                if !readings.is_empty() {
+                    others.push((common.bluetooth.phone_name(addr), readings));
+                }
+            }
+            others.sort_by(|a, b| a.0.cmp(&b.0));
+            ui.heading("Tires");
+            egui::Grid::new("tires")
+                .min_col_width(250.0)
+                .show(ui, |ui| {
+                    for (i, name) in WHEELS.iter().enumerate() {
+                        Self::tire(ui, name, tires[i].as_ref());
+                        if i % 2 == 1 {
+                            ui.end_row();
+                        }
+                    }
+                });
+            ui.separator();
+            ui.heading("Cabin");
+            if others.is_empty() {
+                ui.label("No sensors found");
+            }
+            for (name, readings) in others {
+                ui.horizontal(|ui| {
+                    ui.label(egui::RichText::new(name).strong());
+                    for r in readings {
+                        ui.label(match r {
+                            Reading::Temperature(t) => format!("{:.1} °C", t),
+                            Reading::Humidity(h) => format!("{:.0}% humidity", h),
+                            Reading::Battery(b) => format!("battery {}%", b),
+                            Reading::Tire { pressure, .. } => format!("{:.0} kPa", pressure),
+                        });
+                    }
+                });
+            }
+            // Values of connected sensors, kept up to date by subscriptions
+            for (addr, g) in &common.bluetooth.gatt {
+                let values: Vec<String> = g
+                    .services
+                    .iter()
+                    .flat_map(|s| s.characteristics.iter().map(move |c| (s.id, c)))
+                    .filter_map(|(service, c)| {
+                        let id = gatt::AttributeId {
+                            service,
+                            characteristic: c.id,
+                            descriptor: None,
+                        };
+                        if !g.subscribed.contains(&id) {
+                            return None;
+                        }
+                        gatt::decode(&c.uuid, g.values.get(&id)?)
+                    })
+                    .collect();
+                if !values.is_empty() {
+                    ui.horizontal(|ui| {
+                        ui.label(egui::RichText::new(common.bluetooth.phone_name(addr)).strong());
+                        for v in values {
+                            ui.label(v);
+                        }
+                    });
+                }
+            }
+        });
+        None
+    }
+}
@@ -1,20 +1,160 @@
 use super::CommonWindowProperties;
+use super::MessageToAsync;
 use super::Subwindow;
 use super::SubwindowTrait;
 use eframe::egui;
 
+/// A sound card device that call audio can use
+struct AudioDevice {
+    name: String,
+    description: String,
+    /// The one way the device works, or none if it can play and record
+    direction: Option<alsa::Direction>,
+}
+
+fn audio_devices() -> Vec<AudioDevice> {
+    let hints = match alsa::device_name::HintIter::new_str(None, "pcm") {
+        Ok(h) => h,
+        Err(e) => {
+            println!("Unable to list sound devices {}", e);
+            return Vec::new();
+        }
+    };
+    hints
+        .filter_map(|h| {
+            let name = h.name?;
+            let description = h
+                .desc
+                .map(|d| d.replace('\n', " "))
+                .unwrap_or_else(|| name.clone());
+            Some(AudioDevice {
+                name,
+                description,
+                direction: h.direction,
+            })
+        })
+        .collect()
+}
+
+/// A combo box choosing a device that works in one direction
+fn device_choice(
+    ui: &mut egui::Ui,
+    label: &str,
+    device: &mut String,
+    devices: &[AudioDevice],
+    direction: alsa::Direction,
+) -> bool {
+    let mut changed = false;
+    let selected = devices
+        .iter()
+        .find(|d| d.name == *device)
+        .map(|d| d.description.clone())
+        .unwrap_or_else(|| device.clone());
+    egui::ComboBox::from_label(label)
+        .selected_text(selected)
+        .show_ui(ui, |ui| {
+            changed |= ui
+                .selectable_value(device, "default".to_string(), "default")
+                .changed();
+            for d in devices
+                .iter()
+                .filter(|d| d.direction.map(|dir| dir == direction).unwrap_or(true))
+            {
+                changed |= ui
+                    .selectable_value(device, d.name.clone(), d.description.as_str())
+                    .changed();
+            }
+        });
+    changed
+}
+
+/// Whether a slider has a new value that is not still being dragged
+pub fn settled(r: &egui::Response) -> bool {
+    r.drag_stopped() || (r.changed() && !r.dragged())
+}
+
 pub struct Settings {
-    selected_video: usize,
+    /// The device node of the camera being set up
+    selected_video: Option<std::path::PathBuf>,
     texture: Option<egui::TextureHandle>,
+    /// The camera the texture was made for
+    texture_path: Option<std::path::PathBuf>,
+    /// Sound devices, listed the first time they are shown
+    audio_devices: Option<Vec<AudioDevice>>,
 }
 
 impl Settings {
     pub fn new() -> Self {
         Self {
-            selected_video: 0,
+            selected_video: None,
             texture: None,
+            texture_path: None,
+            audio_devices: None,
         }
     }
+
+    fn call_audio(&mut self, ui: &mut egui::Ui, common: &mut CommonWindowProperties) {
+        egui::CollapsingHeader::new("Call audio").show(ui, |ui| {
+            let devices = self.audio_devices.get_or_insert_with(audio_devices);
+            let c = &mut common.config.voice;
+            let mut changed = false;
+            // Gains are sent while a slider moves but only saved once it is let go
+            let mut save = false;
+            ui.horizontal(|ui| {
+                save |= device_choice(
+                    ui,
+                    "Speaker",
+                    &mut c.output,
+                    devices,
+                    alsa::Direction::Playback,
+                );
+                let r = ui.add(egui::Slider::new(&mut c.speaker_gain, 0.0..=4.0).text("Gain"));
+                changed |= r.changed();
+                save |= settled(&r);
+            });
+            ui.horizontal(|ui| {
+                save |= device_choice(
+                    ui,
+                    "Microphone",
+                    &mut c.input,
+                    devices,
+                    alsa::Direction::Capture,
+                );
+                let r = ui.add(egui::Slider::new(&mut c.mic_gain, 0.0..=4.0).text("Gain"));
+                changed |= r.changed();
+                save |= settled(&r);
+            });
+            save |= ui
+                .checkbox(&mut c.msbc, "Wide band speech (mSBC)")
+                .changed();
+            ui.label("Devices apply from the next call, wide band speech from the next connection");
+            if save {
+                common.config.save();
+            }
+            if changed || save {
+                let _ = common
+                    .tx
+                    .blocking_send(MessageToAsync::VoiceSettings(common.config.voice.clone()));
+            }
+        });
+    }
+
+    fn received_files(&mut self, ui: &mut egui::Ui, common: &mut CommonWindowProperties) {
+        egui::CollapsingHeader::new("Received files").show(ui, |ui| {
+            let c = &mut common.config.object_push;
+            let mut mb = c.max_size / (1024 * 1024);
+            let r = ui.add(egui::Slider::new(&mut mb, 1..=100).text("Largest file (MB)"));
+            if r.changed() {
+                c.max_size = mb * 1024 * 1024;
+            }
+            if settled(&r) {
+                common.config.save();
+                let _ = common.tx.blocking_send(MessageToAsync::PushSettings(
+                    common.config.object_push.clone(),
+                ));
+            }
+        });
+    }
 }
 
 impl SubwindowTrait for Settings {
@@ -29,22 +169,33 @@ impl SubwindowTrait for Settings {
             size.x *= 0.95;
             size.y *= 0.95;
             ui.label("Settings");
-            if !common.video_sources.is_empty() {
+            self.call_audio(ui, common);
+            self.received_files(ui, common);
+            // Fall back to the first camera when the selected one was unplugged
+            let selected = common
+                .video_sources
+                .iter()
+                .position(|v| self.selected_video.as_ref() == Some(&v.path))
+                .or((!common.video_sources.is_empty()).then_some(0));
+            if let Some(selected) = selected {
+                let v = &common.video_sources[selected];
+                self.selected_video = Some(v.path.clone());
+                if self.texture_path.as_ref() != Some(&v.path) {
+                    self.texture = None;
+                    self.texture_path = Some(v.path.clone());
+                }
                 ui.horizontal(|ui| {
                     ui.vertical(|ui| {
                         egui::ComboBox::from_label("Select a camera")
-                            .selected_text(format!("Camera {}", self.selected_video))
+                            .selected_text(common.video_sources[selected].label())
                             .show_ui(ui, |ui| {
-                                for i in 0..common.video_sources.len() {
-                                    if ui
-                                        .selectable_label(false, format!("Camera {}", i))
-                                        .clicked()
-                                    {
-                                        self.selected_video = i;
+                                for (i, v) in common.video_sources.iter().enumerate() {
+                                    if ui.selectable_label(i == selected, v.label()).clicked() {
+                                        self.selected_video = Some(v.path.clone());
                                     }
                                 }
                             });
-                        let vsrc = &mut common.video_sources[self.selected_video];
+                        let vsrc = &mut common.video_sources[selected];
                         for c in &mut vsrc.controls {
                             if c.egui_show(ui) {
                                 c.send_update(&mut vsrc.vsend);
@@ -55,7 +206,7 @@ impl SubwindowTrait for Settings {
                             ui.checkbox(&mut i.vmirror, "V Mirror");
                         }
                     });
-                    let vsrc = &mut common.video_sources[self.selected_video];
+                    let vsrc = &mut common.video_sources[selected];
                     ui.with_layout(egui::Layout::top_down(egui::Align::TOP), |ui| {
                         if let Ok(i) = vsrc.image.lock() {
                             if let Some(pd) = &i.pixel_data {
@@ -0,0 +1,385 @@
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::StreamExt;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use crate::MessageFromAsync;
+use crate::MessageToAsync;
+
+/// The uuid of the serial port profile
+pub const SPP_UUID: &str = "00001101-0000-1000-8000-00805f9b34fb";
+
+/// How long bluez gets to find the serial port and connect to it
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A connection to the serial port of a device
+pub struct SppStream {
+    stream: bluer::rfcomm::Stream,
+    /// Unregistering the profile would make bluez shut the connection down
+    _profile: bluer::rfcomm::ProfileHandle,
+    _session: bluer::Session,
+}
+
+impl AsyncRead for SppStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for SppStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+fn other(e: impl std::fmt::Display) -> std::io::Error {
+    std::io::Error::other(e.to_string())
+}
+
+/// Connect to the serial port of a device.
+/// Bluez looks the port up in the service records of the device, so it does not
+/// matter which rfcomm channel the device put it on.
+pub async fn connect(addr: bluer::Address) -> std::io::Result<SppStream> {
+    let uuid = bluer::Uuid::from_str(SPP_UUID).unwrap();
+    let session = bluer::Session::new().await.map_err(other)?;
+    let mut adapter = None;
+    for n in session.adapter_names().await.map_err(other)? {
+        let a = session.adapter(&n).map_err(other)?;
+        if a.device_addresses().await.map_err(other)?.contains(&addr) {
+            adapter = Some(a);
+            break;
+        }
+    }
+    let adapter = adapter
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "Unknown device"))?;
+    let mut profile = session
+        .register_profile(bluer::rfcomm::Profile {
+            uuid,
+            name: Some("Serial port".to_string()),
+            role: Some(bluer::rfcomm::Role::Client),
+            require_authentication: Some(false),
+            require_authorization: Some(false),
+            auto_connect: Some(false),
+            ..Default::default()
+        })
+        .await
+        .map_err(other)?;
+    let dev = adapter.device(addr).map_err(other)?;
+    // Bluez hands the connection to the profile before the call to connect returns
+    let connecting = dev.connect_profile(&uuid);
+    tokio::pin!(connecting);
+    let mut connected = false;
+    let stream = tokio::time::timeout(CONNECT_TIMEOUT, async {
+        loop {
+            tokio::select! {
+                r = &mut connecting, if !connected => {
+                    r.map_err(other)?;
+                    connected = true;
+                }
+                req = profile.next() => {
+                    let Some(req) = req else {
+                        return Err(other("Serial port profile closed"));
+                    };
+                    if req.device() != addr {
+                        req.reject(bluer::rfcomm::ReqError::Rejected);
+                        continue;
+                    }
+                    return req.accept().map_err(other);
+                }
+            }
+        }
+    })
+    .await
+    .map_err(|_| {
+        std::io::Error::new(std::io::ErrorKind::TimedOut, "No serial port connection")
+    })??;
+    Ok(SppStream {
+        stream,
+        _profile: profile,
+        _session: session,
+    })
+}
+
+/// How many lines the terminal keeps
+pub const TERMINAL_LINES: usize = 500;
+
+/// How long a line without an ending waits for the rest before it is shown, for prompts
+const PARTIAL_TIMEOUT: Duration = Duration::from_millis(300);
+
+/// A line in the terminal
+#[derive(Clone, Debug, PartialEq)]
+pub enum TerminalLine {
+    Sent(String),
+    Received(String),
+}
+
+/// What the terminal is connected to
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum TerminalStatus {
+    #[default]
+    Disconnected,
+    Connecting(bluer::Address),
+    Connected(bluer::Address),
+    Failed(String),
+}
+
+/// The terminal as the gui shows it
+#[derive(Clone, Debug, Default)]
+pub struct TerminalState {
+    pub status: TerminalStatus,
+    /// Oldest first
+    pub lines: VecDeque<TerminalLine>,
+}
+
+impl TerminalState {
+    pub fn add(&mut self, l: TerminalLine) {
+        if self.lines.len() >= TERMINAL_LINES {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(l);
+    }
+}
+
+/// Cuts what a device sends into lines, whichever line ending it uses
+#[derive(Default)]
+struct LineBuffer {
+    partial: Vec<u8>,
+}
+
+impl LineBuffer {
+    /// Add received bytes, returning the lines they finish
+    fn push(&mut self, data: &[u8]) -> Vec<String> {
+        let mut lines = Vec::new();
+        for b in data {
+            if *b == b'\r' || *b == b'\n' {
+                // The second half of a \r\n ends an empty line, which is not shown
+                if !self.partial.is_empty() {
+                    lines.push(String::from_utf8_lossy(&self.partial).into_owned());
+                    self.partial.clear();
+                }
+            } else {
+                self.partial.push(*b);
+            }
+        }
+        lines
+    }
+
+    /// Take the unfinished line, if any
+    fn flush(&mut self) -> Option<String> {
+        if self.partial.is_empty() {
+            return None;
+        }
+        let l = String::from_utf8_lossy(&self.partial).into_owned();
+        self.partial.clear();
+        Some(l)
+    }
+}
+
+/// Pass lines between the gui and a device until asked to disconnect or the device goes away,
+/// giving the device to connect to next when asked to switch to another one
+async fn terminal_session<S: AsyncRead + AsyncWrite>(
+    addr: bluer::Address,
+    stream: S,
+    tx: &tokio::sync::mpsc::Sender<MessageFromAsync>,
+    rx: &mut tokio::sync::mpsc::Receiver<MessageToAsync>,
+) -> std::io::Result<Option<bluer::Address>> {
+    let (mut r, mut w) = tokio::io::split(stream);
+    let mut lines = LineBuffer::default();
+    let mut buf = [0u8; 1024];
+    loop {
+        let waiting = !lines.partial.is_empty();
+        tokio::select! {
+            n = r.read(&mut buf) => {
+                let n = n?;
+                let mut received = lines.push(&buf[..n]);
+                if n == 0 {
+                    received.extend(lines.flush());
+                }
+                for l in received {
+                    let _ = tx
+                        .send(MessageFromAsync::TerminalLine(TerminalLine::Received(l)))
+                        .await;
+                }
+                if n == 0 {
+                    println!("Serial port of {} closed", addr);
+                    return Ok(None);
+                }
+            }
+            _ = tokio::time::sleep(PARTIAL_TIMEOUT), if waiting => {
+                if let Some(l) = lines.flush() {
+                    let _ = tx
+                        .send(MessageFromAsync::TerminalLine(TerminalLine::Received(l)))
+                        .await;
+                }
+            }
+            m = rx.recv() => match m {
+                None | Some(MessageToAsync::TerminalDisconnect) => return Ok(None),
+                Some(MessageToAsync::TerminalSend(s)) => {
+                    w.write_all(s.as_bytes()).await?;
+                    w.flush().await?;
+                    let l = s.trim_end_matches(['\r', '\n']).to_string();
+                    let _ = tx
+                        .send(MessageFromAsync::TerminalLine(TerminalLine::Sent(l)))
+                        .await;
+                }
+                Some(MessageToAsync::TerminalConnect(a)) if a != addr => return Ok(Some(a)),
+                Some(_) => continue,
+            },
+        }
+    }
+}
+
+/// Connect the terminal to serial ports when asked and pass lines to and from the gui
+pub async fn terminal(
+    tx: tokio::sync::mpsc::Sender<MessageFromAsync>,
+    mut rx: tokio::sync::mpsc::Receiver<MessageToAsync>,
+) {
+    let mut next = None;
+    loop {
+        let addr = match next.take() {
+            Some(a) => a,
+            None => match rx.recv().await {
+                Some(MessageToAsync::TerminalConnect(a)) => a,
+                Some(_) => continue,
+                None => return,
+            },
+        };
+        let _ = tx
+            .send(MessageFromAsync::TerminalStatus(
+                TerminalStatus::Connecting(addr),
+            ))
+            .await;
+        let r = match connect(addr).await {
+            Ok(s) => {
+                let _ = tx
+                    .send(MessageFromAsync::TerminalStatus(TerminalStatus::Connected(
+                        addr,
+                    )))
+                    .await;
+                terminal_session(addr, s, &tx, &mut rx).await
+            }
+            Err(e) => Err(e),
+        };
+        let status = match r {
+            Ok(n) => {
+                next = n;
+                TerminalStatus::Disconnected
+            }
+            Err(e) => {
+                println!("Serial port connection to {} failed {}", addr, e);
+                TerminalStatus::Failed(e.to_string())
+            }
+        };
+        let _ = tx.send(MessageFromAsync::TerminalStatus(status)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncBufReadExt;
+
+    const DEVICE: bluer::Address = bluer::Address::new([0x00, 0x1d, 0xa5, 0x68, 0x98, 0x8b]);
+
+    async fn next_line(from: &mut tokio::sync::mpsc::Receiver<MessageFromAsync>) -> TerminalLine {
+        match tokio::time::timeout(Duration::from_secs(5), from.recv()).await {
+            Ok(Some(MessageFromAsync::TerminalLine(l))) => l,
+            _ => panic!("No line from the terminal"),
+        }
+    }
+
+    #[test]
+    fn lines() {
+        let mut b = LineBuffer::default();
+        assert_eq!(b.push(b"ELM327 v1.5\r\r>"), vec!["ELM327 v1.5"]);
+        assert_eq!(b.flush(), Some(">".to_string()));
+        assert_eq!(b.flush(), None);
+        assert_eq!(b.push(b"OK\r\nREADY"), vec!["OK"]);
+        assert_eq!(b.push(b"\n\n"), vec!["READY"]);
+        assert!(b.push(b"41 0C").is_empty());
+        assert_eq!(b.push(b" 1A F8\r"), vec!["41 0C 1A F8"]);
+    }
+
+    #[tokio::test]
+    async fn session() {
+        let (ours, theirs) = tokio::io::duplex(64);
+        let (tx, mut from) = tokio::sync::mpsc::channel(10);
+        let (to, mut rx) = tokio::sync::mpsc::channel(10);
+        let device = async move {
+            let (r, mut w) = tokio::io::split(theirs);
+            let mut r = tokio::io::BufReader::new(r);
+            w.write_all(b"READY\r\n>").await.unwrap();
+            let mut cmd = Vec::new();
+            r.read_until(b'\r', &mut cmd).await.unwrap();
+            assert_eq!(cmd, b"ATI\r");
+            w.write_all(b"ELM327 v1.5\r\r>").await.unwrap();
+            // Hanging up ends the session
+        };
+        let script = async move {
+            assert_eq!(
+                next_line(&mut from).await,
+                TerminalLine::Received("READY".to_string())
+            );
+            // The prompt has no line ending and is shown once nothing more comes
+            assert_eq!(
+                next_line(&mut from).await,
+                TerminalLine::Received(">".to_string())
+            );
+            to.send(MessageToAsync::TerminalSend("ATI\r".to_string()))
+                .await
+                .unwrap();
+            assert_eq!(
+                next_line(&mut from).await,
+                TerminalLine::Sent("ATI".to_string())
+            );
+            assert_eq!(
+                next_line(&mut from).await,
+                TerminalLine::Received("ELM327 v1.5".to_string())
+            );
+            assert_eq!(
+                next_line(&mut from).await,
+                TerminalLine::Received(">".to_string())
+            );
+            to
+        };
+        let (r, (), _to) =
+            tokio::join!(terminal_session(DEVICE, ours, &tx, &mut rx), device, script);
+        assert_eq!(r.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn switch() {
+        let (ours, _theirs) = tokio::io::duplex(64);
+        let (tx, _from) = tokio::sync::mpsc::channel(10);
+        let (to, mut rx) = tokio::sync::mpsc::channel(10);
+        let other = bluer::Address::new([0x00, 0x1d, 0xa5, 0x00, 0x00, 0x01]);
+        to.send(MessageToAsync::TerminalConnect(DEVICE))
+            .await
+            .unwrap();
+        to.send(MessageToAsync::TerminalConnect(other))
+            .await
+            .unwrap();
+        let r = terminal_session(DEVICE, ours, &tx, &mut rx).await;
+        assert_eq!(r.unwrap(), Some(other));
+    }
+}
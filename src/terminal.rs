@@ -0,0 +1,152 @@
+use std::str::FromStr;
+
+use super::CommonWindowProperties;
+use super::MessageToAsync;
+use super::Subwindow;
+use super::SubwindowTrait;
+use crate::spp::{TerminalLine, TerminalStatus, SPP_UUID};
+use eframe::egui;
+
+/// What is sent after each line
+#[derive(Clone, Copy, PartialEq)]
+enum LineEnding {
+    Cr,
+    Lf,
+    CrLf,
+}
+
+impl LineEnding {
+    fn text(self) -> &'static str {
+        match self {
+            LineEnding::Cr => "\r",
+            LineEnding::Lf => "\n",
+            LineEnding::CrLf => "\r\n",
+        }
+    }
+}
+
+pub struct Terminal {
+    input: String,
+    ending: LineEnding,
+}
+
+impl Terminal {
+    pub fn new() -> Self {
+        Self {
+            input: String::new(),
+            // What ELM327 adapters and most modems expect
+            ending: LineEnding::Cr,
+        }
+    }
+
+    /// Buttons to connect to the serial port devices that have been found
+    fn devices(ui: &mut egui::Ui, common: &CommonWindowProperties) {
+        let spp = bluer::Uuid::from_str(SPP_UUID).unwrap();
+        let mut any = false;
+        for (addr, dev) in &common.bluetooth.devices {
+            if !dev.uuids.contains(&spp) {
+                continue;
+            }
+            any = true;
+            if ui
+                .button(format!("Connect to {}", common.bluetooth.phone_name(addr)))
+                .clicked()
+            {
+                let _ = common
+                    .tx
+                    .blocking_send(MessageToAsync::TerminalConnect(*addr));
+            }
+        }
+        if !any {
+            ui.label("No serial port devices found, pair one from the bluetooth page");
+        }
+    }
+
+    fn send(&mut self, common: &CommonWindowProperties) {
+        let line = format!("{}{}", self.input, self.ending.text());
+        let _ = common.tx.blocking_send(MessageToAsync::TerminalSend(line));
+        self.input.clear();
+    }
+}
+
+impl SubwindowTrait for Terminal {
+    fn update(
+        &mut self,
+        ctx: &egui::Context,
+        frame: &mut eframe::Frame,
+        common: &mut CommonWindowProperties,
+    ) -> Option<Subwindow> {
+        egui::CentralPanel::default().show(ctx, |ui| {
+            let connected = match &common.bluetooth.terminal.status {
+                TerminalStatus::Disconnected => {
+                    ui.heading("Serial terminal");
+                    Self::devices(ui, common);
+                    false
+                }
+                TerminalStatus::Failed(e) => {
+                    ui.heading("Serial port connection failed");
+                    ui.colored_label(egui::Color32::RED, e);
+                    Self::devices(ui, common);
+                    false
+                }
+                TerminalStatus::Connecting(addr) => {
+                    ui.horizontal(|ui| {
+                        ui.spinner();
+                        ui.label(format!(
+                            "Connecting to {}",
+                            common.bluetooth.phone_name(addr)
+                        ));
+                    });
+                    false
+                }
+                TerminalStatus::Connected(addr) => {
+                    ui.horizontal(|ui| {
+                        ui.heading(common.bluetooth.phone_name(addr));
+                        if ui.button("Disconnect").clicked() {
+                            let _ = common.tx.blocking_send(MessageToAsync::TerminalDisconnect);
+                        }
+                    });
+                    true
+                }
+            };
+            ui.horizontal(|ui| {
+                let input = ui.add_enabled(
+                    connected,
+                    egui::TextEdit::singleline(&mut self.input)
+                        .font(egui::FontId::monospace(24.0))
+                        .desired_width(500.0),
+                );
+                let enter = input.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+                if ui
+                    .add_enabled(connected, egui::Button::new("Send"))
+                    .clicked()
+                    || (connected && enter)
+                {
+                    self.send(common);
+                    input.request_focus();
+                }
+                ui.selectable_value(&mut self.ending, LineEnding::Cr, "CR");
+                ui.selectable_value(&mut self.ending, LineEnding::Lf, "LF");
+                ui.selectable_value(&mut self.ending, LineEnding::CrLf, "CR LF");
+            });
+            ui.separator();
+            egui::scroll_area::ScrollArea::vertical()
+                .auto_shrink([false; 2])
+                .stick_to_bottom(true)
+                .show(ui, |ui| {
+                    for l in &common.bluetooth.terminal.lines {
+                        let (text, color) = match l {
+                            TerminalLine::Sent(t) => (t, egui::Color32::LIGHT_BLUE),
+                            TerminalLine::Received(t) => (t, ui.visuals().text_color()),
+                        };
+                        ui.label(
+                            egui::RichText::new(text)
+                                .font(egui::FontId::monospace(20.0))
+                                .color(color),
+                        );
+                    }
+                });
+        });
+        None
+    }
+}
@@ -0,0 +1,325 @@
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PhoneNumber {
+    pub number: String,
+    /// The kind of number, such as CELL, HOME or WORK
+    pub kind: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CallKind {
+    Missed,
+    Received,
+    Dialed,
+}
+
+/// The call history entry attached to a vcard by the phone
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CallRecord {
+    pub kind: CallKind,
+    /// The time of the call as sent by the phone, like 20240102T101500
+    pub time: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VCard {
+    pub name: String,
+    pub numbers: Vec<PhoneNumber>,
+    pub call: Option<CallRecord>,
+}
+
+impl VCard {
+    /// The name to show for the card, falling back to the first number
+    pub fn display_name(&self) -> &str {
+        if !self.name.is_empty() {
+            &self.name
+        } else if let Some(n) = self.numbers.first() {
+            &n.number
+        } else {
+            "Unknown"
+        }
+    }
+
+    /// The key contacts are sorted and indexed by
+    pub fn sort_key(&self) -> String {
+        self.display_name().to_lowercase()
+    }
+}
+
+/// Type parameters that say nothing about which number it is
+const IGNORED_TYPES: [&str; 3] = ["VOICE", "PREF", "INTERNET"];
+
+/// One logical line of a vcard, split into its parts
+struct Property {
+    name: String,
+    params: Vec<(String, String)>,
+    value: String,
+}
+
+impl Property {
+    fn parse(line: &str) -> Option<Self> {
+        let (head, value) = line.split_once(':')?;
+        let mut parts = head.split(';');
+        let name = parts.next()?;
+        let name = name.rsplit('.').next().unwrap_or(name).to_uppercase();
+        let mut params = Vec::new();
+        for p in parts {
+            match p.split_once('=') {
+                Some((k, v)) => {
+                    for v in v.split(',') {
+                        params.push((k.to_uppercase(), v.trim_matches('"').to_uppercase()));
+                    }
+                }
+                // vCard 2.1 allows bare types like TEL;CELL
+                None => {
+                    let p = p.to_uppercase();
+                    let k = if p == "QUOTED-PRINTABLE" || p == "BASE64" {
+                        "ENCODING"
+                    } else {
+                        "TYPE"
+                    };
+                    params.push((k.to_string(), p));
+                }
+            }
+        }
+        let mut prop = Self {
+            name,
+            params,
+            value: String::new(),
+        };
+        prop.value = if prop.param("ENCODING", "QUOTED-PRINTABLE") {
+            quoted_printable(value)
+        } else {
+            value.to_string()
+        };
+        Some(prop)
+    }
+
+    fn param(&self, key: &str, value: &str) -> bool {
+        self.params.iter().any(|(k, v)| k == key && v == value)
+    }
+
+    fn types(&self) -> impl Iterator<Item = &str> {
+        self.params
+            .iter()
+            .filter(|(k, _)| k == "TYPE")
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+fn is_quoted_printable(line: &str) -> bool {
+    line.split(':')
+        .next()
+        .map(|h| h.to_uppercase().contains("QUOTED-PRINTABLE"))
+        .unwrap_or(false)
+}
+
+/// Join folded lines back together
+fn unfold(text: &str) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    let mut soft_break = false;
+    for l in text.lines() {
+        let l = l.strip_suffix('\r').unwrap_or(l);
+        if soft_break {
+            // A quoted printable value continues after a trailing =
+            if let Some(last) = lines.last_mut() {
+                last.pop();
+                last.push_str(l);
+            }
+        } else if l.starts_with(' ') || l.starts_with('\t') {
+            if let Some(last) = lines.last_mut() {
+                last.push_str(&l[1..]);
+            }
+        } else if !l.is_empty() {
+            lines.push(l.to_string());
+        }
+        soft_break = lines
+            .last()
+            .map(|last| last.ends_with('=') && is_quoted_printable(last))
+            .unwrap_or(false);
+    }
+    lines
+}
+
+fn quoted_printable(v: &str) -> String {
+    let b = v.as_bytes();
+    let mut out = Vec::with_capacity(b.len());
+    let mut i = 0;
+    while i < b.len() {
+        if b[i] == b'=' && i + 2 < b.len() {
+            let hex = std::str::from_utf8(&b[i + 1..i + 3])
+                .ok()
+                .and_then(|h| u8::from_str_radix(h, 16).ok());
+            if let Some(h) = hex {
+                out.push(h);
+                i += 3;
+                continue;
+            }
+        }
+        out.push(b[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Split a structured value on unescaped semicolons, removing escapes
+fn components(v: &str) -> Vec<String> {
+    let mut out = vec![String::new()];
+    let mut chars = v.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('n') | Some('N') => out.last_mut().unwrap().push('\n'),
+                Some(e) => out.last_mut().unwrap().push(e),
+                None => {}
+            },
+            ';' => out.push(String::new()),
+            c => out.last_mut().unwrap().push(c),
+        }
+    }
+    out
+}
+
+fn unescape(v: &str) -> String {
+    components(v).join(";")
+}
+
+/// Parse every vcard in a phonebook, as sent over PBAP in vCard 2.1 or 3.0 format
+pub fn parse(text: &str) -> Vec<VCard> {
+    let mut cards = Vec::new();
+    let mut card: Option<VCard> = None;
+    for line in unfold(text) {
+        let Some(p) = Property::parse(&line) else {
+            continue;
+        };
+        if p.name == "BEGIN" && p.value.eq_ignore_ascii_case("VCARD") {
+            card = Some(VCard::default());
+            continue;
+        }
+        let Some(c) = card.as_mut() else {
+            continue;
+        };
+        match p.name.as_str() {
+            "END" => {
+                if let Some(c) = card.take() {
+                    cards.push(c);
+                }
+            }
+            "FN" => {
+                let n = unescape(&p.value).trim().to_string();
+                if !n.is_empty() {
+                    c.name = n;
+                }
+            }
+            "N" if c.name.is_empty() => {
+                let n = components(&p.value);
+                let part = |i: usize| n.get(i).map(|s| s.trim()).unwrap_or("");
+                c.name = [part(3), part(1), part(2), part(0), part(4)]
+                    .iter()
+                    .filter(|s| !s.is_empty())
+                    .copied()
+                    .collect::<Vec<&str>>()
+                    .join(" ");
+            }
+            "TEL" => {
+                let number = unescape(&p.value).trim().to_string();
+                if !number.is_empty() {
+                    let kind = p
+                        .types()
+                        .find(|t| !IGNORED_TYPES.contains(t))
+                        .map(|t| t.to_string());
+                    c.numbers.push(PhoneNumber { number, kind });
+                }
+            }
+            "X-IRMC-CALL-DATETIME" => {
+                let kind = p.types().find_map(|t| match t {
+                    "MISSED" => Some(CallKind::Missed),
+                    "RECEIVED" => Some(CallKind::Received),
+                    "DIALED" => Some(CallKind::Dialed),
+                    _ => None,
+                });
+                if let Some(kind) = kind {
+                    let time = p.value.trim();
+                    c.call = Some(CallRecord {
+                        kind,
+                        time: (!time.is_empty()).then(|| time.to_string()),
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+    cards
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vcard_21() {
+        let cards = parse(include_str!("../testdata/phonebook21.vcf"));
+        assert_eq!(cards.len(), 4);
+        assert_eq!(cards[0].name, "");
+        assert!(cards[0].numbers.is_empty());
+        assert_eq!(cards[1].name, "Alice Anderson");
+        assert_eq!(
+            cards[1].numbers,
+            vec![
+                PhoneNumber {
+                    number: "+15551234567".to_string(),
+                    kind: Some("CELL".to_string()),
+                },
+                PhoneNumber {
+                    number: "555-0100".to_string(),
+                    kind: Some("WORK".to_string()),
+                },
+            ]
+        );
+        assert_eq!(cards[2].name, "Zoë Müller");
+        assert_eq!(cards[2].numbers[0].kind, Some("HOME".to_string()));
+        assert_eq!(cards[3].name, "Bob Brown Jr.");
+        assert_eq!(cards[3].numbers[0].number, "5550199");
+        assert_eq!(cards[3].numbers[0].kind, None);
+    }
+
+    #[test]
+    fn vcard_30() {
+        let cards = parse(include_str!("../testdata/phonebook30.vcf"));
+        assert_eq!(cards.len(), 3);
+        assert_eq!(cards[0].name, "Carol Smith, PhD");
+        assert_eq!(cards[0].numbers.len(), 2);
+        assert_eq!(cards[0].numbers[1].kind, Some("HOME".to_string()));
+        assert_eq!(cards[1].name, "Dave Long Folded Name");
+        assert_eq!(cards[1].numbers[0].number, "+44 20 7946 0958");
+        assert_eq!(cards[2].name, "Eve");
+        assert_eq!(cards[2].numbers[0].kind, Some("CELL".to_string()));
+    }
+
+    #[test]
+    fn call_history() {
+        let cards = parse(include_str!("../testdata/history.vcf"));
+        assert_eq!(cards.len(), 3);
+        assert_eq!(
+            cards[0].call,
+            Some(CallRecord {
+                kind: CallKind::Missed,
+                time: Some("20240102T101500".to_string()),
+            })
+        );
+        assert_eq!(cards[0].name, "Alice Anderson");
+        assert_eq!(cards[1].call.as_ref().unwrap().kind, CallKind::Received);
+        assert_eq!(cards[1].display_name(), "+15557654321");
+        assert_eq!(cards[2].call.as_ref().unwrap().kind, CallKind::Dialed);
+        assert_eq!(cards[2].call.as_ref().unwrap().time, None);
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(
+            components("Smith;John\\; Jr;;Dr\\,"),
+            vec!["Smith", "John; Jr", "", "Dr,"]
+        );
+        assert_eq!(quoted_printable("Zo=C3=AB"), "Zoë");
+        assert_eq!(quoted_printable("100=25 = fine"), "100% = fine");
+    }
+}
@@ -0,0 +1,132 @@
+use std::str::FromStr;
+
+use super::CommonWindowProperties;
+use super::MessageToAsync;
+use super::Subwindow;
+use super::SubwindowTrait;
+use crate::obd::{ObdStatus, Pid};
+use crate::spp::SPP_UUID;
+use eframe::egui;
+
+fn pid_label(pid: Pid) -> (&'static str, &'static str) {
+    match pid {
+        Pid::Rpm => ("Engine speed", "rpm"),
+        Pid::Speed => ("Speed", "km/h"),
+        Pid::Coolant => ("Coolant", "°C"),
+        Pid::FuelLevel => ("Fuel", "%"),
+        Pid::Maf => ("Air flow", "g/s"),
+    }
+}
+
+pub struct Vehicle {}
+
+impl Vehicle {
+    pub fn new() -> Self {
+        Self {}
+    }
+
+    /// Buttons to connect to the serial port devices that have been found
+    fn adapters(ui: &mut egui::Ui, common: &CommonWindowProperties) {
+        let spp = bluer::Uuid::from_str(SPP_UUID).unwrap();
+        let mut any = false;
+        for (addr, dev) in &common.bluetooth.devices {
+            if !dev.uuids.contains(&spp) {
+                continue;
+            }
+            any = true;
+            if ui
+                .button(format!("Connect to {}", common.bluetooth.phone_name(addr)))
+                .clicked()
+            {
+                let _ = common.tx.blocking_send(MessageToAsync::ObdConnect(*addr));
+            }
+        }
+        if !any {
+            ui.label("No OBD-II adapters found, pair one from the bluetooth page");
+        }
+    }
+}
+
+impl SubwindowTrait for Vehicle {
+    fn update(
+        &mut self,
+        ctx: &egui::Context,
+        frame: &mut eframe::Frame,
+        common: &mut CommonWindowProperties,
+    ) -> Option<Subwindow> {
+        egui::CentralPanel::default().show(ctx, |ui| {
+            let data = &common.bluetooth.vehicle;
+            match &data.status {
+                ObdStatus::Disconnected => {
+                    ui.heading("No OBD-II adapter connected");
+                    Self::adapters(ui, common);
+                    return;
+                }
+                ObdStatus::Failed(e) => {
+                    ui.heading("OBD-II connection failed");
+                    ui.colored_label(egui::Color32::RED, e);
+                    Self::adapters(ui, common);
+                    return;
+                }
+                ObdStatus::Connecting(addr) => {
+                    ui.horizontal(|ui| {
+                        ui.spinner();
+                        ui.label(format!(
+                            "Connecting to {}",
+                            common.bluetooth.phone_name(addr)
+                        ));
+                    });
+                    return;
+                }
+                ObdStatus::Connected(addr, version) => {
+                    ui.horizontal(|ui| {
+                        ui.label(format!(
+                            "{} ({})",
+                            common.bluetooth.phone_name(addr),
+                            version
+                        ));
+                        if ui.button("Disconnect").clicked() {
+                            let _ = common.tx.blocking_send(MessageToAsync::ObdDisconnect);
+                        }
+                    });
+                }
+            }
+            egui::Grid::new("vehicle data")
+                .min_col_width(200.0)
+                .show(ui, |ui| {
+                    for pid in Pid::ALL {
+                        let (name, unit) = pid_label(pid);
+                        ui.label(egui::RichText::new(name).font(egui::FontId::proportional(32.0)));
+                        let value = match data.value(pid) {
+                            Some(v) if pid == Pid::Maf => format!("{:.1} {}", v, unit),
+                            Some(v) => format!("{:.0} {}", v, unit),
+                            None => "--".to_string(),
+                        };
+                        ui.label(egui::RichText::new(value).font(egui::FontId::proportional(48.0)));
+                        ui.end_row();
+                    }
+                });
+            ui.separator();
+            ui.horizontal(|ui| {
+                ui.heading("Trouble codes");
+                if ui.button("Read codes").clicked() {
+                    let _ = common.tx.blocking_send(MessageToAsync::ObdReadCodes);
+                }
+            });
+            match &data.dtcs {
+                None => {
+                    ui.label("Not read yet");
+                }
+                Some(d) if d.is_empty() => {
+                    ui.label("No trouble codes");
+                }
+                Some(d) => {
+                    for c in d {
+                        ui.colored_label(egui::Color32::YELLOW, c);
+                    }
+                }
+            }
+        });
+        None
+    }
+}
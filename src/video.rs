@@ -1,3 +1,7 @@
+use std::ffi::CString;
+use std::io::Read;
+use std::os::fd::FromRawFd;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::sync::Mutex;
 
@@ -476,31 +480,178 @@ impl VideoFrame {
 }
 
 pub struct VideoSource {
+    /// The device node, like /dev/video0
+    pub path: PathBuf,
+    /// The card name the driver reports
+    pub name: String,
+    /// Where the device is connected, like usb-0000:00:14.0-1
+    pub bus: String,
     pub image: Arc<Mutex<VideoFrame>>,
     pub vsend: std::sync::mpsc::Sender<VideoMessage>,
     pub controls: Vec<ControlElement>,
 }
 
+impl VideoSource {
+    pub fn label(&self) -> String {
+        format!("{} ({})", self.name, self.bus)
+    }
+}
+
 impl Drop for VideoSource {
     fn drop(&mut self) {
-        self.vsend.send(VideoMessage::Quit).unwrap();
+        // The capture thread is already gone when the device was unplugged
+        let _ = self.vsend.send(VideoMessage::Quit);
+    }
+}
+
+/// A video device node appearing or going away
+pub enum Hotplug {
+    Added(PathBuf),
+    Removed(PathBuf),
+}
+
+/// The video device nodes in /dev, in number order
+pub fn video_devices() -> Vec<PathBuf> {
+    let Ok(entries) = std::fs::read_dir("/dev") else {
+        return Vec::new();
+    };
+    let mut devices: Vec<(u32, PathBuf)> = entries
+        .filter_map(|e| e.ok())
+        .filter_map(|e| {
+            let n = e
+                .file_name()
+                .to_str()?
+                .strip_prefix("video")?
+                .parse()
+                .ok()?;
+            Some((n, e.path()))
+        })
+        .collect();
+    devices.sort();
+    devices.into_iter().map(|(_, p)| p).collect()
+}
+
+/// Split the events read from inotify into their masks and file names
+fn inotify_events(mut data: &[u8]) -> Vec<(u32, String)> {
+    let header = std::mem::size_of::<libc::inotify_event>();
+    let mut out = Vec::new();
+    while data.len() >= header {
+        let mask = u32::from_ne_bytes([data[4], data[5], data[6], data[7]]);
+        let len = u32::from_ne_bytes([data[12], data[13], data[14], data[15]]) as usize;
+        let Some(name) = data.get(header..header + len) else {
+            break;
+        };
+        // The name is padded with nul bytes
+        let name = name.split(|b| *b == 0).next().unwrap_or_default();
+        out.push((mask, String::from_utf8_lossy(name).into_owned()));
+        data = &data[header + len..];
+    }
+    out
+}
+
+/// Watch /dev for video devices being plugged in and removed
+pub fn watch_devices() -> std::sync::mpsc::Receiver<Hotplug> {
+    let (s, r) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+        if fd < 0 {
+            println!(
+                "Unable to watch for cameras {}",
+                std::io::Error::last_os_error()
+            );
+            return;
+        }
+        let mut file = unsafe { std::fs::File::from_raw_fd(fd) };
+        let dev = CString::new("/dev").unwrap();
+        // Udev sets the permissions after creating a node, so changes are watched too
+        let mask = libc::IN_CREATE | libc::IN_DELETE | libc::IN_ATTRIB;
+        if unsafe { libc::inotify_add_watch(fd, dev.as_ptr(), mask) } < 0 {
+            println!(
+                "Unable to watch for cameras {}",
+                std::io::Error::last_os_error()
+            );
+            return;
+        }
+        let mut buf = [0u8; 4096];
+        loop {
+            let n = match file.read(&mut buf) {
+                Ok(n) => n,
+                Err(e) => {
+                    println!("Stopped watching for cameras {}", e);
+                    return;
+                }
+            };
+            for (mask, name) in inotify_events(&buf[..n]) {
+                if !name.starts_with("video") {
+                    continue;
+                }
+                let path = Path::new("/dev").join(name);
+                let e = if mask & libc::IN_DELETE != 0 {
+                    Hotplug::Removed(path)
+                } else {
+                    Hotplug::Added(path)
+                };
+                if s.send(e).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+    r
+}
+
+/// Start capturing from cameras that were plugged in and stop the ones that were removed
+pub fn hotplug(common: &mut CommonWindowProperties) {
+    while let Ok(e) = common.video_events.try_recv() {
+        match e {
+            Hotplug::Added(p) => {
+                if common.video_sources.iter().any(|v| v.path == p) {
+                    continue;
+                }
+                match Video::video_start(&p) {
+                    Ok(v) => {
+                        println!("Camera {} added at {}", v.label(), p.display());
+                        common.video_sources.push(v);
+                    }
+                    Err(e) => println!("Not using {} as a camera: {}", p.display(), e),
+                }
+            }
+            Hotplug::Removed(p) => {
+                common.video_sources.retain(|v| v.path != p);
+            }
+        }
     }
 }
 
 pub struct Video {
-    which_video: usize,
+    /// The device node of the camera being shown
+    which_video: PathBuf,
     texture: Option<egui::TextureHandle>,
+    /// The device the texture was made for
+    texture_path: Option<PathBuf>,
 }
 
 impl Video {
-    pub fn video_start(mut dev: Device) -> VideoSource {
+    /// Start capturing from a device, if it is a camera
+    pub fn video_start(path: &Path) -> std::io::Result<VideoSource> {
+        let mut dev = Device::with_path(path)?;
+        let caps = dev.query_caps()?;
+        if !caps
+            .capabilities
+            .contains(v4l::capability::Flags::VIDEO_CAPTURE)
+        {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "Not a video capture device",
+            ));
+        }
         let image = Arc::new(Mutex::new(VideoFrame::new()));
         let (a, b) = std::sync::mpsc::channel();
         let i2 = image.clone();
-        let mut fmt = dev.format().expect("Failed to read format");
+        let mut fmt = dev.format()?;
         let controls: Vec<ControlElement> = dev
             .query_controls()
-            .unwrap()
+            .unwrap_or_default()
             .iter()
             .filter_map(|c| ControlElement::new(c, dev.control(c.id).ok().map(|a| a.value)).ok())
             .collect();
@@ -508,7 +659,13 @@ impl Video {
             fmt.width = 320;
             fmt.height = 240;
             fmt.fourcc = FourCC::new(b"YUYV");
-            let fmt = dev.set_format(&fmt).expect("Failed to write format");
+            let fmt = match dev.set_format(&fmt) {
+                Ok(f) => f,
+                Err(e) => {
+                    println!("Failed to set the video format {}", e);
+                    return;
+                }
+            };
 
             if let Ok(mut i) = i2.lock() {
                 i.width = fmt.width as u16;
@@ -522,10 +679,21 @@ impl Video {
                 "Video framesizes YUYV: {:?}",
                 dev.enum_framesizes(FourCC::new(b"YUYV"))
             );
-            let mut stream = MmapStream::with_buffers(&mut dev, Type::VideoCapture, 4)
-                .expect("Failed to create video buffer stream");
+            let mut stream = match MmapStream::with_buffers(&mut dev, Type::VideoCapture, 4) {
+                Ok(s) => s,
+                Err(e) => {
+                    println!("Failed to create video buffer stream {}", e);
+                    return;
+                }
+            };
             loop {
-                let (buf, meta) = stream.next().unwrap();
+                let (buf, meta) = match stream.next() {
+                    Ok(f) => f,
+                    Err(e) => {
+                        println!("Video capture stopped {}", e);
+                        break;
+                    }
+                };
                 if let Ok(mut i) = i2.lock() {
                     i.pixel_data = Some(PixelData::Yuyv(buf.to_vec()).to_rgb());
                     i.mirroring();
@@ -540,17 +708,21 @@ impl Video {
                 }
             }
         });
-        VideoSource {
+        Ok(VideoSource {
+            path: path.to_path_buf(),
+            name: caps.card,
+            bus: caps.bus,
             image,
             vsend: a,
             controls,
-        }
+        })
     }
 
-    pub fn new() -> Self {
+    pub fn new(which_video: PathBuf) -> Self {
         Self {
-            which_video: 0,
+            which_video,
             texture: None,
+            texture_path: None,
         }
     }
 }
@@ -566,7 +738,18 @@ impl SubwindowTrait for Video {
             egui::ScrollArea::vertical().show(ui, |ui| {
                 ui.label("This is the video page");
                 let mut size = ui.available_size();
-                let vsrc = &mut common.video_sources[self.which_video];
+                let Some(vsrc) = common
+                    .video_sources
+                    .iter_mut()
+                    .find(|v| v.path == self.which_video)
+                else {
+                    ui.label("The camera was disconnected");
+                    return;
+                };
+                if self.texture_path.as_ref() != Some(&vsrc.path) {
+                    self.texture = None;
+                    self.texture_path = Some(vsrc.path.clone());
+                }
                 if let Ok(i) = vsrc.image.lock() {
                     if let Some(pd) = &i.pixel_data {
                         let zoom = (size.x / (i.width as f32)).min(size.y / (i.height as f32));
@@ -600,3 +783,57 @@ impl SubwindowTrait for Video {
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An inotify_event as the kernel packs it, with the name padded to `len` bytes
+    fn event(mask: u32, name: &str, len: usize) -> Vec<u8> {
+        let mut e = Vec::new();
+        e.extend_from_slice(&1i32.to_ne_bytes());
+        e.extend_from_slice(&mask.to_ne_bytes());
+        e.extend_from_slice(&0u32.to_ne_bytes());
+        e.extend_from_slice(&(len as u32).to_ne_bytes());
+        e.extend_from_slice(name.as_bytes());
+        e.resize(std::mem::size_of::<libc::inotify_event>() + len, 0);
+        e
+    }
+
+    #[test]
+    fn events() {
+        let mut data = event(libc::IN_CREATE, "video0", 16);
+        data.extend(event(libc::IN_ATTRIB, "video0", 16));
+        data.extend(event(libc::IN_DELETE, "video12", 8));
+        data.extend(event(libc::IN_CREATE, "", 0));
+        assert_eq!(
+            inotify_events(&data),
+            vec![
+                (libc::IN_CREATE, "video0".to_string()),
+                (libc::IN_ATTRIB, "video0".to_string()),
+                (libc::IN_DELETE, "video12".to_string()),
+                (libc::IN_CREATE, String::new()),
+            ]
+        );
+    }
+
+    #[test]
+    fn truncated() {
+        let mut data = event(libc::IN_CREATE, "video0", 16);
+        let second = event(libc::IN_DELETE, "video1", 16);
+        // A name cut short
+        let mut cut = data.clone();
+        cut.extend_from_slice(&second[..second.len() - 4]);
+        assert_eq!(
+            inotify_events(&cut),
+            vec![(libc::IN_CREATE, "video0".to_string())]
+        );
+        // A header cut short
+        data.extend_from_slice(&second[..10]);
+        assert_eq!(
+            inotify_events(&data),
+            vec![(libc::IN_CREATE, "video0".to_string())]
+        );
+        assert!(inotify_events(&[]).is_empty());
+    }
+}